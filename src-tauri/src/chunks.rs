@@ -0,0 +1,362 @@
+use crate::config::Config;
+use crate::delta::PARTIAL_SUFFIX;
+use crate::error::FileTrackerError;
+use fastcdc::v2020::StreamCDC;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+/// Chunk size bounds for content-defined chunking. Boundaries depend on the contents, so an
+/// insertion only changes the chunks around it.
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+/// Entries younger than this survive garbage collection, so a manifest stored by a sync round
+/// whose state is not saved yet, or for a version whose index is not written yet, is not
+/// reclaimed under it.
+pub const GC_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Keeps garbage collection from running while files are stored: a stored file may reuse an
+/// old chunk that a collection already decided to remove.
+static STORE_LOCK: RwLock<()> = RwLock::new(());
+
+/// One chunk of a file, identified by the BLAKE3 digest of its bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub length: u64,
+}
+
+/// A file's contents as the ordered list of its chunks.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+/// What a garbage collection reclaimed.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct GcReport {
+    pub manifests_removed: usize,
+    pub chunks_removed: usize,
+    pub bytes_freed: u64,
+}
+
+/// Splits a file into content-defined chunks, returning its BLAKE3 digest (which also
+/// identifies its manifest) and its manifest, read in a single pass.
+pub fn digest_file(path: &Path) -> Result<(String, Manifest), FileTrackerError> {
+    let mut manifest = Manifest::default();
+    let mut hasher = blake3::Hasher::new();
+    for_each_chunk(path, |data| {
+        hasher.update(data);
+        manifest.size += data.len() as u64;
+        manifest.chunks.push(ChunkRef {
+            hash: blake3::hash(data).to_hex().to_string(),
+            length: data.len() as u64,
+        });
+        Ok(())
+    })?;
+    Ok((hasher.finalize().to_hex().to_string(), manifest))
+}
+
+fn for_each_chunk(
+    path: &Path,
+    mut handle: impl FnMut(&[u8]) -> Result<(), FileTrackerError>,
+) -> Result<(), FileTrackerError> {
+    let reader = BufReader::new(File::open(path)?);
+    for chunk in StreamCDC::new(reader, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let chunk = chunk.map_err(io::Error::from)?;
+        handle(&chunk.data)?;
+    }
+    Ok(())
+}
+
+/// Deduplicated storage of file contents, shared by every root under `chunks` in the app data
+/// directory. Chunks are stored once by digest in `data/`; manifests are kept in `index.sqlite3`,
+/// keyed by the digest of the whole file.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new(config: &Config) -> Self {
+        ChunkStore {
+            dir: config.data_dir.join("chunks"),
+        }
+    }
+
+    fn connection(&self) -> Result<Connection, FileTrackerError> {
+        fs::create_dir_all(&self.dir)?;
+        let connection = Connection::open(self.dir.join("index.sqlite3"))?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS manifests (
+                 id TEXT PRIMARY KEY,
+                 manifest TEXT NOT NULL,
+                 recorded_at INTEGER NOT NULL
+             ) WITHOUT ROWID;",
+        )?;
+        Ok(connection)
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("data").join(&hash[..2]).join(hash)
+    }
+
+    fn record_manifest(&self, id: &str, manifest: &Manifest) -> Result<(), FileTrackerError> {
+        self.connection()?.execute(
+            "INSERT INTO manifests (id, manifest, recorded_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET recorded_at = excluded.recorded_at",
+            params![id, serde_json::to_string(manifest)?, unix_secs(SystemTime::now())],
+        )?;
+        Ok(())
+    }
+
+    /// Stores the contents of a file, writing only the chunks not already present.
+    /// Returns the identifier of its manifest.
+    pub fn store_file(&self, path: &Path) -> Result<String, FileTrackerError> {
+        let _guard = STORE_LOCK.read().unwrap_or_else(|e| e.into_inner());
+        let mut manifest = Manifest::default();
+        let mut hasher = blake3::Hasher::new();
+        let mut written = 0;
+        for_each_chunk(path, |data| {
+            let hash = blake3::hash(data).to_hex().to_string();
+            let chunk_path = self.chunk_path(&hash);
+            if !chunk_path.exists() {
+                if let Some(parent) = chunk_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                write_chunk(&chunk_path, data)?;
+                written += data.len();
+            }
+            hasher.update(data);
+            manifest.size += data.len() as u64;
+            manifest.chunks.push(ChunkRef {
+                hash,
+                length: data.len() as u64,
+            });
+            Ok(())
+        })?;
+
+        let id = hasher.finalize().to_hex().to_string();
+        self.record_manifest(&id, &manifest)?;
+        log::info!("Stored {} in the chunk store ({} new bytes)", path.display(), written);
+        Ok(id)
+    }
+
+    /// Looks up a manifest by identifier.
+    pub fn manifest(&self, id: &str) -> Result<Manifest, FileTrackerError> {
+        let json: Option<String> = self
+            .connection()?
+            .query_row("SELECT manifest FROM manifests WHERE id = ?1", [id], |row| row.get(0))
+            .optional()?;
+        let json = json.ok_or_else(|| FileTrackerError::ChunkStoreError(format!("unknown manifest {}", id)))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Reads a stored chunk, or `None` if it is missing or corrupt.
+    pub fn read_chunk(&self, hash: &str) -> Option<Vec<u8>> {
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let data = fs::read(self.chunk_path(hash)).ok()?;
+        (blake3::hash(&data).to_hex().as_str() == hash).then_some(data)
+    }
+
+    /// Rebuilds a stored file at `target`. Every chunk is verified against its digest, and the
+    /// file is only moved into place once complete.
+    pub fn restore(&self, id: &str, target: &Path) -> Result<(), FileTrackerError> {
+        let manifest = self.manifest(id)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let partial = target.with_file_name(format!(".{}{}", name, PARTIAL_SUFFIX));
+
+        let result = (|| {
+            let mut output = BufWriter::new(File::create(&partial)?);
+            for chunk in &manifest.chunks {
+                let data = fs::read(self.chunk_path(&chunk.hash)).map_err(|e| {
+                    FileTrackerError::ChunkStoreError(format!("chunk {} is unreadable: {}", chunk.hash, e))
+                })?;
+                if blake3::hash(&data).to_hex().as_str() != chunk.hash {
+                    return Err(FileTrackerError::ChunkStoreError(format!("chunk {} is corrupt", chunk.hash)));
+                }
+                output.write_all(&data)?;
+            }
+            output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&partial, target)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result
+    }
+
+    /// Removes the manifests not in `live` and the chunks no remaining manifest uses.
+    /// Anything recorded or written within [`GC_GRACE_PERIOD`] is kept.
+    pub fn gc(&self, live: &HashSet<String>) -> Result<GcReport, FileTrackerError> {
+        let _guard = STORE_LOCK.write().unwrap_or_else(|e| e.into_inner());
+        let mut report = GcReport::default();
+        let cutoff = SystemTime::now().checked_sub(GC_GRACE_PERIOD).unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        let mut referenced = HashSet::new();
+        let mut expired = Vec::new();
+        {
+            let mut statement = transaction.prepare("SELECT id, manifest, recorded_at FROM manifests")?;
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(0)?;
+                let recorded_at: u64 = row.get(2)?;
+                if live.contains(&id) || recorded_at >= unix_secs(cutoff) {
+                    let manifest: Manifest = serde_json::from_str(&row.get::<_, String>(1)?)?;
+                    referenced.extend(manifest.chunks.into_iter().map(|chunk| chunk.hash));
+                } else {
+                    expired.push(id);
+                }
+            }
+        }
+        for id in &expired {
+            transaction.execute("DELETE FROM manifests WHERE id = ?1", [id])?;
+        }
+        transaction.commit()?;
+        report.manifests_removed = expired.len();
+
+        let data_dir = self.dir.join("data");
+        if data_dir.exists() {
+            for entry in walkdir::WalkDir::new(&data_dir).min_depth(2).max_depth(2) {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                let name = entry.file_name().to_string_lossy();
+                if referenced.contains(name.as_ref()) || metadata.modified()? >= cutoff {
+                    continue;
+                }
+                fs::remove_file(entry.path())?;
+                report.chunks_removed += 1;
+                report.bytes_freed += metadata.len();
+            }
+        }
+        log::info!(
+            "Chunk store garbage collection removed {} manifest(s) and {} chunk(s), freeing {} bytes",
+            report.manifests_removed,
+            report.chunks_removed,
+            report.bytes_freed
+        );
+        Ok(report)
+    }
+}
+
+/// Writes a chunk under a temporary name of its own, then moves it into place. Chunks are
+/// named by their contents, so a copy another writer put there in the meantime is as good.
+fn write_chunk(path: &Path, data: &[u8]) -> Result<(), FileTrackerError> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let index = NEXT.fetch_add(1, Ordering::Relaxed);
+    let temp = path.with_file_name(format!("{}.{}-{}.tmp", name, std::process::id(), index));
+    let result = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    match result {
+        Ok(()) => Ok(()),
+        Err(_) if path.exists() => {
+            let _ = fs::remove_file(&temp);
+            Ok(())
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e.into())
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestDir;
+
+    /// Makes everything in the store look older than the grace period.
+    fn age(store: &ChunkStore) {
+        let past = SystemTime::now() - GC_GRACE_PERIOD * 2;
+        store
+            .connection()
+            .unwrap()
+            .execute("UPDATE manifests SET recorded_at = ?1", [unix_secs(past)])
+            .unwrap();
+        for entry in walkdir::WalkDir::new(store.dir.join("data")).min_depth(2).max_depth(2) {
+            File::options().write(true).open(entry.unwrap().path()).unwrap().set_modified(past).unwrap();
+        }
+    }
+
+    #[test]
+    fn stored_files_are_restored_and_collected() {
+        let test_dir = TestDir::new("chunks");
+        let dir = test_dir.path();
+        let store = ChunkStore::new(&test_dir.config());
+        let shared: Vec<u8> = (0..300_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        let kept = dir.join("kept.bin");
+        let dropped = dir.join("dropped.bin");
+        fs::write(&kept, &shared).unwrap();
+        fs::write(&dropped, [&shared[..], b"and a different tail"].concat()).unwrap();
+
+        let kept_id = store.store_file(&kept).unwrap();
+        let dropped_id = store.store_file(&dropped).unwrap();
+        assert_eq!(kept_id, digest_file(&kept).unwrap().0);
+        let restored = dir.join("restored").join("kept.bin");
+        store.restore(&kept_id, &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), shared);
+
+        // Nothing is reclaimed within the grace period.
+        let report = store.gc(&HashSet::from([kept_id.clone()])).unwrap();
+        assert_eq!((report.manifests_removed, report.chunks_removed), (0, 0));
+
+        age(&store);
+        let report = store.gc(&HashSet::from([kept_id.clone()])).unwrap();
+        assert_eq!(report.manifests_removed, 1);
+        assert!(report.chunks_removed >= 1);
+        assert!(store.manifest(&dropped_id).is_err());
+        store.restore(&kept_id, &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), shared);
+    }
+
+    #[test]
+    fn the_same_file_can_be_stored_concurrently() {
+        let test_dir = TestDir::new("chunks-concurrent");
+        let dir = test_dir.path();
+        let store = ChunkStore::new(&test_dir.config());
+        let contents: Vec<u8> = (0..500_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u8).collect();
+        let file = dir.join("file.bin");
+        fs::write(&file, &contents).unwrap();
+
+        let ids: Vec<String> = std::thread::scope(|scope| {
+            let stores: Vec<_> = (0..4).map(|_| scope.spawn(|| store.store_file(&file).unwrap())).collect();
+            stores.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        assert!(ids.iter().all(|id| *id == ids[0]));
+        let leftovers = walkdir::WalkDir::new(dir.join("data"))
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".tmp"))
+            .count();
+        assert_eq!(leftovers, 0);
+        store.restore(&ids[0], &dir.join("restored.bin")).unwrap();
+        assert_eq!(fs::read(dir.join("restored.bin")).unwrap(), contents);
+    }
+}
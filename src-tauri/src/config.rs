@@ -1,12 +1,47 @@
+use crate::conflicts::ConflictPolicy;
+use crate::error::FileTrackerError;
+use crate::persistence;
+use crate::versions::VersionRetention;
+use serde::{Deserialize, Serialize};
+use std::fs;
 use std::path::PathBuf;
+use tokio::sync::watch;
 
 /// Configuration module for the file monitoring application.
-#[derive(Debug, Clone)]
+///
+/// User-editable settings are persisted in `config.toml` in the app data directory;
+/// the paths are derived at runtime and never written to the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    /// Interval for checking file changes (in seconds).
+    /// Interval for the full rescan that reconciles missed watcher events (in seconds).
     pub sync_interval_secs: u64,
-    /// Path to the state file for persisting FileTracker data.
+    /// Time window used to group bursts of filesystem events (in milliseconds).
+    pub watch_debounce_ms: u64,
+    /// Directory holding the application's persistent files.
+    #[serde(skip)]
+    pub data_dir: PathBuf,
+    /// Path to the state file for persisting FileTracker data. Per-root configurations
+    /// (see `MonitoredRoot::config`) point it at that root's own state file.
+    #[serde(skip)]
     pub state_file_path: String,
+    /// Gitignore-style patterns excluded from every monitored folder, on top of `.egadignore` files.
+    pub ignore_patterns: Vec<String>,
+    /// How paths changed on both sides of a two-way sync are settled.
+    pub conflict_policy: ConflictPolicy,
+    /// Previous versions kept for files overwritten or deleted by sync.
+    pub version_retention: VersionRetention,
+    /// Days a path deleted by sync stays in the trash before it is purged.
+    pub trash_purge_after_days: u64,
+    /// Name other devices see when pairing with this one.
+    pub device_name: String,
+    /// TCP port on which paired devices connect to sync folders directly. Device sync is off
+    /// when unset; changes apply on the next launch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_port: Option<u16>,
+    /// Announce this device and look for others on the local network over mDNS. Off by
+    /// default; changes apply on the next launch.
+    pub discovery_enabled: bool,
 }
 
 impl Config {
@@ -14,35 +49,186 @@ impl Config {
     pub fn get_app_data_dir() -> Result<PathBuf, std::io::Error> {
         let data_dir = dirs::data_dir()
             .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Could not find data directory"))?;
-        
+
         let app_dir = data_dir.join("egadsync");
-        
+
         // Create the directory if it doesn't exist
         if !app_dir.exists() {
             std::fs::create_dir_all(&app_dir)?;
         }
-        
+
         Ok(app_dir)
     }
-    
+
+    /// Patterns ignored out of the box: VCS metadata, dependency folders and editor/temporary files.
+    pub fn default_ignore_patterns() -> Vec<String> {
+        [".git/", "node_modules/", "*.swp", "*.swo", "*~", "*.tmp", ".DS_Store"]
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .collect()
+    }
+
+    /// The machine's host name.
+    pub fn default_device_name() -> String {
+        gethostname::gethostname().to_string_lossy().to_string()
+    }
+
     /// Creates a new Config with a secure state file path
     pub fn new() -> Result<Self, std::io::Error> {
-        let app_data_dir = Self::get_app_data_dir()?;
-        let state_file_path = app_data_dir.join("state.json");
-        
-        Ok(Config {
+        Ok(Self::with_data_dir(Self::get_app_data_dir()?))
+    }
+
+    /// The default settings, keeping the application's files in `data_dir`.
+    fn with_data_dir(data_dir: PathBuf) -> Self {
+        let state_file_path = data_dir.join("state.json");
+        Config {
             sync_interval_secs: 60,
+            watch_debounce_ms: 500,
+            data_dir,
             state_file_path: state_file_path.to_string_lossy().to_string(),
-        })
+            ignore_patterns: Self::default_ignore_patterns(),
+            conflict_policy: ConflictPolicy::default(),
+            version_retention: VersionRetention::default(),
+            trash_purge_after_days: 30,
+            device_name: Self::default_device_name(),
+            peer_port: None,
+            discovery_enabled: false,
+        }
+    }
+
+    /// Path of the persisted configuration file.
+    pub fn config_file_path(&self) -> PathBuf {
+        self.data_dir.join("config.toml")
+    }
+
+    /// Loads `config.toml`, writing the defaults on first launch.
+    /// Unknown keys and invalid values are reported instead of being replaced by defaults.
+    pub fn load() -> Result<Self, FileTrackerError> {
+        let defaults = Config::default();
+        let path = defaults.config_file_path();
+        if !path.exists() {
+            defaults.save()?;
+            return Ok(defaults);
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        let mut config: Config = toml::from_str(&contents)?;
+        config.data_dir = defaults.data_dir;
+        config.state_file_path = defaults.state_file_path;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Validates and writes the settings to `config.toml`.
+    pub fn save(&self) -> Result<(), FileTrackerError> {
+        self.validate()?;
+        let contents = toml::to_string_pretty(self).map_err(|e| FileTrackerError::InvalidConfig(e.to_string()))?;
+        persistence::write_atomic(&self.config_file_path(), contents.as_bytes())?;
+        log::info!("Saved configuration to {}", self.config_file_path().display());
+        Ok(())
+    }
+
+    /// Checks that every setting is within its accepted range.
+    pub fn validate(&self) -> Result<(), FileTrackerError> {
+        if !(1..=86_400).contains(&self.sync_interval_secs) {
+            return Err(FileTrackerError::InvalidConfig(
+                "sync_interval_secs must be between 1 and 86400".to_string(),
+            ));
+        }
+        if !(50..=60_000).contains(&self.watch_debounce_ms) {
+            return Err(FileTrackerError::InvalidConfig(
+                "watch_debounce_ms must be between 50 and 60000".to_string(),
+            ));
+        }
+        if self.version_retention.keep_last > 1_000 || self.version_retention.keep_daily_days > 3_650 {
+            return Err(FileTrackerError::InvalidConfig(
+                "version_retention allows at most 1000 versions and 3650 days".to_string(),
+            ));
+        }
+        if !(1..=3_650).contains(&self.trash_purge_after_days) {
+            return Err(FileTrackerError::InvalidConfig(
+                "trash_purge_after_days must be between 1 and 3650".to_string(),
+            ));
+        }
+        if self.device_name.trim().is_empty() || self.device_name.chars().count() > 64 {
+            return Err(FileTrackerError::InvalidConfig(
+                "device_name must have between 1 and 64 characters".to_string(),
+            ));
+        }
+        if self.peer_port == Some(0) {
+            return Err(FileTrackerError::InvalidConfig("peer_port must be between 1 and 65535".to_string()));
+        }
+        let mut builder = ignore::gitignore::GitignoreBuilder::new(&self.data_dir);
+        for pattern in &self.ignore_patterns {
+            builder
+                .add_line(None, pattern)
+                .map_err(|e| FileTrackerError::InvalidConfig(format!("invalid ignore pattern {:?}: {}", pattern, e)))?;
+        }
+        Ok(())
     }
 }
 
 impl Default for Config {
     fn default() -> Self {
         // Use the secure configuration by default, fallback to current directory if it fails
-        Self::new().unwrap_or_else(|_| Config {
-            sync_interval_secs: 60,
-            state_file_path: "./state.json".to_string(),
-        })
+        Self::new().unwrap_or_else(|_| Self::with_data_dir(PathBuf::from(".")))
+    }
+}
+
+/// The running configuration, shared with the sync loops so they pick up changes.
+pub struct SharedConfig(watch::Sender<Config>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        SharedConfig(watch::Sender::new(config))
+    }
+
+    /// Returns a copy of the current configuration.
+    pub fn get(&self) -> Config {
+        self.0.borrow().clone()
+    }
+
+    /// Validates, persists and publishes a new configuration.
+    pub fn update(&self, config: Config) -> Result<(), FileTrackerError> {
+        config.save()?;
+        self.0.send_replace(config);
+        Ok(())
+    }
+
+    /// Subscribes to configuration changes.
+    pub fn subscribe(&self) -> watch::Receiver<Config> {
+        self.0.subscribe()
+    }
+}
+
+/// A scratch directory for a test, removed when dropped, so also when the test panics.
+#[cfg(test)]
+pub struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    /// Creates an empty directory named after `name` and this process.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("egadsync-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+
+    /// Default settings with every file the app keeps inside this directory, built without
+    /// touching the real app data directory.
+    pub fn config(&self) -> Config {
+        Config::with_data_dir(self.0.join("data"))
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
     }
 }
@@ -0,0 +1,330 @@
+use crate::config::Config;
+use crate::error::FileTrackerError;
+use crate::file_tracker::FileTracker;
+use crate::mirror;
+use crate::persistence;
+use crate::roots::MonitoredRoot;
+use crate::trash::Trash;
+use crate::two_way::Conflict;
+use crate::versions::VersionHistory;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// How conflicts found during two-way sync are settled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// The side modified last wins. When one side deleted the path, the surviving copy wins.
+    NewestWins,
+    /// The monitored folder always wins.
+    SourceWins,
+    /// Both versions are kept; the older one is renamed to `name (conflict YYYY-MM-DD host).ext`.
+    KeepBoth,
+    /// Conflicts stay pending until the user resolves them.
+    #[default]
+    AskUser,
+}
+
+impl ConflictPolicy {
+    /// Picks the resolution for a conflict, or `None` when the user has to decide.
+    pub fn resolution_for(&self, conflict: &Conflict) -> Option<Resolution> {
+        match self {
+            ConflictPolicy::NewestWins => Some(match (conflict.source_modified, conflict.destination_modified) {
+                (Some(source), Some(destination)) if destination > source => Resolution::KeepDestination,
+                (None, Some(_)) => Resolution::KeepDestination,
+                _ => Resolution::KeepSource,
+            }),
+            ConflictPolicy::SourceWins => Some(Resolution::KeepSource),
+            ConflictPolicy::KeepBoth => Some(Resolution::KeepBoth),
+            ConflictPolicy::AskUser => None,
+        }
+    }
+}
+
+/// How a single conflict is resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// Makes the destination match the monitored folder.
+    KeepSource,
+    /// Makes the monitored folder match the destination.
+    KeepDestination,
+    /// Keeps both versions on both sides under different names.
+    KeepBoth,
+}
+
+/// A pending conflict together with the root it belongs to.
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingConflict {
+    pub root_id: String,
+    #[serde(flatten)]
+    pub conflict: Conflict,
+}
+
+/// The unresolved conflicts of one root, persisted next to its state.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ConflictLog {
+    pub conflicts: Vec<Conflict>,
+}
+
+impl ConflictLog {
+    /// Loads the conflicts of a root; a root without a conflict file has none.
+    pub fn load(root: &MonitoredRoot, base: &Config) -> Result<Self, FileTrackerError> {
+        let path = root.conflicts_path(base);
+        if !path.exists() {
+            return Ok(ConflictLog::default());
+        }
+        let mut file = File::open(&path)?;
+        let mut json_data = String::new();
+        file.read_to_string(&mut json_data)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+
+    pub fn save(&self, root: &MonitoredRoot, base: &Config) -> Result<(), FileTrackerError> {
+        let json = serde_json::to_string_pretty(self)?;
+        persistence::write_atomic(&root.conflicts_path(base), json.as_bytes())?;
+        Ok(())
+    }
+
+    /// Adds conflicts, replacing older entries for the same path.
+    pub fn record(&mut self, conflicts: Vec<Conflict>) {
+        for conflict in conflicts {
+            self.conflicts.retain(|pending| pending.path != conflict.path);
+            self.conflicts.push(conflict);
+        }
+    }
+
+    /// Removes and returns the conflict for a path.
+    pub fn take(&mut self, path: &Path) -> Result<Conflict, FileTrackerError> {
+        let index = self
+            .conflicts
+            .iter()
+            .position(|conflict| conflict.path == path)
+            .ok_or(FileTrackerError::ConflictNotFound)?;
+        Ok(self.conflicts.remove(index))
+    }
+
+    /// Paths that must not be synchronized until their conflict is resolved.
+    pub fn blocked_paths(&self) -> HashSet<PathBuf> {
+        self.conflicts.iter().map(|conflict| conflict.path.clone()).collect()
+    }
+}
+
+/// Applies a resolution to both sides, then rescans the affected paths so neither
+/// tracker reports the resolution as a new change. Whatever a side loses is saved to
+/// `history` first.
+pub async fn resolve(
+    source: &mut FileTracker,
+    destination: &mut FileTracker,
+    relative: &Path,
+    resolution: Resolution,
+    history: &VersionHistory,
+) -> Result<(), FileTrackerError> {
+    let source_path = source.root_target.join(relative);
+    let destination_path = destination.root_target.join(relative);
+    log::info!("Resolving conflict on {} with {:?}", relative.display(), resolution);
+
+    let touched = tokio::task::spawn_blocking({
+        let (source_root, destination_root) = (source.root_target.clone(), destination.root_target.clone());
+        let (relative, history) = (relative.to_path_buf(), history.clone());
+        move || apply(&source_root, &destination_root, resolution, &relative, &history)
+    })
+    .await??;
+
+    let mut source_paths = vec![source_path];
+    let mut destination_paths = vec![destination_path];
+    if let Some(copy_name) = touched {
+        source_paths.push(source.root_target.join(relative.with_file_name(&copy_name)));
+        destination_paths.push(destination.root_target.join(relative.with_file_name(&copy_name)));
+    }
+    source.diff_paths(source_paths).await?;
+    destination.diff_paths(destination_paths).await?;
+    Ok(())
+}
+
+/// Performs the filesystem side of a resolution of the conflict at `relative` between the folders
+/// `source_root` and `destination_root`. Returns the name of the conflict copy, if one was made.
+fn apply(
+    source_root: &Path,
+    destination_root: &Path,
+    resolution: Resolution,
+    relative: &Path,
+    history: &VersionHistory,
+) -> Result<Option<String>, FileTrackerError> {
+    let (source, destination) = (&source_root.join(relative), &destination_root.join(relative));
+    let source_modified = modified(source)?;
+    let destination_modified = modified(destination)?;
+
+    match resolution {
+        Resolution::KeepSource => replace(source, destination_root, relative, history).map(|_| None),
+        Resolution::KeepDestination => replace(destination, source_root, relative, history).map(|_| None),
+        Resolution::KeepBoth => match (source_modified, destination_modified) {
+            (Some(source_time), Some(destination_time)) => {
+                let (winner, loser) = if destination_time > source_time {
+                    (destination, source)
+                } else {
+                    (source, destination)
+                };
+                // The copy is made on both sides, so its name must be free on both.
+                let copy = conflict_copy_path(loser, SystemTime::now(), |candidate| {
+                    candidate.exists() || winner.with_file_name(candidate.file_name().unwrap_or_default()).exists()
+                });
+                let copy_name = copy.file_name().unwrap_or_default();
+                let other_side_copy = winner.with_file_name(copy_name);
+                history.preserve(relative, loser)?;
+                history.preserve(&relative.with_file_name(copy_name), &other_side_copy)?;
+                fs::rename(loser, &copy)?;
+                mirror::copy_entry(&copy, &other_side_copy)?;
+                mirror::copy_entry(winner, loser)?;
+                Ok(Some(copy_name.to_string_lossy().to_string()))
+            }
+            // Only one version survives; keeping both means keeping that one.
+            (Some(_), None) => replace(source, destination_root, relative, history).map(|_| None),
+            _ => replace(destination, source_root, relative, history).map(|_| None),
+        },
+    }
+}
+
+/// Makes `relative` under the folder `target_root` an exact copy of `winner`, or removes it when
+/// `winner` no longer exists. The current copy is saved to `history` and moved to the trash first.
+fn replace(
+    winner: &Path,
+    target_root: &Path,
+    relative: &Path,
+    history: &VersionHistory,
+) -> Result<(), FileTrackerError> {
+    let target = target_root.join(relative);
+    history.preserve(relative, &target)?;
+    Trash::new(target_root).discard(&target)?;
+    if modified(winner)?.is_some() {
+        mirror::copy_entry(winner, &target)?;
+    }
+    Ok(())
+}
+
+fn modified(path: &Path) -> Result<Option<SystemTime>, FileTrackerError> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.modified()?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Builds `name (conflict YYYY-MM-DD host).ext` next to `path` for the conflict date `when`,
+/// adding a counter if that name is taken.
+pub fn conflict_copy_path(path: &Path, when: SystemTime, is_taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let host = gethostname::gethostname().to_string_lossy().to_string();
+    let label = format!("conflict {} {}", date(when), host);
+
+    let mut candidate = path.with_file_name(format!("{} ({}){}", stem, label, extension));
+    let mut counter = 2;
+    while is_taken(&candidate) {
+        candidate = path.with_file_name(format!("{} ({} {}){}", stem, label, counter, extension));
+        counter += 1;
+    }
+    candidate
+}
+
+/// Formats a time as a `YYYY-MM-DD` date (UTC).
+fn date(time: SystemTime) -> String {
+    let secs = time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    // Civil-from-days conversion (proleptic Gregorian calendar).
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roots::{RootRegistry, SyncMode};
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn dates_are_formatted_in_utc() {
+        assert_eq!(date(at(0)), "1970-01-01");
+        assert_eq!(date(at(951_782_400)), "2000-02-29");
+        assert_eq!(date(at(951_868_799)), "2000-02-29");
+        assert_eq!(date(at(1_709_164_800)), "2024-02-29");
+        assert_eq!(date(at(1_735_689_599)), "2024-12-31");
+        assert_eq!(date(at(4_107_542_400)), "2100-03-01");
+    }
+
+    #[test]
+    fn conflict_copies_get_a_free_name() {
+        let host = gethostname::gethostname().to_string_lossy().to_string();
+        let when = at(1_709_164_800);
+        let path = Path::new("/docs/report.final.txt");
+
+        let copy = conflict_copy_path(path, when, |_| false);
+        assert_eq!(copy, Path::new("/docs").join(format!("report.final (conflict 2024-02-29 {}).txt", host)));
+
+        let second = Path::new("/docs").join(format!("report.final (conflict 2024-02-29 {} 2).txt", host));
+        let taken = [copy.clone(), second];
+        let copy = conflict_copy_path(path, when, |candidate| taken.iter().any(|taken| taken == candidate));
+        assert_eq!(copy, Path::new("/docs").join(format!("report.final (conflict 2024-02-29 {} 3).txt", host)));
+
+        let copy = conflict_copy_path(Path::new("/docs/Makefile"), when, |_| false);
+        assert_eq!(copy, Path::new("/docs").join(format!("Makefile (conflict 2024-02-29 {})", host)));
+    }
+
+    #[tokio::test]
+    async fn resolving_keeps_a_version_of_what_is_overwritten() {
+        let dir = std::env::temp_dir().join(format!("egadsync-resolve-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (folder, mirror) = (dir.join("folder"), dir.join("mirror"));
+        for (side, text) in [(&folder, "source edit"), (&mirror, "destination edit")] {
+            fs::create_dir_all(side).unwrap();
+            fs::write(side.join("notes.txt"), text).unwrap();
+        }
+        let config = Config {
+            data_dir: dir.join("data"),
+            state_file_path: dir.join("data").join("state.json").to_string_lossy().to_string(),
+            ..Config::default()
+        };
+        let root = RootRegistry::load(&config)
+            .unwrap()
+            .add(&folder, Some(mirror.clone()), None, None, None, SyncMode::TwoWay)
+            .unwrap();
+        let history = VersionHistory::for_root(&root, &config);
+        let mut source = FileTracker::new(&folder, Some(mirror.clone()), &root.config(&config)).unwrap();
+        let mut destination = FileTracker::new(&mirror, None, &root.destination_config(&config)).unwrap();
+        let relative = Path::new("notes.txt");
+
+        resolve(&mut source, &mut destination, relative, Resolution::KeepSource, &history).await.unwrap();
+        assert_eq!(fs::read_to_string(mirror.join(relative)).unwrap(), "source edit");
+        assert_eq!(Trash::new(&mirror).list().unwrap()[0].path, relative);
+        let versions = history.list(relative).unwrap();
+        assert_eq!(versions.len(), 1);
+        let restored = dir.join("restored.txt");
+        history.restore(relative, versions[0].id, &restored).unwrap();
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "destination edit");
+
+        fs::write(mirror.join(relative), "second destination edit").unwrap();
+        resolve(&mut source, &mut destination, relative, Resolution::KeepDestination, &history).await.unwrap();
+        assert_eq!(fs::read_to_string(folder.join(relative)).unwrap(), "second destination edit");
+        assert_eq!(history.list(relative).unwrap().len(), 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::error::FileTrackerError;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Files smaller than this are copied whole; the signature would not save anything.
+pub const DELTA_MIN_SIZE: u64 = 1024 * 1024;
+
+/// Suffix of the temporary file a patched copy is written to before replacing the original.
+/// Paths with it are never scanned or replicated.
+pub const PARTIAL_SUFFIX: &str = ".egadsync-partial";
+
+/// Size charged for each block reference in a delta, as it would be encoded on the wire.
+const COPY_OP_BYTES: u64 = 8;
+
+/// Literal runs are flushed once they reach this size, bounding the memory used.
+const MAX_LITERAL: usize = 256 * 1024;
+
+/// How much of the source is read at a time.
+const READ_AHEAD: usize = 256 * 1024;
+
+/// Bytes moved to bring a destination file up to date.
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct TransferStats {
+    /// Size of the files written.
+    pub file_bytes: u64,
+    /// Data that had to be sent: whole files when copied, literals and block references when patched.
+    pub sent_bytes: u64,
+}
+
+impl std::ops::AddAssign for TransferStats {
+    fn add_assign(&mut self, other: TransferStats) {
+        self.file_bytes += other.file_bytes;
+        self.sent_bytes += other.sent_bytes;
+    }
+}
+
+/// Weak and strong checksums of one block of the destination copy.
+#[derive(Debug, Clone)]
+struct BlockSignature {
+    weak: u32,
+    strong: [u8; 16],
+    len: usize,
+}
+
+/// Block checksums of the destination copy of a file, the basis the delta is computed against.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    block_size: usize,
+    blocks: Vec<BlockSignature>,
+}
+
+impl Signature {
+    /// Computes the signature of `reader`, split in blocks of `block_size` bytes.
+    pub fn compute(mut reader: impl Read, block_size: usize) -> io::Result<Self> {
+        let mut blocks = Vec::new();
+        let mut block = vec![0; block_size];
+        loop {
+            let len = read_full(&mut reader, &mut block)?;
+            if len == 0 {
+                break;
+            }
+            blocks.push(BlockSignature {
+                weak: Rolling::new(&block[..len]).digest(),
+                strong: strong_hash(&block[..len]),
+                len,
+            });
+            if len < block_size {
+                break;
+            }
+        }
+        Ok(Signature { block_size, blocks })
+    }
+
+    /// Block size suited to a file of `len` bytes: about its square root, like rsync.
+    pub fn block_size_for(len: u64) -> usize {
+        ((len as f64).sqrt() as usize).clamp(2048, 128 * 1024)
+    }
+
+    fn lookup(&self) -> HashMap<u32, Vec<usize>> {
+        let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            index.entry(block.weak).or_default().push(i);
+        }
+        index
+    }
+}
+
+/// One instruction of a delta: reuse a block of the destination copy, or write new bytes.
+#[derive(Debug)]
+pub enum DeltaOp<'a> {
+    Copy(usize),
+    Literal(&'a [u8]),
+}
+
+/// Adler-style checksum that can slide over the data one byte at a time.
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let mut rolling = Rolling { a: 0, b: 0, len: 0 };
+        for &byte in data {
+            rolling.push(byte);
+        }
+        rolling
+    }
+
+    /// Appends a byte at the end of the window.
+    fn push(&mut self, byte: u8) {
+        self.a = self.a.wrapping_add(byte as u32);
+        self.b = self.b.wrapping_add(self.a);
+        self.len += 1;
+    }
+
+    /// Removes the byte at the start of the window.
+    fn pop(&mut self, byte: u8) {
+        self.a = self.a.wrapping_sub(byte as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(byte as u32));
+        self.len -= 1;
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Computes the delta turning the file described by `signature` into the contents of `source`,
+/// handing each instruction to `emit` as it is found.
+pub fn delta(
+    mut source: impl Read,
+    signature: &Signature,
+    mut emit: impl FnMut(DeltaOp) -> io::Result<()>,
+) -> io::Result<()> {
+    let block_size = signature.block_size;
+    let index = signature.lookup();
+    let mut buf: Vec<u8> = Vec::new();
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+
+    loop {
+        // Keep one byte past the window loaded, so it can slide without waiting for a refill.
+        if buf.len() <= pos + block_size && !eof {
+            buf.drain(..literal_start);
+            pos -= literal_start;
+            literal_start = 0;
+            let wanted = pos + block_size + READ_AHEAD;
+            let start = buf.len();
+            buf.resize(wanted, 0);
+            let read = read_full(&mut source, &mut buf[start..])?;
+            buf.truncate(start + read);
+            eof = start + read < wanted;
+        }
+
+        let end = (pos + block_size).min(buf.len());
+        if pos >= end {
+            break;
+        }
+        let window = &buf[pos..end];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let matched = index.get(&weak).and_then(|candidates| {
+            let strong = strong_hash(window);
+            candidates
+                .iter()
+                .copied()
+                .find(|&i| signature.blocks[i].len == window.len() && signature.blocks[i].strong == strong)
+        });
+
+        match matched {
+            Some(block) => {
+                if literal_start < pos {
+                    emit(DeltaOp::Literal(&buf[literal_start..pos]))?;
+                }
+                emit(DeltaOp::Copy(block))?;
+                pos = end;
+                literal_start = pos;
+                rolling = None;
+            }
+            None => {
+                if let Some(rolling) = rolling.as_mut() {
+                    rolling.pop(buf[pos]);
+                    if let Some(&next) = buf.get(end) {
+                        rolling.push(next);
+                    }
+                }
+                pos += 1;
+                if pos - literal_start >= MAX_LITERAL {
+                    emit(DeltaOp::Literal(&buf[literal_start..pos]))?;
+                    literal_start = pos;
+                }
+            }
+        }
+    }
+
+    if literal_start < buf.len() {
+        emit(DeltaOp::Literal(&buf[literal_start..]))?;
+    }
+    Ok(())
+}
+
+/// Brings `dest` up to date with `source` by writing only the blocks that changed.
+///
+/// The patched file is assembled next to `dest` from the unchanged blocks of the old copy and
+/// the new bytes of `source`, then renamed over it with the permissions of `dest`, so an
+/// interrupted patch leaves `dest` intact.
+pub fn patch_file(source: &Path, dest: &Path, modified: SystemTime) -> Result<TransferStats, FileTrackerError> {
+    let file_bytes = fs::metadata(source)?.len();
+    let block_size = Signature::block_size_for(fs::metadata(dest)?.len());
+    let signature = Signature::compute(BufReader::new(File::open(dest)?), block_size)?;
+
+    let permissions = fs::metadata(dest)?.permissions();
+
+    let partial = partial_path(dest);
+    let result = (|| {
+        let mut basis = File::open(dest)?;
+        let mut output = BufWriter::new(File::create(&partial)?);
+        let mut block = vec![0; block_size];
+        let mut sent_bytes = 0;
+
+        delta(BufReader::new(File::open(source)?), &signature, |op| match op {
+            DeltaOp::Literal(bytes) => {
+                sent_bytes += bytes.len() as u64;
+                output.write_all(bytes)
+            }
+            DeltaOp::Copy(index) => {
+                sent_bytes += COPY_OP_BYTES;
+                let len = signature.blocks[index].len;
+                basis.seek(SeekFrom::Start((index * block_size) as u64))?;
+                basis.read_exact(&mut block[..len])?;
+                output.write_all(&block[..len])
+            }
+        })?;
+
+        let output = output.into_inner().map_err(|e| e.into_error())?;
+        output.set_modified(modified)?;
+        output.set_permissions(permissions)?;
+        output.sync_all()?;
+        fs::rename(&partial, dest)?;
+        Ok::<_, io::Error>(sent_bytes)
+    })();
+
+    match result {
+        Ok(sent_bytes) => {
+            log::info!(
+                "Patched {}: sent {} of {} bytes",
+                dest.display(),
+                sent_bytes,
+                file_bytes
+            );
+            Ok(TransferStats { file_bytes, sent_bytes })
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e.into())
+        }
+    }
+}
+
+/// Temporary file next to `dest` that a new copy is written to before replacing it.
+pub fn partial_path(dest: &Path) -> PathBuf {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    dest.with_file_name(format!(".{}{}", name, PARTIAL_SUFFIX))
+}
+
+fn strong_hash(data: &[u8]) -> [u8; 16] {
+    let mut strong = [0; 16];
+    strong.copy_from_slice(&blake3::hash(data).as_bytes()[..16]);
+    strong
+}
+
+/// Reads until `buf` is full or the reader is exhausted, returning how much was read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 2048;
+
+    /// Bytes that never repeat a block, so each block of the old copy matches in one place only.
+    fn data(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x2545_f491;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Patches a file holding `old` into `new` and returns the stats of the patch.
+    fn patch(name: &str, old: &[u8], new: &[u8]) -> TransferStats {
+        let dir = std::env::temp_dir().join(format!("egadsync-delta-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        fs::write(&source, new).unwrap();
+        fs::write(&dest, old).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+
+        let stats = patch_file(&source, &dest, modified).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), new);
+        assert_eq!(fs::metadata(&dest).unwrap().modified().unwrap(), modified);
+        assert!(!partial_path(&dest).exists());
+        assert_eq!(stats.file_bytes, new.len() as u64);
+        let _ = fs::remove_dir_all(&dir);
+        stats
+    }
+
+    fn copies(blocks: usize) -> u64 {
+        blocks as u64 * COPY_OP_BYTES
+    }
+
+    #[test]
+    fn inserted_bytes_are_the_only_literal() {
+        let old = data(32 * BLOCK);
+        let mut new = old.clone();
+        new.splice(4 * BLOCK..4 * BLOCK, data(100).into_iter().rev());
+        assert_eq!(patch("insert", &old, &new).sent_bytes, 100 + copies(32));
+    }
+
+    #[test]
+    fn deleting_bytes_resends_the_rest_of_their_block() {
+        let old = data(32 * BLOCK);
+        let mut new = old.clone();
+        new.drain(4 * BLOCK..4 * BLOCK + 100);
+        assert_eq!(patch("delete", &old, &new).sent_bytes, (BLOCK - 100) as u64 + copies(31));
+    }
+
+    #[test]
+    fn appended_bytes_follow_the_copied_blocks() {
+        let old = data(32 * BLOCK);
+        let mut new = old.clone();
+        new.extend(data(100).into_iter().rev());
+        assert_eq!(patch("append", &old, &new).sent_bytes, 100 + copies(32));
+    }
+
+    #[test]
+    fn short_final_block_is_copied() {
+        let old = data(32 * BLOCK + 500);
+        let mut new = old.clone();
+        new[0] ^= 0xff;
+        assert_eq!(patch("short-block", &old, &new).sent_bytes, BLOCK as u64 + copies(32));
+        assert_eq!(patch("unchanged", &old, &old).sent_bytes, copies(33));
+    }
+
+    #[test]
+    fn empty_files_are_patched() {
+        let contents = data(3 * BLOCK);
+        assert_eq!(patch("from-empty", &[], &contents).sent_bytes, contents.len() as u64);
+        assert_eq!(patch("to-empty", &contents, &[]).sent_bytes, 0);
+        assert_eq!(patch("both-empty", &[], &[]).sent_bytes, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn patched_file_keeps_its_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("egadsync-delta-permissions-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        fs::write(&source, data(4 * BLOCK)).unwrap();
+        fs::write(&dest, data(2 * BLOCK)).unwrap();
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o750)).unwrap();
+
+        patch_file(&source, &dest, SystemTime::now()).unwrap();
+        assert_eq!(fs::metadata(&dest).unwrap().permissions().mode() & 0o777, 0o750);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::config::Config;
+use crate::delta::TransferStats;
+use crate::encryption::{Cipher, EncryptedDestination, Encryption};
+use crate::error::FileTrackerError;
+use crate::file_tracker::FileChange;
+use crate::mirror::{self, ChangePlan, MirrorReport};
+use crate::s3::{S3Config, S3Destination};
+use crate::secrets;
+use crate::sftp::{SftpConfig, SftpDestination};
+use crate::webdav::{WebDavConfig, WebDavDestination};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A place changes are replicated to other than a local folder. Paths are relative to the
+/// destination's root, and every operation blocks until the server has applied it.
+pub trait Destination {
+    /// Creates a directory, along with any missing parents.
+    fn create_dir(&mut self, relative: &Path) -> Result<(), FileTrackerError>;
+
+    /// Writes a local file, creating missing parents and setting its modification time.
+    /// Returns the bytes sent.
+    fn upload(&mut self, relative: &Path, source: &Path, modified: SystemTime) -> Result<u64, FileTrackerError>;
+
+    /// Writes the file at `relative` to the local path `target`. Fails with a `NotFound` I/O
+    /// error when there is no such file. Returns the bytes received.
+    fn download(&mut self, relative: &Path, target: &Path) -> Result<u64, FileTrackerError>;
+
+    /// Moves an entry. Fails with a `NotFound` I/O error when `from` does not exist.
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), FileTrackerError>;
+
+    /// Removes a file or a whole directory tree. A missing path is not an error.
+    fn remove(&mut self, relative: &Path) -> Result<(), FileTrackerError>;
+
+    /// Paths of every file at the destination, relative to its root.
+    fn list_files(&mut self) -> Result<Vec<PathBuf>, FileTrackerError>;
+
+    /// Tells a destination that keeps conflict copies next to files changed on the server that
+    /// names are encrypted with `cipher`, so the copies get encrypted names as well.
+    fn set_name_cipher(&mut self, _cipher: Cipher) {}
+
+    /// Called once a batch of changes is applied, to save what the destination keeps about the remote side.
+    fn finish(&mut self) -> Result<(), FileTrackerError> {
+        Ok(())
+    }
+}
+
+/// Remote destination of a root in mirror mode, stored with the root in `roots.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RemoteDestination {
+    Sftp(SftpConfig),
+    S3(S3Config),
+    #[serde(rename = "webdav")]
+    WebDav(WebDavConfig),
+}
+
+impl RemoteDestination {
+    /// Opens a connection to the destination, with the secrets kept in the secret store of
+    /// `base`. `state_path` is where it may keep what it learns about the remote side between
+    /// connections.
+    pub fn connect(&self, base: &Config, state_path: &Path) -> Result<Box<dyn Destination>, FileTrackerError> {
+        match self {
+            RemoteDestination::Sftp(config) => Ok(Box::new(SftpDestination::connect(config, base)?)),
+            RemoteDestination::S3(config) => Ok(Box::new(S3Destination::connect(config, base)?)),
+            RemoteDestination::WebDav(config) => Ok(Box::new(WebDavDestination::connect(config, base, state_path)?)),
+        }
+    }
+
+    /// Moves a secret given in the settings to the secret store of `base`, so it is never
+    /// written to `roots.json`. Returns whether there was one.
+    pub fn store_secrets(&mut self, base: &Config) -> Result<bool, FileTrackerError> {
+        let Some(account) = self.secret_account() else { return Ok(false) };
+        let secret = match self {
+            RemoteDestination::Sftp(_) => return Ok(false),
+            RemoteDestination::S3(config) => &mut config.secret_access_key,
+            RemoteDestination::WebDav(config) => &mut config.password,
+        };
+        let Some(value) = secret.as_deref() else { return Ok(false) };
+        secrets::set(base, &account, value)?;
+        *secret = None;
+        Ok(true)
+    }
+
+    /// Secret store account of the secret the settings may give, if this kind of destination
+    /// takes one.
+    pub fn secret_account(&self) -> Option<String> {
+        match self {
+            RemoteDestination::Sftp(_) => None,
+            RemoteDestination::S3(config) => Some(S3Config::secret_account(&config.access_key_id)),
+            RemoteDestination::WebDav(config) => Some(WebDavConfig::secret_account(&config.url, &config.username)),
+        }
+    }
+
+    /// Drops a secret given in the settings without storing it.
+    pub fn clear_secrets(&mut self) {
+        match self {
+            RemoteDestination::Sftp(_) => {}
+            RemoteDestination::S3(config) => config.secret_access_key = None,
+            RemoteDestination::WebDav(config) => config.password = None,
+        }
+    }
+
+    /// Where the destination is, for logs and events.
+    pub fn describe(&self) -> String {
+        match self {
+            RemoteDestination::Sftp(config) => {
+                format!("sftp://{}@{}:{}{}", config.username, config.host, config.port, config.remote_path)
+            }
+            RemoteDestination::S3(config) => format!(
+                "s3://{}/{} at {}",
+                config.bucket,
+                config.prefix.trim_matches('/'),
+                config.endpoint.as_deref().unwrap_or("AWS")
+            ),
+            RemoteDestination::WebDav(config) => format!("{} as {}", config.url, config.username),
+        }
+    }
+}
+
+/// Connects to a remote destination, encrypting everything sent to it when `encryption` is set.
+pub fn open(
+    base: &Config,
+    remote: &RemoteDestination,
+    encryption: Option<&Encryption>,
+    state_path: &Path,
+) -> Result<Box<dyn Destination>, FileTrackerError> {
+    let cipher = encryption.map(|encryption| encryption.unlock(base)).transpose()?;
+    let destination = remote.connect(base, state_path)?;
+    Ok(match cipher {
+        Some(cipher) => Box::new(EncryptedDestination::new(destination, cipher)),
+        None => destination,
+    })
+}
+
+/// Replicates the given changes from `root_target` to a remote destination, in the same order
+/// as a local mirror. A failing change does not stop the remaining ones.
+///
+/// Remote copies are replaced and removed directly: versions and the trash only cover local folders.
+pub fn apply_changes(
+    base: &Config,
+    root_target: &Path,
+    remote: &RemoteDestination,
+    encryption: Option<&Encryption>,
+    state_path: &Path,
+    changes: &[FileChange],
+) -> MirrorReport {
+    let mut report = MirrorReport::default();
+    let mut destination = match open(base, remote, encryption, state_path) {
+        Ok(destination) => destination,
+        Err(e) => {
+            log::error!("Failed to connect to {}: {}", remote.describe(), e);
+            report.failed = changes.iter().map(|change| (change.path().to_path_buf(), e.to_string())).collect();
+            return report;
+        }
+    };
+
+    let plan = ChangePlan::new(changes);
+    let mut transfer = TransferStats::default();
+    let mut record = |path: &Path, result: Result<(), FileTrackerError>| match result {
+        Ok(()) => report.applied += 1,
+        Err(e) => {
+            log::error!("Failed to replicate {}: {}", path.display(), e);
+            report.failed.push((path.to_path_buf(), e.to_string()));
+        }
+    };
+
+    for path in plan.dirs {
+        let result = mirror::relative_path(root_target, path).and_then(|relative| destination.create_dir(relative));
+        record(path, result);
+    }
+    for (from, to, _) in plan.renames {
+        let result = (|| {
+            let relative_from = mirror::relative_path(root_target, &from)?;
+            let relative_to = mirror::relative_path(root_target, to)?;
+            match destination.rename(relative_from, relative_to) {
+                // The old copy is gone; send the entry again instead.
+                Err(FileTrackerError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    transfer += upload_entry(destination.as_mut(), relative_to, to)?;
+                    Ok(())
+                }
+                other => other,
+            }
+        })();
+        record(to, result);
+    }
+    for (path, metadata, _) in plan.files {
+        let result = mirror::relative_path(root_target, path).and_then(|relative| {
+            let sent_bytes = destination.upload(relative, path, metadata.modified())?;
+            transfer += TransferStats {
+                file_bytes: sent_bytes,
+                sent_bytes,
+            };
+            Ok(())
+        });
+        record(path, result);
+    }
+    for path in plan.covered {
+        record(path, Ok(()));
+    }
+    for path in plan.deletions {
+        let result = mirror::relative_path(root_target, path).and_then(|relative| destination.remove(relative));
+        record(path, result);
+    }
+
+    if let Err(e) = destination.finish() {
+        log::error!("Failed to save the state of {}: {}", remote.describe(), e);
+    }
+    report.transfer = transfer;
+    log::info!(
+        "Mirrored {} change(s) to {} ({} failed)",
+        report.applied,
+        remote.describe(),
+        report.failed.len()
+    );
+    report
+}
+
+/// Uploads a local file or directory tree to `relative`.
+fn upload_entry(
+    destination: &mut dyn Destination,
+    relative: &Path,
+    source: &Path,
+) -> Result<TransferStats, FileTrackerError> {
+    let mut transfer = TransferStats::default();
+    for entry in walkdir::WalkDir::new(source).follow_links(false) {
+        let entry = entry?;
+        let target = relative.join(entry.path().strip_prefix(source).unwrap_or(entry.path()));
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            destination.create_dir(&target)?;
+        } else {
+            let sent_bytes = destination.upload(&target, entry.path(), metadata.modified()?)?;
+            transfer += TransferStats {
+                file_bytes: sent_bytes,
+                sent_bytes,
+            };
+        }
+    }
+    Ok(transfer)
+}
+
+/// Rejects a remote destination whose settings cannot work, before the root is registered.
+pub fn validate_remote(remote: &RemoteDestination) -> Result<(), FileTrackerError> {
+    match remote {
+        RemoteDestination::Sftp(config) => {
+            if config.host.trim().is_empty() || config.username.trim().is_empty() {
+                return Err(FileTrackerError::InvalidConfig("SFTP needs a host and a username".to_string()));
+            }
+            if !config.remote_path.starts_with('/') {
+                return Err(FileTrackerError::InvalidConfig("the SFTP path must be absolute".to_string()));
+            }
+            fs::metadata(&config.private_key).map_err(|e| {
+                FileTrackerError::InvalidConfig(format!("private key {}: {}", config.private_key.display(), e))
+            })?;
+            Ok(())
+        }
+        RemoteDestination::S3(config) => {
+            if config.bucket.trim().is_empty() || config.access_key_id.trim().is_empty() {
+                return Err(FileTrackerError::InvalidConfig("S3 needs a bucket and an access key id".to_string()));
+            }
+            Ok(())
+        }
+        RemoteDestination::WebDav(config) => {
+            let url = reqwest::Url::parse(&config.url)
+                .map_err(|e| FileTrackerError::InvalidConfig(format!("invalid WebDAV URL {}: {}", config.url, e)))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(FileTrackerError::InvalidConfig("the WebDAV URL must use http or https".to_string()));
+            }
+            if config.username.trim().is_empty() {
+                return Err(FileTrackerError::InvalidConfig("WebDAV needs a username".to_string()));
+            }
+            Ok(())
+        }
+    }
+}
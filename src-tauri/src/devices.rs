@@ -0,0 +1,289 @@
+use crate::config::Config;
+use crate::error::FileTrackerError;
+use crate::persistence;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{ClientConfig, DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Context string of the key derivation producing pairing codes.
+const PAIRING_CODE_CONTEXT: &str = "egadsync 2025 device pairing code";
+
+/// This installation's identity towards other devices: a self-signed certificate created on
+/// first use. Its fingerprint is the device identifier that paired devices pin.
+pub struct DeviceIdentity {
+    pub id: String,
+    certificate: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl DeviceIdentity {
+    fn dir(config: &Config) -> PathBuf {
+        config.data_dir.join("device")
+    }
+
+    /// Loads the device certificate, generating it on first launch.
+    pub fn load_or_create(config: &Config) -> Result<Self, FileTrackerError> {
+        let dir = Self::dir(config);
+        let (certificate_path, key_path) = (dir.join("certificate.der"), dir.join("key.der"));
+        if !certificate_path.exists() || !key_path.exists() {
+            let generated = rcgen::generate_simple_self_signed(vec!["egadsync".to_string()])
+                .map_err(|e| FileTrackerError::PeerError(format!("cannot create the device certificate: {}", e)))?;
+            fs::create_dir_all(&dir)?;
+            persistence::write_private(&key_path, &generated.key_pair.serialize_der())?;
+            persistence::write_atomic(&certificate_path, generated.cert.der())?;
+            log::info!("Created the device certificate in {}", dir.display());
+        }
+
+        let certificate = CertificateDer::from(fs::read(&certificate_path)?);
+        Ok(DeviceIdentity {
+            id: fingerprint(&certificate),
+            certificate,
+            key: PrivatePkcs8KeyDer::from(fs::read(&key_path)?),
+        })
+    }
+
+    /// TLS settings for accepting connections. Any client certificate that proves possession of
+    /// its key is accepted here; whether the device is paired is checked once connected.
+    pub fn server_config(&self) -> Result<ServerConfig, FileTrackerError> {
+        let provider = provider();
+        let config = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(tls_error)?
+            .with_client_cert_verifier(Arc::new(DeviceCertVerifier(provider)))
+            .with_single_cert(vec![self.certificate.clone()], PrivateKeyDer::Pkcs8(self.key.clone_key()))
+            .map_err(tls_error)?;
+        Ok(config)
+    }
+
+    /// TLS settings for connecting to other devices, with the same deferred check.
+    pub fn client_config(&self) -> Result<ClientConfig, FileTrackerError> {
+        let provider = provider();
+        let config = ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(tls_error)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(DeviceCertVerifier(provider)))
+            .with_client_auth_cert(vec![self.certificate.clone()], PrivateKeyDer::Pkcs8(self.key.clone_key()))
+            .map_err(tls_error)?;
+        Ok(config)
+    }
+}
+
+/// Identifier of the device presenting `certificate`: the BLAKE3 digest of its DER encoding.
+pub fn fingerprint(certificate: &CertificateDer<'_>) -> String {
+    blake3::hash(certificate.as_ref()).to_hex().to_string()
+}
+
+/// Fills a buffer with random bytes from the TLS provider.
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], FileTrackerError> {
+    let mut bytes = [0; N];
+    provider()
+        .secure_random
+        .fill(&mut bytes)
+        .map_err(|_| FileTrackerError::PeerError("no source of randomness".to_string()))?;
+    Ok(bytes)
+}
+
+/// The six-digit code both devices show while pairing. It depends on both certificates and on
+/// a random value from each side, where the connecting side commits to its value before seeing
+/// the other one. A device in the middle would have to make both codes match by chance.
+pub fn pairing_code(server_id: &str, client_id: &str, server_nonce: &[u8], client_nonce: &[u8]) -> String {
+    let mut hasher = blake3::Hasher::new_derive_key(PAIRING_CODE_CONTEXT);
+    for part in [server_id.as_bytes(), client_id.as_bytes(), server_nonce, client_nonce] {
+        hasher.update(&(part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    let digest = hasher.finalize();
+    let mut first = [0; 8];
+    first.copy_from_slice(&digest.as_bytes()[..8]);
+    let number = u64::from_le_bytes(first) % 1_000_000;
+    format!("{:03} {:03}", number / 1_000, number % 1_000)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn tls_error(err: rustls::Error) -> FileTrackerError {
+    FileTrackerError::PeerError(err.to_string())
+}
+
+/// Accepts any certificate whose holder signs the handshake with its key, in both directions.
+/// Devices are self-signed, so trust comes from comparing fingerprints with the paired ones.
+#[derive(Debug)]
+struct DeviceCertVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for DeviceCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for DeviceCertVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// This device as shown to the user and to devices pairing with it.
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalDevice {
+    pub id: String,
+    pub name: String,
+    /// Port other devices connect to, if device sync is enabled.
+    pub peer_port: Option<u16>,
+}
+
+/// A device this one trusts, identified by the fingerprint of its certificate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PairedDevice {
+    pub id: String,
+    pub name: String,
+    /// Where it was last reached or announced itself, as `host:port`.
+    pub address: Option<String>,
+    pub paired_at: SystemTime,
+}
+
+/// A pairing waiting for the user to compare the codes shown on both devices and confirm.
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingRequest {
+    pub device_id: String,
+    pub name: String,
+    pub address: Option<String>,
+    pub code: String,
+    pub requested_at: SystemTime,
+}
+
+/// The paired devices, persisted in `devices.json` in the app data directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DeviceRegistry {
+    pub devices: Vec<PairedDevice>,
+}
+
+impl DeviceRegistry {
+    fn file_path(config: &Config) -> PathBuf {
+        config.data_dir.join("devices.json")
+    }
+
+    pub fn load(config: &Config) -> Result<Self, FileTrackerError> {
+        let path = Self::file_path(config);
+        if !path.exists() {
+            return Ok(DeviceRegistry::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, config: &Config) -> Result<(), FileTrackerError> {
+        let path = Self::file_path(config);
+        let json = serde_json::to_string_pretty(self)?;
+        persistence::write_atomic(&path, json.as_bytes())?;
+        log::info!("Saved paired devices to {}", path.display());
+        Ok(())
+    }
+
+    /// Looks up a device by identifier.
+    pub fn get(&self, id: &str) -> Option<&PairedDevice> {
+        self.devices.iter().find(|device| device.id == id)
+    }
+
+    /// Adds a confirmed device, replacing an earlier pairing with it.
+    pub fn pair(&mut self, request: PairingRequest) -> PairedDevice {
+        self.devices.retain(|device| device.id != request.device_id);
+        let device = PairedDevice {
+            id: request.device_id,
+            name: request.name,
+            address: request.address,
+            paired_at: SystemTime::now(),
+        };
+        self.devices.push(device.clone());
+        device
+    }
+
+    /// Forgets a device, returning it.
+    pub fn remove(&mut self, id: &str) -> Result<PairedDevice, FileTrackerError> {
+        let index = self
+            .devices
+            .iter()
+            .position(|device| device.id == id)
+            .ok_or(FileTrackerError::DeviceNotFound)?;
+        Ok(self.devices.remove(index))
+    }
+
+    /// Records where a device can be reached. Returns whether anything changed.
+    pub fn set_address(&mut self, id: &str, address: &str) -> bool {
+        match self.devices.iter_mut().find(|device| device.id == id) {
+            Some(device) if device.address.as_deref() != Some(address) => {
+                device.address = Some(address.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use crate::config::Config;
+use crate::devices::DeviceRegistry;
+use crate::error::FileTrackerError;
+use crate::peer::PeerNode;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// DNS-SD service type EgadSync instances announce themselves under.
+const SERVICE_TYPE: &str = "_egadsync._tcp.local.";
+
+/// Another EgadSync instance found on the local network.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredDevice {
+    pub id: String,
+    pub name: String,
+    /// Where it accepts device connections, as `host:port`.
+    pub address: String,
+    /// Whether this device is already paired with it.
+    pub paired: bool,
+}
+
+/// Announces this device on the local network over mDNS and keeps track of the other
+/// instances announcing themselves, so they can be paired without typing an address.
+///
+/// Devices are only announced when device sync listens on a port. A paired device seen at a new
+/// address is handed to the [`PeerNode`], which only saves the address once the device proves
+/// who it is there; announcements themselves are not trusted.
+#[derive(Clone)]
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    /// Devices currently announced, by DNS-SD instance name.
+    found: Arc<Mutex<HashMap<String, DiscoveredDevice>>>,
+}
+
+impl Discovery {
+    /// Starts announcing and browsing. `on_change` is called whenever a device appears or goes away.
+    pub fn start(
+        config: &Config,
+        node: &PeerNode,
+        on_change: impl Fn() + Send + 'static,
+    ) -> Result<Self, FileTrackerError> {
+        let device_id = node.device_id();
+        let daemon = ServiceDaemon::new().map_err(discovery_error)?;
+        if let Some(port) = config.peer_port {
+            // The full identifier does not fit in a DNS label; it travels in the TXT record.
+            let instance = format!("egadsync-{}", &device_id[..16.min(device_id.len())]);
+            let properties = [("id", device_id), ("name", config.device_name.as_str())];
+            let host = format!("{}.local.", instance);
+            let service = ServiceInfo::new(SERVICE_TYPE, &instance, &host, "", port, &properties[..])
+                .map_err(discovery_error)?
+                .enable_addr_auto();
+            daemon.register(service).map_err(discovery_error)?;
+            log::info!("Announcing this device on the local network as {}", instance);
+        }
+
+        let events = daemon.browse(SERVICE_TYPE).map_err(discovery_error)?;
+        let discovery = Discovery {
+            daemon,
+            found: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let (found, node) = (discovery.found.clone(), node.clone());
+        // The receiver is closed when the daemon shuts down, which ends the thread.
+        std::thread::spawn(move || {
+            while let Ok(event) = events.recv() {
+                let changed = match event {
+                    ServiceEvent::ServiceResolved(service) => match discovered(&service) {
+                        Some(device) if device.id != node.device_id() => {
+                            node.saw_address(&device.id, &device.address);
+                            let mut found = found.lock().unwrap_or_else(|e| e.into_inner());
+                            let previous = found.insert(service.get_fullname().to_string(), device.clone());
+                            previous.is_none_or(|previous| previous.address != device.address)
+                        }
+                        _ => false,
+                    },
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        let mut found = found.lock().unwrap_or_else(|e| e.into_inner());
+                        found.remove(&fullname).is_some()
+                    }
+                    _ => false,
+                };
+                if changed {
+                    on_change();
+                }
+            }
+        });
+        Ok(discovery)
+    }
+
+    /// The devices currently announced on the network, marking the ones in `registry` as paired.
+    pub fn devices(&self, registry: &DeviceRegistry) -> Vec<DiscoveredDevice> {
+        let found = self.found.lock().unwrap_or_else(|e| e.into_inner());
+        let mut devices: Vec<DiscoveredDevice> = found
+            .values()
+            .map(|device| DiscoveredDevice {
+                paired: registry.get(&device.id).is_some(),
+                ..device.clone()
+            })
+            .collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        devices
+    }
+
+    /// Where a device currently announced on the network accepts connections.
+    pub fn address(&self, device_id: &str) -> Option<String> {
+        let found = self.found.lock().unwrap_or_else(|e| e.into_inner());
+        found.values().find(|device| device.id == device_id).map(|device| device.address.clone())
+    }
+
+    /// Stops announcing this device and browsing.
+    pub fn stop(&self) {
+        if let Err(e) = self.daemon.shutdown() {
+            log::warn!("Failed to stop device discovery: {}", e);
+        }
+    }
+}
+
+/// Reads a device from a resolved announcement, preferring an IPv4 address.
+fn discovered(service: &ServiceInfo) -> Option<DiscoveredDevice> {
+    let id = service.get_property_val_str("id")?.to_string();
+    let addresses = service.get_addresses();
+    let ip = addresses
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addresses.iter().find(|ip| matches!(ip, IpAddr::V6(v6) if !v6.is_unicast_link_local())))?;
+    Some(DiscoveredDevice {
+        name: service.get_property_val_str("name").unwrap_or(&id).to_string(),
+        id,
+        address: SocketAddr::new(*ip, service.get_port()).to_string(),
+        paired: false,
+    })
+}
+
+fn discovery_error(err: mdns_sd::Error) -> FileTrackerError {
+    FileTrackerError::PeerError(format!("device discovery failed: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(addresses: &str, properties: &[(&str, &str)]) -> ServiceInfo {
+        ServiceInfo::new(SERVICE_TYPE, "egadsync-test", "egadsync-test.local.", addresses, 7878, properties).unwrap()
+    }
+
+    #[test]
+    fn announcements_prefer_ipv4_and_default_the_name_to_the_id() {
+        let service = announcement("fe80::1,2001:db8::1,192.168.1.20", &[("id", "abc"), ("name", "Notebook")]);
+        let device = discovered(&service).unwrap();
+        assert_eq!((device.id.as_str(), device.name.as_str()), ("abc", "Notebook"));
+        assert_eq!(device.address, "192.168.1.20:7878");
+        assert!(!device.paired);
+
+        let device = discovered(&announcement("fe80::1,2001:db8::1", &[("id", "abc")])).unwrap();
+        assert_eq!(device.name, "abc");
+        assert_eq!(device.address, "[2001:db8::1]:7878");
+    }
+
+    #[test]
+    fn announcements_without_an_id_or_a_usable_address_are_ignored() {
+        assert!(discovered(&announcement("192.168.1.20", &[("name", "Notebook")])).is_none());
+        // Link-local addresses need a scope this announcement does not carry.
+        assert!(discovered(&announcement("fe80::1", &[("id", "abc"), ("name", "Notebook")])).is_none());
+    }
+}
@@ -0,0 +1,805 @@
+use crate::conflicts;
+use crate::config::Config;
+use crate::delta::{TransferStats, PARTIAL_SUFFIX};
+use crate::destination::{Destination, RemoteDestination};
+use crate::error::FileTrackerError;
+use crate::file_tracker::{FileChange, FileMetadata, FileTracker};
+use crate::mirror::{self, MirrorReport};
+use crate::secrets;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::SystemTime;
+
+/// Shortest passphrase accepted for a new encrypted root.
+const MIN_PASSPHRASE_LEN: usize = 12;
+
+/// Context strings of the keys derived from the passphrase, one per use.
+const CONTENTS_CONTEXT: &str = "egadsync 2025 encrypted file contents";
+const NAMES_CONTEXT: &str = "egadsync 2025 encrypted file names";
+const NAME_NONCES_CONTEXT: &str = "egadsync 2025 encrypted file name nonces";
+const KEY_CHECK_CONTEXT: &str = "egadsync 2025 encryption key check";
+
+/// Start of every encrypted file, followed by the random part of its block nonces.
+const MAGIC: &[u8; 8] = b"EGADENC1";
+const NONCE_PREFIX_LEN: usize = 19;
+
+/// Plaintext bytes per encrypted block, each followed by its authentication tag.
+const BLOCK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Longest encrypted name written to a destination, the limit of most filesystems.
+const MAX_NAME_LEN: usize = 255;
+
+/// Plain file at the top of an encrypted destination holding what is needed, besides the
+/// passphrase, to derive the key. Its name is never produced by [`Cipher::encrypt_name`].
+const HEADER_NAME: &str = ".egadsync-encryption.json";
+
+/// Keys already derived in this session, by encryption identifier.
+static UNLOCKED: OnceLock<Mutex<HashMap<String, Cipher>>> = OnceLock::new();
+
+fn unlocked() -> MutexGuard<'static, HashMap<String, Cipher>> {
+    UNLOCKED.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Client-side encryption settings of a root, stored with it in `roots.json`.
+///
+/// The key is derived from a passphrase with Argon2id. The passphrase itself is kept in the
+/// secret store under the account `encryption/<id>`; `key_check` only tells whether a
+/// passphrase derives the right key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Encryption {
+    pub id: String,
+    salt: String,
+    memory_kib: u32,
+    iterations: u32,
+    key_check: String,
+}
+
+impl Encryption {
+    /// Sets up encryption with a new key derived from `passphrase`, and stores the passphrase
+    /// in the secret store of `base` so sync rounds can unlock it.
+    pub fn create(base: &Config, passphrase: &str) -> Result<Self, FileTrackerError> {
+        if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+            return Err(FileTrackerError::InvalidConfig(format!(
+                "the encryption passphrase must have at least {} characters",
+                MIN_PASSPHRASE_LEN
+            )));
+        }
+        let mut id = [0; 16];
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut id);
+        OsRng.fill_bytes(&mut salt);
+        let mut encryption = Encryption {
+            id: hex(&id),
+            salt: hex(&salt),
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            key_check: String::new(),
+        };
+        let master = encryption.derive_master(passphrase)?;
+        encryption.key_check = hex(&blake3::derive_key(KEY_CHECK_CONTEXT, &master));
+        secrets::set(base, &Self::secret_account(&encryption.id), passphrase)?;
+        log::info!("Created encryption key {}", encryption.id);
+        Ok(encryption)
+    }
+
+    /// Secret store account holding the passphrase of the key `id`.
+    pub fn secret_account(id: &str) -> String {
+        format!("encryption/{}", id)
+    }
+
+    /// Derives the key from the passphrase in the secret store of `base`. Keys are kept in
+    /// memory once derived, so the deliberately slow derivation runs once per key and session.
+    pub fn unlock(&self, base: &Config) -> Result<Cipher, FileTrackerError> {
+        if let Some(cipher) = unlocked().get(&self.id) {
+            return Ok(cipher.clone());
+        }
+        let cipher = self.unlock_with(&secrets::get(base, &Self::secret_account(&self.id))?)?;
+        unlocked().insert(self.id.clone(), cipher.clone());
+        Ok(cipher)
+    }
+
+    /// Drops the key derived for `id` in this session, so it is read from the secret store again.
+    pub fn forget_key(id: &str) {
+        unlocked().remove(id);
+    }
+
+    /// Derives the key from a passphrase, refusing one that does not match.
+    pub fn unlock_with(&self, passphrase: &str) -> Result<Cipher, FileTrackerError> {
+        let master = self.derive_master(passphrase)?;
+        if hex(&blake3::derive_key(KEY_CHECK_CONTEXT, &master)) != self.key_check {
+            return Err(FileTrackerError::WrongPassphrase);
+        }
+        Ok(Cipher {
+            contents: XChaCha20Poly1305::new(&blake3::derive_key(CONTENTS_CONTEXT, &master).into()),
+            names: XChaCha20Poly1305::new(&blake3::derive_key(NAMES_CONTEXT, &master).into()),
+            name_nonces: blake3::derive_key(NAME_NONCES_CONTEXT, &master),
+        })
+    }
+
+    /// Writes the salt, the derivation parameters and the key check to the top of `destination`,
+    /// so what is stored there can be restored with the passphrase alone.
+    pub fn write_header(&self, destination: &mut dyn Destination) -> Result<(), FileTrackerError> {
+        let scratch = scratch_path();
+        let result = serde_json::to_vec_pretty(self)
+            .map_err(FileTrackerError::from)
+            .and_then(|json| Ok(fs::write(&scratch, json)?))
+            .and_then(|_| destination.upload(Path::new(HEADER_NAME), &scratch, SystemTime::now()));
+        let _ = fs::remove_file(&scratch);
+        result.map(|_| ())
+    }
+
+    /// Reads the header written by [`Encryption::write_header`], or `None` when `destination`
+    /// has none, as with roots created before it was written.
+    pub fn read_header(destination: &mut dyn Destination) -> Result<Option<Self>, FileTrackerError> {
+        let scratch = scratch_path();
+        let result = match destination.download(Path::new(HEADER_NAME), &scratch) {
+            Ok(_) => fs::read_to_string(&scratch)
+                .map_err(FileTrackerError::from)
+                .and_then(|json| Ok(Some(serde_json::from_str(&json)?))),
+            Err(FileTrackerError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        let _ = fs::remove_file(&scratch);
+        result
+    }
+
+    fn derive_master(&self, passphrase: &str) -> Result<[u8; 32], FileTrackerError> {
+        let salt = unhex(&self.salt).ok_or_else(|| encryption_error("the salt is not hexadecimal"))?;
+        let params = Params::new(self.memory_kib, self.iterations, Params::DEFAULT_P_COST, Some(32))
+            .map_err(encryption_error)?;
+        let mut master = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut master)
+            .map_err(encryption_error)?;
+        Ok(master)
+    }
+}
+
+/// Keys unlocked from an [`Encryption`].
+///
+/// Names are encrypted one path component at a time, deterministically: the nonce is a keyed
+/// hash of the name, so the same name always gives the same object name and a renamed
+/// directory keeps the names of its entries. Contents use a random nonce per file and are
+/// split in blocks whose nonces also mark the last one, so truncation is detected.
+#[derive(Clone)]
+pub struct Cipher {
+    contents: XChaCha20Poly1305,
+    names: XChaCha20Poly1305,
+    name_nonces: [u8; 32],
+}
+
+impl Cipher {
+    /// Encrypts one name into lowercase base32, which any destination accepts as a file name.
+    pub fn encrypt_name(&self, name: &str) -> Result<String, FileTrackerError> {
+        let digest = blake3::keyed_hash(&self.name_nonces, name.as_bytes());
+        let mut nonce = [0; 24];
+        nonce.copy_from_slice(&digest.as_bytes()[..24]);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(self.names.encrypt(&nonce.into(), name.as_bytes()).map_err(encryption_error)?);
+        let encoded = base32::encode(base32::Alphabet::Rfc4648Lower { padding: false }, &sealed);
+        if encoded.len() > MAX_NAME_LEN {
+            return Err(encryption_error(format!("the name {} is too long to encrypt", name)));
+        }
+        Ok(encoded)
+    }
+
+    /// Decrypts a name produced by [`Cipher::encrypt_name`].
+    pub fn decrypt_name(&self, encoded: &str) -> Result<String, FileTrackerError> {
+        let sealed = base32::decode(base32::Alphabet::Rfc4648Lower { padding: false }, encoded)
+            .filter(|sealed| sealed.len() >= 24 + TAG_LEN)
+            .ok_or_else(|| encryption_error(format!("{} is not an encrypted name", encoded)))?;
+        let (nonce, ciphertext) = sealed.split_at(24);
+        let nonce: [u8; 24] = nonce.try_into().map_err(encryption_error)?;
+        let name = self
+            .names
+            .decrypt(&nonce.into(), ciphertext)
+            .map_err(|_| encryption_error(format!("{} was not encrypted with this key", encoded)))?;
+        String::from_utf8(name).map_err(encryption_error)
+    }
+
+    /// Object name for a conflict copy of the object `object`, kept next to it: the decrypted
+    /// name gets the usual conflict label, then is encrypted again so it can still be restored.
+    pub fn conflict_copy_path(
+        &self,
+        object: &str,
+        when: SystemTime,
+        is_taken: impl Fn(&str) -> bool,
+    ) -> Result<String, FileTrackerError> {
+        let (parent, name) = match object.rsplit_once('/') {
+            Some((parent, name)) => (format!("{}/", parent), name),
+            None => (String::new(), object),
+        };
+        let name = self.decrypt_name(name)?;
+        let object_of = |name: &str| Ok::<_, FileTrackerError>(format!("{}{}", parent, self.encrypt_name(name)?));
+        let copy = conflicts::conflict_copy_path(Path::new(&name), when, |candidate| {
+            // A name too long to encrypt is not taken; encrypting it below reports the error.
+            object_of(&candidate.to_string_lossy()).is_ok_and(|candidate| is_taken(&candidate))
+        });
+        object_of(&copy.to_string_lossy())
+    }
+
+    /// Path relative to the root of an object name produced by [`Cipher::encrypt_path`].
+    pub fn decrypt_path(&self, object: &Path) -> Result<PathBuf, FileTrackerError> {
+        let mut relative = PathBuf::new();
+        for component in object.components() {
+            if let Component::Normal(part) = component {
+                let part = part
+                    .to_str()
+                    .ok_or_else(|| encryption_error(format!("{} is not an encrypted name", object.display())))?;
+                relative.push(self.decrypt_name(part)?);
+            }
+        }
+        Ok(relative)
+    }
+
+    /// Object name of a path relative to the root: its encrypted components joined with `/`.
+    pub fn encrypt_path(&self, relative: &Path) -> Result<String, FileTrackerError> {
+        let mut parts = Vec::new();
+        for component in relative.components() {
+            if let Component::Normal(part) = component {
+                let part = part
+                    .to_str()
+                    .ok_or_else(|| encryption_error(format!("{} is not valid UTF-8", relative.display())))?;
+                parts.push(self.encrypt_name(part)?);
+            }
+        }
+        Ok(parts.join("/"))
+    }
+
+    /// Encrypts `source` into `target`, returning the size of the encrypted file.
+    pub fn encrypt_file(&self, source: &Path, target: &Path) -> Result<u64, FileTrackerError> {
+        let mut input = BufReader::new(File::open(source)?);
+        let mut output = BufWriter::new(File::create(target)?);
+        let mut prefix = [0; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        output.write_all(MAGIC)?;
+        output.write_all(&prefix)?;
+        let mut written = (MAGIC.len() + NONCE_PREFIX_LEN) as u64;
+
+        // A block is only known to be the last one once the next read comes back empty.
+        let (mut current, mut next) = (vec![0; BLOCK_SIZE], vec![0; BLOCK_SIZE]);
+        let mut length = read_block(&mut input, &mut current)?;
+        for counter in 0.. {
+            let next_length = if length == BLOCK_SIZE { read_block(&mut input, &mut next)? } else { 0 };
+            let last = next_length == 0;
+            let nonce = block_nonce(&prefix, counter, last);
+            let sealed = self.contents.encrypt(&nonce, &current[..length]).map_err(encryption_error)?;
+            output.write_all(&sealed)?;
+            written += sealed.len() as u64;
+            if last {
+                break;
+            }
+            std::mem::swap(&mut current, &mut next);
+            length = next_length;
+        }
+        output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(written)
+    }
+
+    /// Decrypts `source` into `target`, returning the size of the plaintext. Fails on any
+    /// block that was altered, reordered or cut off, leaving `target` incomplete.
+    pub fn decrypt_file(&self, source: &Path, target: &Path) -> Result<u64, FileTrackerError> {
+        let mut input = BufReader::new(File::open(source)?);
+        let mut header = [0; MAGIC.len() + NONCE_PREFIX_LEN];
+        input
+            .read_exact(&mut header)
+            .map_err(|_| encryption_error(format!("{} is not an encrypted file", source.display())))?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(encryption_error(format!("{} is not an encrypted file", source.display())));
+        }
+        let mut prefix = [0; NONCE_PREFIX_LEN];
+        prefix.copy_from_slice(&header[MAGIC.len()..]);
+
+        let mut output = BufWriter::new(File::create(target)?);
+        let mut written = 0;
+        let (mut current, mut next) = (vec![0; BLOCK_SIZE + TAG_LEN], vec![0; BLOCK_SIZE + TAG_LEN]);
+        let mut length = read_block(&mut input, &mut current)?;
+        for counter in 0.. {
+            let next_length = if length == current.len() { read_block(&mut input, &mut next)? } else { 0 };
+            let last = next_length == 0;
+            let nonce = block_nonce(&prefix, counter, last);
+            let block = self.contents.decrypt(&nonce, &current[..length]).map_err(|_| {
+                encryption_error(format!("{} is corrupt or was not encrypted with this key", source.display()))
+            })?;
+            output.write_all(&block)?;
+            written += block.len() as u64;
+            if last {
+                break;
+            }
+            std::mem::swap(&mut current, &mut next);
+            length = next_length;
+        }
+        output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(written)
+    }
+}
+
+/// Nonce of a content block: the file's random prefix, the block counter and whether it is the last block.
+fn block_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0; 24];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..23].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = last as u8;
+    nonce.into()
+}
+
+/// Reads until `buffer` is full or the input ends, returning how much was read.
+fn read_block(input: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// An object at an encrypted destination, with the path it decrypts to.
+pub type ListedObject = (PathBuf, Result<PathBuf, FileTrackerError>);
+
+/// A remote destination that only ever receives ciphertext: names and contents are encrypted
+/// before they leave this device.
+pub struct EncryptedDestination {
+    inner: Box<dyn Destination>,
+    cipher: Cipher,
+}
+
+impl EncryptedDestination {
+    pub fn new(mut inner: Box<dyn Destination>, cipher: Cipher) -> Self {
+        inner.set_name_cipher(cipher.clone());
+        EncryptedDestination { inner, cipher }
+    }
+
+    fn object(&self, relative: &Path) -> Result<PathBuf, FileTrackerError> {
+        Ok(PathBuf::from(self.cipher.encrypt_path(relative)?))
+    }
+
+    /// Downloads and decrypts the object stored under `object_name` into `target`.
+    /// Returns the bytes received.
+    pub fn download_object(&mut self, object_name: &Path, target: &Path) -> Result<u64, FileTrackerError> {
+        let scratch = scratch_path();
+        let result = self.inner.download(object_name, &scratch).and_then(|received| {
+            self.cipher.decrypt_file(&scratch, target)?;
+            Ok(received)
+        });
+        let _ = fs::remove_file(&scratch);
+        result
+    }
+
+    /// Every object at the destination with the path it decrypts to. The header and the
+    /// leftovers of interrupted uploads are left out.
+    pub fn list_objects(&mut self) -> Result<Vec<ListedObject>, FileTrackerError> {
+        Ok(self
+            .inner
+            .list_files()?
+            .into_iter()
+            .filter(|object| {
+                let name = object.file_name().unwrap_or_default().to_string_lossy();
+                object != Path::new(HEADER_NAME) && !name.ends_with(PARTIAL_SUFFIX)
+            })
+            .map(|object| {
+                let relative = self.cipher.decrypt_path(&object);
+                (object, relative)
+            })
+            .collect())
+    }
+}
+
+impl Destination for EncryptedDestination {
+    fn create_dir(&mut self, relative: &Path) -> Result<(), FileTrackerError> {
+        let object = self.object(relative)?;
+        self.inner.create_dir(&object)
+    }
+
+    /// The file is encrypted into a scratch file first, which is what gets uploaded.
+    fn upload(&mut self, relative: &Path, source: &Path, modified: SystemTime) -> Result<u64, FileTrackerError> {
+        let object = self.object(relative)?;
+        let scratch = scratch_path();
+        let result = self
+            .cipher
+            .encrypt_file(source, &scratch)
+            .and_then(|_| self.inner.upload(&object, &scratch, modified));
+        let _ = fs::remove_file(&scratch);
+        result
+    }
+
+    fn download(&mut self, relative: &Path, target: &Path) -> Result<u64, FileTrackerError> {
+        let object = self.object(relative)?;
+        self.download_object(&object, target)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), FileTrackerError> {
+        let (from, to) = (self.object(from)?, self.object(to)?);
+        self.inner.rename(&from, &to)
+    }
+
+    fn remove(&mut self, relative: &Path) -> Result<(), FileTrackerError> {
+        let object = self.object(relative)?;
+        self.inner.remove(&object)
+    }
+
+    /// Objects whose names do not decrypt with this key are left out.
+    fn list_files(&mut self) -> Result<Vec<PathBuf>, FileTrackerError> {
+        Ok(self.list_objects()?.into_iter().filter_map(|(_, relative)| relative.ok()).collect())
+    }
+
+    fn finish(&mut self) -> Result<(), FileTrackerError> {
+        self.inner.finish()
+    }
+}
+
+/// A fresh path in the temporary directory for a file on its way to or from the destination.
+fn scratch_path() -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let index = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("egadsync-{}-{}{}", std::process::id(), index, PARTIAL_SUFFIX))
+}
+
+/// Records in the tracker state the object names of the entries a replication round wrote:
+/// everything at or below an applied change, except the changes that failed.
+pub fn record_object_names(
+    file_tracker: &mut FileTracker,
+    cipher: &Cipher,
+    changes: &[FileChange],
+    report: &MirrorReport,
+) {
+    let failed: HashSet<&Path> = report.failed.iter().map(|(path, _)| path.as_path()).collect();
+    let applied: HashSet<&Path> = changes
+        .iter()
+        .filter(|change| !matches!(change, FileChange::Deleted(_)))
+        .map(FileChange::path)
+        .filter(|path| !failed.contains(path))
+        .collect();
+    let root_target = file_tracker.root_target.clone();
+    let unnamed: Vec<PathBuf> = file_tracker
+        .files_state
+        .iter()
+        .filter(|(path, metadata)| {
+            metadata.object_name().is_none()
+                && !failed.contains(path.as_path())
+                && path.ancestors().any(|ancestor| applied.contains(ancestor))
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+    for path in unnamed {
+        let named = mirror::relative_path(&root_target, &path).and_then(|relative| cipher.encrypt_path(relative));
+        match named {
+            Ok(object_name) => file_tracker.set_object_name(&path, object_name),
+            Err(e) => log::warn!("No object name for {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Connects to the remote destination of a new encrypted root and writes the encryption
+/// header there. See [`Encryption::write_header`].
+pub fn publish_header(
+    base: &Config,
+    remote: &RemoteDestination,
+    encryption: &Encryption,
+    state_path: &Path,
+) -> Result<(), FileTrackerError> {
+    let mut destination = remote.connect(base, state_path)?;
+    encryption.write_header(destination.as_mut())?;
+    destination.finish()
+}
+
+/// Decrypts what is stored at an encrypted remote destination into `target`, which must be a
+/// new or empty folder. The key is derived from the header at the destination, so `passphrase`
+/// and the remote settings are enough; without a passphrase, the one in the secret store of
+/// `base` for `registered` is used.
+///
+/// When the root is registered, `target` must be outside it, modification times are taken from
+/// its tracker state and tracked files missing from the destination are reported as failed.
+pub fn restore(
+    base: &Config,
+    remote: &RemoteDestination,
+    passphrase: Option<&str>,
+    registered: Option<(&FileTracker, &Encryption)>,
+    target: &Path,
+) -> Result<MirrorReport, FileTrackerError> {
+    if let Some((file_tracker, _)) = registered {
+        mirror::validate_destination(&file_tracker.root_target, target)?;
+    }
+    fs::create_dir_all(target)?;
+    if fs::read_dir(target)?.next().is_some() {
+        return Err(FileTrackerError::InvalidConfig(format!("{} is not empty", target.display())));
+    }
+    // Nothing is written back: the state a destination keeps is only needed by sync rounds.
+    let mut destination = remote.connect(base, &scratch_path())?;
+    let encryption = match (Encryption::read_header(destination.as_mut())?, registered) {
+        (Some(header), Some((_, encryption))) if header.id != encryption.id => {
+            return Err(encryption_error(format!("{} holds another encryption key", remote.describe())))
+        }
+        (Some(header), _) => header,
+        (None, Some((_, encryption))) => encryption.clone(),
+        (None, None) => {
+            return Err(encryption_error(format!("{} has no encryption header", remote.describe())));
+        }
+    };
+    let cipher = match passphrase {
+        Some(passphrase) => encryption.unlock_with(passphrase)?,
+        None => encryption.unlock(base)?,
+    };
+    let report = restore_from(
+        &mut EncryptedDestination::new(destination, cipher),
+        registered.map(|(file_tracker, _)| file_tracker),
+        target,
+    )?;
+    log::info!(
+        "Restored {} entries from {} into {} ({} failed)",
+        report.applied,
+        remote.describe(),
+        target.display(),
+        report.failed.len()
+    );
+    Ok(report)
+}
+
+/// Downloads and decrypts every object listed at `destination` into `target`.
+fn restore_from(
+    destination: &mut EncryptedDestination,
+    file_tracker: Option<&FileTracker>,
+    target: &Path,
+) -> Result<MirrorReport, FileTrackerError> {
+    let mut objects = destination.list_objects()?;
+    objects.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut report = MirrorReport::default();
+    let mut transfer = TransferStats::default();
+    let mut restored_paths = HashSet::new();
+    for (object, relative) in objects {
+        let result = relative.and_then(|relative| {
+            let tracked =
+                file_tracker.and_then(|tracker| tracker.files_state.get(&tracker.root_target.join(&relative)));
+            transfer += restore_file(destination, &object, &target.join(&relative), tracked)?;
+            restored_paths.insert(relative);
+            Ok(())
+        });
+        match result {
+            Ok(()) => report.applied += 1,
+            Err(e) => {
+                log::error!("Failed to restore {}: {}", object.display(), e);
+                report.failed.push((object, e.to_string()));
+            }
+        }
+    }
+
+    // Directories are not objects on every destination, so empty ones come from the tracker,
+    // as do the object names of files the listing missed.
+    if let Some(file_tracker) = file_tracker {
+        let mut entries: Vec<_> = file_tracker.files_state.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        for (path, metadata) in entries {
+            let Ok(relative) = mirror::relative_path(&file_tracker.root_target, path) else { continue };
+            if metadata.is_dir() {
+                fs::create_dir_all(target.join(relative))?;
+                continue;
+            }
+            if restored_paths.contains(relative) {
+                continue;
+            }
+            let result = match metadata.object_name() {
+                Some(object) => restore_file(destination, Path::new(object), &target.join(relative), Some(metadata)),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "not at the remote destination").into()),
+            };
+            match result {
+                Ok(received) => {
+                    transfer += received;
+                    report.applied += 1;
+                }
+                Err(e) => {
+                    log::error!("Failed to restore {}: {}", path.display(), e);
+                    report.failed.push((path.clone(), e.to_string()));
+                }
+            }
+        }
+    }
+    report.transfer = transfer;
+    Ok(report)
+}
+
+/// Downloads and decrypts one object to `restored`, with the modification time of its tracker
+/// entry when there is one.
+fn restore_file(
+    destination: &mut EncryptedDestination,
+    object: &Path,
+    restored: &Path,
+    tracked: Option<&FileMetadata>,
+) -> Result<TransferStats, FileTrackerError> {
+    if let Some(parent) = restored.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let name = restored.file_name().unwrap_or_default().to_string_lossy();
+    let partial = restored.with_file_name(format!(".{}{}", name, PARTIAL_SUFFIX));
+    let received = destination.download_object(object, &partial).and_then(|received| {
+        if let Some(metadata) = tracked {
+            File::options().write(true).open(&partial)?.set_modified(metadata.modified())?;
+        }
+        fs::rename(&partial, restored)?;
+        Ok(received)
+    });
+    if received.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    Ok(TransferStats {
+        sent_bytes: received?,
+        file_bytes: fs::metadata(restored)?.len(),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encryption_error(err: impl std::fmt::Display) -> FileTrackerError {
+    FileTrackerError::EncryptionError(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An encryption set up without touching the secret store, with cheap derivation parameters.
+    fn test_encryption(passphrase: &str) -> Encryption {
+        let mut encryption = Encryption {
+            id: "test".to_string(),
+            salt: hex(&[7; 16]),
+            memory_kib: 64,
+            iterations: 1,
+            key_check: String::new(),
+        };
+        let master = encryption.derive_master(passphrase).unwrap();
+        encryption.key_check = hex(&blake3::derive_key(KEY_CHECK_CONTEXT, &master));
+        encryption
+    }
+
+    /// A destination kept in a local folder.
+    struct FolderDestination(PathBuf);
+
+    impl Destination for FolderDestination {
+        fn create_dir(&mut self, relative: &Path) -> Result<(), FileTrackerError> {
+            Ok(fs::create_dir_all(self.0.join(relative))?)
+        }
+
+        fn upload(&mut self, relative: &Path, source: &Path, _modified: SystemTime) -> Result<u64, FileTrackerError> {
+            let target = self.0.join(relative);
+            fs::create_dir_all(target.parent().unwrap())?;
+            Ok(fs::copy(source, target)?)
+        }
+
+        fn download(&mut self, relative: &Path, target: &Path) -> Result<u64, FileTrackerError> {
+            Ok(fs::copy(self.0.join(relative), target)?)
+        }
+
+        fn rename(&mut self, from: &Path, to: &Path) -> Result<(), FileTrackerError> {
+            Ok(fs::rename(self.0.join(from), self.0.join(to))?)
+        }
+
+        fn remove(&mut self, relative: &Path) -> Result<(), FileTrackerError> {
+            Ok(fs::remove_file(self.0.join(relative))?)
+        }
+
+        fn list_files(&mut self) -> Result<Vec<PathBuf>, FileTrackerError> {
+            Ok(walkdir::WalkDir::new(&self.0)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_file())
+                .map(|entry| entry.path().strip_prefix(&self.0).unwrap().to_path_buf())
+                .collect())
+        }
+    }
+
+    #[test]
+    fn names_and_contents_roundtrip() {
+        let encryption = test_encryption("correct horse battery");
+        assert!(matches!(encryption.unlock_with("wrong horse battery"), Err(FileTrackerError::WrongPassphrase)));
+        let cipher = encryption.unlock_with("correct horse battery").unwrap();
+
+        let object = cipher.encrypt_path(Path::new("Fotos/férias 2024.jpg")).unwrap();
+        let parts: Vec<&str> = object.split('/').collect();
+        assert_eq!(parts.len(), 2);
+        assert!(!object.contains("Fotos"));
+        assert_eq!(cipher.decrypt_name(parts[1]).unwrap(), "férias 2024.jpg");
+        assert_eq!(cipher.encrypt_path(Path::new("Fotos")).unwrap(), parts[0]);
+
+        let dir = std::env::temp_dir().join(format!("egadsync-encryption-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for size in [0, 10, BLOCK_SIZE, 2 * BLOCK_SIZE + 5] {
+            let plain: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            fs::write(dir.join("plain"), &plain).unwrap();
+            cipher.encrypt_file(&dir.join("plain"), &dir.join("sealed")).unwrap();
+            cipher.decrypt_file(&dir.join("sealed"), &dir.join("opened")).unwrap();
+            assert_eq!(fs::read(dir.join("opened")).unwrap(), plain);
+
+            // Dropping the last block must not go unnoticed.
+            let sealed = fs::read(dir.join("sealed")).unwrap();
+            if size > BLOCK_SIZE {
+                let cut = MAGIC.len() + NONCE_PREFIX_LEN + BLOCK_SIZE + TAG_LEN;
+                fs::write(dir.join("sealed"), &sealed[..cut]).unwrap();
+                assert!(cipher.decrypt_file(&dir.join("sealed"), &dir.join("opened")).is_err());
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn conflict_copies_keep_encrypted_names() {
+        let cipher = test_encryption("correct horse battery").unlock_with("correct horse battery").unwrap();
+        let host = gethostname::gethostname().to_string_lossy().to_string();
+        let when = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_709_164_800);
+        let object = cipher.encrypt_path(Path::new("Fotos/férias.jpg")).unwrap();
+        let (parent, _) = object.split_once('/').unwrap();
+
+        let first = cipher.encrypt_name(&format!("férias (conflict 2024-02-29 {}).jpg", host)).unwrap();
+        let copy = cipher.conflict_copy_path(&object, when, |_| false).unwrap();
+        assert_eq!(copy, format!("{}/{}", parent, first));
+        let copy = cipher.conflict_copy_path(&object, when, |candidate| candidate.ends_with(&first)).unwrap();
+        let (_, name) = copy.split_once('/').unwrap();
+        assert_eq!(cipher.decrypt_name(name).unwrap(), format!("férias (conflict 2024-02-29 {} 2).jpg", host));
+
+        let top = cipher.encrypt_path(Path::new("notes.txt")).unwrap();
+        let copy = cipher.conflict_copy_path(&top, when, |_| false).unwrap();
+        assert!(cipher.decrypt_name(&copy).unwrap().starts_with("notes (conflict "));
+        assert!(cipher.conflict_copy_path("notes.txt", when, |_| false).is_err());
+    }
+
+    #[test]
+    fn remote_copies_restore_with_the_passphrase_alone() {
+        let dir = std::env::temp_dir().join(format!("egadsync-encryption-restore-{}", std::process::id()));
+        let (local, remote, target) = (dir.join("local"), dir.join("remote"), dir.join("target"));
+        fs::create_dir_all(local.join("Fotos")).unwrap();
+        fs::create_dir_all(&target).unwrap();
+        fs::write(local.join("Fotos/férias.jpg"), b"photo").unwrap();
+        fs::write(local.join("notes.txt"), b"notes").unwrap();
+
+        let encryption = test_encryption("correct horse battery");
+        let mut folder = FolderDestination(remote.clone());
+        assert_eq!(Encryption::read_header(&mut folder).unwrap(), None);
+        encryption.write_header(&mut folder).unwrap();
+        let cipher = encryption.unlock_with("correct horse battery").unwrap();
+        let mut destination = EncryptedDestination::new(Box::new(folder), cipher);
+        for relative in ["Fotos/férias.jpg", "notes.txt"] {
+            destination.upload(Path::new(relative), &local.join(relative), SystemTime::now()).unwrap();
+        }
+        fs::write(remote.join(format!(".leftover{}", PARTIAL_SUFFIX)), b"partial").unwrap();
+
+        // Only the passphrase and what the destination holds are needed.
+        let mut folder = FolderDestination(remote.clone());
+        let header = Encryption::read_header(&mut folder).unwrap().unwrap();
+        assert!(matches!(header.unlock_with("wrong horse battery"), Err(FileTrackerError::WrongPassphrase)));
+        let cipher = header.unlock_with("correct horse battery").unwrap();
+        let mut destination = EncryptedDestination::new(Box::new(folder), cipher);
+        let mut listed = destination.list_files().unwrap();
+        listed.sort();
+        assert_eq!(listed, [PathBuf::from("Fotos/férias.jpg"), PathBuf::from("notes.txt")]);
+
+        let report = restore_from(&mut destination, None, &target).unwrap();
+        assert_eq!(report.applied, 2);
+        assert!(report.failed.is_empty());
+        assert_eq!(fs::read(target.join("Fotos/férias.jpg")).unwrap(), b"photo");
+        assert_eq!(fs::read(target.join("notes.txt")).unwrap(), b"notes");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -7,20 +7,60 @@ use std::io;
 #[derive(Debug)]
 pub enum FileTrackerError {
     NotADirectory,
+    InvalidDestination,
+    RootNotFound,
+    RootAlreadyMonitored,
+    RootsOverlap,
+    InvalidConfig(String),
+    InvalidState(String),
     IoError(io::Error),
     WalkdirError(walkdir::Error),
     JoinError(tokio::task::JoinError),
     SerdeJsonError(serde_json::Error),
+    WatchError(notify::Error),
+    IgnoreError(ignore::Error),
+    ConfigParseError(toml::de::Error),
+    DatabaseError(rusqlite::Error),
+    ConflictNotFound,
+    VersionNotFound,
+    TrashEntryNotFound,
+    ChunkStoreError(String),
+    DestinationError(String),
+    PeerError(String),
+    DeviceNotFound,
+    EncryptionError(String),
+    WrongPassphrase,
+    SecretError(String),
 }
 
 impl Error for FileTrackerError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             FileTrackerError::NotADirectory => None,
+            FileTrackerError::InvalidDestination => None,
+            FileTrackerError::RootNotFound => None,
+            FileTrackerError::RootAlreadyMonitored => None,
+            FileTrackerError::RootsOverlap => None,
+            FileTrackerError::InvalidConfig(_) => None,
+            FileTrackerError::InvalidState(_) => None,
             FileTrackerError::IoError(err) => Some(err),
             FileTrackerError::WalkdirError(err) => Some(err),
             FileTrackerError::JoinError(err) => Some(err),
             FileTrackerError::SerdeJsonError(err) => Some(err),
+            FileTrackerError::WatchError(err) => Some(err),
+            FileTrackerError::IgnoreError(err) => Some(err),
+            FileTrackerError::ConfigParseError(err) => Some(err),
+            FileTrackerError::DatabaseError(err) => Some(err),
+            FileTrackerError::ChunkStoreError(_) => None,
+            FileTrackerError::ConflictNotFound => None,
+            FileTrackerError::VersionNotFound => None,
+            FileTrackerError::TrashEntryNotFound => None,
+            FileTrackerError::DestinationError(_) => None,
+            FileTrackerError::PeerError(_) => None,
+            FileTrackerError::DeviceNotFound => None,
+            FileTrackerError::EncryptionError(_) => None,
+            FileTrackerError::WrongPassphrase => None,
+            FileTrackerError::SecretError(_) => None,
         }
     }
 }
@@ -29,10 +69,34 @@ impl fmt::Display for FileTrackerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             FileTrackerError::NotADirectory => write!(f, "The specified path is not a directory"),
+            FileTrackerError::InvalidDestination => {
+                write!(f, "The destination folder must not overlap the monitored folder")
+            }
+            FileTrackerError::RootNotFound => write!(f, "No monitored folder with this identifier"),
+            FileTrackerError::RootAlreadyMonitored => write!(f, "This folder is already being monitored"),
+            FileTrackerError::RootsOverlap => {
+                write!(f, "The folders must not be inside or contain a folder that is already synced")
+            }
+            FileTrackerError::InvalidConfig(details) => write!(f, "Invalid configuration: {}", details),
+            FileTrackerError::InvalidState(details) => write!(f, "Invalid saved state: {}", details),
             FileTrackerError::IoError(err) => write!(f, "I/O error: {}", err),
             FileTrackerError::WalkdirError(err) => write!(f, "File scanning error: {}", err),
             FileTrackerError::JoinError(err) => write!(f, "Background task error: {}", err),
             FileTrackerError::SerdeJsonError(err) => write!(f, "Serialization error: {}", err),
+            FileTrackerError::WatchError(err) => write!(f, "File watcher error: {}", err),
+            FileTrackerError::IgnoreError(err) => write!(f, "Ignore rule error: {}", err),
+            FileTrackerError::ConfigParseError(err) => write!(f, "Configuration file error: {}", err),
+            FileTrackerError::DatabaseError(err) => write!(f, "State database error: {}", err),
+            FileTrackerError::ConflictNotFound => write!(f, "No pending conflict for this path"),
+            FileTrackerError::VersionNotFound => write!(f, "No such version for this path"),
+            FileTrackerError::TrashEntryNotFound => write!(f, "No such entry in the trash"),
+            FileTrackerError::ChunkStoreError(details) => write!(f, "Chunk store error: {}", details),
+            FileTrackerError::DestinationError(details) => write!(f, "Remote destination error: {}", details),
+            FileTrackerError::PeerError(details) => write!(f, "Device sync error: {}", details),
+            FileTrackerError::DeviceNotFound => write!(f, "No paired device with this identifier"),
+            FileTrackerError::EncryptionError(details) => write!(f, "Encryption error: {}", details),
+            FileTrackerError::WrongPassphrase => write!(f, "The passphrase does not match the encryption key"),
+            FileTrackerError::SecretError(details) => write!(f, "Secret store error: {}", details),
         }
     }
 }
@@ -48,6 +112,33 @@ impl serde::Serialize for FileTrackerError {
                 state.serialize_field("type", "NotADirectory")?;
                 state.serialize_field("details", "The specified path is not a directory")?;
             }
+            FileTrackerError::InvalidDestination => {
+                state.serialize_field("type", "InvalidDestination")?;
+                state.serialize_field("details", "The destination folder must not overlap the monitored folder")?;
+            }
+            FileTrackerError::RootNotFound => {
+                state.serialize_field("type", "RootNotFound")?;
+                state.serialize_field("details", "No monitored folder with this identifier")?;
+            }
+            FileTrackerError::RootAlreadyMonitored => {
+                state.serialize_field("type", "RootAlreadyMonitored")?;
+                state.serialize_field("details", "This folder is already being monitored")?;
+            }
+            FileTrackerError::RootsOverlap => {
+                state.serialize_field("type", "RootsOverlap")?;
+                state.serialize_field(
+                    "details",
+                    "The folders must not be inside or contain a folder that is already synced",
+                )?;
+            }
+            FileTrackerError::InvalidConfig(details) => {
+                state.serialize_field("type", "InvalidConfig")?;
+                state.serialize_field("details", details)?;
+            }
+            FileTrackerError::InvalidState(details) => {
+                state.serialize_field("type", "InvalidState")?;
+                state.serialize_field("details", details)?;
+            }
             FileTrackerError::IoError(err) => {
                 state.serialize_field("type", "IoError")?;
                 state.serialize_field("details", &err.to_string())?;
@@ -64,6 +155,62 @@ impl serde::Serialize for FileTrackerError {
                 state.serialize_field("type", "SerdeJsonError")?;
                 state.serialize_field("details", &err.to_string())?;
             }
+            FileTrackerError::WatchError(err) => {
+                state.serialize_field("type", "WatchError")?;
+                state.serialize_field("details", &err.to_string())?;
+            }
+            FileTrackerError::IgnoreError(err) => {
+                state.serialize_field("type", "IgnoreError")?;
+                state.serialize_field("details", &err.to_string())?;
+            }
+            FileTrackerError::ConfigParseError(err) => {
+                state.serialize_field("type", "ConfigParseError")?;
+                state.serialize_field("details", &err.to_string())?;
+            }
+            FileTrackerError::DatabaseError(err) => {
+                state.serialize_field("type", "DatabaseError")?;
+                state.serialize_field("details", &err.to_string())?;
+            }
+            FileTrackerError::ConflictNotFound => {
+                state.serialize_field("type", "ConflictNotFound")?;
+                state.serialize_field("details", "No pending conflict for this path")?;
+            }
+            FileTrackerError::VersionNotFound => {
+                state.serialize_field("type", "VersionNotFound")?;
+                state.serialize_field("details", "No such version for this path")?;
+            }
+            FileTrackerError::TrashEntryNotFound => {
+                state.serialize_field("type", "TrashEntryNotFound")?;
+                state.serialize_field("details", "No such entry in the trash")?;
+            }
+            FileTrackerError::ChunkStoreError(details) => {
+                state.serialize_field("type", "ChunkStoreError")?;
+                state.serialize_field("details", details)?;
+            }
+            FileTrackerError::DestinationError(details) => {
+                state.serialize_field("type", "DestinationError")?;
+                state.serialize_field("details", details)?;
+            }
+            FileTrackerError::PeerError(details) => {
+                state.serialize_field("type", "PeerError")?;
+                state.serialize_field("details", details)?;
+            }
+            FileTrackerError::DeviceNotFound => {
+                state.serialize_field("type", "DeviceNotFound")?;
+                state.serialize_field("details", "No paired device with this identifier")?;
+            }
+            FileTrackerError::EncryptionError(details) => {
+                state.serialize_field("type", "EncryptionError")?;
+                state.serialize_field("details", details)?;
+            }
+            FileTrackerError::WrongPassphrase => {
+                state.serialize_field("type", "WrongPassphrase")?;
+                state.serialize_field("details", "The passphrase does not match the encryption key")?;
+            }
+            FileTrackerError::SecretError(details) => {
+                state.serialize_field("type", "SecretError")?;
+                state.serialize_field("details", details)?;
+            }
         }
         state.end()
     }
@@ -92,3 +239,27 @@ impl From<serde_json::Error> for FileTrackerError {
         FileTrackerError::SerdeJsonError(err)
     }
 }
+
+impl From<notify::Error> for FileTrackerError {
+    fn from(err: notify::Error) -> Self {
+        FileTrackerError::WatchError(err)
+    }
+}
+
+impl From<ignore::Error> for FileTrackerError {
+    fn from(err: ignore::Error) -> Self {
+        FileTrackerError::IgnoreError(err)
+    }
+}
+
+impl From<toml::de::Error> for FileTrackerError {
+    fn from(err: toml::de::Error) -> Self {
+        FileTrackerError::ConfigParseError(err)
+    }
+}
+
+impl From<rusqlite::Error> for FileTrackerError {
+    fn from(err: rusqlite::Error) -> Self {
+        FileTrackerError::DatabaseError(err)
+    }
+}
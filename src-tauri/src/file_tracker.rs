@@ -1,12 +1,14 @@
+use crate::chunks::ChunkStore;
 use crate::config::Config;
 use crate::error::FileTrackerError;
+use crate::ignore_rules::IgnoreRules;
+use crate::state_store::{self, StateDelta, StateStore};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::SystemTime;
-use walkdir::WalkDir;
 
 /// Represents a change in a file or directory.
 #[derive(Debug, Serialize, Clone)]
@@ -14,6 +16,9 @@ pub enum FileChange {
     Created(PathBuf, FileMetadata),
     Modified(PathBuf, FileMetadata),
     Deleted(PathBuf),
+    /// An entry moved from the first path to the second. Directory moves are reported once
+    /// for the directory itself, not for each descendant.
+    Renamed(PathBuf, PathBuf, FileMetadata),
 }
 
 impl std::fmt::Display for FileChange {
@@ -22,41 +27,233 @@ impl std::fmt::Display for FileChange {
             FileChange::Created(path, _) => write!(f, "Novo: {}", path.display()),
             FileChange::Modified(path, _) => write!(f, "Modificado: {}", path.display()),
             FileChange::Deleted(path) => write!(f, "Deletado: {}", path.display()),
+            FileChange::Renamed(from, to, _) => write!(f, "Renomeado: {} -> {}", from.display(), to.display()),
+        }
+    }
+}
+
+impl FileChange {
+    /// The path the change leaves in place: the new path of a rename.
+    pub fn path(&self) -> &Path {
+        match self {
+            FileChange::Created(path, _) | FileChange::Modified(path, _) | FileChange::Deleted(path) => path,
+            FileChange::Renamed(_, to, _) => to,
         }
     }
 }
 
 /// Metadata for a file or directory.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct FileMetadata {
     last_modified: SystemTime,
     size: u64,
     is_dir: bool,
+    /// Inode change time (Unix only). Any write bumps it, even when the mtime is restored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status_changed: Option<SystemTime>,
+    /// Device and inode numbers (Unix only), used to recognize renamed entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_id: Option<(u64, u64)>,
+    /// BLAKE3 digest of the contents, computed when a file is first seen or its metadata changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    /// Chunk manifest of the contents in the chunk store, recorded along with the digest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    manifest: Option<String>,
+    /// Name the entry is stored under at an encrypted destination, once it has been replicated there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    object_name: Option<String>,
+}
+
+impl FileMetadata {
+    /// Returns whether the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Returns the last modification time recorded for the entry.
+    pub fn modified(&self) -> SystemTime {
+        self.last_modified
+    }
+
+    /// Returns the content digest, if it has been computed.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    /// Returns the identifier of the contents' chunk manifest, if one was recorded.
+    pub fn manifest(&self) -> Option<&str> {
+        self.manifest.as_deref()
+    }
+
+    /// Returns the name of the entry at an encrypted destination, if it was replicated there.
+    pub fn object_name(&self) -> Option<&str> {
+        self.object_name.as_deref()
+    }
+
+    /// Builds the tracked metadata from filesystem metadata.
+    fn from_fs(metadata: &fs::Metadata) -> Result<Self, std::io::Error> {
+        Ok(FileMetadata {
+            last_modified: metadata.modified()?,
+            size: metadata.len(),
+            is_dir: metadata.is_dir(),
+            status_changed: Self::status_changed(metadata),
+            file_id: Self::file_id(metadata),
+            hash: None,
+            manifest: None,
+            object_name: None,
+        })
+    }
+
+    #[cfg(unix)]
+    fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        Some((metadata.dev(), metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn status_changed(metadata: &fs::Metadata) -> Option<SystemTime> {
+        use std::os::unix::fs::MetadataExt;
+        let secs = u64::try_from(metadata.ctime()).ok()?;
+        let nanos = u32::try_from(metadata.ctime_nsec()).ok()?;
+        Some(SystemTime::UNIX_EPOCH + std::time::Duration::new(secs, nanos))
+    }
+
+    #[cfg(not(unix))]
+    fn status_changed(_metadata: &fs::Metadata) -> Option<SystemTime> {
+        None
+    }
+
+    /// Whether the filesystem metadata suggests the entry may have changed.
+    /// The change time is only compared when both sides know it, so states saved
+    /// before it was tracked do not report every file as modified.
+    fn differs_from(&self, other: &FileMetadata) -> bool {
+        let status_changed = match (self.status_changed, other.status_changed) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        };
+        self.last_modified != other.last_modified || self.size != other.size || status_changed
+    }
+
+    /// Whether the contents may differ, ignoring the change time (a rename bumps it too).
+    fn content_may_differ(&self, other: &FileMetadata) -> bool {
+        let metadata_changed = self.last_modified != other.last_modified || self.size != other.size;
+        metadata_changed && !self.same_content(other)
+    }
+
+    /// Whether both entries are files with the same known content digest.
+    fn same_content(&self, other: &FileMetadata) -> bool {
+        !self.is_dir && self.hash.is_some() && self.hash == other.hash
+    }
+}
+
+/// Hashes each file, skipping (with a warning) the ones that cannot be read. With a chunk
+/// store, the file is also stored in it and the identifier of its manifest returned.
+fn hash_paths(paths: Vec<PathBuf>, chunk_store: Option<&ChunkStore>) -> Vec<(PathBuf, String, Option<String>)> {
+    let mut digests = Vec::new();
+    for path in paths {
+        if let Some(chunk_store) = chunk_store {
+            match chunk_store.store_file(&path) {
+                // A manifest is identified by the digest of the whole contents.
+                Ok(manifest) => {
+                    digests.push((path, manifest.clone(), Some(manifest)));
+                    continue;
+                }
+                Err(e) => log::warn!("Failed to store {} in the chunk store: {}", path.display(), e),
+            }
+        }
+        match hash_file(&path) {
+            Ok(hash) => digests.push((path, hash, None)),
+            Err(e) => log::warn!("Failed to hash {}: {}", path.display(), e),
+        }
+    }
+    digests
+}
+
+/// Computes the BLAKE3 digest of a file's contents.
+fn hash_file(path: &Path) -> Result<String, std::io::Error> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Which generation of the state file a tracker was loaded from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum StateGeneration {
+    /// The latest saved state.
+    Current,
+    /// The previous generation, restored because the latest one was missing or corrupt.
+    Backup,
 }
 
 /// Tracks files in a directory and their metadata.
 #[derive(Serialize, Deserialize)]
 pub struct FileTracker {
     pub root_target: PathBuf,
+    /// Folder that receives a one-way mirror of `root_target`, if configured.
+    #[serde(default)]
+    pub root_destination: Option<PathBuf>,
     pub files_state: HashMap<PathBuf, FileMetadata>,
+    /// Ignore rules applied while scanning; rebuilt from the config when the tracker is loaded.
+    #[serde(skip)]
+    ignore_rules: IgnoreRules,
+    /// Changes to `files_state` not yet written to the state store.
+    #[serde(skip)]
+    pending: StateDelta,
+    /// Whether the store holds a full state that `pending` can be applied to.
+    #[serde(skip)]
+    baseline_saved: bool,
+    /// The store the state was loaded from or saved to, with its state file path, kept open between saves.
+    #[serde(skip)]
+    store: Mutex<Option<(String, Box<dyn StateStore + Send>)>>,
+    /// Where the contents of hashed files are stored; set from the config when the tracker is loaded.
+    #[serde(skip)]
+    chunk_store: Option<ChunkStore>,
 }
 
 impl FileTracker {
-    /// Creates a new FileTracker for the specified directory.
-    pub fn new<T: AsRef<std::path::Path>>(root_target: T, config: &Config) -> Result<Self, FileTrackerError> {
+    /// Creates a new FileTracker for the specified directory, optionally mirrored to `root_destination`.
+    pub fn new<T: AsRef<std::path::Path>>(
+        root_target: T,
+        root_destination: Option<PathBuf>,
+        config: &Config,
+    ) -> Result<Self, FileTrackerError> {
         log::info!("Initializing FileTracker for directory: {}", root_target.as_ref().display());
         let root_target = root_target.as_ref();
-        let files_state = Self::scan_dir(root_target)?;
-        let file_tracker = FileTracker {
+        if let Some(destination) = &root_destination {
+            crate::mirror::validate_destination(root_target, destination)?;
+        }
+        let ignore_rules = IgnoreRules::new(root_target, &config.ignore_patterns)?;
+        let mut files_state = Self::scan_dir(root_target, &ignore_rules)?;
+        // Hash the baseline too, so the first change to a file is compared by content.
+        let to_hash: Vec<PathBuf> =
+            files_state.iter().filter(|(_, metadata)| !metadata.is_dir).map(|(path, _)| path.clone()).collect();
+        let chunk_store = ChunkStore::new(config);
+        Self::store_digests(&mut files_state, hash_paths(to_hash, Some(&chunk_store)));
+        let mut file_tracker = FileTracker {
             files_state,
             root_target: root_target.to_path_buf(),
+            root_destination,
+            ignore_rules,
+            pending: StateDelta::default(),
+            baseline_saved: false,
+            store: Mutex::new(None),
+            chunk_store: Some(chunk_store),
         };
         file_tracker.save(config)?;
         Ok(file_tracker)
     }
 
-    /// Scans a directory and returns its file metadata.
-    pub fn scan_dir<T: AsRef<std::path::Path>>(target: T) -> Result<HashMap<PathBuf, FileMetadata>, FileTrackerError> {
+    /// Scans a directory and returns its file metadata, skipping entries excluded by `ignore_rules`.
+    pub fn scan_dir<T: AsRef<std::path::Path>>(
+        target: T,
+        ignore_rules: &IgnoreRules,
+    ) -> Result<HashMap<PathBuf, FileMetadata>, FileTrackerError> {
         let target = target.as_ref();
         let target_metadata = fs::metadata(target)?;
 
@@ -66,48 +263,275 @@ impl FileTracker {
         }
 
         let mut current_state = HashMap::new();
-        for entry in WalkDir::new(target).follow_links(false) {
+        for entry in ignore_rules.walker(target) {
             let entry = entry?;
             let metadata = entry.metadata()?;
-            current_state.insert(
-                entry.into_path(),
-                FileMetadata {
-                    last_modified: metadata.modified()?,
-                    size: metadata.len(),
-                    is_dir: metadata.is_dir(),
-                },
-            );
+            current_state.insert(entry.into_path(), FileMetadata::from_fs(&metadata)?);
+        }
+        Ok(current_state)
+    }
+
+    /// Scans only the given paths, descending into the ones that are directories.
+    /// Paths that no longer exist are simply absent from the result.
+    fn scan_paths(
+        paths: &[PathBuf],
+        ignore_rules: &IgnoreRules,
+    ) -> Result<HashMap<PathBuf, FileMetadata>, FileTrackerError> {
+        let mut current_state = HashMap::new();
+        for path in paths {
+            match fs::symlink_metadata(path) {
+                Ok(metadata) if ignore_rules.is_ignored(path, metadata.is_dir()) => {}
+                Ok(metadata) if metadata.is_dir() => current_state.extend(Self::scan_dir(path, ignore_rules)?),
+                Ok(metadata) => {
+                    current_state.insert(path.clone(), FileMetadata::from_fs(&metadata)?);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
         }
         Ok(current_state)
     }
 
     /// Computes differences between the current and previous file states.
     pub async fn diff(&mut self) -> Result<Vec<FileChange>, FileTrackerError> {
-        let new_state = tokio::task::spawn_blocking({
+        let mut new_state = tokio::task::spawn_blocking({
             let target = self.root_target.clone();
-            move || Self::scan_dir(target)
+            let ignore_rules = self.ignore_rules.clone();
+            move || Self::scan_dir(target, &ignore_rules)
+        })
+        .await??;
+
+        let changes = Self::changes_between(&self.files_state, &mut new_state, self.chunk_store.clone()).await?;
+        self.pending.record(&self.files_state, &new_state);
+        self.files_state = new_state;
+
+        Ok(changes)
+    }
+
+    /// Computes differences for a set of changed paths reported by the filesystem watcher,
+    /// rescanning only those paths (and the subtrees of directories) instead of the whole root.
+    pub async fn diff_paths(&mut self, paths: Vec<PathBuf>) -> Result<Vec<FileChange>, FileTrackerError> {
+        let scopes = Self::collapse_scopes(&self.root_target, paths);
+        if scopes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut new_state = tokio::task::spawn_blocking({
+            let scopes = scopes.clone();
+            let ignore_rules = self.ignore_rules.clone();
+            move || Self::scan_paths(&scopes, &ignore_rules)
         })
         .await??;
 
+        let old_state = self.entries_under(&scopes)?;
+
+        let changes = Self::changes_between(&old_state, &mut new_state, self.chunk_store.clone()).await?;
+        self.pending.record(&old_state, &new_state);
+        for path in old_state.keys() {
+            self.files_state.remove(path);
+        }
+        self.files_state.extend(new_state);
+
+        Ok(changes)
+    }
+
+    /// Returns the tracked entries for the scopes and everything below them. They are read
+    /// from the saved state, with the changes not saved yet applied on top.
+    fn entries_under(&mut self, scopes: &[PathBuf]) -> Result<HashMap<PathBuf, FileMetadata>, FileTrackerError> {
+        let in_scope = |path: &Path| scopes.iter().any(|scope| path.starts_with(scope));
+        let store = self.store.get_mut().unwrap_or_else(|e| e.into_inner());
+        let Some((_, store)) = store.as_mut().filter(|_| self.baseline_saved) else {
+            return Ok(self
+                .files_state
+                .iter()
+                .filter(|(path, _)| in_scope(path))
+                .map(|(path, metadata)| (path.clone(), metadata.clone()))
+                .collect());
+        };
+
+        let mut entries = HashMap::new();
+        for scope in scopes {
+            entries.extend(store.entries_under(scope)?);
+        }
+        for path in &self.pending.removed {
+            entries.remove(path);
+        }
+        entries.extend(
+            self.pending
+                .upserted
+                .iter()
+                .filter(|(path, _)| in_scope(path))
+                .map(|(path, metadata)| (path.clone(), metadata.clone())),
+        );
+        Ok(entries)
+    }
+
+    /// Keeps only the paths under the root, dropping any path already covered by an ancestor.
+    fn collapse_scopes(root_target: &Path, mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        paths.retain(|path| path.starts_with(root_target));
+        paths.sort();
+        paths.dedup();
+
+        let mut scopes: Vec<PathBuf> = Vec::new();
+        for path in paths {
+            if !scopes.last().is_some_and(|scope| path.starts_with(scope)) {
+                scopes.push(path);
+            }
+        }
+        scopes
+    }
+
+    /// Compares two snapshots of (part of) the tree.
+    ///
+    /// Files whose metadata changed (and new files) are hashed, so a `touch` is not reported
+    /// as a modification. Known digests and manifests are carried over into `new_state`,
+    /// including from an entry that vanished with the same device/inode, size and modification
+    /// time (a rename). Hashed files are stored in `chunk_store`.
+    async fn changes_between(
+        old_state: &HashMap<PathBuf, FileMetadata>,
+        new_state: &mut HashMap<PathBuf, FileMetadata>,
+        chunk_store: Option<ChunkStore>,
+    ) -> Result<Vec<FileChange>, FileTrackerError> {
+        let vanished: HashMap<(u64, u64), &FileMetadata> = old_state
+            .iter()
+            .filter(|(path, metadata)| !metadata.is_dir && !new_state.contains_key(*path))
+            .filter_map(|(_, metadata)| Some((metadata.file_id?, metadata)))
+            .collect();
+        let mut to_hash = Vec::new();
+        for (path, new_metadata) in new_state.iter_mut() {
+            // Object names only depend on the path, so they survive changes to the contents.
+            if let Some(old_metadata) = old_state.get(path) {
+                new_metadata.object_name = old_metadata.object_name.clone();
+            }
+            match old_state.get(path) {
+                Some(old_metadata) if !old_metadata.differs_from(new_metadata) => {
+                    new_metadata.hash = old_metadata.hash.clone();
+                    new_metadata.manifest = old_metadata.manifest.clone();
+                }
+                None if !new_metadata.is_dir => {
+                    let renamed = new_metadata.file_id.and_then(|id| vanished.get(&id)).filter(|old_metadata| {
+                        old_metadata.hash.is_some()
+                            && old_metadata.last_modified == new_metadata.last_modified
+                            && old_metadata.size == new_metadata.size
+                    });
+                    match renamed {
+                        Some(old_metadata) => {
+                            new_metadata.hash = old_metadata.hash.clone();
+                            new_metadata.manifest = old_metadata.manifest.clone();
+                        }
+                        None => to_hash.push(path.clone()),
+                    }
+                }
+                _ if !new_metadata.is_dir => to_hash.push(path.clone()),
+                _ => {}
+            }
+        }
+
+        let digests = tokio::task::spawn_blocking(move || hash_paths(to_hash, chunk_store.as_ref())).await?;
+        Self::store_digests(new_state, digests);
+
         let mut changes = Vec::new();
-        for (path, new_metadata) in &new_state {
-            match self.files_state.get(path) {
+        for (path, new_metadata) in new_state.iter() {
+            match old_state.get(path) {
                 Some(old_metadata) => {
-                    if old_metadata.last_modified != new_metadata.last_modified || old_metadata.size != new_metadata.size {
+                    if old_metadata.differs_from(new_metadata) && !old_metadata.same_content(new_metadata) {
                         changes.push(FileChange::Modified(path.to_path_buf(), new_metadata.clone()));
                     }
                 }
                 None => changes.push(FileChange::Created(path.to_path_buf(), new_metadata.clone())),
             }
         }
-        for path in self.files_state.keys() {
-            if !new_state.contains_key(path) {
+        for path in old_state.keys() {
+            // Entries that still exist were only excluded by new ignore rules; they are dropped
+            // from the state without being reported (and removed from the mirror) as deletions.
+            if !new_state.contains_key(path) && fs::symlink_metadata(path).is_err() {
                 changes.push(FileChange::Deleted(path.to_path_buf()));
             }
         }
-        self.files_state = new_state;
+        Ok(Self::detect_renames(old_state, changes))
+    }
 
-        Ok(changes)
+    /// Pairs deletions with creations of the same entry and replaces them with renames.
+    ///
+    /// Entries are matched by device/inode where available, and otherwise by size and content
+    /// digest. Renames of descendants that follow from a directory rename are dropped, and a
+    /// renamed file whose contents also changed is additionally reported as modified.
+    fn detect_renames(old_state: &HashMap<PathBuf, FileMetadata>, changes: Vec<FileChange>) -> Vec<FileChange> {
+        let mut by_id: HashMap<(u64, u64, bool), PathBuf> = HashMap::new();
+        let mut by_content: HashMap<(String, u64), PathBuf> = HashMap::new();
+        for change in &changes {
+            if let FileChange::Deleted(path) = change {
+                let Some(old_metadata) = old_state.get(path) else { continue };
+                if let Some((dev, ino)) = old_metadata.file_id {
+                    by_id.insert((dev, ino, old_metadata.is_dir), path.clone());
+                } else if let Some(hash) = &old_metadata.hash {
+                    by_content.insert((hash.clone(), old_metadata.size), path.clone());
+                }
+            }
+        }
+        if by_id.is_empty() && by_content.is_empty() {
+            return changes;
+        }
+
+        let mut renames: Vec<(PathBuf, PathBuf, FileMetadata)> = Vec::new();
+        let mut others = Vec::new();
+        for change in changes {
+            if let FileChange::Created(path, metadata) = &change {
+                let by_id_match = metadata
+                    .file_id
+                    .and_then(|(dev, ino)| by_id.remove(&(dev, ino, metadata.is_dir)));
+                let from = by_id_match.or_else(|| {
+                    let hash = metadata.hash.clone()?;
+                    by_content.remove(&(hash, metadata.size))
+                });
+                if let Some(from) = from {
+                    renames.push((from, path.clone(), metadata.clone()));
+                    continue;
+                }
+            }
+            others.push(change);
+        }
+
+        let renamed_from: std::collections::HashSet<PathBuf> =
+            renames.iter().map(|(from, _, _)| from.clone()).collect();
+        others.retain(|change| !matches!(change, FileChange::Deleted(path) if renamed_from.contains(path)));
+
+        // Drop renames implied by an ancestor directory's rename.
+        renames.sort_by_key(|(from, _, _)| from.components().count());
+        let mut collapsed: Vec<(PathBuf, PathBuf, FileMetadata)> = Vec::new();
+        for (from, to, metadata) in renames {
+            let implied = collapsed.iter().any(|(dir_from, dir_to, dir_metadata)| {
+                dir_metadata.is_dir
+                    && from
+                        .strip_prefix(dir_from)
+                        .is_ok_and(|relative| to == dir_to.join(relative))
+            });
+            let content_changed = old_state
+                .get(&from)
+                .is_some_and(|old_metadata| !metadata.is_dir && old_metadata.content_may_differ(&metadata));
+            if content_changed {
+                others.push(FileChange::Modified(to.clone(), metadata.clone()));
+            }
+            if !implied {
+                collapsed.push((from, to, metadata));
+            }
+        }
+
+        let mut result: Vec<FileChange> = collapsed
+            .into_iter()
+            .map(|(from, to, metadata)| FileChange::Renamed(from, to, metadata))
+            .collect();
+        result.extend(others);
+        result
+    }
+
+    fn store_digests(state: &mut HashMap<PathBuf, FileMetadata>, digests: Vec<(PathBuf, String, Option<String>)>) {
+        for (path, hash, manifest) in digests {
+            if let Some(metadata) = state.get_mut(&path) {
+                metadata.hash = Some(hash);
+                metadata.manifest = manifest;
+            }
+        }
     }
 
     pub fn get_only_file_changes(all_changes: Vec<FileChange>) -> Vec<FileChange> {
@@ -123,39 +547,343 @@ impl FileTracker {
 
                         None
                     },
-                    FileChange::Deleted(_) => {
+                    FileChange::Deleted(_) |
+                    FileChange::Renamed(..) => {
                         Some(element)
                     }
                 }
             }).collect::<Vec<FileChange>>()
     }
 
-    /// Saves the current state to the configured state file.
-    pub fn save(&self, config: &Config) -> Result<(), FileTrackerError> {
-        let mut file = File::create(&config.state_file_path)?;
-        let json = serde_json::to_string_pretty(self)?;
-        file.write(json.as_bytes())?;
-        log::info!("Saved state to {}", config.state_file_path);
+    /// Puts the entries of changes that could not be replicated back to their last saved state,
+    /// so the next diff reports them again instead of the failure being saved as done.
+    /// `failed` holds the paths a replication reported as failed: the new path of a rename.
+    pub fn keep_unsynced<'a>(&mut self, changes: &[FileChange], failed: impl IntoIterator<Item = &'a Path>) {
+        let failed: HashSet<&Path> = failed.into_iter().collect();
+        let mut reverted: HashMap<PathBuf, Option<FileMetadata>> = HashMap::new();
+        for change in changes.iter().filter(|change| failed.contains(change.path())) {
+            let touched = match change {
+                FileChange::Renamed(from, to, _) => vec![from, to],
+                FileChange::Created(path, _) | FileChange::Modified(path, _) | FileChange::Deleted(path) => vec![path],
+            };
+            for (path, previous) in &self.pending.previous {
+                if touched.iter().any(|touched| path.starts_with(touched)) {
+                    reverted.insert(path.clone(), previous.clone());
+                }
+            }
+            // Entries of a new tracker are saved before they are first replicated.
+            if let FileChange::Created(path, _) = change {
+                reverted.entry(path.clone()).or_insert(None);
+            }
+        }
+        if reverted.is_empty() {
+            return;
+        }
+
+        log::info!("Keeping {} entry(ies) that failed to replicate for the next round", reverted.len());
+        for (path, previous) in reverted {
+            match previous {
+                Some(metadata) => {
+                    self.files_state.insert(path.clone(), metadata.clone());
+                    self.pending.removed.remove(&path);
+                    self.pending.upserted.insert(path, metadata);
+                }
+                None => {
+                    self.files_state.remove(&path);
+                    self.pending.upserted.remove(&path);
+                    self.pending.removed.insert(path);
+                }
+            }
+        }
+    }
+
+    /// Records the name an entry is stored under at an encrypted destination.
+    pub fn set_object_name(&mut self, path: &Path, object_name: String) {
+        if let Some(metadata) = self.files_state.get_mut(path) {
+            metadata.object_name = Some(object_name);
+            self.pending.removed.remove(path);
+            self.pending.upserted.insert(path.to_path_buf(), metadata.clone());
+        }
+    }
+
+    /// Persists the state. Only the entries changed since the last save are written,
+    /// except for the first save of a new tracker.
+    pub fn save(&mut self, config: &Config) -> Result<(), FileTrackerError> {
+        let mut store = self.take_store(config);
+        let result = if self.baseline_saved {
+            store.apply_delta(self, &self.pending)
+        } else {
+            store.save_all(self)
+        };
+        self.keep_store(config, store);
+        result?;
+        self.baseline_saved = true;
+        log::info!(
+            "Saved state to {} ({} updated, {} removed)",
+            config.state_file_path,
+            self.pending.upserted.len(),
+            self.pending.removed.len()
+        );
+        self.pending = StateDelta::default();
         Ok(())
     }
 
-    /// Loads the FileTracker state from the configured state file.
+    /// Loads the FileTracker state from the configured state store.
     pub fn get(config: &Config) -> Result<Self, FileTrackerError> {
-        let mut file = File::open(&config.state_file_path)?;
-        let mut json_data = String::new();
-        file.read_to_string(&mut json_data)?;
-        Ok(serde_json::from_str(&json_data)?)
+        Self::load(config).map(|(file_tracker, _)| file_tracker)
+    }
+
+    /// Loads the FileTracker state, reporting whether the previous generation had to be
+    /// restored because the latest one was missing or corrupt.
+    pub fn load(config: &Config) -> Result<(Self, StateGeneration), FileTrackerError> {
+        let mut store = state_store::open(config);
+        let (mut file_tracker, generation) = store.load()?;
+        file_tracker.set_ignore_patterns(&config.ignore_patterns)?;
+        file_tracker.chunk_store = Some(ChunkStore::new(config));
+        file_tracker.baseline_saved = true;
+        file_tracker.keep_store(config, store);
+        Ok((file_tracker, generation))
+    }
+
+    /// Takes the store kept by the tracker, or opens one when none is kept for `config`'s state file.
+    fn take_store(&mut self, config: &Config) -> Box<dyn StateStore + Send> {
+        match self.store.get_mut().unwrap_or_else(|e| e.into_inner()).take() {
+            Some((path, store)) if path == config.state_file_path => store,
+            _ => state_store::open(config),
+        }
+    }
+
+    fn keep_store(&mut self, config: &Config, store: Box<dyn StateStore + Send>) {
+        *self.store.get_mut().unwrap_or_else(|e| e.into_inner()) = Some((config.state_file_path.clone(), store));
+    }
+
+    /// Rebuilds a tracker from persisted fields.
+    pub(crate) fn from_parts(
+        root_target: PathBuf,
+        root_destination: Option<PathBuf>,
+        files_state: HashMap<PathBuf, FileMetadata>,
+    ) -> Self {
+        FileTracker {
+            root_target,
+            root_destination,
+            files_state,
+            ignore_rules: IgnoreRules::default(),
+            pending: StateDelta::default(),
+            baseline_saved: false,
+            store: Mutex::new(None),
+            chunk_store: None,
+        }
+    }
+
+    /// Replaces the global ignore patterns used by subsequent scans.
+    pub fn set_ignore_patterns(&mut self, patterns: &[String]) -> Result<(), FileTrackerError> {
+        self.ignore_rules = IgnoreRules::new(&self.root_target, patterns)?;
+        Ok(())
     }
 
     /// Stops monitoring and deletes the state file.
     pub fn stop_monitoring_and_delete_state(config: &Config) -> Result<(), FileTrackerError> {
-        fs::remove_file(&config.state_file_path)?;
+        state_store::open(config).delete()?;
         log::info!("Stopped monitoring and deleted state file at {}", config.state_file_path);
         Ok(())
     }
 
-    /// Checks if monitoring is active by checking the state file's existence.
+    /// Checks if monitoring is active by checking whether a saved state exists.
     pub fn is_monitoring_active(config: &Config) -> bool {
-        std::path::Path::new(&config.state_file_path).exists()
+        state_store::open(config).exists()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(dir: &Path) -> (Config, FileTracker) {
+        let folder = dir.join("folder");
+        fs::create_dir_all(&folder).unwrap();
+        let config = Config {
+            data_dir: dir.join("data"),
+            state_file_path: dir.join("state.json").to_string_lossy().to_string(),
+            ..Config::default()
+        };
+        let file_tracker = FileTracker::new(&folder, None, &config).unwrap();
+        (config, file_tracker)
+    }
+
+    #[tokio::test]
+    async fn unsynced_changes_are_reported_again() {
+        let dir = std::env::temp_dir().join(format!("egadsync-tracker-unsynced-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (config, mut file_tracker) = tracker(&dir);
+        let file = file_tracker.root_target.join("notes.txt");
+        fs::write(&file, "first").unwrap();
+
+        let changes = file_tracker.diff().await.unwrap();
+        assert!(matches!(
+            &FileTracker::get_only_file_changes(changes.clone())[..],
+            [FileChange::Created(path, _)] if *path == file
+        ));
+        file_tracker.keep_unsynced(&changes, [file.as_path()]);
+        file_tracker.save(&config).unwrap();
+        let mut reloaded = FileTracker::get(&config).unwrap();
+        let changes = reloaded.diff().await.unwrap();
+        assert!(matches!(
+            &FileTracker::get_only_file_changes(changes.clone())[..],
+            [FileChange::Created(path, _)] if *path == file
+        ));
+        reloaded.save(&config).unwrap();
+
+        fs::remove_file(&file).unwrap();
+        let changes = reloaded.diff().await.unwrap();
+        reloaded.keep_unsynced(&changes, [file.as_path()]);
+        reloaded.save(&config).unwrap();
+        let changes = FileTracker::get(&config).unwrap().diff().await.unwrap();
+        assert!(matches!(
+            &FileTracker::get_only_file_changes(changes)[..],
+            [FileChange::Deleted(path)] if *path == file
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn baseline_is_hashed_and_renames_keep_their_digest() {
+        let dir = std::env::temp_dir().join(format!("egadsync-tracker-baseline-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let folder = dir.join("folder");
+        fs::create_dir_all(&folder).unwrap();
+        let file = folder.join("report.txt");
+        fs::write(&file, "quarterly numbers").unwrap();
+        let (_, mut file_tracker) = tracker(&dir);
+        let digest = blake3::hash(b"quarterly numbers").to_hex().to_string();
+        assert_eq!(file_tracker.files_state[&file].hash(), Some(digest.as_str()));
+
+        // Rewriting the same contents is not a modification, even on the first change.
+        fs::write(&file, "quarterly numbers").unwrap();
+        let changes = file_tracker.diff().await.unwrap();
+        assert!(FileTracker::get_only_file_changes(changes).is_empty());
+
+        let renamed = folder.join("final.txt");
+        fs::rename(&file, &renamed).unwrap();
+        let changes = file_tracker.diff().await.unwrap();
+        let files = FileTracker::get_only_file_changes(changes);
+        assert!(matches!(&files[..], [FileChange::Renamed(from, to, _)] if *from == file && *to == renamed));
+        assert_eq!(file_tracker.files_state[&renamed].hash(), Some(digest.as_str()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hashed_files_reference_a_restorable_manifest() {
+        let dir = std::env::temp_dir().join(format!("egadsync-tracker-manifest-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (config, mut file_tracker) = tracker(&dir);
+        let file = file_tracker.root_target.join("notes.txt");
+        fs::write(&file, "first draft").unwrap();
+        file_tracker.diff().await.unwrap();
+
+        let metadata = &file_tracker.files_state[&file];
+        assert_eq!(metadata.manifest(), metadata.hash());
+        let restored = dir.join("restored.txt");
+        ChunkStore::new(&config).restore(metadata.manifest().unwrap(), &restored).unwrap();
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "first draft");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watcher_paths_collapse_to_their_topmost_scopes() {
+        let root = Path::new("/r");
+        let paths = vec![
+            PathBuf::from("/r/a/b/c.txt"),
+            PathBuf::from("/elsewhere/x"),
+            PathBuf::from("/r/a"),
+            PathBuf::from("/r/d.txt"),
+            PathBuf::from("/r/a/b"),
+            PathBuf::from("/r/d.txt"),
+            PathBuf::from("/r/ab"),
+        ];
+        assert_eq!(
+            FileTracker::collapse_scopes(root, paths),
+            [PathBuf::from("/r/a"), PathBuf::from("/r/ab"), PathBuf::from("/r/d.txt")]
+        );
+    }
+
+    fn metadata(is_dir: bool, file_id: Option<(u64, u64)>, hash: Option<&str>) -> FileMetadata {
+        FileMetadata {
+            last_modified: SystemTime::UNIX_EPOCH,
+            size: 8,
+            is_dir,
+            status_changed: None,
+            file_id,
+            hash: hash.map(str::to_string),
+            manifest: None,
+            object_name: None,
+        }
+    }
+
+    #[test]
+    fn renames_are_detected_by_inode_and_by_content() {
+        let old_state = HashMap::from([
+            (PathBuf::from("/r/a"), metadata(true, Some((1, 10)), None)),
+            (PathBuf::from("/r/a/f"), metadata(false, Some((1, 11)), Some("f"))),
+            (PathBuf::from("/r/a/g"), metadata(false, Some((1, 12)), Some("g"))),
+            (PathBuf::from("/r/h"), metadata(false, None, Some("h"))),
+        ]);
+        let mut edited = metadata(false, Some((1, 12)), Some("g2"));
+        edited.size = 9;
+        let changes = vec![
+            FileChange::Deleted(PathBuf::from("/r/a")),
+            FileChange::Deleted(PathBuf::from("/r/a/f")),
+            FileChange::Deleted(PathBuf::from("/r/a/g")),
+            FileChange::Deleted(PathBuf::from("/r/h")),
+            FileChange::Created(PathBuf::from("/r/b"), metadata(true, Some((1, 10)), None)),
+            FileChange::Created(PathBuf::from("/r/b/f"), metadata(false, Some((1, 11)), Some("f"))),
+            FileChange::Created(PathBuf::from("/r/b/g2"), edited),
+            FileChange::Created(PathBuf::from("/r/i"), metadata(false, None, Some("h"))),
+        ];
+
+        let mut summary: Vec<String> = FileTracker::detect_renames(&old_state, changes)
+            .iter()
+            .map(|change| change.to_string())
+            .collect();
+        summary.sort();
+        assert_eq!(
+            summary,
+            [
+                "Modificado: /r/b/g2",
+                "Renomeado: /r/a -> /r/b",
+                "Renomeado: /r/a/g -> /r/b/g2",
+                "Renomeado: /r/h -> /r/i",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn watched_paths_see_changes_not_saved_yet() {
+        let dir = std::env::temp_dir().join(format!("egadsync-tracker-paths-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (_, mut file_tracker) = tracker(&dir);
+        let folder = file_tracker.root_target.clone();
+        let (kept, removed) = (folder.join("kept.txt"), folder.join("removed.txt"));
+        fs::write(&kept, "one").unwrap();
+        fs::write(&removed, "two").unwrap();
+        let changes = file_tracker.diff_paths(vec![kept.clone(), removed.clone()]).await.unwrap();
+        assert_eq!(FileTracker::get_only_file_changes(changes).len(), 2);
+
+        // Neither file is saved yet: only the pending changes know about them.
+        fs::write(&kept, "one, edited").unwrap();
+        fs::remove_file(&removed).unwrap();
+        let mut summary: Vec<String> = file_tracker
+            .diff_paths(vec![folder.clone()])
+            .await
+            .unwrap()
+            .iter()
+            .filter(|change| change.path() != folder)
+            .map(|change| change.to_string())
+            .collect();
+        summary.sort();
+        assert_eq!(
+            summary,
+            [format!("Deletado: {}", removed.display()), format!("Modificado: {}", kept.display())]
+        );
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
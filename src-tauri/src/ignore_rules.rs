@@ -0,0 +1,207 @@
+use crate::error::FileTrackerError;
+use crate::delta::PARTIAL_SUFFIX;
+use crate::trash::TRASH_DIR_NAME;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{Match, WalkBuilder};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Name of the per-directory ignore file, using gitignore syntax.
+pub const IGNORE_FILE_NAME: &str = ".egadignore";
+
+/// Gitignore-style rules deciding which paths under a monitored root are skipped.
+///
+/// Rules come from the global patterns in the configuration and from `.egadignore`
+/// files anywhere inside the root. Ignored directories are never descended into. The trash
+/// directory at the top of the root and partially written files are always ignored.
+#[derive(Clone)]
+pub struct IgnoreRules {
+    root: PathBuf,
+    global: Gitignore,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        IgnoreRules {
+            root: PathBuf::new(),
+            global: Gitignore::empty(),
+        }
+    }
+}
+
+impl IgnoreRules {
+    /// Builds the rules for `root` from the given global patterns.
+    pub fn new(root: &Path, patterns: &[String]) -> Result<Self, FileTrackerError> {
+        let mut builder = GitignoreBuilder::new(root);
+        builder.add_line(None, &format!("/{}/", TRASH_DIR_NAME))?;
+        builder.add_line(None, &format!("*{}", PARTIAL_SUFFIX))?;
+        for pattern in patterns {
+            builder.add_line(None, pattern)?;
+        }
+        Ok(IgnoreRules {
+            root: root.to_path_buf(),
+            global: builder.build()?,
+        })
+    }
+
+    /// Returns a walker over `target` (the root or a directory below it) that skips ignored entries.
+    pub fn walker(&self, target: &Path) -> ignore::Walk {
+        let global = self.global.clone();
+        let ancestors = self.ancestor_ignores(target);
+        let root = self.root.clone();
+
+        WalkBuilder::new(target)
+            .standard_filters(false)
+            .follow_links(false)
+            .add_custom_ignore_filename(IGNORE_FILE_NAME)
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|file_type| file_type.is_dir());
+                let path = entry.path();
+                if !path.starts_with(&root) {
+                    return true;
+                }
+                !global.matched(path, is_dir).is_ignore()
+                    && !ancestors.iter().any(|ignore| ignore.matched(path, is_dir).is_ignore())
+            })
+            .build()
+    }
+
+    /// Checks whether `path` or any of its parents is excluded. Used for paths reported by the
+    /// watcher, which do not go through a walk from the root.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if !path.starts_with(&self.root) {
+            return false;
+        }
+        if self.global.matched_path_or_any_parents(path, is_dir).is_ignore() {
+            return true;
+        }
+        let Some(parent) = path.parent() else { return false };
+
+        let mut ignored = false;
+        for ignore in self.ancestor_ignores(parent).iter().chain(self.own_ignore(parent).iter()) {
+            match ignore.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => ignored = true,
+                Match::Whitelist(_) => ignored = false,
+                Match::None => {}
+            }
+        }
+        ignored
+    }
+
+    /// Lists the top-most entries under `target` excluded by these rules.
+    pub fn excluded_paths(&self, target: &Path) -> Result<Vec<PathBuf>, FileTrackerError> {
+        let mut included = HashSet::new();
+        for entry in self.walker(target) {
+            included.insert(entry?.into_path());
+        }
+
+        let mut excluded = Vec::new();
+        let mut walk = walkdir::WalkDir::new(target).follow_links(false).into_iter();
+        while let Some(entry) = walk.next() {
+            let entry = entry?;
+            if !included.contains(entry.path()) {
+                excluded.push(entry.path().to_path_buf());
+                if entry.file_type().is_dir() {
+                    walk.skip_current_dir();
+                }
+            }
+        }
+        excluded.sort();
+        Ok(excluded)
+    }
+
+    /// Loads the `.egadignore` files of the directories between the root and `dir`, excluding `dir`.
+    fn ancestor_ignores(&self, dir: &Path) -> Vec<Gitignore> {
+        let Ok(relative) = dir.strip_prefix(&self.root) else { return Vec::new() };
+
+        let mut current = self.root.clone();
+        let mut ignores = Vec::new();
+        for component in relative.components() {
+            ignores.extend(self.own_ignore(&current));
+            current.push(component);
+        }
+        ignores
+    }
+
+    /// Loads the `.egadignore` file located directly in `dir`, if any.
+    fn own_ignore(&self, dir: &Path) -> Option<Gitignore> {
+        let file = dir.join(IGNORE_FILE_NAME);
+        if !file.is_file() {
+            return None;
+        }
+        let (ignore, err) = Gitignore::new(&file);
+        if let Some(err) = err {
+            log::warn!("Invalid rule in {}: {}", file.display(), err);
+        }
+        Some(ignore)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn global_patterns_and_ignore_files_are_honored() {
+        let root = std::env::temp_dir().join(format!("egadsync-ignore-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["build/out", "docs/drafts", TRASH_DIR_NAME] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "notes.txt",
+            "editor.swp",
+            "build/out/app.bin",
+            "docs/a.md",
+            "docs/b.tmp",
+            "docs/keep.tmp",
+            "docs/drafts/c.md",
+        ] {
+            fs::write(root.join(file), "x").unwrap();
+        }
+        fs::write(root.join(format!("upload{}", PARTIAL_SUFFIX)), "x").unwrap();
+        fs::write(root.join(TRASH_DIR_NAME).join("old.txt"), "x").unwrap();
+        fs::write(root.join("docs").join(IGNORE_FILE_NAME), "*.tmp\n!keep.tmp\ndrafts/\n").unwrap();
+        let rules = IgnoreRules::new(&root, &["*.swp".to_string(), "/build/".to_string()]).unwrap();
+
+        let mut walked: Vec<PathBuf> = rules
+            .walker(&root)
+            .map(|entry| entry.unwrap().into_path().strip_prefix(&root).unwrap().to_path_buf())
+            .collect();
+        walked.sort();
+        let expected: Vec<PathBuf> = ["", "docs", "docs/.egadignore", "docs/a.md", "docs/keep.tmp", "notes.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(walked, expected);
+
+        assert!(rules.is_ignored(&root.join("build/out/app.bin"), false));
+        assert!(rules.is_ignored(&root.join("docs/b.tmp"), false));
+        assert!(rules.is_ignored(&root.join("docs/drafts/c.md"), false));
+        assert!(!rules.is_ignored(&root.join("docs/keep.tmp"), false));
+        assert!(!rules.is_ignored(&root.join("notes.txt"), false));
+        assert!(!rules.is_ignored(Path::new("/outside/editor.swp"), false));
+
+        let excluded: Vec<PathBuf> = rules
+            .excluded_paths(&root)
+            .unwrap()
+            .iter()
+            .map(|path| path.strip_prefix(&root).unwrap().to_path_buf())
+            .collect();
+        let mut expected: Vec<PathBuf> = [
+            TRASH_DIR_NAME.to_string(),
+            "build".to_string(),
+            "docs/b.tmp".to_string(),
+            "docs/drafts".to_string(),
+            "editor.swp".to_string(),
+            format!("upload{}", PARTIAL_SUFFIX),
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        expected.sort();
+        assert_eq!(excluded, expected);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
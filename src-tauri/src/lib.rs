@@ -1,24 +1,65 @@
 use tauri::{
-    menu::{Menu, MenuItem},
+    menu::{IsMenuItem, Menu, MenuItem, Submenu},
     tray::{TrayIconBuilder, TrayIconEvent},
-    AppHandle, Emitter, Manager,
+    AppHandle, Emitter, Manager, State,
 };
 use tauri_plugin_autostart::ManagerExt;
 
+pub mod chunks;
 pub mod config;
+pub mod conflicts;
+pub mod delta;
+pub mod destination;
+pub mod devices;
+pub mod discovery;
+pub mod encryption;
 pub mod error;
 pub mod file_tracker;
+pub mod ignore_rules;
 pub mod logger;
+pub mod mirror;
+pub mod peer;
+pub mod persistence;
+pub mod roots;
+pub mod s3;
+pub mod secrets;
+pub mod sftp;
+pub mod state_schema;
+pub mod state_store;
 pub mod sync;
+pub mod trash;
+pub mod two_way;
+pub mod versions;
+pub mod watcher;
+pub mod webdav;
 
-use config::Config;
+use chunks::{ChunkStore, GcReport};
+use config::{Config, SharedConfig};
+use conflicts::{ConflictLog, PendingConflict, Resolution};
+use destination::RemoteDestination;
+use devices::{DeviceRegistry, LocalDevice, PairedDevice, PairingRequest};
+use discovery::{DiscoveredDevice, Discovery};
+use encryption::Encryption;
 use error::FileTrackerError;
-use file_tracker::FileTracker;
-use sync::start_sync_loop;
+use file_tracker::{FileMetadata, FileTracker};
+use ignore_rules::IgnoreRules;
+use mirror::MirrorReport;
+use peer::{PeerLink, PeerNode};
+use roots::{MonitoredRoot, RegistryLock, RootRegistry, SyncMode};
+use std::path::{Path, PathBuf};
+use sync::{mirror_changes, record_conflicts, two_way_payload, SyncManager};
+use trash::TrashedItem;
+use versions::{FileVersion, VersionHistory};
+
+/// Prefix of the tray menu ids of discovered devices, followed by the device identifier.
+const DEVICE_MENU_PREFIX: &str = "device:";
 
 #[derive(Debug, Clone, PartialEq)]
 enum TrayMenuId {
     Open,
+    Pause,
+    Resume,
+    EmptyTrash,
     Quit,
 }
 
@@ -26,6 +67,9 @@ impl TrayMenuId {
     fn as_str(&self) -> &'static str {
         match self {
             TrayMenuId::Open => "open",
+            TrayMenuId::Pause => "pause",
+            TrayMenuId::Resume => "resume",
+            TrayMenuId::EmptyTrash => "empty_trash",
             TrayMenuId::Quit => "quit",
         }
     }
@@ -33,6 +77,9 @@ impl TrayMenuId {
     fn from_str(s: &str) -> Option<Self> {
         match s {
             "open" => Some(TrayMenuId::Open),
+            "pause" => Some(TrayMenuId::Pause),
+            "resume" => Some(TrayMenuId::Resume),
+            "empty_trash" => Some(TrayMenuId::EmptyTrash),
             "quit" => Some(TrayMenuId::Quit),
             _ => None,
         }
@@ -41,26 +88,676 @@ impl TrayMenuId {
     fn label(&self) -> &'static str {
         match self {
             TrayMenuId::Open => "Abrir",
+            TrayMenuId::Pause => "Pausar monitoramento",
+            TrayMenuId::Resume => "Retomar monitoramento",
+            TrayMenuId::EmptyTrash => "Esvaziar lixeira",
             TrayMenuId::Quit => "Sair",
         }
     }
 }
 
 #[tauri::command]
-fn stop_monitoring() -> Result<(), FileTrackerError> {
-    let config = Config::default();
-    FileTracker::stop_monitoring_and_delete_state(&config)
+async fn stop_monitoring(
+    config: State<'_, SharedConfig>,
+    sync_manager: State<'_, SyncManager>,
+    registry_lock: State<'_, RegistryLock>,
+    root_id: Option<String>,
+) -> Result<(), FileTrackerError> {
+    let config = config.get();
+    let ids = match root_id {
+        Some(id) => vec![id],
+        None => RootRegistry::load(&config)?.roots.into_iter().map(|root| root.id).collect(),
+    };
+    for id in ids {
+        unregister_root(&config, &sync_manager, &registry_lock, &id).await?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn get_save_state(config: State<'_, SharedConfig>, root_id: Option<String>) -> Result<FileTracker, FileTrackerError> {
+    let config = config.get();
+    let registry = RootRegistry::load(&config)?;
+    let root = match root_id {
+        Some(id) => registry.get(&id),
+        None => registry.roots.first(),
+    }
+    .ok_or(FileTrackerError::RootNotFound)?;
+    FileTracker::get(&root.config(&config))
+}
+
+#[tauri::command]
+fn get_monitoring_status(config: State<'_, SharedConfig>) -> bool {
+    RootRegistry::load(&config.get()).is_ok_and(|registry| registry.roots.iter().any(|root| !root.paused))
+}
+
+#[tauri::command]
+async fn pause_monitoring(app: AppHandle, root_id: Option<String>) -> Result<(), FileTrackerError> {
+    set_paused(&app, root_id, true).await
+}
+
+#[tauri::command]
+async fn resume_monitoring(app: AppHandle, root_id: Option<String>) -> Result<(), FileTrackerError> {
+    set_paused(&app, root_id, false).await
+}
+
+/// Pauses or resumes one root (or all of them). Paused roots keep their tracker state, so the
+/// first scan after resuming reports everything that changed in the meantime.
+async fn set_paused(app: &AppHandle, root_id: Option<String>, paused: bool) -> Result<(), FileTrackerError> {
+    let config = app.state::<SharedConfig>().get();
+    let sync_manager = app.state::<SyncManager>();
+    let registry_lock = app.state::<RegistryLock>();
+    let _guard = registry_lock.lock().await;
+    let mut registry = RootRegistry::load(&config)?;
+    let ids = match root_id {
+        Some(id) => vec![id],
+        None => registry.roots.iter().map(|root| root.id.clone()).collect(),
+    };
+
+    for id in ids {
+        let root = registry.set_paused(&id, paused)?;
+        registry.save(&config)?;
+        if paused {
+            sync_manager.stop(&id).await;
+        } else if !sync_manager.is_running(&id) {
+            sync_manager.start(app.clone(), root);
+            let _ = app.emit("sync_started", "Monitoramento retomado");
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn list_roots(config: State<'_, SharedConfig>) -> Result<Vec<MonitoredRoot>, FileTrackerError> {
+    Ok(RootRegistry::load(&config.get())?.roots.into_iter().map(MonitoredRoot::without_secrets).collect())
+}
+
+#[tauri::command]
+async fn add_root(
+    app: AppHandle,
+    target_folder: String,
+    destination_folder: Option<String>,
+    remote_destination: Option<RemoteDestination>,
+    encryption_passphrase: Option<String>,
+    sync_interval_secs: Option<u64>,
+    sync_mode: Option<SyncMode>,
+) -> Result<MonitoredRoot, FileTrackerError> {
+    let config = app.state::<SharedConfig>().get();
+    // Held until the new root is saved, through the first scan of the folder.
+    let registry_lock = app.state::<RegistryLock>();
+    let guard = registry_lock.lock().await;
+    let mut registry = RootRegistry::load(&config)?;
+    let target_folder = PathBuf::from(target_folder);
+    let destination_folder = destination_folder
+        .filter(|folder| !folder.trim().is_empty())
+        .map(PathBuf::from);
+    let encryption_passphrase = encryption_passphrase.filter(|passphrase| !passphrase.is_empty());
+    let sync_mode = sync_mode.unwrap_or_default();
+    registry.check_new(
+        &target_folder,
+        destination_folder.as_deref(),
+        remote_destination.as_ref(),
+        encryption_passphrase.is_some(),
+        sync_mode,
+    )?;
+
+    let RootSecrets {
+        remote_destination,
+        encryption,
+        replaced,
+    } = tokio::task::spawn_blocking({
+        let config = config.clone();
+        move || store_root_secrets(&config, remote_destination, encryption_passphrase)
+    })
+    .await??;
+    let added: Result<_, FileTrackerError> = async {
+        let root = registry.add(
+            &target_folder,
+            destination_folder,
+            remote_destination,
+            encryption,
+            sync_interval_secs,
+            sync_mode,
+        )?;
+        if let (Some(remote), Some(encryption)) = (root.remote_destination.clone(), root.encryption.clone()) {
+            let (config, state_path) = (config.clone(), root.remote_state_path(&config));
+            tokio::task::spawn_blocking(move || encryption::publish_header(&config, &remote, &encryption, &state_path))
+                .await??;
+        }
+        let file_tracker = tokio::task::spawn_blocking({
+            let root = root.clone();
+            let root_config = root.config(&config);
+            move || FileTracker::new(&root.root_target, root.root_destination.clone(), &root_config)
+        })
+        .await??;
+        registry.save(&config)?;
+        Ok((root, file_tracker))
+    }
+    .await;
+    let (root, mut file_tracker) = match added {
+        Ok(added) => added,
+        Err(e) => {
+            tokio::task::spawn_blocking(move || revert_secrets(&config, replaced)).await?;
+            return Err(e);
+        }
+    };
+    drop(guard);
+
+    let _ = app.emit("sync_started", "Monitoramento iniciado");
+    match (root.sync_mode, root.root_destination.clone()) {
+        (SyncMode::TwoWay, Some(root_destination)) => {
+            let destination_config = root.destination_config(&config);
+            let mut destination = tokio::task::spawn_blocking({
+                let destination_config = destination_config.clone();
+                move || FileTracker::new(&root_destination, None, &destination_config)
+            })
+            .await??;
+            let history = VersionHistory::for_root(&root, &config);
+            let report = two_way::initial_reconcile(&mut file_tracker, &mut destination, &history).await?;
+            record_conflicts(&app, &root, &config, &mut file_tracker, &mut destination, &report.conflicts).await?;
+            file_tracker.save(&root.config(&config))?;
+            destination.save(&destination_config)?;
+            let _ = app.emit("file_diffs", two_way_payload(&root, &[], report));
+        }
+        _ => {
+            let changes = mirror::initial_changes(&file_tracker);
+            mirror_changes(&app, &root, &config, &mut file_tracker, &changes).await;
+            file_tracker.save(&root.config(&config))?;
+        }
+    }
+    app.state::<SyncManager>().start(app.clone(), root.clone());
+    Ok(root.without_secrets())
+}
+
+/// What [`store_root_secrets`] stored for a new root.
+struct RootSecrets {
+    remote_destination: Option<RemoteDestination>,
+    encryption: Option<Encryption>,
+    /// Accounts written, with the secret each held before.
+    replaced: Vec<(String, Option<String>)>,
+}
+
+/// Moves the secrets a new root is given to the secret store: its encryption passphrase and
+/// the secret in its remote settings. Nothing is left stored when this fails.
+fn store_root_secrets(
+    config: &Config,
+    mut remote_destination: Option<RemoteDestination>,
+    passphrase: Option<String>,
+) -> Result<RootSecrets, FileTrackerError> {
+    let encryption = passphrase.map(|passphrase| Encryption::create(config, &passphrase)).transpose()?;
+    let mut replaced: Vec<_> = encryption
+        .iter()
+        .map(|encryption| (Encryption::secret_account(&encryption.id), None))
+        .collect();
+    if let Some((remote, account)) = remote_destination
+        .as_mut()
+        .and_then(|remote| remote.secret_account().map(|account| (remote, account)))
+    {
+        let stored = secrets::find(config, &account).and_then(|previous| Ok((remote.store_secrets(config)?, previous)));
+        match stored {
+            Ok((true, previous)) => replaced.push((account, previous)),
+            Ok((false, _)) => {}
+            Err(e) => {
+                revert_secrets(config, replaced);
+                return Err(e);
+            }
+        }
+    }
+    Ok(RootSecrets {
+        remote_destination,
+        encryption,
+        replaced,
+    })
+}
+
+/// Puts back the secrets stored for a root that could not be added: accounts that held a
+/// secret get it again, the others are cleared.
+fn revert_secrets(config: &Config, replaced: Vec<(String, Option<String>)>) {
+    for (account, previous) in replaced.into_iter().rev() {
+        let result = match previous {
+            Some(secret) => secrets::set(config, &account, &secret),
+            None => secrets::clear(config, &account),
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to put back the secret of {}: {}", account, e);
+        }
+    }
+}
+
+#[tauri::command]
+async fn remove_root(
+    config: State<'_, SharedConfig>,
+    sync_manager: State<'_, SyncManager>,
+    registry_lock: State<'_, RegistryLock>,
+    root_id: String,
+) -> Result<(), FileTrackerError> {
+    unregister_root(&config.get(), &sync_manager, &registry_lock, &root_id).await
+}
+
+/// Stops the sync loop of a root, then removes it from the registry and deletes its state.
+async fn unregister_root(
+    config: &Config,
+    sync_manager: &SyncManager,
+    registry_lock: &RegistryLock,
+    root_id: &str,
+) -> Result<(), FileTrackerError> {
+    let guard = registry_lock.lock().await;
+    let mut registry = RootRegistry::load(config)?;
+    let root = registry.remove(root_id)?;
+    sync_manager.stop(root_id).await;
+    registry.save(config)?;
+    drop(guard);
+    if root.sync_mode == SyncMode::TwoWay {
+        FileTracker::stop_monitoring_and_delete_state(&root.destination_config(config))?;
+        match std::fs::remove_file(root.conflicts_path(config)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    match std::fs::remove_dir_all(root.versions_dir(config)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    if root.remote_destination.is_some() {
+        match std::fs::remove_file(root.remote_state_path(config)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    if root.peer.is_some() {
+        match std::fs::remove_file(root.peer_state_path(config)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    FileTracker::stop_monitoring_and_delete_state(&root.config(config))
+}
+
+/// Lists the conflicts waiting for the user, for one root or for all of them.
+#[tauri::command]
+fn list_conflicts(
+    config: State<'_, SharedConfig>,
+    root_id: Option<String>,
+) -> Result<Vec<PendingConflict>, FileTrackerError> {
+    let config = config.get();
+    let mut pending = Vec::new();
+    for root in selected_roots(&config, root_id.as_deref())? {
+        let conflict_log = ConflictLog::load(&root, &config)?;
+        pending.extend(conflict_log.conflicts.into_iter().map(|conflict| PendingConflict {
+            root_id: root.id.clone(),
+            conflict,
+        }));
+    }
+    Ok(pending)
+}
+
+#[tauri::command]
+async fn resolve_conflict(
+    config: State<'_, SharedConfig>,
+    sync_manager: State<'_, SyncManager>,
+    root_id: String,
+    path: String,
+    resolution: Resolution,
+) -> Result<(), FileTrackerError> {
+    let config = config.get();
+    let root = RootRegistry::load(&config)?.get(&root_id).cloned().ok_or(FileTrackerError::RootNotFound)?;
+    sync_manager.resolve_conflict(&config, &root, PathBuf::from(path), resolution).await
+}
+
+/// Lists the saved versions of a file, newest first. `path` is relative to the root.
+#[tauri::command]
+fn list_versions(
+    config: State<'_, SharedConfig>,
+    root_id: String,
+    path: String,
+) -> Result<Vec<FileVersion>, FileTrackerError> {
+    let config = config.get();
+    let root = RootRegistry::load(&config)?.get(&root_id).cloned().ok_or(FileTrackerError::RootNotFound)?;
+    VersionHistory::for_root(&root, &config).list(Path::new(&path))
+}
+
+/// Restores a saved version into the monitored folder; the sync loop then propagates it like any edit.
+#[tauri::command]
+async fn restore_version(
+    config: State<'_, SharedConfig>,
+    root_id: String,
+    path: String,
+    version_id: u64,
+) -> Result<(), FileTrackerError> {
+    let config = config.get();
+    let root = RootRegistry::load(&config)?.get(&root_id).cloned().ok_or(FileTrackerError::RootNotFound)?;
+    tokio::task::spawn_blocking(move || {
+        let relative = Path::new(&path);
+        VersionHistory::for_root(&root, &config).restore(relative, version_id, &root.root_target.join(relative))
+    })
+    .await?
+}
+
+/// Decrypts what is stored at an encrypted remote destination into `target_folder`, which must
+/// be new or empty. Either `root_id` names a registered root, whose passphrase is taken from the
+/// secret store unless one is given, or `remote_destination` and `passphrase` describe a
+/// destination this device does not know, e.g. after reinstalling. Entries that could not be
+/// restored are listed in the report.
+#[tauri::command]
+async fn restore_encrypted(
+    config: State<'_, SharedConfig>,
+    root_id: Option<String>,
+    remote_destination: Option<RemoteDestination>,
+    passphrase: Option<String>,
+    target_folder: String,
+) -> Result<MirrorReport, FileTrackerError> {
+    let config = config.get();
+    let root = match root_id {
+        Some(root_id) => {
+            let registry = RootRegistry::load(&config)?;
+            Some(registry.get(&root_id).cloned().ok_or(FileTrackerError::RootNotFound)?)
+        }
+        None => None,
+    };
+    let (remote, encryption) = match (&root, remote_destination) {
+        (Some(root), _) => match (root.remote_destination.clone(), root.encryption.clone()) {
+            (Some(remote), Some(encryption)) => (remote, Some(encryption)),
+            _ => {
+                return Err(FileTrackerError::InvalidConfig(
+                    "this folder has no encrypted remote destination".to_string(),
+                ))
+            }
+        },
+        (None, Some(remote)) if passphrase.is_some() => (remote, None),
+        (None, _) => {
+            return Err(FileTrackerError::InvalidConfig(
+                "restoring needs a registered folder, or a remote destination and its passphrase".to_string(),
+            ))
+        }
+    };
+    tokio::task::spawn_blocking(move || {
+        let file_tracker = root.map(|root| FileTracker::get(&root.config(&config))).transpose()?;
+        let registered = file_tracker.as_ref().zip(encryption.as_ref());
+        encryption::restore(&config, &remote, passphrase.as_deref(), registered, Path::new(&target_folder))
+    })
+    .await?
+}
+
+/// Stores a secret, such as an S3 secret key, a WebDAV password, the passphrase of an SFTP key
+/// or an encryption passphrase. Secrets are never sent back to the frontend: `has_secret` only
+/// tells whether one is set.
+#[tauri::command]
+async fn set_secret(config: State<'_, SharedConfig>, account: String, secret: String) -> Result<(), FileTrackerError> {
+    secrets::validate_account(&account)?;
+    let config = config.get();
+    let encryption = encryption_for_account(&config, &account)?;
+    tokio::task::spawn_blocking(move || {
+        // A wrong passphrase would otherwise only show up as failing sync rounds.
+        if let Some(encryption) = &encryption {
+            encryption.unlock_with(&secret)?;
+        }
+        secrets::set(&config, &account, &secret)
+    })
+    .await?
+}
+
+/// Removes a stored secret. Destinations that need it fail until it is set again.
+#[tauri::command]
+async fn clear_secret(config: State<'_, SharedConfig>, account: String) -> Result<(), FileTrackerError> {
+    secrets::validate_account(&account)?;
+    let config = config.get();
+    if let Some(encryption) = encryption_for_account(&config, &account)? {
+        Encryption::forget_key(&encryption.id);
+    }
+    tokio::task::spawn_blocking(move || secrets::clear(&config, &account)).await?
+}
+
+#[tauri::command]
+async fn has_secret(config: State<'_, SharedConfig>, account: String) -> Result<bool, FileTrackerError> {
+    secrets::validate_account(&account)?;
+    let config = config.get();
+    Ok(tokio::task::spawn_blocking(move || secrets::find(&config, &account)).await??.is_some())
+}
+
+/// The encryption of a registered root whose passphrase is stored under `account`, if any.
+fn encryption_for_account(config: &Config, account: &str) -> Result<Option<Encryption>, FileTrackerError> {
+    Ok(RootRegistry::load(config)?
+        .roots
+        .into_iter()
+        .filter_map(|root| root.encryption)
+        .find(|encryption| Encryption::secret_account(&encryption.id) == account))
+}
+
+/// Lists the paths deleted by sync that are still in the trash, for one root or for all of them.
+#[tauri::command]
+fn list_trash(config: State<'_, SharedConfig>, root_id: Option<String>) -> Result<Vec<TrashedItem>, FileTrackerError> {
+    let config = config.get();
+    let mut items = Vec::new();
+    for root in selected_roots(&config, root_id.as_deref())? {
+        for trash in root.trashes() {
+            items.extend(trash.list()?.into_iter().map(|entry| TrashedItem {
+                root_id: root.id.clone(),
+                folder: trash.folder().to_path_buf(),
+                entry,
+            }));
+        }
+    }
+    Ok(items)
+}
+
+/// Moves a trash entry back to where it was deleted from, returning that path.
+#[tauri::command]
+async fn restore_from_trash(
+    config: State<'_, SharedConfig>,
+    root_id: String,
+    folder: String,
+    id: u64,
+) -> Result<String, FileTrackerError> {
+    let config = config.get();
+    let root = RootRegistry::load(&config)?.get(&root_id).cloned().ok_or(FileTrackerError::RootNotFound)?;
+    let trash = root
+        .trashes()
+        .into_iter()
+        .find(|trash| trash.folder() == Path::new(&folder))
+        .ok_or(FileTrackerError::TrashEntryNotFound)?;
+    let restored = tokio::task::spawn_blocking(move || trash.restore(id)).await??;
+    Ok(restored.display().to_string())
+}
+
+/// Permanently deletes the trash of one root or of all of them, returning how many entries were removed.
+#[tauri::command]
+async fn empty_trash(config: State<'_, SharedConfig>, root_id: Option<String>) -> Result<usize, FileTrackerError> {
+    empty_trashes(&config.get(), root_id).await
+}
+
+async fn empty_trashes(config: &Config, root_id: Option<String>) -> Result<usize, FileTrackerError> {
+    let trashes: Vec<_> = selected_roots(config, root_id.as_deref())?
+        .iter()
+        .flat_map(MonitoredRoot::trashes)
+        .collect();
+    tokio::task::spawn_blocking(move || trashes.iter().map(|trash| trash.empty()).sum()).await?
+}
+
+/// Reclaims the chunks no longer used by a tracked file or a saved version.
+#[tauri::command]
+async fn collect_garbage(config: State<'_, SharedConfig>) -> Result<GcReport, FileTrackerError> {
+    let config = config.get();
+    tokio::task::spawn_blocking(move || {
+        let mut live = std::collections::HashSet::new();
+        for root in RootRegistry::load(&config)?.roots {
+            let mut tracker_configs = vec![root.config(&config)];
+            if root.sync_mode == SyncMode::TwoWay {
+                tracker_configs.push(root.destination_config(&config));
+            }
+            for tracker_config in tracker_configs {
+                let tracker = FileTracker::get(&tracker_config)?;
+                live.extend(tracker.files_state.values().filter_map(FileMetadata::manifest).map(str::to_string));
+            }
+            live.extend(VersionHistory::for_root(&root, &config).manifests()?);
+        }
+        ChunkStore::new(&config).gc(&live)
+    })
+    .await?
+}
+
+/// This device as other devices see it.
+#[tauri::command]
+fn get_device_identity(config: State<'_, SharedConfig>, node: State<'_, PeerNode>) -> LocalDevice {
+    let config = config.get();
+    LocalDevice {
+        id: node.device_id().to_string(),
+        name: config.device_name,
+        peer_port: config.peer_port,
+    }
+}
+
+/// Starts pairing with the device listening at `address` (`host:port`). The returned code is
+/// shown on both devices, and the pairing is confirmed on each with `confirm_pairing`.
+#[tauri::command]
+async fn pair_device(
+    config: State<'_, SharedConfig>,
+    node: State<'_, PeerNode>,
+    address: String,
+) -> Result<PairingRequest, FileTrackerError> {
+    node.pair(&config.get(), address.trim()).await
+}
+
+#[tauri::command]
+fn list_pairing_requests(node: State<'_, PeerNode>) -> Vec<PairingRequest> {
+    node.pairing_requests()
+}
+
+/// Accepts or rejects a pending pairing once the user compared the codes of both devices.
+#[tauri::command]
+fn confirm_pairing(
+    config: State<'_, SharedConfig>,
+    node: State<'_, PeerNode>,
+    device_id: String,
+    accept: bool,
+) -> Result<Option<PairedDevice>, FileTrackerError> {
+    node.confirm_pairing(&config.get(), &device_id, accept)
+}
+
+/// Devices announcing themselves on the local network; empty when discovery is disabled.
+#[tauri::command]
+fn list_discovered_devices(app: AppHandle) -> Result<Vec<DiscoveredDevice>, FileTrackerError> {
+    let Some(discovery) = app.try_state::<Discovery>() else { return Ok(Vec::new()) };
+    let registry = DeviceRegistry::load(&app.state::<SharedConfig>().get())?;
+    Ok(discovery.devices(&registry))
+}
+
+#[tauri::command]
+fn list_devices(config: State<'_, SharedConfig>) -> Result<Vec<PairedDevice>, FileTrackerError> {
+    Ok(DeviceRegistry::load(&config.get())?.devices)
+}
+
+/// Forgets a paired device and unlinks the roots synced with it.
+#[tauri::command]
+async fn remove_device(app: AppHandle, device_id: String) -> Result<(), FileTrackerError> {
+    let config = app.state::<SharedConfig>().get();
+    let mut devices = DeviceRegistry::load(&config)?;
+    devices.remove(&device_id)?;
+    devices.save(&config)?;
+
+    let registry_lock = app.state::<RegistryLock>();
+    let _guard = registry_lock.lock().await;
+    let mut registry = RootRegistry::load(&config)?;
+    let linked: Vec<String> = registry
+        .roots
+        .iter()
+        .filter(|root| root.peer.as_ref().is_some_and(|peer| peer.device_id == device_id))
+        .map(|root| root.id.clone())
+        .collect();
+    for id in linked {
+        link_root(&app, &config, &mut registry, &id, None)?;
+    }
+    Ok(())
+}
+
+/// Links a root to a paired device under a share name both devices use, or unlinks it.
+#[tauri::command]
+async fn set_root_peer(
+    app: AppHandle,
+    root_id: String,
+    peer: Option<PeerLink>,
+) -> Result<MonitoredRoot, FileTrackerError> {
+    let config = app.state::<SharedConfig>().get();
+    if let Some(peer) = &peer {
+        DeviceRegistry::load(&config)?
+            .get(&peer.device_id)
+            .ok_or(FileTrackerError::DeviceNotFound)?;
+    }
+    let registry_lock = app.state::<RegistryLock>();
+    let _guard = registry_lock.lock().await;
+    let mut registry = RootRegistry::load(&config)?;
+    Ok(link_root(&app, &config, &mut registry, &root_id, peer)?.without_secrets())
+}
+
+/// Changes the device a root is synced with, forgetting what was agreed with the previous one,
+/// and restarts its sync loop if running.
+fn link_root(
+    app: &AppHandle,
+    config: &Config,
+    registry: &mut RootRegistry,
+    root_id: &str,
+    peer: Option<PeerLink>,
+) -> Result<MonitoredRoot, FileTrackerError> {
+    let root = registry.set_peer(root_id, peer)?;
+    registry.save(config)?;
+    match std::fs::remove_file(root.peer_state_path(config)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let sync_manager = app.state::<SyncManager>();
+    if sync_manager.is_running(root_id) {
+        sync_manager.start(app.clone(), root.clone());
+    }
+    Ok(root)
+}
+
+/// The registered roots, or only the one with `root_id` when given.
+fn selected_roots(config: &Config, root_id: Option<&str>) -> Result<Vec<MonitoredRoot>, FileTrackerError> {
+    let registry = RootRegistry::load(config)?;
+    Ok(registry
+        .roots
+        .into_iter()
+        .filter(|root| root_id.is_none_or(|id| root.id == id))
+        .collect())
+}
+
+/// Returns the running configuration.
+#[tauri::command]
+fn get_config(config: State<'_, SharedConfig>) -> Config {
+    config.get()
 }
 
+/// Applies a partial update (only the keys present in `changes`) on top of the running configuration.
 #[tauri::command]
-fn get_save_state() -> Result<FileTracker, FileTrackerError> {
-    FileTracker::get(&Config::default())
+fn update_config(config: State<'_, SharedConfig>, changes: serde_json::Value) -> Result<Config, FileTrackerError> {
+    let mut merged = serde_json::to_value(config.get())?;
+    match (&mut merged, changes) {
+        (serde_json::Value::Object(current), serde_json::Value::Object(changes)) => current.extend(changes),
+        _ => return Err(FileTrackerError::InvalidConfig("expected an object of settings".to_string())),
+    }
+
+    let defaults = config.get();
+    let updated = Config {
+        data_dir: defaults.data_dir,
+        state_file_path: defaults.state_file_path,
+        ..serde_json::from_value::<Config>(merged)?
+    };
+    config.update(updated.clone())?;
+    Ok(updated)
 }
 
 #[tauri::command]
-fn get_monitoring_status() -> bool {
-    let config = Config::default();
-    FileTracker::is_monitoring_active(&config)
+async fn preview_ignored(
+    config: State<'_, SharedConfig>,
+    target_folder: String,
+    patterns: Option<Vec<String>>,
+) -> Result<Vec<String>, FileTrackerError> {
+    let patterns = patterns.unwrap_or_else(|| config.get().ignore_patterns);
+    tokio::task::spawn_blocking(move || {
+        let root = std::path::Path::new(&target_folder);
+        let excluded = IgnoreRules::new(root, &patterns)?.excluded_paths(root)?;
+        Ok(excluded.iter().map(|path| path.display().to_string()).collect())
+    })
+    .await?
 }
 
 #[tauri::command]
@@ -81,42 +778,91 @@ async fn select_folder(app: AppHandle) -> Result<Option<String>, String> {
 }
 
 #[tauri::command]
-fn setup(app: AppHandle, target_folder: &str) {
+fn setup(app: AppHandle, target_folder: &str, destination_folder: Option<&str>, sync_mode: Option<SyncMode>) {
     let target_folder = target_folder.to_string();
-    let config = Config::default();
+    let destination_folder = destination_folder.map(str::to_string);
     tauri::async_runtime::spawn(async move {
-        match FileTracker::new(&target_folder, &config) {
-            Ok(_) => {
-                let _ = app.emit("sync_started", "Monitoramento iniciado");
-                start_sync_loop(app);
-            }
-            Err(e) => {
-                log::error!("Failed to initialize FileTracker: {}", e);
-                let _ = app.emit("sync_error", format!("Erro ao iniciar: {}", e));
-            }
+        if let Err(e) = add_root(app.clone(), target_folder, destination_folder, None, None, None, sync_mode).await {
+            log::error!("Failed to initialize FileTracker: {}", e);
+            let _ = app.emit("sync_error", format!("Erro ao iniciar: {}", e));
         }
     });
 }
 
 fn create_tray_menu(app: &AppHandle) -> Result<Menu<tauri::Wry>, tauri::Error> {
-    let open_item = MenuItem::with_id(
-        app,
-        TrayMenuId::Open.as_str(),
-        TrayMenuId::Open.label(),
-        true,
-        None::<&str>,
-    )?;
-    let quit_item = MenuItem::with_id(
+    let open_item = create_menu_item(app, TrayMenuId::Open)?;
+    let pause_item = create_menu_item(app, TrayMenuId::Pause)?;
+    let resume_item = create_menu_item(app, TrayMenuId::Resume)?;
+    let empty_trash_item = create_menu_item(app, TrayMenuId::EmptyTrash)?;
+    let quit_item = create_menu_item(app, TrayMenuId::Quit)?;
+    let menu = Menu::with_items(
         app,
-        TrayMenuId::Quit.as_str(),
-        TrayMenuId::Quit.label(),
-        true,
-        None::<&str>,
+        &[&open_item, &pause_item, &resume_item, &empty_trash_item, &quit_item],
     )?;
-    let menu = Menu::with_items(app, &[&open_item, &quit_item])?;
+    if let Some(discovery) = app.try_state::<Discovery>() {
+        let devices_menu = create_devices_submenu(app, &discovery)?;
+        menu.insert(&devices_menu, 4)?;
+    }
     Ok(menu)
 }
 
+/// Lists the devices found on the network; choosing one that is not paired yet starts pairing.
+fn create_devices_submenu(app: &AppHandle, discovery: &Discovery) -> Result<Submenu<tauri::Wry>, tauri::Error> {
+    let registry = DeviceRegistry::load(&app.state::<SharedConfig>().get()).unwrap_or_else(|e| {
+        log::error!("Failed to load paired devices: {}", e);
+        DeviceRegistry::default()
+    });
+    let devices = discovery.devices(&registry);
+    let items = match devices.is_empty() {
+        true => vec![MenuItem::new(app, "Nenhum dispositivo encontrado", false, None::<&str>)?],
+        false => devices
+            .iter()
+            .map(|device| {
+                let id = format!("{}{}", DEVICE_MENU_PREFIX, device.id);
+                let label = match device.paired {
+                    true => format!("{} (pareado)", device.name),
+                    false => format!("Parear com {}", device.name),
+                };
+                MenuItem::with_id(app, id, label, !device.paired, None::<&str>)
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    let items: Vec<&dyn IsMenuItem<tauri::Wry>> = items.iter().map(|item| item as &dyn IsMenuItem<_>).collect();
+    Submenu::with_items(app, "Dispositivos na rede", true, &items)
+}
+
+/// Rebuilds the tray menu, after the discovered devices changed.
+fn refresh_tray_menu(app: &AppHandle) {
+    let Some(tray) = app.tray_by_id("main_tray") else { return };
+    if let Err(e) = create_tray_menu(app).and_then(|menu| tray.set_menu(Some(menu))) {
+        log::error!("Failed to refresh the tray menu: {}", e);
+    }
+}
+
+/// Starts pairing with a device chosen in the tray, then shows the window where the code is compared.
+fn pair_discovered_device(app: &AppHandle, device_id: &str) {
+    let config = app.state::<SharedConfig>().get();
+    let address = app.try_state::<Discovery>().and_then(|discovery| discovery.address(device_id));
+    let (Some(address), Some(node)) = (address, app.try_state::<PeerNode>()) else { return };
+    let (app, node) = (app.clone(), node.inner().clone());
+    tauri::async_runtime::spawn(async move {
+        match node.pair(&config, &address).await {
+            Ok(request) => {
+                let _ = app.emit("pairing_started", request);
+                handle_menu_action(&app, TrayMenuId::Open);
+            }
+            Err(e) => {
+                log::error!("Failed to pair with {}: {}", address, e);
+                let _ = app.emit("sync_error", format!("Erro ao parear: {}", e));
+            }
+        }
+    });
+}
+
+fn create_menu_item(app: &AppHandle, menu_id: TrayMenuId) -> Result<MenuItem<tauri::Wry>, tauri::Error> {
+    MenuItem::with_id(app, menu_id.as_str(), menu_id.label(), true, None::<&str>)
+}
+
 fn handle_tray_event(app: &AppHandle, event: TrayIconEvent) {
     match event {
         TrayIconEvent::Click { .. } => {
@@ -137,7 +883,35 @@ fn handle_menu_action(app: &AppHandle, menu_id: TrayMenuId) {
                 let _ = window.set_focus();
             }
         }
+        TrayMenuId::Pause | TrayMenuId::Resume => {
+            let app = app.clone();
+            let paused = menu_id == TrayMenuId::Pause;
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = set_paused(&app, None, paused).await {
+                    log::error!("Failed to change paused state: {}", e);
+                    let _ = app.emit("sync_error", format!("Erro ao pausar/retomar: {}", e));
+                }
+            });
+        }
+        TrayMenuId::EmptyTrash => {
+            let app = app.clone();
+            tauri::async_runtime::spawn(async move {
+                let config = app.state::<SharedConfig>().get();
+                match empty_trashes(&config, None).await {
+                    Ok(_) => {
+                        let _ = app.emit("trash_changed", ());
+                    }
+                    Err(e) => {
+                        log::error!("Failed to empty trash: {}", e);
+                        let _ = app.emit("sync_error", format!("Erro ao esvaziar lixeira: {}", e));
+                    }
+                }
+            });
+        }
         TrayMenuId::Quit => {
+            if let Some(discovery) = app.try_state::<Discovery>() {
+                discovery.stop();
+            }
             app.exit(0);
         }
     }
@@ -165,8 +939,11 @@ pub fn run() {
                     handle_tray_event(tray.app_handle(), event);
                 })
                 .on_menu_event(|app, event| {
-                    if let Some(menu_id) = TrayMenuId::from_str(event.id().as_ref()) {
+                    let id = event.id().as_ref();
+                    if let Some(menu_id) = TrayMenuId::from_str(id) {
                         handle_menu_action(app, menu_id);
+                    } else if let Some(device_id) = id.strip_prefix(DEVICE_MENU_PREFIX) {
+                        pair_discovered_device(app, device_id);
                     }
                 })
                 .build(app)?;
@@ -186,10 +963,71 @@ pub fn run() {
             let autostart_manager = app.autolaunch();
             let _ = autostart_manager.enable();
 
-            // Start sync if it was previously active
-            let config = Config::default();
-            if FileTracker::is_monitoring_active(&config) {
-                start_sync_loop(app.handle().clone());
+            // Load the persisted configuration; an invalid file is reported, not overwritten,
+            // and nothing is synced on the defaults until it is fixed
+            let (config, config_loaded) = match Config::load() {
+                Ok(config) => (config, true),
+                Err(e) => {
+                    log::error!("Failed to load configuration, not starting sync: {}", e);
+                    let _ = app.emit("config_error", format!("Erro no arquivo de configuração: {}", e));
+                    (Config::default(), false)
+                }
+            };
+            app.manage(SharedConfig::new(config.clone()));
+            app.manage(SyncManager::default());
+            app.manage(RegistryLock::default());
+
+            // Accept connections from paired devices when device sync is enabled
+            match PeerNode::new(&config) {
+                Ok(node) => {
+                    if let (true, Some(port)) = (config_loaded, config.peer_port) {
+                        let (node, config, app) = (node.clone(), config.clone(), app.handle().clone());
+                        tauri::async_runtime::spawn(async move {
+                            match tokio::net::TcpListener::bind(("0.0.0.0", port)).await {
+                                Ok(listener) => {
+                                    let on_pairing = move |request: &PairingRequest| {
+                                        let _ = app.emit("pairing_requested", request.clone());
+                                    };
+                                    node.listen(config, listener, on_pairing).await;
+                                }
+                                Err(e) => {
+                                    log::error!("Failed to listen for devices on port {}: {}", port, e);
+                                    let _ = app.emit("sync_error", format!("Erro ao escutar na porta {}: {}", port, e));
+                                }
+                            }
+                        });
+                    }
+                    app.manage(node);
+                }
+                Err(e) => log::error!("Failed to load the device identity: {}", e),
+            }
+
+            // Announce this device and look for others on the local network, if enabled
+            if let (true, Some(node)) = (config.discovery_enabled, app.try_state::<PeerNode>()) {
+                let handle = app.handle().clone();
+                match Discovery::start(&config, &node, move || refresh_tray_menu(&handle)) {
+                    Ok(discovery) => {
+                        app.manage(discovery);
+                        refresh_tray_menu(app.handle());
+                    }
+                    Err(e) => log::error!("Failed to start device discovery: {}", e),
+                }
+            }
+
+            // Resume every root that was previously monitored, once the secrets older versions
+            // kept in roots.json are in the secret store
+            if !config_loaded {
+                return Ok(());
+            }
+            match RootRegistry::load(&config) {
+                Ok(mut registry) => {
+                    registry.move_inline_secrets(&config);
+                    let sync_manager = app.state::<SyncManager>();
+                    for root in registry.roots.into_iter().filter(|root| !root.paused) {
+                        sync_manager.start(app.handle().clone(), root);
+                    }
+                }
+                Err(e) => log::error!("Failed to load monitored roots: {}", e),
             }
 
             Ok(())
@@ -199,7 +1037,35 @@ pub fn run() {
             get_save_state,
             get_monitoring_status,
             stop_monitoring,
-            select_folder
+            select_folder,
+            preview_ignored,
+            list_roots,
+            add_root,
+            remove_root,
+            get_config,
+            update_config,
+            pause_monitoring,
+            resume_monitoring,
+            list_conflicts,
+            resolve_conflict,
+            list_versions,
+            restore_version,
+            restore_encrypted,
+            set_secret,
+            clear_secret,
+            has_secret,
+            list_trash,
+            restore_from_trash,
+            empty_trash,
+            collect_garbage,
+            get_device_identity,
+            pair_device,
+            list_pairing_requests,
+            confirm_pairing,
+            list_devices,
+            list_discovered_devices,
+            remove_device,
+            set_root_peer
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,336 @@
+use crate::delta::{self, TransferStats, DELTA_MIN_SIZE};
+use crate::error::FileTrackerError;
+use crate::file_tracker::{FileChange, FileMetadata, FileTracker};
+use crate::trash::Trash;
+use crate::versions::VersionHistory;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Summary of applying a batch of changes to the destination folder.
+#[derive(Debug, Default, serde::Serialize, Clone)]
+pub struct MirrorReport {
+    /// Number of changes successfully replicated.
+    pub applied: usize,
+    /// Changes that could not be replicated, with the reason.
+    pub failed: Vec<(PathBuf, String)>,
+    /// Bytes written to the destination versus bytes that had to be sent.
+    pub transfer: TransferStats,
+}
+
+/// Ensures the destination exists and does not overlap the monitored folder.
+pub fn validate_destination(root_target: &Path, root_destination: &Path) -> Result<(), FileTrackerError> {
+    fs::create_dir_all(root_destination)?;
+    let root_target = root_target.canonicalize()?;
+    let root_destination = root_destination.canonicalize()?;
+
+    if root_destination.starts_with(&root_target) || root_target.starts_with(&root_destination) {
+        return Err(FileTrackerError::InvalidDestination);
+    }
+    Ok(())
+}
+
+/// Builds the changes needed to bring an empty destination up to date with the tracker state.
+pub fn initial_changes(file_tracker: &FileTracker) -> Vec<FileChange> {
+    file_tracker
+        .files_state
+        .iter()
+        .map(|(path, metadata)| FileChange::Created(path.clone(), metadata.clone()))
+        .collect()
+}
+
+/// Replicates the given changes from `root_target` into `root_destination`.
+///
+/// Directories are created first, then renames are applied, then files are copied, and
+/// deletions run last from the deepest path upwards. A failing change does not stop the remaining ones.
+///
+/// Before a modified file or the target of a rename is overwritten, or a deleted path removed,
+/// the destination's copy is saved to `history`; if that fails, the change is not applied.
+/// Deleted paths are moved to the destination's trash rather than removed. Large modified files
+/// are patched with a block-level delta instead of being copied whole.
+pub fn apply_changes(
+    root_target: &Path,
+    root_destination: &Path,
+    changes: &[FileChange],
+    history: &VersionHistory,
+) -> MirrorReport {
+    let plan = ChangePlan::new(changes);
+
+    let mut report = MirrorReport::default();
+    let mut transfer = TransferStats::default();
+    let mut record = |path: &Path, result: Result<(), FileTrackerError>| match result {
+        Ok(()) => report.applied += 1,
+        Err(e) => {
+            log::error!("Failed to replicate {}: {}", path.display(), e);
+            report.failed.push((path.to_path_buf(), e.to_string()));
+        }
+    };
+
+    for path in plan.dirs {
+        let result = destination_path(root_target, root_destination, path)
+            .and_then(|dest| fs::create_dir_all(dest).map_err(FileTrackerError::from));
+        record(path, result);
+    }
+    for (from, to, metadata) in plan.renames {
+        let result = destination_path(root_target, root_destination, &from).and_then(|dest_from| {
+            let dest_to = destination_path(root_target, root_destination, to)?;
+            preserve(history, root_destination, &dest_to)?;
+            rename_path(to, &dest_from, &dest_to, metadata)
+        });
+        record(to, result);
+    }
+    for (path, metadata, modified) in plan.files {
+        let result = destination_path(root_target, root_destination, path).and_then(|dest| {
+            if modified {
+                preserve(history, root_destination, &dest)?;
+            }
+            transfer += transfer_file(path, &dest, metadata.modified(), modified)?;
+            Ok(())
+        });
+        record(path, result);
+    }
+    let trash = Trash::new(root_destination);
+    for path in plan.covered {
+        record(path, Ok(()));
+    }
+    for path in plan.deletions {
+        let result = destination_path(root_target, root_destination, path).and_then(|dest| {
+            preserve(history, root_destination, &dest)?;
+            trash.discard(&dest)
+        });
+        record(path, result);
+    }
+
+    report.transfer = transfer;
+    log::info!(
+        "Mirrored {} change(s) to {} ({} failed)",
+        report.applied,
+        root_destination.display(),
+        report.failed.len()
+    );
+    report
+}
+
+/// A batch of changes grouped in the order they are replicated: directories shallowest first,
+/// renames (directories before files, which may move into them), files, then deletions deepest first.
+pub struct ChangePlan<'a> {
+    pub dirs: Vec<&'a Path>,
+    /// Renames in the order they are applied. The source of an entry below a directory renamed
+    /// earlier in the batch is where that rename left it.
+    pub renames: Vec<(PathBuf, &'a Path, &'a FileMetadata)>,
+    /// Files to write, and whether each replaces an existing copy.
+    pub files: Vec<(&'a Path, &'a FileMetadata, bool)>,
+    pub deletions: Vec<&'a Path>,
+    /// Deleted paths below a deleted directory, removed along with it.
+    pub covered: Vec<&'a Path>,
+}
+
+impl<'a> ChangePlan<'a> {
+    pub fn new(changes: &'a [FileChange]) -> Self {
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        let mut deletions = Vec::new();
+        let mut renames = Vec::new();
+
+        for change in changes {
+            match change {
+                FileChange::Created(path, metadata) | FileChange::Modified(path, metadata) => {
+                    if metadata.is_dir() {
+                        dirs.push(path.as_path());
+                    } else {
+                        files.push((path.as_path(), metadata, matches!(change, FileChange::Modified(..))));
+                    }
+                }
+                FileChange::Deleted(path) => deletions.push(path.as_path()),
+                FileChange::Renamed(from, to, metadata) => renames.push((from.as_path(), to.as_path(), metadata)),
+            }
+        }
+        dirs.sort_by_key(|path| path.components().count());
+        renames.sort_by_key(|(from, _, metadata)| (!metadata.is_dir(), from.components().count()));
+        let mut moved: Vec<(PathBuf, &Path)> = Vec::new();
+        let renames = renames
+            .into_iter()
+            .map(|(from, to, metadata)| {
+                let mut from = from.to_path_buf();
+                for (dir_from, dir_to) in &moved {
+                    if let Ok(relative) = from.strip_prefix(dir_from) {
+                        from = dir_to.join(relative);
+                    }
+                }
+                if metadata.is_dir() {
+                    moved.push((from.clone(), to));
+                }
+                (from, to, metadata)
+            })
+            .collect();
+        deletions.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+
+        let deleted: HashSet<&Path> = deletions.iter().copied().collect();
+        let (covered, deletions) = deletions
+            .into_iter()
+            .partition(|path| path.ancestors().skip(1).any(|ancestor| deleted.contains(ancestor)));
+        ChangePlan {
+            dirs,
+            renames,
+            files,
+            deletions,
+            covered,
+        }
+    }
+}
+
+/// Saves the current version of a destination path before it is replaced.
+fn preserve(history: &VersionHistory, root_destination: &Path, dest: &Path) -> Result<(), FileTrackerError> {
+    match dest.strip_prefix(root_destination) {
+        Ok(relative) => history.preserve(relative, dest),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Maps a path under `root_target` to the equivalent path under `root_destination`.
+fn destination_path(root_target: &Path, root_destination: &Path, path: &Path) -> Result<PathBuf, FileTrackerError> {
+    Ok(root_destination.join(relative_path(root_target, path)?))
+}
+
+/// Strips `root_target` from a path reported by its tracker.
+pub fn relative_path<'a>(root_target: &Path, path: &'a Path) -> Result<&'a Path, FileTrackerError> {
+    path.strip_prefix(root_target).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is outside {}", path.display(), root_target.display()),
+        )
+        .into()
+    })
+}
+
+/// Writes a file to the destination. When `patch` is set and a large copy already exists there,
+/// only the changed blocks are written; otherwise the file is copied whole.
+fn transfer_file(
+    source: &Path,
+    dest: &Path,
+    modified: std::time::SystemTime,
+    patch: bool,
+) -> Result<TransferStats, FileTrackerError> {
+    if patch && fs::metadata(dest).is_ok_and(|existing| existing.is_file() && existing.len() >= DELTA_MIN_SIZE) {
+        return delta::patch_file(source, dest, modified);
+    }
+    copy_file(source, dest, modified)?;
+    let file_bytes = fs::metadata(dest)?.len();
+    Ok(TransferStats {
+        file_bytes,
+        sent_bytes: file_bytes,
+    })
+}
+
+/// Copies a file, creating missing parent directories and preserving its modification time.
+fn copy_file(source: &Path, dest: &Path, modified: std::time::SystemTime) -> Result<(), FileTrackerError> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(source, dest)?;
+    fs::File::options().write(true).open(dest)?.set_modified(modified)?;
+    Ok(())
+}
+
+/// Moves an entry inside the destination. When the old copy is missing, the entry is
+/// copied again from `source` instead.
+fn rename_path(
+    source: &Path,
+    dest_from: &Path,
+    dest_to: &Path,
+    metadata: &FileMetadata,
+) -> Result<(), FileTrackerError> {
+    if let Some(parent) = dest_to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(dest_from, dest_to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if metadata.is_dir() {
+                copy_tree(source, dest_to)
+            } else {
+                copy_file(source, dest_to, metadata.modified())
+            }
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Copies a file or a whole directory tree, preserving modification times.
+pub fn copy_entry(source: &Path, dest: &Path) -> Result<(), FileTrackerError> {
+    let metadata = fs::symlink_metadata(source)?;
+    if metadata.is_dir() {
+        copy_tree(source, dest)
+    } else {
+        copy_file(source, dest, metadata.modified()?)
+    }
+}
+
+/// Recursively copies a directory tree from the source folder.
+fn copy_tree(source: &Path, dest: &Path) -> Result<(), FileTrackerError> {
+    for entry in walkdir::WalkDir::new(source).follow_links(false) {
+        let entry = entry?;
+        let target = dest.join(entry.path().strip_prefix(source).unwrap_or(entry.path()));
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            copy_file(entry.path(), &target, metadata.modified()?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes a file or directory tree, treating an already missing path as success.
+pub fn remove_path(dest: &Path) -> Result<(), FileTrackerError> {
+    let result = match fs::symlink_metadata(dest) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(dest),
+        Ok(_) => fs::remove_file(dest),
+        Err(e) => Err(e),
+    };
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => Ok(other?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::roots::{RootRegistry, SyncMode};
+
+    #[tokio::test]
+    async fn file_renamed_inside_a_renamed_directory() {
+        let dir = std::env::temp_dir().join(format!("egadsync-mirror-renames-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (folder, mirror) = (dir.join("folder"), dir.join("mirror"));
+        fs::create_dir_all(folder.join("a")).unwrap();
+        fs::write(folder.join("a").join("f.txt"), "contents").unwrap();
+        let config = Config {
+            data_dir: dir.join("data"),
+            state_file_path: dir.join("data").join("state.json").to_string_lossy().to_string(),
+            ..Config::default()
+        };
+        fs::create_dir_all(&config.data_dir).unwrap();
+        let root = RootRegistry::load(&config)
+            .unwrap()
+            .add(&folder, Some(mirror.clone()), None, None, None, SyncMode::Mirror)
+            .unwrap();
+        let history = VersionHistory::for_root(&root, &config);
+        let mut file_tracker = FileTracker::new(&folder, Some(mirror.clone()), &root.config(&config)).unwrap();
+        let report = apply_changes(&folder, &mirror, &initial_changes(&file_tracker), &history);
+        assert!(report.failed.is_empty());
+
+        fs::rename(folder.join("a"), folder.join("b")).unwrap();
+        fs::rename(folder.join("b").join("f.txt"), folder.join("b").join("g.txt")).unwrap();
+        let changes = file_tracker.diff().await.unwrap();
+        let report = apply_changes(&folder, &mirror, &changes, &history);
+        assert!(report.failed.is_empty());
+        assert_eq!(fs::read_to_string(mirror.join("b").join("g.txt")).unwrap(), "contents");
+        assert!(!mirror.join("a").exists());
+        assert!(!mirror.join("b").join("f.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
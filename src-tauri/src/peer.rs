@@ -0,0 +1,1203 @@
+use crate::chunks::{self, ChunkRef, ChunkStore, Manifest};
+use crate::config::Config;
+use crate::conflicts;
+use crate::delta::{self, TransferStats};
+use crate::devices::{self, DeviceIdentity, DeviceRegistry, PairedDevice, PairingRequest};
+use crate::error::FileTrackerError;
+use crate::file_tracker::FileTracker;
+use crate::ignore_rules::IgnoreRules;
+use crate::mirror::MirrorReport;
+use crate::persistence;
+use crate::roots::{MonitoredRoot, RootRegistry};
+use crate::trash::Trash;
+use crate::versions::VersionHistory;
+use rustls::pki_types::{CertificateDer, ServerName};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Largest message accepted from another device.
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// How long to wait for the other device before dropping the connection.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a pairing waits for the user to confirm it.
+const PAIRING_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// At most this many pairings wait at once; older ones are dropped.
+const MAX_PENDING_PAIRINGS: usize = 16;
+
+/// How long an address must wait after asking to pair before it may ask again.
+const PAIRING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Chunks asked for in a single request.
+const CHUNKS_PER_REQUEST: usize = 64;
+
+/// What the agreed state records for a directory, in place of a content digest.
+const DIR_KEY: &str = "/";
+
+/// The paired device a root is synced with, and the name both devices share the folder under.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerLink {
+    pub device_id: String,
+    pub share: String,
+}
+
+/// An entry of a shared folder as sent to the other device. Paths use `/` separators.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub path: String,
+    pub is_dir: bool,
+    pub modified: SystemTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl IndexEntry {
+    /// What the entry holds: the content digest of a file, [`DIR_KEY`] for a directory.
+    fn key(&self) -> &str {
+        match &self.hash {
+            _ if self.is_dir => DIR_KEY,
+            Some(hash) => hash,
+            None => "",
+        }
+    }
+}
+
+/// Messages sent by the connecting device.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    /// Opens a sync session; only paired devices are answered.
+    Hello { name: String, port: Option<u16> },
+    /// Starts pairing, committing to a random value revealed once the other side sent its own.
+    PairCommit {
+        name: String,
+        port: Option<u16>,
+        commitment: [u8; 32],
+    },
+    PairReveal { nonce: [u8; 32] },
+    Index { share: String },
+    Manifest { share: String, path: String },
+    Chunks { share: String, path: String, hashes: Vec<String> },
+}
+
+/// Messages sent by the accepting device.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Welcome { name: String },
+    PairNonce { name: String, nonce: [u8; 32] },
+    /// The pairing now waits for the user on both devices.
+    PairPending,
+    Index { entries: Vec<IndexEntry> },
+    Manifest { hash: String, manifest: Manifest },
+    /// Followed by a frame holding the chunk's bytes.
+    Chunk { hash: String },
+    Error { message: String },
+}
+
+/// Length-prefixed JSON messages over a TLS stream.
+struct Connection<S> {
+    stream: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    async fn send(&mut self, message: &impl Serialize) -> Result<(), FileTrackerError> {
+        self.send_frame(&serde_json::to_vec(message)?).await
+    }
+
+    async fn send_frame(&mut self, bytes: &[u8]) -> Result<(), FileTrackerError> {
+        let write = async {
+            self.stream.write_u32(bytes.len() as u32).await?;
+            self.stream.write_all(bytes).await?;
+            self.stream.flush().await
+        };
+        Ok(tokio::time::timeout(TIMEOUT, write).await.map_err(timed_out)??)
+    }
+
+    async fn recv<T: DeserializeOwned>(&mut self) -> Result<T, FileTrackerError> {
+        Ok(serde_json::from_slice(&self.recv_frame().await?)?)
+    }
+
+    async fn recv_frame(&mut self) -> Result<Vec<u8>, FileTrackerError> {
+        let read = async {
+            let len = self.stream.read_u32().await? as usize;
+            if len > MAX_FRAME {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} byte message", len)));
+            }
+            let mut bytes = vec![0; len];
+            self.stream.read_exact(&mut bytes).await?;
+            Ok(bytes)
+        };
+        Ok(tokio::time::timeout(TIMEOUT, read).await.map_err(timed_out)??)
+    }
+
+    /// Sends a request and waits for its response, turning error responses into errors.
+    async fn call(&mut self, request: &Request) -> Result<Response, FileTrackerError> {
+        self.send(request).await?;
+        match self.recv().await? {
+            Response::Error { message } => Err(FileTrackerError::PeerError(message)),
+            response => Ok(response),
+        }
+    }
+}
+
+/// The state both devices last agreed on for a shared folder, kept per root on each side.
+/// A path whose entry differs from it on one side only changed there.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AgreedState {
+    entries: BTreeMap<String, String>,
+}
+
+impl AgreedState {
+    fn load(path: &Path) -> Result<Self, FileTrackerError> {
+        if !path.exists() {
+            return Ok(AgreedState::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self, path: &Path) -> Result<(), FileTrackerError> {
+        persistence::write_atomic(path, serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    fn set(&mut self, path: &str, key: Option<&str>) {
+        match key {
+            Some(key) => self.entries.insert(path.to_string(), key.to_string()),
+            None => self.entries.remove(path),
+        };
+    }
+}
+
+/// A remote change to bring into the local folder: the remote entry, or `None` to delete the
+/// local one. `keep_local` moves a local copy changed since the last agreement out of the way first.
+struct Incoming<'a> {
+    path: &'a str,
+    remote: Option<&'a IndexEntry>,
+    keep_local: bool,
+}
+
+/// Summary of one sync round with a paired device.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PeerReport {
+    pub device: String,
+    /// Changes received from the device and applied to the local folder.
+    pub received: MirrorReport,
+    /// Local paths written or removed, for the tracker to pick up.
+    #[serde(skip)]
+    pub written: Vec<PathBuf>,
+}
+
+/// A folder served to the connected device during one session.
+struct ServedFolder {
+    root_target: PathBuf,
+    tracker: FileTracker,
+    manifests: HashMap<String, Manifest>,
+}
+
+/// This device's side of direct device-to-device sync: it serves shared folders to paired
+/// devices, pulls their changes into the local folders, and pairs with new devices.
+///
+/// Each device pulls from the other on its own schedule, so a change travels when the receiving
+/// device next syncs. Files are fetched chunk by chunk, skipping the chunks already present in
+/// the previous local copy or in the chunk store.
+#[derive(Clone)]
+pub struct PeerNode {
+    identity: Arc<DeviceIdentity>,
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    pairings: Arc<Mutex<Vec<PairingRequest>>>,
+    /// When each address last asked to pair, so one host cannot flood the pending pairings.
+    pairing_attempts: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+    /// Addresses devices were last announced at on the local network, by device id. They are
+    /// tried before the saved ones and only saved once the device answers there.
+    announced: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl PeerNode {
+    pub fn new(config: &Config) -> Result<Self, FileTrackerError> {
+        let identity = DeviceIdentity::load_or_create(config)?;
+        Ok(PeerNode {
+            acceptor: TlsAcceptor::from(Arc::new(identity.server_config()?)),
+            connector: TlsConnector::from(Arc::new(identity.client_config()?)),
+            identity: Arc::new(identity),
+            pairings: Arc::new(Mutex::new(Vec::new())),
+            pairing_attempts: Arc::new(Mutex::new(HashMap::new())),
+            announced: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Identifier other devices know this one by.
+    pub fn device_id(&self) -> &str {
+        &self.identity.id
+    }
+
+    /// Notes that the device `device_id` was announced at `address`, which anyone on the network
+    /// could claim. The next sync with it tries that address first.
+    pub fn saw_address(&self, device_id: &str, address: &str) {
+        let mut announced = self.announced.lock().unwrap_or_else(|e| e.into_inner());
+        announced.insert(device_id.to_string(), address.to_string());
+    }
+
+    /// Accepts connections from other devices until the listener fails. `on_pairing` is told
+    /// about each pairing started by another device.
+    pub async fn listen(
+        self,
+        config: Config,
+        listener: TcpListener,
+        on_pairing: impl Fn(&PairingRequest) + Send + Sync + 'static,
+    ) {
+        let on_pairing = Arc::new(on_pairing);
+        log::info!("Accepting device connections on {:?}", listener.local_addr());
+        loop {
+            let (tcp, address) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::error!("Stopped accepting device connections: {}", e);
+                    return;
+                }
+            };
+            let (node, config, on_pairing) = (self.clone(), config.clone(), on_pairing.clone());
+            tokio::spawn(async move {
+                if let Err(e) = node.serve(&config, tcp, address, on_pairing.as_ref()).await {
+                    log::warn!("Connection from {} ended: {}", address, e);
+                }
+            });
+        }
+    }
+
+    async fn serve(
+        &self,
+        config: &Config,
+        tcp: TcpStream,
+        address: SocketAddr,
+        on_pairing: &(impl Fn(&PairingRequest) + ?Sized),
+    ) -> Result<(), FileTrackerError> {
+        let stream = tokio::time::timeout(TIMEOUT, self.acceptor.accept(tcp)).await.map_err(timed_out)??;
+        let device_id = peer_id(stream.get_ref().1.peer_certificates())?;
+        let mut connection = Connection { stream };
+
+        match connection.recv().await? {
+            Request::Hello { port, .. } => {
+                let mut registry = DeviceRegistry::load(config)?;
+                if registry.get(&device_id).is_none() {
+                    let message = "this device is not paired".to_string();
+                    connection.send(&Response::Error { message }).await?;
+                    return Err(FileTrackerError::PeerError(format!("{} is not paired", address)));
+                }
+                if let Some(port) = port {
+                    if registry.set_address(&device_id, &SocketAddr::new(address.ip(), port).to_string()) {
+                        registry.save(config)?;
+                    }
+                }
+                let name = config.device_name.clone();
+                connection.send(&Response::Welcome { name }).await?;
+                self.serve_requests(config, &device_id, &mut connection).await
+            }
+            Request::PairCommit { name, port, commitment } => {
+                if !self.allow_pairing(address.ip()) {
+                    let message = "too many pairing requests; try again later".to_string();
+                    connection.send(&Response::Error { message }).await?;
+                    return Err(FileTrackerError::PeerError(format!("{} asked to pair too often", address)));
+                }
+                let nonce = devices::random_bytes::<32>()?;
+                let own_name = config.device_name.clone();
+                connection.send(&Response::PairNonce { name: own_name, nonce }).await?;
+                let Request::PairReveal { nonce: client_nonce } = connection.recv().await? else {
+                    return Err(FileTrackerError::PeerError("unexpected pairing message".to_string()));
+                };
+                if blake3::hash(&client_nonce).as_bytes() != &commitment {
+                    return Err(FileTrackerError::PeerError(format!("{} broke its pairing commitment", address)));
+                }
+                let request = PairingRequest {
+                    code: devices::pairing_code(&self.identity.id, &device_id, &nonce, &client_nonce),
+                    device_id,
+                    name,
+                    address: port.map(|port| SocketAddr::new(address.ip(), port).to_string()),
+                    requested_at: SystemTime::now(),
+                };
+                self.add_pairing(request.clone());
+                connection.send(&Response::PairPending).await?;
+                log::info!("{} ({}) asked to pair", request.name, address);
+                on_pairing(&request);
+                Ok(())
+            }
+            _ => Err(FileTrackerError::PeerError("unexpected first message".to_string())),
+        }
+    }
+
+    /// Answers requests from a paired device until it disconnects.
+    async fn serve_requests<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        config: &Config,
+        device_id: &str,
+        connection: &mut Connection<S>,
+    ) -> Result<(), FileTrackerError> {
+        let mut folders: HashMap<String, ServedFolder> = HashMap::new();
+        loop {
+            let request = match connection.recv().await {
+                Ok(request) => request,
+                Err(FileTrackerError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if let Err(e) = answer(config, device_id, &mut folders, request, connection).await {
+                connection.send(&Response::Error { message: e.to_string() }).await?;
+            }
+        }
+    }
+
+    /// Starts pairing with the device listening at `address`. Both devices then show the
+    /// returned code, and each side confirms with [`PeerNode::confirm_pairing`] once the user
+    /// has checked that the codes match.
+    pub async fn pair(&self, config: &Config, address: &str) -> Result<PairingRequest, FileTrackerError> {
+        let (mut connection, server_id) = self.connect(address).await?;
+        if server_id == self.identity.id {
+            return Err(FileTrackerError::PeerError("cannot pair a device with itself".to_string()));
+        }
+        let nonce = devices::random_bytes::<32>()?;
+        let commit = Request::PairCommit {
+            name: config.device_name.clone(),
+            port: config.peer_port,
+            commitment: *blake3::hash(&nonce).as_bytes(),
+        };
+        let (name, server_nonce) = match connection.call(&commit).await? {
+            Response::PairNonce { name, nonce } => (name, nonce),
+            _ => return Err(FileTrackerError::PeerError("unexpected pairing response".to_string())),
+        };
+        match connection.call(&Request::PairReveal { nonce }).await? {
+            Response::PairPending => {}
+            _ => return Err(FileTrackerError::PeerError("unexpected pairing response".to_string())),
+        }
+
+        let request = PairingRequest {
+            code: devices::pairing_code(&server_id, &self.identity.id, &server_nonce, &nonce),
+            device_id: server_id,
+            name,
+            address: Some(address.to_string()),
+            requested_at: SystemTime::now(),
+        };
+        self.add_pairing(request.clone());
+        Ok(request)
+    }
+
+    /// Records a pairing request from `ip`, refusing it when that address asked too recently.
+    fn allow_pairing(&self, ip: IpAddr) -> bool {
+        let mut attempts = self.pairing_attempts.lock().unwrap_or_else(|e| e.into_inner());
+        attempts.retain(|_, at| at.elapsed() < PAIRING_INTERVAL);
+        if attempts.contains_key(&ip) {
+            return false;
+        }
+        attempts.insert(ip, Instant::now());
+        true
+    }
+
+    fn add_pairing(&self, request: PairingRequest) {
+        let mut pairings = self.pairings.lock().unwrap_or_else(|e| e.into_inner());
+        pairings.retain(|pending| pending.device_id != request.device_id);
+        pairings.push(request);
+        if pairings.len() > MAX_PENDING_PAIRINGS {
+            pairings.remove(0);
+        }
+    }
+
+    /// Pairings waiting for confirmation on this device.
+    pub fn pairing_requests(&self) -> Vec<PairingRequest> {
+        let mut pairings = self.pairings.lock().unwrap_or_else(|e| e.into_inner());
+        pairings.retain(|pending| pending.requested_at.elapsed().is_ok_and(|age| age < PAIRING_TTL));
+        pairings.clone()
+    }
+
+    /// Accepts or rejects a pending pairing. An accepted device is trusted from then on.
+    pub fn confirm_pairing(
+        &self,
+        config: &Config,
+        device_id: &str,
+        accept: bool,
+    ) -> Result<Option<PairedDevice>, FileTrackerError> {
+        let request = {
+            let mut pairings = self.pairings.lock().unwrap_or_else(|e| e.into_inner());
+            let index = pairings
+                .iter()
+                .position(|pending| pending.device_id == device_id)
+                .ok_or(FileTrackerError::DeviceNotFound)?;
+            pairings.remove(index)
+        };
+        if !accept || request.requested_at.elapsed().map_or(true, |age| age >= PAIRING_TTL) {
+            return Ok(None);
+        }
+        let mut registry = DeviceRegistry::load(config)?;
+        let device = registry.pair(request);
+        registry.save(config)?;
+        log::info!("Paired with {} ({})", device.name, device.id);
+        Ok(Some(device))
+    }
+
+    /// Connects to a paired device at the address it was last announced at, then at the saved
+    /// one. An announced address is saved once the device has proven its identity there.
+    async fn connect_device(
+        &self,
+        base: &Config,
+        device: &PairedDevice,
+    ) -> Result<Connection<tokio_rustls::client::TlsStream<TcpStream>>, FileTrackerError> {
+        let announced = self.announced.lock().unwrap_or_else(|e| e.into_inner()).get(&device.id).cloned();
+        let mut addresses: Vec<&str> = announced.iter().map(String::as_str).collect();
+        if let Some(saved) = device.address.as_deref().filter(|saved| announced.as_deref() != Some(*saved)) {
+            addresses.push(saved);
+        }
+
+        let mut last_error = FileTrackerError::PeerError(format!("no known address for {}", device.name));
+        for address in addresses {
+            match self.connect(address).await {
+                Ok((connection, device_id)) if device_id == device.id => {
+                    if device.address.as_deref() != Some(address) {
+                        let mut registry = DeviceRegistry::load(base)?;
+                        if registry.set_address(&device.id, address) {
+                            registry.save(base)?;
+                            log::info!("{} is now at {}", device.name, address);
+                        }
+                    }
+                    return Ok(connection);
+                }
+                Ok(_) => {
+                    let message = format!("the device at {} is not {}", address, device.name);
+                    last_error = FileTrackerError::PeerError(message);
+                }
+                Err(e) => last_error = e,
+            }
+            // Not tried again until it is announced again.
+            if announced.as_deref() == Some(address) {
+                self.announced.lock().unwrap_or_else(|e| e.into_inner()).remove(&device.id);
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn connect(
+        &self,
+        address: &str,
+    ) -> Result<(Connection<tokio_rustls::client::TlsStream<TcpStream>>, String), FileTrackerError> {
+        let tcp = tokio::time::timeout(TIMEOUT, TcpStream::connect(address)).await.map_err(timed_out)??;
+        let name = ServerName::try_from("egadsync").map_err(|e| FileTrackerError::PeerError(e.to_string()))?;
+        let stream = tokio::time::timeout(TIMEOUT, self.connector.connect(name, tcp)).await.map_err(timed_out)??;
+        let device_id = peer_id(stream.get_ref().1.peer_certificates())?;
+        Ok((Connection { stream }, device_id))
+    }
+
+    /// Pulls the changes of the paired device into a root's folder.
+    ///
+    /// Each path is compared on both sides with the state last agreed on. Changes made only on
+    /// the other device are applied here; changes made only here are left for the other device
+    /// to pull. When both changed a path, the newer entry wins on both devices and the losing
+    /// local file is kept next to it as a conflict copy. Overwritten files are saved as versions
+    /// and deleted ones go to the trash.
+    pub async fn sync_root(
+        &self,
+        base: &Config,
+        root: &MonitoredRoot,
+        file_tracker: &FileTracker,
+    ) -> Result<PeerReport, FileTrackerError> {
+        let link = root
+            .peer
+            .as_ref()
+            .ok_or_else(|| FileTrackerError::PeerError("the folder is not linked to a device".to_string()))?;
+        let device = DeviceRegistry::load(base)?
+            .get(&link.device_id)
+            .cloned()
+            .ok_or(FileTrackerError::DeviceNotFound)?;
+        let mut connection = self.connect_device(base, &device).await?;
+        let hello = Request::Hello {
+            name: base.device_name.clone(),
+            port: base.peer_port,
+        };
+        if !matches!(connection.call(&hello).await?, Response::Welcome { .. }) {
+            return Err(FileTrackerError::PeerError("unexpected response to hello".to_string()));
+        }
+        let share = link.share.clone();
+        let remote = match connection.call(&Request::Index { share: share.clone() }).await? {
+            Response::Index { entries } => entries,
+            _ => return Err(FileTrackerError::PeerError("unexpected response to index".to_string())),
+        };
+
+        let ignore_rules = IgnoreRules::new(&root.root_target, &base.ignore_patterns)?;
+        let remote: BTreeMap<String, IndexEntry> = remote
+            .into_iter()
+            .filter(|entry| match local_path(&entry.path) {
+                Some(relative) => !ignore_rules.is_ignored(&root.root_target.join(relative), entry.is_dir),
+                None => {
+                    log::warn!("Ignoring invalid path {:?} from {}", entry.path, device.name);
+                    false
+                }
+            })
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+        let local: BTreeMap<String, IndexEntry> =
+            index_entries(file_tracker).into_iter().map(|entry| (entry.path.clone(), entry)).collect();
+
+        let state_path = root.peer_state_path(base);
+        let mut agreed = AgreedState::load(&state_path)?;
+        let incoming = plan(&local, &remote, &mut agreed);
+
+        let mut session = Session {
+            connection: &mut connection,
+            share,
+            root_target: &root.root_target,
+            store: ChunkStore::new(base),
+            history: VersionHistory::for_root(root, base),
+            local: &local,
+            by_hash: local
+                .values()
+                .filter(|entry| !entry.is_dir)
+                .map(|entry| (entry.key().to_string(), entry.path.clone()))
+                .collect(),
+        };
+        let mut report = PeerReport {
+            device: device.name.clone(),
+            ..PeerReport::default()
+        };
+        // Entries are created parents first and deleted children first.
+        let (deletions, updates): (Vec<_>, Vec<_>) = incoming.into_iter().partition(|change| change.remote.is_none());
+        for change in updates.iter().chain(deletions.iter().rev()) {
+            let target = root.root_target.join(local_path(change.path).unwrap_or_default());
+            match session.apply(change, &target).await {
+                Ok((transfer, mut written)) => {
+                    agreed.set(change.path, change.remote.map(IndexEntry::key));
+                    report.received.applied += 1;
+                    report.received.transfer += transfer;
+                    report.written.append(&mut written);
+                }
+                Err(e) => {
+                    log::error!("Failed to receive {} from {}: {}", change.path, device.name, e);
+                    report.received.failed.push((target, e.to_string()));
+                }
+            }
+        }
+        agreed.save(&state_path)?;
+        log::info!(
+            "Received {} change(s) from {} ({} failed)",
+            report.received.applied,
+            device.name,
+            report.received.failed.len()
+        );
+        Ok(report)
+    }
+}
+
+/// Compares both sides with the agreed state, returning the remote changes to apply here.
+/// The agreed state is updated for every path except those, which are updated once applied.
+fn plan<'a>(
+    local: &'a BTreeMap<String, IndexEntry>,
+    remote: &'a BTreeMap<String, IndexEntry>,
+    agreed: &mut AgreedState,
+) -> Vec<Incoming<'a>> {
+    let paths: BTreeSet<String> = local.keys().chain(remote.keys()).chain(agreed.entries.keys()).cloned().collect();
+    let mut incoming = Vec::new();
+    for path in paths {
+        let (local_entry, remote_entry) = (local.get(&path), remote.get(&path));
+        let (local_key, remote_key) = (local_entry.map(IndexEntry::key), remote_entry.map(IndexEntry::key));
+        let agreed_key = agreed.entries.get(&path).map(String::as_str);
+        if local_key == remote_key {
+            agreed.set(&path, remote_key);
+            continue;
+        }
+        if remote_key == agreed_key {
+            // Only changed here; the other device pulls it.
+            continue;
+        }
+        let local_changed = local_key != agreed_key;
+        if !local_changed || remote_wins(remote_entry, local_entry) {
+            let Some((path, _)) = local.get_key_value(&path).or_else(|| remote.get_key_value(&path)) else {
+                // Deleted on both sides since the last agreement.
+                agreed.set(&path, None);
+                continue;
+            };
+            incoming.push(Incoming {
+                path,
+                remote: remote_entry,
+                keep_local: local_changed && local_entry.is_some(),
+            });
+        } else {
+            // The local entry wins; the other device replaces its own with it.
+            agreed.set(&path, remote_key);
+        }
+    }
+    incoming
+}
+
+/// Decides a path changed on both devices the same way on each of them: an entry beats a
+/// deletion, then the later modification wins, then the greater digest.
+fn remote_wins(remote: Option<&IndexEntry>, local: Option<&IndexEntry>) -> bool {
+    match (remote, local) {
+        (Some(remote), Some(local)) => (remote.modified, remote.key()) > (local.modified, local.key()),
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+/// One sync round's connection and the local places data can be taken from.
+struct Session<'a, S> {
+    connection: &'a mut Connection<S>,
+    share: String,
+    root_target: &'a Path,
+    store: ChunkStore,
+    history: VersionHistory,
+    /// The local index the round started from.
+    local: &'a BTreeMap<String, IndexEntry>,
+    /// A local file for each content digest, so moved and copied files are not downloaded.
+    by_hash: HashMap<String, String>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<'_, S> {
+    /// Applies one remote change, returning what was transferred and the local paths touched.
+    async fn apply(
+        &mut self,
+        change: &Incoming<'_>,
+        target: &Path,
+    ) -> Result<(TransferStats, Vec<PathBuf>), FileTrackerError> {
+        let mut written = vec![target.to_path_buf()];
+        if change.keep_local && fs::symlink_metadata(target).is_ok() {
+            let copy = conflicts::conflict_copy_path(target, SystemTime::now(), |candidate| candidate.exists());
+            fs::rename(target, &copy)?;
+            log::warn!("{} changed on both devices; kept the local copy as {}", target.display(), copy.display());
+            written.push(copy);
+        }
+
+        let transfer = match change.remote {
+            None => {
+                match fs::symlink_metadata(target) {
+                    Ok(metadata) if metadata.is_dir() => self.remove_dir(target)?,
+                    Ok(_) => Trash::new(self.root_target).discard(target)?,
+                    Err(_) => {}
+                }
+                TransferStats::default()
+            }
+            Some(entry) if entry.is_dir => {
+                if fs::symlink_metadata(target).is_ok_and(|metadata| !metadata.is_dir()) {
+                    Trash::new(self.root_target).discard(target)?;
+                }
+                fs::create_dir_all(target)?;
+                TransferStats::default()
+            }
+            Some(entry) => self.fetch(entry, target).await?,
+        };
+        Ok((transfer, written))
+    }
+
+    /// Removes a directory deleted on the other device, once the entries below it are gone.
+    /// Files added below it here since keep it in place: the other device gets it back with them.
+    /// Anything else left is ignored here, and goes to the trash along with the directory.
+    fn remove_dir(&self, target: &Path) -> Result<(), FileTrackerError> {
+        match fs::remove_dir(target) {
+            Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                let tracked = walkdir::WalkDir::new(target).min_depth(1).into_iter().any(|entry| {
+                    entry
+                        .ok()
+                        .and_then(|entry| wire_path(entry.path().strip_prefix(self.root_target).ok()?))
+                        .is_some_and(|path| self.local.contains_key(&path))
+                });
+                if tracked {
+                    log::info!("Kept {}: files were added to it on this device", target.display());
+                    Ok(())
+                } else {
+                    Trash::new(self.root_target).discard(target)
+                }
+            }
+            other => Ok(other?),
+        }
+    }
+
+    /// Brings a remote file into place. Its chunks are taken from a local file with the same
+    /// contents, the previous local copy or the chunk store when possible, and requested otherwise.
+    async fn fetch(&mut self, entry: &IndexEntry, target: &Path) -> Result<TransferStats, FileTrackerError> {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = delta::partial_path(target);
+        let result = match self.by_hash.get(entry.key()).and_then(|path| local_path(path)) {
+            Some(relative) if self.root_target.join(&relative) != target => {
+                let size = tokio::fs::copy(self.root_target.join(relative), &partial).await?;
+                Ok(TransferStats {
+                    file_bytes: size,
+                    sent_bytes: 0,
+                })
+            }
+            _ => self.download(entry, target, &partial).await,
+        };
+        let finish = async {
+            let transfer = result?;
+            let file = fs::File::options().write(true).open(&partial)?;
+            file.set_modified(entry.modified)?;
+            file.sync_all()?;
+            self.preserve(&entry.path, target).await?;
+            fs::rename(&partial, target)?;
+            Ok(transfer)
+        };
+        let result = finish.await;
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result
+    }
+
+    async fn download(
+        &mut self,
+        entry: &IndexEntry,
+        target: &Path,
+        partial: &Path,
+    ) -> Result<TransferStats, FileTrackerError> {
+        let request = Request::Manifest {
+            share: self.share.clone(),
+            path: entry.path.clone(),
+        };
+        let (hash, manifest) = match self.connection.call(&request).await? {
+            Response::Manifest { hash, manifest } => (hash, manifest),
+            _ => return Err(FileTrackerError::PeerError("unexpected response to manifest".to_string())),
+        };
+        let previous = previous_chunks(target).await;
+        let mut previous_file = match previous.is_empty() {
+            true => None,
+            false => tokio::fs::File::open(target).await.ok(),
+        };
+
+        let mut output = tokio::io::BufWriter::new(tokio::fs::File::create(partial).await?);
+        let mut hasher = blake3::Hasher::new();
+        let mut sent_bytes = 0;
+        let mut missing: Vec<&ChunkRef> = Vec::new();
+        for chunk in &manifest.chunks {
+            let local = match (previous.get(&chunk.hash), previous_file.as_mut()) {
+                (Some(&offset), Some(file)) => read_chunk(file, offset, chunk).await,
+                _ => None,
+            }
+            .or_else(|| self.store.read_chunk(&chunk.hash));
+            match local {
+                Some(data) => {
+                    sent_bytes += self.request_chunks(&entry.path, &mut missing, &mut output, &mut hasher).await?;
+                    hasher.update(&data);
+                    output.write_all(&data).await?;
+                }
+                None => {
+                    missing.push(chunk);
+                    if missing.len() == CHUNKS_PER_REQUEST {
+                        sent_bytes += self.request_chunks(&entry.path, &mut missing, &mut output, &mut hasher).await?;
+                    }
+                }
+            }
+        }
+        sent_bytes += self.request_chunks(&entry.path, &mut missing, &mut output, &mut hasher).await?;
+        output.flush().await?;
+
+        if hasher.finalize().to_hex().as_str() != hash {
+            return Err(FileTrackerError::PeerError(format!("{} arrived corrupt", entry.path)));
+        }
+        Ok(TransferStats {
+            file_bytes: manifest.size,
+            sent_bytes,
+        })
+    }
+
+    /// Requests the chunks in `missing` and appends them to the output, emptying the list.
+    /// Returns the bytes received.
+    async fn request_chunks<W: AsyncWrite + Unpin>(
+        &mut self,
+        path: &str,
+        missing: &mut Vec<&ChunkRef>,
+        output: &mut W,
+        hasher: &mut blake3::Hasher,
+    ) -> Result<u64, FileTrackerError> {
+        if missing.is_empty() {
+            return Ok(0);
+        }
+        let request = Request::Chunks {
+            share: self.share.clone(),
+            path: path.to_string(),
+            hashes: missing.iter().map(|chunk| chunk.hash.clone()).collect(),
+        };
+        self.connection.send(&request).await?;
+        let mut received = 0;
+        for chunk in missing.drain(..) {
+            match self.connection.recv().await? {
+                Response::Chunk { hash } if hash == chunk.hash => {}
+                Response::Error { message } => return Err(FileTrackerError::PeerError(message)),
+                _ => return Err(FileTrackerError::PeerError("unexpected response to chunks".to_string())),
+            }
+            let data = self.connection.recv_frame().await?;
+            if blake3::hash(&data).to_hex().as_str() != chunk.hash {
+                return Err(FileTrackerError::PeerError(format!("chunk {} arrived corrupt", chunk.hash)));
+            }
+            hasher.update(&data);
+            output.write_all(&data).await?;
+            received += data.len() as u64;
+        }
+        Ok(received)
+    }
+
+    async fn preserve(&self, path: &str, target: &Path) -> Result<(), FileTrackerError> {
+        let history = self.history.clone();
+        let (relative, target) = (local_path(path).unwrap_or_default(), target.to_path_buf());
+        tokio::task::spawn_blocking(move || history.preserve(&relative, &target)).await?
+    }
+}
+
+/// Reads a chunk of the previous local copy, or `None` if it no longer holds it.
+/// Offsets of the chunks of the current local copy of `target`, which a new version may reuse.
+async fn previous_chunks(target: &Path) -> HashMap<String, u64> {
+    let target = target.to_path_buf();
+    let manifest = match tokio::task::spawn_blocking(move || chunks::digest_file(&target)).await {
+        Ok(Ok((_, manifest))) => manifest,
+        _ => return HashMap::new(),
+    };
+    let mut offsets = HashMap::new();
+    let mut offset = 0;
+    for chunk in manifest.chunks {
+        offsets.entry(chunk.hash).or_insert(offset);
+        offset += chunk.length;
+    }
+    offsets
+}
+
+async fn read_chunk(file: &mut tokio::fs::File, offset: u64, chunk: &ChunkRef) -> Option<Vec<u8>> {
+    let mut data = vec![0; usize::try_from(chunk.length).ok()?];
+    file.seek(SeekFrom::Start(offset)).await.ok()?;
+    file.read_exact(&mut data).await.ok()?;
+    (blake3::hash(&data).to_hex().as_str() == chunk.hash).then_some(data)
+}
+
+/// Handles one request of a paired device.
+async fn answer<S: AsyncRead + AsyncWrite + Unpin>(
+    config: &Config,
+    device_id: &str,
+    folders: &mut HashMap<String, ServedFolder>,
+    request: Request,
+    connection: &mut Connection<S>,
+) -> Result<(), FileTrackerError> {
+    let share = match &request {
+        Request::Index { share } | Request::Manifest { share, .. } | Request::Chunks { share, .. } => share.clone(),
+        _ => return Err(FileTrackerError::PeerError("unexpected request".to_string())),
+    };
+    if !folders.contains_key(&share) {
+        let (config, device_id, name) = (config.clone(), device_id.to_string(), share.clone());
+        let folder = tokio::task::spawn_blocking(move || served_folder(&config, &device_id, &name)).await??;
+        folders.insert(share.clone(), folder);
+    }
+    let Some(folder) = folders.get_mut(&share) else { return Ok(()) };
+
+    match request {
+        Request::Index { .. } => {
+            let entries = index_entries(&folder.tracker);
+            connection.send(&Response::Index { entries }).await
+        }
+        Request::Manifest { path, .. } => {
+            let target = served_path(folder, &path)?;
+            folder
+                .tracker
+                .files_state
+                .get(&target)
+                .filter(|metadata| !metadata.is_dir())
+                .ok_or_else(|| FileTrackerError::PeerError(format!("{} is not shared", path)))?;
+            let (hash, manifest) = tokio::task::spawn_blocking(move || chunks::digest_file(&target)).await??;
+            folder.manifests.insert(path, manifest.clone());
+            connection.send(&Response::Manifest { hash, manifest }).await
+        }
+        Request::Chunks { path, hashes, .. } => {
+            let target = served_path(folder, &path)?;
+            let manifest = folder
+                .manifests
+                .get(&path)
+                .ok_or_else(|| FileTrackerError::PeerError(format!("no manifest was requested for {}", path)))?;
+            let mut offsets = HashMap::new();
+            let mut offset = 0;
+            for chunk in &manifest.chunks {
+                offsets.entry(chunk.hash.as_str()).or_insert((offset, chunk));
+                offset += chunk.length;
+            }
+            let mut file = tokio::fs::File::open(&target).await?;
+            for hash in hashes.iter().take(CHUNKS_PER_REQUEST) {
+                let data = match offsets.get(hash.as_str()) {
+                    Some(&(offset, chunk)) => read_chunk(&mut file, offset, chunk).await,
+                    None => None,
+                }
+                .ok_or_else(|| FileTrackerError::PeerError(format!("{} changed since it was indexed", path)))?;
+                connection.send(&Response::Chunk { hash: hash.clone() }).await?;
+                connection.send_frame(&data).await?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Finds the root shared with `device_id` under `share` and loads its saved state.
+fn served_folder(config: &Config, device_id: &str, share: &str) -> Result<ServedFolder, FileTrackerError> {
+    let registry = RootRegistry::load(config)?;
+    let link = PeerLink {
+        device_id: device_id.to_string(),
+        share: share.to_string(),
+    };
+    let root = registry
+        .roots
+        .iter()
+        .find(|root| !root.paused && root.peer.as_ref() == Some(&link))
+        .ok_or_else(|| FileTrackerError::PeerError(format!("no folder is shared as {} with this device", share)))?;
+    Ok(ServedFolder {
+        root_target: root.root_target.clone(),
+        tracker: FileTracker::get(&root.config(config))?,
+        manifests: HashMap::new(),
+    })
+}
+
+fn served_path(folder: &ServedFolder, path: &str) -> Result<PathBuf, FileTrackerError> {
+    let relative = local_path(path).ok_or_else(|| FileTrackerError::PeerError(format!("invalid path {:?}", path)))?;
+    Ok(folder.root_target.join(relative))
+}
+
+/// The entries of a tracker as sent to the other device. Files not hashed yet are left out
+/// until a later round.
+fn index_entries(file_tracker: &FileTracker) -> Vec<IndexEntry> {
+    file_tracker
+        .files_state
+        .iter()
+        .filter(|(_, metadata)| metadata.is_dir() || metadata.hash().is_some())
+        .filter_map(|(path, metadata)| {
+            let relative = path.strip_prefix(&file_tracker.root_target).ok()?;
+            Some(IndexEntry {
+                path: wire_path(relative)?,
+                is_dir: metadata.is_dir(),
+                modified: metadata.modified(),
+                hash: metadata.hash().map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// A relative path with `/` separators, or `None` for paths that cannot be sent.
+fn wire_path(relative: &Path) -> Option<String> {
+    let parts = relative
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => part.to_str().filter(|part| !part.contains('/')),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// The local relative path of a path received from the other device, refusing anything that
+/// could point outside the shared folder.
+fn local_path(path: &str) -> Option<PathBuf> {
+    let parts: Vec<&str> = path.split('/').collect();
+    let valid = parts
+        .iter()
+        .all(|part| !part.is_empty() && *part != "." && *part != ".." && !part.contains(['\\', ':', '\0']));
+    valid.then(|| parts.iter().collect())
+}
+
+/// Identifier of the device at the other end of a TLS connection.
+fn peer_id(certificates: Option<&[CertificateDer<'_>]>) -> Result<String, FileTrackerError> {
+    certificates
+        .and_then(|certificates| certificates.first())
+        .map(devices::fingerprint)
+        .ok_or_else(|| FileTrackerError::PeerError("the device sent no certificate".to_string()))
+}
+
+fn timed_out(_: tokio::time::error::Elapsed) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "the other device stopped responding")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roots::SyncMode;
+
+    fn instance(dir: &Path, name: &str, port: Option<u16>) -> (Config, PeerNode) {
+        let data_dir = dir.join(format!("{}-data", name));
+        fs::create_dir_all(&data_dir).unwrap();
+        let config = Config {
+            data_dir: data_dir.clone(),
+            state_file_path: data_dir.join("state.json").to_string_lossy().to_string(),
+            device_name: name.to_string(),
+            peer_port: port,
+            ..Config::default()
+        };
+        let node = PeerNode::new(&config).unwrap();
+        (config, node)
+    }
+
+    fn share(config: &Config, folder: &Path, device_id: &str) -> MonitoredRoot {
+        let mut registry = RootRegistry::load(config).unwrap();
+        let root = registry.add(folder, None, None, None, None, SyncMode::Mirror).unwrap();
+        let link = PeerLink {
+            device_id: device_id.to_string(),
+            share: "docs".to_string(),
+        };
+        let root = registry.set_peer(&root.id, Some(link)).unwrap();
+        registry.save(config).unwrap();
+        FileTracker::new(folder, None, &root.config(config)).unwrap();
+        root
+    }
+
+    /// Rescans a root and saves its state, as its sync loop would.
+    async fn rescan(config: &Config, root: &MonitoredRoot) -> FileTracker {
+        let mut tracker = FileTracker::get(&root.config(config)).unwrap();
+        tracker.diff().await.unwrap();
+        tracker.save(&root.config(config)).unwrap();
+        tracker
+    }
+
+    /// Two instances with their own data directories, paired and syncing over loopback.
+    #[tokio::test]
+    async fn devices_pair_and_sync_over_loopback() {
+        let dir = std::env::temp_dir().join(format!("egadsync-peer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (config_a, node_a) = instance(&dir, "a", Some(port));
+        let (config_b, node_b) = instance(&dir, "b", None);
+
+        let (pairings, mut pairings_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(node_a.clone().listen(config_a.clone(), listener, move |request: &PairingRequest| {
+            let _ = pairings.send(request.clone());
+        }));
+
+        // Unpaired devices are refused.
+        let (folder_a, folder_b) = (dir.join("folder-a"), dir.join("folder-b"));
+        fs::create_dir_all(folder_a.join("notes")).unwrap();
+        fs::create_dir_all(&folder_b).unwrap();
+        let root_a = share(&config_a, &folder_a, node_b.device_id());
+        let root_b = share(&config_b, &folder_b, node_a.device_id());
+        let mut registry = DeviceRegistry::default();
+        registry.pair(PairingRequest {
+            device_id: node_a.device_id().to_string(),
+            name: "a".to_string(),
+            address: Some(format!("127.0.0.1:{}", port)),
+            code: String::new(),
+            requested_at: SystemTime::now(),
+        });
+        registry.save(&config_b).unwrap();
+        let tracker_b = rescan(&config_b, &root_b).await;
+        assert!(node_b.sync_root(&config_b, &root_b, &tracker_b).await.is_err());
+
+        // Both sides show the same code and confirm it.
+        let request_b = node_b.pair(&config_b, &format!("127.0.0.1:{}", port)).await.unwrap();
+        let request_a = pairings_rx.recv().await.unwrap();
+        assert_eq!(request_a.code, request_b.code);
+        assert_eq!(request_a.device_id, node_b.device_id());
+        assert_eq!(request_b.device_id, node_a.device_id());
+        node_a.confirm_pairing(&config_a, node_b.device_id(), true).unwrap();
+        node_b.confirm_pairing(&config_b, node_a.device_id(), true).unwrap();
+        assert!(node_a.pairing_requests().is_empty());
+
+        let large: Vec<u8> = (0..400_000u32).flat_map(|i| (i * 7919).to_le_bytes()).collect();
+        fs::write(folder_a.join("notes/large.bin"), &large).unwrap();
+        fs::write(folder_a.join("notes/.egadignore"), b"*.log\n").unwrap();
+        fs::write(folder_a.join("notes/debug.log"), b"only on a").unwrap();
+        fs::write(folder_a.join("a.txt"), b"from a").unwrap();
+        fs::write(folder_b.join("b.txt"), b"from b").unwrap();
+        rescan(&config_a, &root_a).await;
+        let tracker_b = rescan(&config_b, &root_b).await;
+
+        let report = node_b.sync_root(&config_b, &root_b, &tracker_b).await.unwrap();
+        assert!(report.received.failed.is_empty(), "{:?}", report.received.failed);
+        assert_eq!(fs::read(folder_b.join("notes/large.bin")).unwrap(), large);
+        assert_eq!(fs::read(folder_b.join("a.txt")).unwrap(), b"from a");
+        assert!(folder_b.join("notes/.egadignore").exists() && !folder_b.join("notes/debug.log").exists());
+        assert!(folder_b.join("b.txt").exists());
+        assert!(!folder_a.join("b.txt").exists());
+
+        // A small edit only transfers the chunks around it.
+        let mut edited = large.clone();
+        edited[1_000_000..1_000_010].copy_from_slice(b"0123456789");
+        fs::write(folder_a.join("notes/large.bin"), &edited).unwrap();
+        rescan(&config_a, &root_a).await;
+        let tracker_b = rescan(&config_b, &root_b).await;
+        let report = node_b.sync_root(&config_b, &root_b, &tracker_b).await.unwrap();
+        assert_eq!(fs::read(folder_b.join("notes/large.bin")).unwrap(), edited);
+        assert!(report.received.transfer.sent_bytes < edited.len() as u64 / 2);
+
+        // A pulls b.txt back; a deletion on B then reaches A through the trash.
+        let tracker_a = rescan(&config_a, &root_a).await;
+        let listener_b = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port_b = listener_b.local_addr().unwrap().port();
+        tokio::spawn(node_b.clone().listen(config_b.clone(), listener_b, |_: &PairingRequest| {}));
+        let saved_address = |config: &Config| {
+            let registry = DeviceRegistry::load(config).unwrap();
+            registry.get(node_b.device_id()).unwrap().address.clone()
+        };
+        // An announcement is only saved once the device answers at that address.
+        node_a.saw_address(node_b.device_id(), &format!("127.0.0.1:{}", port));
+        assert!(node_a.sync_root(&config_a, &root_a, &tracker_a).await.is_err());
+        assert_eq!(saved_address(&config_a), None);
+        node_a.saw_address(node_b.device_id(), &format!("127.0.0.1:{}", port_b));
+        node_a.sync_root(&config_a, &root_a, &tracker_a).await.unwrap();
+        assert_eq!(fs::read(folder_a.join("b.txt")).unwrap(), b"from b");
+        assert_eq!(saved_address(&config_a), Some(format!("127.0.0.1:{}", port_b)));
+
+        fs::remove_file(folder_b.join("a.txt")).unwrap();
+        rescan(&config_b, &root_b).await;
+        let tracker_a = rescan(&config_a, &root_a).await;
+        node_a.sync_root(&config_a, &root_a, &tracker_a).await.unwrap();
+        assert!(!folder_a.join("a.txt").exists());
+        assert_eq!(Trash::new(&folder_a).list().unwrap().len(), 1);
+
+        // A deleted directory still holding files ignored here goes to the trash with them.
+        fs::remove_dir_all(folder_b.join("notes")).unwrap();
+        rescan(&config_b, &root_b).await;
+        let tracker_a = rescan(&config_a, &root_a).await;
+        let report = node_a.sync_root(&config_a, &root_a, &tracker_a).await.unwrap();
+        assert!(report.received.failed.is_empty(), "{:?}", report.received.failed);
+        assert!(!folder_a.join("notes").exists());
+        assert_eq!(Trash::new(&folder_a).list().unwrap()[0].path, Path::new("notes"));
+
+        // Edited on both sides: the later edit wins on both, the other is kept as a conflict copy.
+        fs::write(folder_a.join("b.txt"), b"edited on a").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        fs::write(folder_b.join("b.txt"), b"edited on b").unwrap();
+        let tracker_a = rescan(&config_a, &root_a).await;
+        let tracker_b = rescan(&config_b, &root_b).await;
+        node_a.sync_root(&config_a, &root_a, &tracker_a).await.unwrap();
+        node_b.sync_root(&config_b, &root_b, &tracker_b).await.unwrap();
+        assert_eq!(fs::read(folder_a.join("b.txt")).unwrap(), b"edited on b");
+        assert_eq!(fs::read(folder_b.join("b.txt")).unwrap(), b"edited on b");
+        let copies = fs::read_dir(&folder_a)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().contains("conflict"))
+            .count();
+        assert_eq!(copies, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pairing_requests_are_limited_per_address() {
+        let dir = std::env::temp_dir().join(format!("egadsync-pairing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (_, node) = instance(&dir, "a", None);
+        let (first, second): (IpAddr, IpAddr) = ("192.168.1.20".parse().unwrap(), "192.168.1.21".parse().unwrap());
+
+        assert!(node.allow_pairing(first));
+        assert!(!node.allow_pairing(first));
+        assert!(node.allow_pairing(second));
+        let expired = Instant::now().checked_sub(PAIRING_INTERVAL).unwrap();
+        node.pairing_attempts.lock().unwrap().insert(first, expired);
+        assert!(node.allow_pairing(first));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn paths_from_other_devices_stay_inside_the_folder() {
+        assert_eq!(local_path("a/b.txt"), Some(PathBuf::from("a").join("b.txt")));
+        for path in ["", "/etc/passwd", "a/../../b", "./a", "a//b", "a\\..\\b", "C:/x"] {
+            assert_eq!(local_path(path), None, "{}", path);
+        }
+    }
+}
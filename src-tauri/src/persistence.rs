@@ -0,0 +1,124 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Path of the previous generation kept next to `path`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, "bak")
+}
+
+/// Writes `contents` to `path` so that readers see either the old or the new file, never a
+/// truncated one: the data goes to a temporary file that is flushed to disk and then renamed over `path`.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = write_temp(path, contents)?;
+    fs::rename(&temp_path, path)?;
+    sync_parent(path)
+}
+
+/// Like `write_atomic`, but first moves the current file to its backup path so the previous
+/// generation can be restored if the new one turns out unreadable.
+pub fn write_with_backup(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = write_temp(path, contents)?;
+    match fs::rename(path, backup_path(path)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    fs::rename(&temp_path, path)?;
+    sync_parent(path)
+}
+
+/// Replaces an unreadable `path` with a copy of its backup. The unreadable file is kept
+/// with a `.corrupt` suffix for inspection.
+pub fn restore_backup(path: &Path) -> io::Result<()> {
+    match fs::rename(path, with_suffix(path, "corrupt")) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let contents = fs::read(backup_path(path))?;
+    write_atomic(path, &contents)
+}
+
+/// Removes `path` and its backup, treating missing files as already removed.
+pub fn remove_with_backup(path: &Path) -> io::Result<()> {
+    for file in [path.to_path_buf(), backup_path(path)] {
+        match fs::remove_file(&file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Like `write_atomic`, for files only their owner may read, such as private keys. On Unix the
+/// temporary file is created with mode 0600, so the contents are never readable by others,
+/// not even before a permission change.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = with_suffix(path, "tmp");
+    // A leftover from an interrupted write would keep whatever mode it was created with.
+    match fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sync_parent(path)
+}
+
+fn write_temp(path: &Path, contents: &[u8]) -> io::Result<PathBuf> {
+    let temp_path = with_suffix(path, "tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(temp_path)
+}
+
+/// Flushes the directory entry so the rename itself survives a power loss.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn private_files_are_only_readable_by_their_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("egadsync-persistence-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key.der");
+        // A leftover temporary file readable by anyone is not reused.
+        fs::write(with_suffix(&path, "tmp"), b"old").unwrap();
+        fs::set_permissions(with_suffix(&path, "tmp"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"secret").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"secret");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
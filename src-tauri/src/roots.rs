@@ -0,0 +1,392 @@
+use crate::config::Config;
+use crate::destination::{self, RemoteDestination};
+use crate::encryption::Encryption;
+use crate::error::FileTrackerError;
+use crate::peer::PeerLink;
+use crate::persistence;
+use crate::state_store::{JsonStateStore, StateStore};
+use crate::trash::Trash;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// How changes flow between a monitored folder and its destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    /// Changes in the monitored folder are copied to the destination.
+    #[default]
+    Mirror,
+    /// Changes on either side are copied to the other; paths changed on both are conflicts.
+    TwoWay,
+}
+
+/// A folder monitored independently of the others, with its own state, interval and destination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitoredRoot {
+    /// Stable identifier derived from the folder path, carried in every event about this root.
+    pub id: String,
+    pub root_target: PathBuf,
+    pub root_destination: Option<PathBuf>,
+    /// Server receiving the mirror instead of a local destination folder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_destination: Option<RemoteDestination>,
+    /// Client-side encryption of what is sent to the remote destination.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// Paired device this folder is synced with directly, in addition to any destination.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerLink>,
+    /// Reconcile interval for this root; follows the global setting when unset.
+    pub sync_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub sync_mode: SyncMode,
+    /// Paused roots keep their state but have no running sync loop, also across restarts.
+    #[serde(default)]
+    pub paused: bool,
+}
+
+impl MonitoredRoot {
+    /// Derives the configuration used by this root's tracker and sync loop from the global one.
+    pub fn config(&self, base: &Config) -> Config {
+        let state_file_path = Self::states_dir(base).join(format!("{}.json", self.id));
+        Config {
+            sync_interval_secs: self.sync_interval_secs.unwrap_or(base.sync_interval_secs),
+            state_file_path: state_file_path.to_string_lossy().to_string(),
+            ..base.clone()
+        }
+    }
+
+    /// Configuration of the tracker kept for the destination side in two-way mode.
+    pub fn destination_config(&self, base: &Config) -> Config {
+        let state_file_path = Self::states_dir(base).join(format!("{}.destination.json", self.id));
+        Config {
+            state_file_path: state_file_path.to_string_lossy().to_string(),
+            ..self.config(base)
+        }
+    }
+
+    /// File holding the unresolved two-way conflicts of this root.
+    pub fn conflicts_path(&self, base: &Config) -> PathBuf {
+        Self::states_dir(base).join(format!("{}.conflicts.json", self.id))
+    }
+
+    /// File where the remote destination keeps what it knows about the server between rounds.
+    pub fn remote_state_path(&self, base: &Config) -> PathBuf {
+        Self::states_dir(base).join(format!("{}.remote.json", self.id))
+    }
+
+    /// File holding the state both devices last agreed on, for sync with the paired device.
+    pub fn peer_state_path(&self, base: &Config) -> PathBuf {
+        Self::states_dir(base).join(format!("{}.peer.json", self.id))
+    }
+
+    /// Trashes of the folders sync deletes from: the destination, and in two-way mode the monitored folder too.
+    pub fn trashes(&self) -> Vec<Trash> {
+        let mut trashes: Vec<Trash> = self.root_destination.iter().map(|folder| Trash::new(folder)).collect();
+        if self.sync_mode == SyncMode::TwoWay {
+            trashes.push(Trash::new(&self.root_target));
+        }
+        trashes
+    }
+
+    /// The root as shown to the interface: without any secret still kept in `roots.json`.
+    pub fn without_secrets(mut self) -> Self {
+        if let Some(remote) = self.remote_destination.as_mut() {
+            remote.clear_secrets();
+        }
+        self
+    }
+
+    /// Directory holding the previous versions of this root's files.
+    pub fn versions_dir(&self, base: &Config) -> PathBuf {
+        base.data_dir.join("versions").join(&self.id)
+    }
+
+    fn states_dir(base: &Config) -> PathBuf {
+        base.data_dir.join("states")
+    }
+
+    /// Computes the identifier of a root from its canonical path.
+    fn id_for(root_target: &Path) -> String {
+        let digest = blake3::hash(canonical(root_target).to_string_lossy().as_bytes());
+        digest.to_hex()[..16].to_string()
+    }
+}
+
+/// Resolves a path for comparisons with other roots, keeping it as given when it does not exist yet.
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Serializes changes to `roots.json`, kept in the app state. A command that changes the
+/// registry holds the lock from loading it to saving it, so concurrent commands do not
+/// overwrite each other's changes.
+#[derive(Default)]
+pub struct RegistryLock(tokio::sync::Mutex<()>);
+
+impl RegistryLock {
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.0.lock().await
+    }
+}
+
+/// The set of monitored roots, persisted in `roots.json` in the app data directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RootRegistry {
+    pub roots: Vec<MonitoredRoot>,
+}
+
+impl RootRegistry {
+    fn file_path(config: &Config) -> PathBuf {
+        config.data_dir.join("roots.json")
+    }
+
+    /// Loads the registry, importing the single-folder state of older versions on first use.
+    pub fn load(config: &Config) -> Result<Self, FileTrackerError> {
+        fs::create_dir_all(MonitoredRoot::states_dir(config))?;
+        let path = Self::file_path(config);
+        if !path.exists() {
+            let registry = Self::migrate_legacy_state(config)?;
+            registry.save(config)?;
+            return Ok(registry);
+        }
+
+        let mut file = File::open(&path)?;
+        let mut json_data = String::new();
+        file.read_to_string(&mut json_data)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+
+    /// Moves the destination secrets that older versions kept in `roots.json` to the secret store.
+    /// Secrets that cannot be moved stay where they are and keep working. Run once at startup.
+    pub fn move_inline_secrets(&mut self, config: &Config) {
+        let mut moved = false;
+        for remote in self.roots.iter_mut().filter_map(|root| root.remote_destination.as_mut()) {
+            match remote.store_secrets(config) {
+                Ok(stored) => moved |= stored,
+                Err(e) => log::warn!("Failed to move the secret of {} to the secret store: {}", remote.describe(), e),
+            }
+        }
+        if moved {
+            if let Err(e) = self.save(config) {
+                log::error!("Failed to save monitored roots without their secrets: {}", e);
+            }
+        }
+    }
+
+    /// Saves the registry to `roots.json`.
+    pub fn save(&self, config: &Config) -> Result<(), FileTrackerError> {
+        let path = Self::file_path(config);
+        let json = serde_json::to_string_pretty(self)?;
+        persistence::write_atomic(&path, json.as_bytes())?;
+        log::info!("Saved monitored roots to {}", path.display());
+        Ok(())
+    }
+
+    /// Checks that a root can be registered, before anything is stored for it. Adding a folder
+    /// that is already monitored, or whose folder or destination is inside or contains the
+    /// folder or destination of another root, is an error. Two-way sync needs a local
+    /// destination, and a remote destination replaces the local one.
+    pub fn check_new(
+        &self,
+        root_target: &Path,
+        root_destination: Option<&Path>,
+        remote_destination: Option<&RemoteDestination>,
+        encrypted: bool,
+        sync_mode: SyncMode,
+    ) -> Result<(), FileTrackerError> {
+        let id = MonitoredRoot::id_for(root_target);
+        if self.get(&id).is_some() {
+            return Err(FileTrackerError::RootAlreadyMonitored);
+        }
+        let added: Vec<PathBuf> = std::iter::once(root_target)
+            .chain(root_destination)
+            .map(canonical)
+            .collect();
+        for root in &self.roots {
+            let existing = std::iter::once(&root.root_target).chain(root.root_destination.as_ref());
+            for path in existing.map(|path| canonical(path)) {
+                if added.iter().any(|new| new.starts_with(&path) || path.starts_with(new)) {
+                    return Err(FileTrackerError::RootsOverlap);
+                }
+            }
+        }
+        if sync_mode == SyncMode::TwoWay && root_destination.is_none() {
+            return Err(FileTrackerError::InvalidConfig(
+                "two-way sync requires a destination folder".to_string(),
+            ));
+        }
+        if let Some(remote) = remote_destination {
+            if sync_mode != SyncMode::Mirror || root_destination.is_some() {
+                return Err(FileTrackerError::InvalidConfig(
+                    "a remote destination is only supported in mirror mode, without a destination folder".to_string(),
+                ));
+            }
+            destination::validate_remote(remote)?;
+        }
+        if encrypted && remote_destination.is_none() {
+            return Err(FileTrackerError::InvalidConfig(
+                "encryption is only supported with a remote destination".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Registers a new root, after the checks of [`RootRegistry::check_new`].
+    pub fn add(
+        &mut self,
+        root_target: &Path,
+        root_destination: Option<PathBuf>,
+        remote_destination: Option<RemoteDestination>,
+        encryption: Option<Encryption>,
+        sync_interval_secs: Option<u64>,
+        sync_mode: SyncMode,
+    ) -> Result<MonitoredRoot, FileTrackerError> {
+        self.check_new(
+            root_target,
+            root_destination.as_deref(),
+            remote_destination.as_ref(),
+            encryption.is_some(),
+            sync_mode,
+        )?;
+        let root = MonitoredRoot {
+            id: MonitoredRoot::id_for(root_target),
+            root_target: root_target.to_path_buf(),
+            root_destination,
+            remote_destination,
+            encryption,
+            peer: None,
+            sync_interval_secs,
+            sync_mode,
+            paused: false,
+        };
+        self.roots.push(root.clone());
+        Ok(root)
+    }
+
+    /// Unregisters a root, returning it.
+    pub fn remove(&mut self, id: &str) -> Result<MonitoredRoot, FileTrackerError> {
+        let index = self
+            .roots
+            .iter()
+            .position(|root| root.id == id)
+            .ok_or(FileTrackerError::RootNotFound)?;
+        Ok(self.roots.remove(index))
+    }
+
+    /// Marks a root as paused or active, returning its updated entry.
+    pub fn set_paused(&mut self, id: &str, paused: bool) -> Result<MonitoredRoot, FileTrackerError> {
+        let root = self
+            .roots
+            .iter_mut()
+            .find(|root| root.id == id)
+            .ok_or(FileTrackerError::RootNotFound)?;
+        root.paused = paused;
+        Ok(root.clone())
+    }
+
+    /// Links a root to a paired device, or unlinks it, returning its updated entry.
+    /// A device serves a folder only to the device it is linked with, under the same share name.
+    pub fn set_peer(&mut self, id: &str, peer: Option<PeerLink>) -> Result<MonitoredRoot, FileTrackerError> {
+        if let Some(link) = &peer {
+            if link.share.trim().is_empty() {
+                return Err(FileTrackerError::InvalidConfig("the share name must not be empty".to_string()));
+            }
+            if self.roots.iter().any(|root| root.id != id && root.peer.as_ref() == Some(link)) {
+                return Err(FileTrackerError::InvalidConfig(format!(
+                    "another folder is already shared as {} with this device",
+                    link.share
+                )));
+            }
+        }
+        let root = self
+            .roots
+            .iter_mut()
+            .find(|root| root.id == id)
+            .ok_or(FileTrackerError::RootNotFound)?;
+        root.peer = peer;
+        Ok(root.clone())
+    }
+
+    /// Looks up a root by identifier.
+    pub fn get(&self, id: &str) -> Option<&MonitoredRoot> {
+        self.roots.iter().find(|root| root.id == id)
+    }
+
+    /// Builds the registry from the legacy `state.json`, moving it to the per-root location.
+    fn migrate_legacy_state(config: &Config) -> Result<Self, FileTrackerError> {
+        let mut registry = RootRegistry::default();
+        let mut legacy = JsonStateStore::new(Path::new(&config.state_file_path));
+        if !legacy.exists() {
+            return Ok(registry);
+        }
+
+        let (file_tracker, _) = legacy.load()?;
+        let root = registry.add(
+            &file_tracker.root_target,
+            file_tracker.root_destination.clone(),
+            None,
+            None,
+            None,
+            SyncMode::Mirror,
+        )?;
+        // The per-root store imports this file the first time the root is loaded.
+        fs::rename(&config.state_file_path, root.config(config).state_file_path)?;
+        legacy.delete()?;
+        log::info!("Migrated legacy state for {} to root {}", root.root_target.display(), root.id);
+        Ok(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_roots_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("egadsync-roots-overlap-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for folder in ["docs/inner", "mirror/inner", "photos", "other"] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        let mut registry = RootRegistry::default();
+        registry.add(&dir.join("docs"), Some(dir.join("mirror")), None, None, None, SyncMode::Mirror).unwrap();
+
+        let overlapping = [
+            (dir.join("docs/inner"), None),
+            (dir.clone(), None),
+            (dir.join("mirror/inner"), None),
+            (dir.join("photos"), Some(dir.join("docs/inner"))),
+            (dir.join("photos"), Some(dir.join("mirror"))),
+        ];
+        for (target, destination) in overlapping {
+            let result = registry.add(&target, destination, None, None, None, SyncMode::Mirror);
+            assert!(matches!(result, Err(FileTrackerError::RootsOverlap)), "{} was accepted", target.display());
+        }
+        registry.add(&dir.join("photos"), Some(dir.join("other")), None, None, None, SyncMode::Mirror).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn roots_are_shown_without_their_secrets() {
+        let dir = std::env::temp_dir().join(format!("egadsync-roots-secrets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let remote: RemoteDestination = serde_json::from_value(serde_json::json!({
+            "kind": "s3",
+            "bucket": "backups",
+            "access_key_id": "minio",
+            "secret_access_key": "minio123",
+        }))
+        .unwrap();
+        let root = RootRegistry::default()
+            .add(&dir, None, Some(remote), None, None, SyncMode::Mirror)
+            .unwrap();
+
+        let shown = serde_json::to_string(&root.clone().without_secrets()).unwrap();
+        assert!(serde_json::to_string(&root).unwrap().contains("minio123"));
+        assert!(!shown.contains("minio123") && shown.contains("backups"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
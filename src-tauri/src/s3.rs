@@ -0,0 +1,540 @@
+use crate::config::Config;
+use crate::destination::Destination;
+use crate::error::FileTrackerError;
+use crate::secrets;
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier};
+use aws_sdk_s3::Client;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use tokio::runtime::Handle;
+
+/// Files at least this large are sent as a multipart upload, in parts of this size.
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Objects larger than this cannot be copied in one request and are copied in parts instead.
+const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Size of the parts a large object is copied in, enough for the largest objects S3 stores.
+const COPY_PART_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Keys deleted per request, the most S3 accepts.
+const DELETE_BATCH: usize = 1000;
+
+/// Connection settings of an S3-compatible bucket (AWS, MinIO, Ceph, ...).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    /// Endpoint of a self-hosted server, such as `http://127.0.0.1:9000`; AWS when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to every object key, so several roots can share a bucket.
+    #[serde(default)]
+    pub prefix: String,
+    pub access_key_id: String,
+    /// Secret key, accepted when the root is added and then moved to the secret store under
+    /// the account `s3/<access key id>`, where it is read from when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_access_key: Option<String>,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+impl S3Config {
+    /// Secret store account holding the secret key of `access_key_id`.
+    pub fn secret_account(access_key_id: &str) -> String {
+        format!("s3/{}", access_key_id)
+    }
+
+    fn secret_access_key(&self, base: &Config) -> Result<String, FileTrackerError> {
+        match &self.secret_access_key {
+            Some(secret) => Ok(secret.clone()),
+            None => secrets::get(base, &Self::secret_account(&self.access_key_id)),
+        }
+    }
+}
+
+/// A bucket on an S3-compatible server. Objects are keyed by their path relative to the
+/// monitored folder; S3 has no directories, so they only exist through the objects below them.
+///
+/// The SDK is asynchronous, so every operation blocks on the runtime the client was created
+/// in. It must be used from a blocking task, never from async code.
+pub struct S3Destination {
+    client: Client,
+    runtime: Handle,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Destination {
+    /// Builds the client and checks that the bucket is reachable with the given credentials.
+    /// The secret key is read from the secret store of `base` when the settings do not give it.
+    pub fn connect(config: &S3Config, base: &Config) -> Result<Self, FileTrackerError> {
+        let runtime = Handle::try_current()
+            .map_err(|e| FileTrackerError::DestinationError(format!("no async runtime: {}", e)))?;
+        let secret_access_key = config.secret_access_key(base)?;
+        let credentials = Credentials::new(&config.access_key_id, secret_access_key, None, None, "egadsync");
+        let mut builder = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(config.region.clone()))
+            .credentials_provider(credentials);
+        if let Some(endpoint) = &config.endpoint {
+            // Self-hosted servers rarely have a DNS name per bucket.
+            builder = builder.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        let destination = S3Destination {
+            client: Client::from_conf(builder.build()),
+            runtime,
+            bucket: config.bucket.clone(),
+            prefix: key_prefix(&config.prefix),
+        };
+        destination
+            .runtime
+            .block_on(destination.client.head_bucket().bucket(&destination.bucket).send())
+            .map_err(s3_error)?;
+        log::info!("Connected to bucket {}", config.bucket);
+        Ok(destination)
+    }
+
+    fn key(&self, relative: &Path) -> String {
+        object_key(&self.prefix, relative)
+    }
+
+    /// Keys of the object at `key` and of every object below it.
+    fn keys_at(&self, key: &str) -> Result<Vec<String>, FileTrackerError> {
+        Ok(self.objects_at(key)?.into_iter().map(|(key, _)| key).collect())
+    }
+
+    /// Keys and sizes of the object at `key` and of every object below it.
+    fn objects_at(&self, key: &str) -> Result<Vec<(String, u64)>, FileTrackerError> {
+        let dir_prefix = format!("{}/", key);
+        let mut objects = self.objects_with_prefix(key)?;
+        objects.retain(|(found, _)| found.as_str() == key || found.starts_with(&dir_prefix));
+        Ok(objects)
+    }
+
+    /// Keys and sizes of every object whose key starts with `prefix`.
+    fn objects_with_prefix(&self, prefix: &str) -> Result<Vec<(String, u64)>, FileTrackerError> {
+        let mut objects = Vec::new();
+        let mut continuation = None;
+        loop {
+            let page = self
+                .runtime
+                .block_on(
+                    self.client
+                        .list_objects_v2()
+                        .bucket(&self.bucket)
+                        .prefix(prefix)
+                        .set_continuation_token(continuation)
+                        .send(),
+                )
+                .map_err(s3_error)?;
+            objects.extend(
+                page.contents()
+                    .iter()
+                    .filter_map(|object| Some((object.key()?.to_string(), object.size().unwrap_or(0) as u64))),
+            );
+            match page.next_continuation_token() {
+                Some(token) if page.is_truncated() == Some(true) => continuation = Some(token.to_string()),
+                _ => return Ok(objects),
+            }
+        }
+    }
+
+    fn delete_keys(&self, keys: &[String]) -> Result<(), FileTrackerError> {
+        for batch in keys.chunks(DELETE_BATCH) {
+            let objects = batch
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(s3_error)?;
+            let delete = Delete::builder().set_objects(Some(objects)).quiet(true).build().map_err(s3_error)?;
+            let output = self
+                .runtime
+                .block_on(self.client.delete_objects().bucket(&self.bucket).delete(delete).send())
+                .map_err(s3_error)?;
+            if let Some(error) = output.errors().first() {
+                return Err(FileTrackerError::DestinationError(format!(
+                    "could not delete {}: {}",
+                    error.key().unwrap_or_default(),
+                    error.message().unwrap_or_default()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a large file in parts, aborting the upload if any part fails so the bucket
+    /// does not keep the parts around.
+    fn upload_multipart(&self, key: &str, source: &Path, size: u64, mtime: String) -> Result<(), FileTrackerError> {
+        let upload = self
+            .runtime
+            .block_on(
+                self.client
+                    .create_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .metadata("mtime", mtime)
+                    .send(),
+            )
+            .map_err(s3_error)?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| FileTrackerError::DestinationError("the server returned no upload id".to_string()))?;
+
+        let result = self.runtime.block_on(async {
+            let mut parts = Vec::new();
+            for (index, offset) in (0..size).step_by(PART_SIZE as usize).enumerate() {
+                let part_number = index as i32 + 1;
+                let body = ByteStream::read_from()
+                    .path(source)
+                    .offset(offset)
+                    .length(Length::Exact(PART_SIZE.min(size - offset)))
+                    .build()
+                    .await
+                    .map_err(s3_error)?;
+                let part = self
+                    .client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+                parts.push(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(part.e_tag().map(str::to_string))
+                        .build(),
+                );
+            }
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+                .send()
+                .await
+                .map_err(s3_error)?;
+            Ok(())
+        });
+
+        if result.is_err() {
+            self.abort_multipart(key, upload_id);
+        }
+        result
+    }
+
+    /// Copies an object too large for a single copy request, part by part on the server. The
+    /// metadata is not carried over by part copies, so it is read from the source first.
+    fn copy_multipart(&self, source_key: &str, target: &str, size: u64) -> Result<(), FileTrackerError> {
+        let head = self
+            .runtime
+            .block_on(self.client.head_object().bucket(&self.bucket).key(source_key).send())
+            .map_err(s3_error)?;
+        let upload = self
+            .runtime
+            .block_on(
+                self.client
+                    .create_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(target)
+                    .set_metadata(head.metadata().cloned())
+                    .send(),
+            )
+            .map_err(s3_error)?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| FileTrackerError::DestinationError("the server returned no upload id".to_string()))?;
+        let copy_source = format!("{}/{}", self.bucket, encode_key(source_key));
+
+        let result = self.runtime.block_on(async {
+            let mut parts = Vec::new();
+            for (part_number, range) in copy_ranges(size) {
+                let part = self
+                    .client
+                    .upload_part_copy()
+                    .bucket(&self.bucket)
+                    .key(target)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .copy_source(&copy_source)
+                    .copy_source_range(range)
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+                parts.push(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(part.copy_part_result().and_then(|result| result.e_tag()).map(str::to_string))
+                        .build(),
+                );
+            }
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(target)
+                .upload_id(upload_id)
+                .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+                .send()
+                .await
+                .map_err(s3_error)?;
+            Ok(())
+        });
+
+        if result.is_err() {
+            self.abort_multipart(target, upload_id);
+        }
+        result
+    }
+
+    /// Drops the parts of a failed multipart upload, so the bucket does not keep them around.
+    fn abort_multipart(&self, key: &str, upload_id: &str) {
+        let abort = self.client.abort_multipart_upload().bucket(&self.bucket).key(key).upload_id(upload_id);
+        if let Err(e) = self.runtime.block_on(abort.send()) {
+            log::warn!("Failed to abort the upload of {}: {}", key, DisplayErrorContext(e));
+        }
+    }
+}
+
+impl Destination for S3Destination {
+    fn create_dir(&mut self, _relative: &Path) -> Result<(), FileTrackerError> {
+        Ok(())
+    }
+
+    /// The modification time is kept in the `mtime` metadata of the object, in seconds since the epoch.
+    fn upload(&mut self, relative: &Path, source: &Path, modified: SystemTime) -> Result<u64, FileTrackerError> {
+        let key = self.key(relative);
+        let size = fs::metadata(source)?.len();
+        let mtime = modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+            .to_string();
+        if size >= PART_SIZE {
+            self.upload_multipart(&key, source, size, mtime)?;
+        } else {
+            self.runtime
+                .block_on(async {
+                    let body = ByteStream::from_path(source).await.map_err(s3_error)?;
+                    self.client
+                        .put_object()
+                        .bucket(&self.bucket)
+                        .key(&key)
+                        .metadata("mtime", mtime)
+                        .body(body)
+                        .send()
+                        .await
+                        .map_err(s3_error)
+                })?;
+        }
+        Ok(size)
+    }
+
+    fn download(&mut self, relative: &Path, target: &Path) -> Result<u64, FileTrackerError> {
+        let key = self.key(relative);
+        self.runtime.block_on(async {
+            let object = match self.client.get_object().bucket(&self.bucket).key(&key).send().await {
+                Ok(object) => object,
+                Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("no object at {}", key)).into())
+                }
+                Err(e) => return Err(s3_error(e)),
+            };
+            let mut file = tokio::fs::File::create(target).await?;
+            Ok(tokio::io::copy(&mut object.body.into_async_read(), &mut file).await?)
+        })
+    }
+
+    /// S3 cannot move objects: each one is copied to its new key, then the old keys are deleted.
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), FileTrackerError> {
+        let (from_key, to_key) = (self.key(from), self.key(to));
+        let objects = self.objects_at(&from_key)?;
+        if objects.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no object at {}", from_key)).into());
+        }
+        for (key, size) in &objects {
+            let target = format!("{}{}", to_key, &key[from_key.len()..]);
+            if *size > MAX_COPY_SIZE {
+                self.copy_multipart(key, &target, *size)?;
+                continue;
+            }
+            let copy_source = format!("{}/{}", self.bucket, encode_key(key));
+            self.runtime
+                .block_on(
+                    self.client
+                        .copy_object()
+                        .bucket(&self.bucket)
+                        .key(&target)
+                        .copy_source(copy_source)
+                        .send(),
+                )
+                .map_err(s3_error)?;
+        }
+        let keys: Vec<String> = objects.into_iter().map(|(key, _)| key).collect();
+        self.delete_keys(&keys)
+    }
+
+    /// On a versioned bucket the deletes leave delete markers, and the previous versions stay available.
+    fn remove(&mut self, relative: &Path) -> Result<(), FileTrackerError> {
+        let keys = self.keys_at(&self.key(relative))?;
+        self.delete_keys(&keys)
+    }
+
+    fn list_files(&mut self) -> Result<Vec<PathBuf>, FileTrackerError> {
+        Ok(self
+            .objects_with_prefix(&self.prefix)?
+            .into_iter()
+            .filter_map(|(key, _)| key.strip_prefix(&self.prefix).map(PathBuf::from))
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .collect())
+    }
+}
+
+/// The configured prefix without surrounding slashes, ending with one unless it is empty.
+fn key_prefix(prefix: &str) -> String {
+    match prefix.trim_matches('/') {
+        "" => String::new(),
+        prefix => format!("{}/", prefix),
+    }
+}
+
+/// Object key of a relative path under `prefix`, always with `/` separators.
+fn object_key(prefix: &str, relative: &Path) -> String {
+    let parts: Vec<_> = relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    format!("{}{}", prefix, parts.join("/"))
+}
+
+/// Part numbers and byte ranges, in the form of the `x-amz-copy-source-range` header, of the
+/// parts an object of `size` bytes is copied in.
+fn copy_ranges(size: u64) -> impl Iterator<Item = (i32, String)> {
+    (0..size).step_by(COPY_PART_SIZE as usize).enumerate().map(move |(index, offset)| {
+        let end = (offset + COPY_PART_SIZE).min(size) - 1;
+        (index as i32 + 1, format!("bytes={}-{}", offset, end))
+    })
+}
+
+/// Percent-encodes a key for the `x-amz-copy-source` header, keeping the `/` separators.
+fn encode_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn s3_error(err: impl std::error::Error) -> FileTrackerError {
+    FileTrackerError::DestinationError(DisplayErrorContext(err).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestDir;
+    use std::time::Duration;
+
+    #[test]
+    fn keys_use_the_prefix_and_slashes() {
+        assert_eq!(key_prefix(""), "");
+        assert_eq!(key_prefix("/"), "");
+        assert_eq!(key_prefix("/backups/laptop/"), "backups/laptop/");
+
+        let prefix = key_prefix("backups");
+        assert_eq!(object_key(&prefix, Path::new("docs/report.txt")), "backups/docs/report.txt");
+        assert_eq!(object_key(&prefix, Path::new("./docs//report.txt")), "backups/docs/report.txt");
+        assert_eq!(object_key("", Path::new("docs")), "docs");
+    }
+
+    #[test]
+    fn large_objects_are_copied_in_parts_covering_every_byte() {
+        let size = 2 * COPY_PART_SIZE + 10;
+        let ranges: Vec<_> = copy_ranges(size).collect();
+        assert_eq!(
+            ranges,
+            [
+                (1, "bytes=0-1073741823".to_string()),
+                (2, "bytes=1073741824-2147483647".to_string()),
+                (3, "bytes=2147483648-2147483657".to_string()),
+            ]
+        );
+        assert_eq!(copy_ranges(MAX_COPY_SIZE + 1).count(), 6);
+        assert_eq!(copy_ranges(COPY_PART_SIZE).count(), 1);
+    }
+
+    #[test]
+    fn copy_sources_are_percent_encoded() {
+        assert_eq!(encode_key("backups/docs/report-1_final.v2~.txt"), "backups/docs/report-1_final.v2~.txt");
+        assert_eq!(encode_key("docs/small file+1.txt"), "docs/small%20file%2B1.txt");
+        assert_eq!(encode_key("docs/résumé?.txt"), "docs/r%C3%A9sum%C3%A9%3F.txt");
+    }
+
+    /// Runs against a real server, e.g. a local MinIO:
+    ///
+    /// ```text
+    /// docker run -p 9000:9000 -e MINIO_ROOT_USER=minio -e MINIO_ROOT_PASSWORD=minio123 minio/minio server /data
+    /// EGADSYNC_S3_ENDPOINT=http://127.0.0.1:9000 EGADSYNC_S3_BUCKET=egadsync \
+    /// EGADSYNC_S3_ACCESS_KEY=minio EGADSYNC_S3_SECRET_KEY=minio123 cargo test s3 -- --ignored
+    /// ```
+    #[test]
+    #[ignore = "needs an S3-compatible server"]
+    fn s3_applies_changes() {
+        let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+        let config = S3Config {
+            endpoint: std::env::var("EGADSYNC_S3_ENDPOINT").ok(),
+            region: default_region(),
+            bucket: var("EGADSYNC_S3_BUCKET"),
+            prefix: format!("test-{}", std::process::id()),
+            access_key_id: var("EGADSYNC_S3_ACCESS_KEY"),
+            secret_access_key: Some(var("EGADSYNC_S3_SECRET_KEY")),
+        };
+        let test_dir = TestDir::new("s3");
+        let local = test_dir.path();
+        let small = local.join("small.txt");
+        fs::write(&small, b"hello").unwrap();
+        let large = local.join("large.bin");
+        fs::write(&large, vec![7u8; PART_SIZE as usize + 1024]).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let mut destination = S3Destination::connect(&config, &test_dir.config()).unwrap();
+        destination.upload(Path::new("dir/small file.txt"), &small, modified).unwrap();
+        destination.upload(Path::new("dir/large.bin"), &large, modified).unwrap();
+        let prefix = destination.key(Path::new("dir"));
+        assert_eq!(destination.keys_at(&prefix).unwrap().len(), 2);
+        assert!(destination.list_files().unwrap().contains(&PathBuf::from("dir/small file.txt")));
+
+        destination.rename(Path::new("dir"), Path::new("moved")).unwrap();
+        assert!(destination.keys_at(&prefix).unwrap().is_empty());
+        assert_eq!(destination.keys_at(&destination.key(Path::new("moved"))).unwrap().len(), 2);
+        let missing = destination.rename(Path::new("dir"), Path::new("other"));
+        assert!(matches!(missing, Err(FileTrackerError::IoError(e)) if e.kind() == io::ErrorKind::NotFound));
+
+        destination.remove(Path::new("moved")).unwrap();
+        assert!(destination.keys_at(&destination.key(Path::new("moved"))).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+use crate::config::Config;
+use crate::error::FileTrackerError;
+use crate::persistence;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::XChaCha20Poly1305;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Keyring service under which secrets are stored.
+const KEYRING_SERVICE: &str = "egadsync";
+
+/// Prefixes of the accounts secrets may be stored under, one per kind of secret.
+const ACCOUNT_PREFIXES: [&str; 4] = ["sftp/", "s3/", "webdav/", "encryption/"];
+
+/// Environment variable holding the passphrase of the fallback file. Without it, the file is
+/// encrypted with a random key kept next to it, readable by the owner only.
+const PASSPHRASE_VAR: &str = "EGADSYNC_SECRETS_PASSPHRASE";
+
+/// Start of the fallback file, followed by how its key is obtained, the salt and the nonce.
+const MAGIC: &[u8; 8] = b"EGADSEC1";
+const KEY_FILE_MODE: u8 = 0;
+const PASSPHRASE_MODE: u8 = 1;
+const HEADER_LEN: usize = 8 + 1 + 16 + 24;
+
+/// Reads a secret, failing when none is stored for `account`.
+pub fn get(config: &Config, account: &str) -> Result<String, FileTrackerError> {
+    find(config, account)?.ok_or_else(|| FileTrackerError::SecretError(format!("no secret is stored for {}", account)))
+}
+
+/// Reads a secret from the OS keyring (the Secret Service on Linux), or from the fallback
+/// file in the data directory of `config` when the keyring has none or cannot be reached.
+pub fn find(config: &Config, account: &str) -> Result<Option<String>, FileTrackerError> {
+    match keyring_entry(account).and_then(|entry| entry.get_password()) {
+        Ok(secret) => return Ok(Some(secret)),
+        Err(keyring::Error::NoEntry) => {}
+        Err(e) if unavailable(&e) => log::debug!("No keyring to read {} from: {}", account, e),
+        Err(e) => return Err(keyring_error(account, e)),
+    }
+    SecretFile::new(&config.data_dir).get(account)
+}
+
+/// Stores a secret, replacing any previous one for `account`. It goes to the OS keyring when
+/// there is one, and to the fallback file otherwise.
+pub fn set(config: &Config, account: &str, secret: &str) -> Result<(), FileTrackerError> {
+    let file = SecretFile::new(&config.data_dir);
+    match keyring_entry(account).and_then(|entry| entry.set_password(secret)) {
+        Ok(()) => {
+            // An older copy in the file would otherwise be found once the keyring is gone.
+            if let Err(e) = file.remove(account) {
+                log::warn!("Failed to remove the older copy of {} from {}: {}", account, file.path().display(), e);
+            }
+            Ok(())
+        }
+        Err(e) if unavailable(&e) => {
+            log::warn!("No keyring available ({}); storing {} in {}", e, account, file.path().display());
+            file.set(account, secret)
+        }
+        Err(e) => Err(keyring_error(account, e)),
+    }
+}
+
+/// Removes the secret of `account` wherever it is stored. A missing secret is not an error.
+pub fn clear(config: &Config, account: &str) -> Result<(), FileTrackerError> {
+    match keyring_entry(account).and_then(|entry| entry.delete_credential()) {
+        Ok(()) | Err(keyring::Error::NoEntry) => {}
+        Err(e) if unavailable(&e) => log::debug!("No keyring to remove {} from: {}", account, e),
+        Err(e) => return Err(keyring_error(account, e)),
+    }
+    SecretFile::new(&config.data_dir).remove(account)
+}
+
+/// Rejects accounts that do not name a kind of secret this app uses.
+pub fn validate_account(account: &str) -> Result<(), FileTrackerError> {
+    let known = ACCOUNT_PREFIXES
+        .iter()
+        .any(|prefix| account.strip_prefix(prefix).is_some_and(|rest| !rest.trim().is_empty()));
+    if !known {
+        return Err(FileTrackerError::InvalidConfig(format!("unknown secret account {}", account)));
+    }
+    Ok(())
+}
+
+fn keyring_entry(account: &str) -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, account)
+}
+
+/// Whether a keyring error means there is no usable keyring, such as on a headless machine
+/// without a Secret Service, rather than a problem with the entry itself.
+fn unavailable(err: &keyring::Error) -> bool {
+    matches!(err, keyring::Error::NoStorageAccess(_) | keyring::Error::PlatformFailure(_))
+}
+
+fn keyring_error(account: &str, err: keyring::Error) -> FileTrackerError {
+    FileTrackerError::SecretError(format!("keyring entry {}: {}", account, err))
+}
+
+/// Serializes changes to the fallback file, which are a load, a change and a save.
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Secrets kept in `secrets.enc` in the app data directory, for machines without a keyring.
+///
+/// The whole file is one XChaCha20-Poly1305 message. Its key is derived with Argon2id from
+/// `EGADSYNC_SECRETS_PASSPHRASE` when set, and is otherwise a random key in `secrets.key`.
+struct SecretFile {
+    dir: PathBuf,
+}
+
+impl SecretFile {
+    fn new(dir: &Path) -> Self {
+        SecretFile { dir: dir.to_path_buf() }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join("secrets.enc")
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.dir.join("secrets.key")
+    }
+
+    fn get(&self, account: &str) -> Result<Option<String>, FileTrackerError> {
+        Ok(self.load()?.remove(account))
+    }
+
+    fn set(&self, account: &str, secret: &str) -> Result<(), FileTrackerError> {
+        let _guard = FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut secrets = self.load()?;
+        secrets.insert(account.to_string(), secret.to_string());
+        self.save(&secrets)
+    }
+
+    fn remove(&self, account: &str) -> Result<(), FileTrackerError> {
+        let _guard = FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut secrets = self.load()?;
+        if secrets.remove(account).is_some() {
+            self.save(&secrets)?;
+        }
+        Ok(())
+    }
+
+    fn load(&self) -> Result<BTreeMap<String, String>, FileTrackerError> {
+        let sealed = match fs::read(self.path()) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        if sealed.len() < HEADER_LEN || &sealed[..MAGIC.len()] != MAGIC {
+            return Err(self.error("is not a secrets file"));
+        }
+        let (mode, salt) = (sealed[MAGIC.len()], &sealed[MAGIC.len() + 1..MAGIC.len() + 17]);
+        let mut nonce = [0; 24];
+        nonce.copy_from_slice(&sealed[MAGIC.len() + 17..HEADER_LEN]);
+        let cipher = XChaCha20Poly1305::new(&self.key(mode, salt, false)?.into());
+        let json = cipher
+            .decrypt(&nonce.into(), &sealed[HEADER_LEN..])
+            .map_err(|_| self.error("cannot be decrypted with this key"))?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    fn save(&self, secrets: &BTreeMap<String, String>) -> Result<(), FileTrackerError> {
+        let mode = match std::env::var_os(PASSPHRASE_VAR) {
+            Some(_) => PASSPHRASE_MODE,
+            None => KEY_FILE_MODE,
+        };
+        let mut salt = [0; 16];
+        let mut nonce = [0; 24];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = XChaCha20Poly1305::new(&self.key(mode, &salt, true)?.into());
+        let ciphertext = cipher
+            .encrypt(&nonce.into(), serde_json::to_vec(secrets)?.as_slice())
+            .map_err(|_| self.error("cannot be encrypted"))?;
+
+        let mut sealed = MAGIC.to_vec();
+        sealed.push(mode);
+        sealed.extend_from_slice(&salt);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(ciphertext);
+        fs::create_dir_all(&self.dir)?;
+        persistence::write_private(&self.path(), &sealed)?;
+        Ok(())
+    }
+
+    /// The key of the file, creating the key file on first use when `create` is set.
+    fn key(&self, mode: u8, salt: &[u8], create: bool) -> Result<[u8; 32], FileTrackerError> {
+        let mut key = [0; 32];
+        match mode {
+            PASSPHRASE_MODE => {
+                let passphrase = std::env::var(PASSPHRASE_VAR)
+                    .map_err(|_| self.error(&format!("is protected by a passphrase; set {}", PASSPHRASE_VAR)))?;
+                let params = Params::new(Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST, None)
+                    .map_err(|e| self.error(&e.to_string()))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .map_err(|e| self.error(&e.to_string()))?;
+            }
+            KEY_FILE_MODE => match fs::read(self.key_path()) {
+                Ok(stored) if stored.len() == key.len() => key.copy_from_slice(&stored),
+                Ok(_) => return Err(self.error("has a key file of the wrong size")),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && create => {
+                    OsRng.fill_bytes(&mut key);
+                    fs::create_dir_all(&self.dir)?;
+                    persistence::write_private(&self.key_path(), &key)?;
+                    log::info!("Created the key of the secrets file in {}", self.key_path().display());
+                }
+                Err(e) => return Err(e.into()),
+            },
+            _ => return Err(self.error("uses an unknown key mode")),
+        }
+        Ok(key)
+    }
+
+    fn error(&self, details: &str) -> FileTrackerError {
+        FileTrackerError::SecretError(format!("{} {}", self.path().display(), details))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback_file_keeps_secrets_encrypted() {
+        let dir = std::env::temp_dir().join(format!("egadsync-secrets-{}", std::process::id()));
+        let file = SecretFile::new(&dir);
+        assert_eq!(file.get("s3/minio").unwrap(), None);
+
+        file.set("s3/minio", "minio123").unwrap();
+        file.set("encryption/abc", "correct horse battery").unwrap();
+        file.remove("encryption/abc").unwrap();
+        assert_eq!(file.get("s3/minio").unwrap().as_deref(), Some("minio123"));
+        assert_eq!(file.get("encryption/abc").unwrap(), None);
+        let sealed = fs::read(file.path()).unwrap();
+        assert!(!sealed.windows(8).any(|window| window == b"minio123"));
+
+        // Without its key the file cannot be read.
+        fs::remove_file(file.key_path()).unwrap();
+        assert!(file.get("s3/minio").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(validate_account("webdav/alice@cloud.example.com").is_ok());
+        assert!(validate_account("webdav/").is_err());
+        assert!(validate_account("other/alice").is_err());
+    }
+
+    #[test]
+    fn concurrent_changes_to_the_fallback_file_are_all_kept() {
+        let dir = std::env::temp_dir().join(format!("egadsync-secrets-concurrent-{}", std::process::id()));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let dir = dir.clone();
+                std::thread::spawn(move || SecretFile::new(&dir).set(&format!("s3/bucket{}", i), "secret").unwrap())
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(SecretFile::new(&dir).load().unwrap().len(), 8);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
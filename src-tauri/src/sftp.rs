@@ -0,0 +1,358 @@
+use crate::config::Config;
+use crate::delta::PARTIAL_SUFFIX;
+use crate::destination::Destination;
+use crate::error::FileTrackerError;
+use crate::secrets;
+use serde::{Deserialize, Serialize};
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, KnownHosts, Session, Sftp};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How long to wait for the server before giving up on a connection or an operation.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connection settings of an SFTP destination. Only key-based authentication is supported,
+/// and the server's host key must already be in `known_hosts`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SftpConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: String,
+    /// Private key used to log in, in OpenSSH or PEM format. Its passphrase, if any, is read
+    /// from the secret store under the account `sftp/<username>@<host>`.
+    pub private_key: PathBuf,
+    /// Absolute path of the folder on the server that receives the mirror.
+    pub remote_path: String,
+    /// File the server's host key is checked against; `~/.ssh/known_hosts` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub known_hosts: Option<PathBuf>,
+}
+
+fn default_port() -> u16 {
+    22
+}
+
+impl SftpConfig {
+    /// Secret store account holding the passphrase of the private key of `username` on `host`.
+    pub fn secret_account(host: &str, username: &str) -> String {
+        format!("sftp/{}@{}", username, host)
+    }
+}
+
+/// An open SFTP session on the destination server.
+pub struct SftpDestination {
+    // The session must outlive the SFTP channel opened on it.
+    sftp: Sftp,
+    _session: Session,
+    root: PathBuf,
+    /// Directories known to exist, so uploads do not check their parents every time.
+    created: HashSet<PathBuf>,
+}
+
+impl SftpDestination {
+    /// Connects and logs in, refusing servers whose host key is not in `known_hosts`. The
+    /// passphrase of the key, if any, is read from the secret store of `base`.
+    pub fn connect(config: &SftpConfig, base: &Config) -> Result<Self, FileTrackerError> {
+        let tcp = TcpStream::connect((config.host.as_str(), config.port))?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        let mut session = Session::new().map_err(ssh_error)?;
+        session.set_timeout(TIMEOUT.as_millis() as u32);
+        session.set_tcp_stream(tcp);
+        session.handshake().map_err(ssh_error)?;
+        verify_host_key(&session, config)?;
+
+        let passphrase = secrets::find(base, &SftpConfig::secret_account(&config.host, &config.username))?;
+        session
+            .userauth_pubkey_file(&config.username, None, &config.private_key, passphrase.as_deref())
+            .map_err(ssh_error)?;
+        if !session.authenticated() {
+            return Err(FileTrackerError::DestinationError(format!(
+                "{} refused the key of {}",
+                config.host, config.username
+            )));
+        }
+        let sftp = session.sftp().map_err(ssh_error)?;
+        log::info!("Connected to {}:{} over SFTP", config.host, config.port);
+        Ok(SftpDestination {
+            sftp,
+            _session: session,
+            root: PathBuf::from(&config.remote_path),
+            created: HashSet::new(),
+        })
+    }
+
+    fn remote(&self, relative: &Path) -> PathBuf {
+        self.root.join(relative)
+    }
+
+    /// Creates a remote directory and its missing ancestors, up to the destination root.
+    fn create_dir_all(&mut self, dir: &Path) -> Result<(), FileTrackerError> {
+        if self.created.contains(dir) {
+            return Ok(());
+        }
+        let missing: Vec<PathBuf> = dir
+            .ancestors()
+            .take_while(|ancestor| !self.created.contains(*ancestor))
+            .filter(|ancestor| self.sftp.stat(ancestor).is_err())
+            .map(Path::to_path_buf)
+            .collect();
+        for ancestor in missing.iter().rev() {
+            match self.sftp.mkdir(ancestor, 0o755) {
+                // Another client may have created it in the meantime.
+                Err(e) if self.sftp.stat(ancestor).is_ok_and(|stat| stat.is_dir()) => {
+                    log::debug!("{} already exists: {}", ancestor.display(), e)
+                }
+                other => other.map_err(ssh_error)?,
+            }
+        }
+        self.created.extend(dir.ancestors().map(Path::to_path_buf));
+        Ok(())
+    }
+
+    fn remove_tree(&self, path: &Path) -> Result<(), FileTrackerError> {
+        let stat = match self.sftp.lstat(path).map_err(io::Error::from) {
+            Ok(stat) => stat,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if stat.is_dir() {
+            for (child, _) in self.sftp.readdir(path).map_err(ssh_error)? {
+                self.remove_tree(&child)?;
+            }
+            self.sftp.rmdir(path).map_err(ssh_error)?;
+        } else {
+            self.sftp.unlink(path).map_err(ssh_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Destination for SftpDestination {
+    fn create_dir(&mut self, relative: &Path) -> Result<(), FileTrackerError> {
+        self.create_dir_all(&self.remote(relative))
+    }
+
+    /// The file is written next to its final path first, so an interrupted upload never
+    /// leaves a truncated copy behind.
+    fn upload(&mut self, relative: &Path, source: &Path, modified: SystemTime) -> Result<u64, FileTrackerError> {
+        let target = self.remote(relative);
+        if let Some(parent) = target.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let partial = target.with_file_name(format!(".{}{}", name, PARTIAL_SUFFIX));
+
+        let mut remote_file = self.sftp.create(&partial).map_err(ssh_error)?;
+        let sent = io::copy(&mut File::open(source)?, &mut remote_file)?;
+        let mtime = modified.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        remote_file
+            .setstat(FileStat {
+                size: None,
+                uid: None,
+                gid: None,
+                perm: None,
+                atime: Some(mtime),
+                mtime: Some(mtime),
+            })
+            .map_err(ssh_error)?;
+        drop(remote_file);
+
+        // SFTP v3 renames do not replace an existing file.
+        match self.sftp.unlink(&target).map_err(io::Error::from) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.sftp.rename(&partial, &target, None).map_err(ssh_error)?;
+        Ok(sent)
+    }
+
+    fn download(&mut self, relative: &Path, target: &Path) -> Result<u64, FileTrackerError> {
+        let mut remote_file = self.sftp.open(self.remote(relative)).map_err(ssh_error)?;
+        Ok(io::copy(&mut remote_file, &mut File::create(target)?)?)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), FileTrackerError> {
+        let (from, to) = (self.remote(from), self.remote(to));
+        self.sftp.lstat(&from).map_err(ssh_error)?;
+        if let Some(parent) = to.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.sftp.rename(&from, &to, None).map_err(ssh_error)?;
+        self.created.retain(|dir| !dir.starts_with(&from));
+        Ok(())
+    }
+
+    fn remove(&mut self, relative: &Path) -> Result<(), FileTrackerError> {
+        let path = self.remote(relative);
+        self.remove_tree(&path)?;
+        self.created.retain(|dir| !dir.starts_with(&path));
+        Ok(())
+    }
+
+    fn list_files(&mut self) -> Result<Vec<PathBuf>, FileTrackerError> {
+        let mut files = Vec::new();
+        let mut pending = vec![self.root.clone()];
+        while let Some(dir) = pending.pop() {
+            for (path, stat) in self.sftp.readdir(&dir).map_err(ssh_error)? {
+                if stat.is_dir() {
+                    pending.push(path);
+                } else if let Ok(relative) = path.strip_prefix(&self.root) {
+                    files.push(relative.to_path_buf());
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Checks the server's host key against `known_hosts`. Unknown and changed keys are both refused.
+fn verify_host_key(session: &Session, config: &SftpConfig) -> Result<(), FileTrackerError> {
+    let path = match &config.known_hosts {
+        Some(path) => path.clone(),
+        None => dirs::home_dir().unwrap_or_default().join(".ssh").join("known_hosts"),
+    };
+    let mut known_hosts = session.known_hosts().map_err(ssh_error)?;
+    known_hosts
+        .read_file(&path, KnownHostFileKind::OpenSSH)
+        .map_err(|e| FileTrackerError::DestinationError(format!("cannot read {}: {}", path.display(), e)))?;
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| FileTrackerError::DestinationError(format!("{} sent no host key", config.host)))?;
+
+    check_host_key(&known_hosts, config, key, &path)
+}
+
+/// Maps the result of looking up `key` in the `known_hosts` read from `path` to an error.
+fn check_host_key(
+    known_hosts: &KnownHosts,
+    config: &SftpConfig,
+    key: &[u8],
+    path: &Path,
+) -> Result<(), FileTrackerError> {
+    match known_hosts.check_port(&config.host, config.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => Err(FileTrackerError::DestinationError(format!(
+            "{} is not in {}",
+            config.host,
+            path.display()
+        ))),
+        CheckResult::Mismatch => Err(FileTrackerError::DestinationError(format!(
+            "the host key of {} does not match {}",
+            config.host,
+            path.display()
+        ))),
+        CheckResult::Failure => Err(FileTrackerError::DestinationError(format!(
+            "could not check the host key of {}",
+            config.host
+        ))),
+    }
+}
+
+/// Maps libssh2 errors to I/O errors, which keeps "no such file" recognizable.
+fn ssh_error(err: ssh2::Error) -> FileTrackerError {
+    io::Error::from(err).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestDir;
+    use std::fs;
+
+    /// An ed25519 public key blob whose 32 key bytes are all `byte`.
+    fn host_key(byte: u8) -> Vec<u8> {
+        let mut key = Vec::new();
+        key.extend_from_slice(&[0, 0, 0, 11]);
+        key.extend_from_slice(b"ssh-ed25519");
+        key.extend_from_slice(&[0, 0, 0, 32]);
+        key.extend_from_slice(&[byte; 32]);
+        key
+    }
+
+    #[test]
+    fn unknown_and_changed_host_keys_are_refused() {
+        let dir = std::env::temp_dir().join(format!("egadsync-known-hosts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("known_hosts");
+        fs::write(
+            &path,
+            "[sync.example]:2222 ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcH\n",
+        )
+        .unwrap();
+        let session = Session::new().unwrap();
+        let mut known_hosts = session.known_hosts().unwrap();
+        known_hosts.read_file(&path, KnownHostFileKind::OpenSSH).unwrap();
+        let config = SftpConfig {
+            host: "sync.example".to_string(),
+            port: 2222,
+            username: "sync".to_string(),
+            private_key: PathBuf::from("id_ed25519"),
+            remote_path: "/srv/sync".to_string(),
+            known_hosts: Some(path.clone()),
+        };
+        let message = |result: Result<(), FileTrackerError>| match result {
+            Err(FileTrackerError::DestinationError(message)) => message,
+            other => panic!("expected a destination error, got {:?}", other),
+        };
+
+        assert!(check_host_key(&known_hosts, &config, &host_key(7), &path).is_ok());
+        let changed = message(check_host_key(&known_hosts, &config, &host_key(8), &path));
+        assert!(changed.starts_with("the host key of sync.example does not match"), "{}", changed);
+        let other_port = SftpConfig {
+            port: 22,
+            ..config.clone()
+        };
+        let unknown = message(check_host_key(&known_hosts, &other_port, &host_key(7), &path));
+        assert!(unknown.starts_with("sync.example is not in"), "{}", unknown);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Runs against a real server, e.g. sshd on loopback or an OpenSSH container:
+    ///
+    /// ```text
+    /// EGADSYNC_SFTP_HOST=127.0.0.1 EGADSYNC_SFTP_PORT=2222 EGADSYNC_SFTP_USER=sync \
+    /// EGADSYNC_SFTP_KEY=~/.ssh/id_ed25519 EGADSYNC_SFTP_PATH=/tmp/egadsync \
+    /// cargo test sftp -- --ignored
+    /// ```
+    #[test]
+    #[ignore = "needs an SFTP server"]
+    fn sftp_applies_changes() {
+        let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+        let config = SftpConfig {
+            host: var("EGADSYNC_SFTP_HOST"),
+            port: std::env::var("EGADSYNC_SFTP_PORT").map_or(22, |port| port.parse().unwrap()),
+            username: var("EGADSYNC_SFTP_USER"),
+            private_key: PathBuf::from(var("EGADSYNC_SFTP_KEY")),
+            remote_path: var("EGADSYNC_SFTP_PATH"),
+            known_hosts: std::env::var("EGADSYNC_SFTP_KNOWN_HOSTS").ok().map(PathBuf::from),
+        };
+        let test_dir = TestDir::new("sftp");
+        let local = test_dir.path();
+        let source = local.join("a.txt");
+        fs::write(&source, b"hello").unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+        let mut destination = SftpDestination::connect(&config, &test_dir.config()).unwrap();
+        destination.remove(Path::new("test")).unwrap();
+        assert_eq!(destination.upload(Path::new("test/deep/a.txt"), &source, modified).unwrap(), 5);
+        let stat = destination.sftp.stat(&destination.remote(Path::new("test/deep/a.txt"))).unwrap();
+        assert_eq!(stat.size, Some(5));
+        assert_eq!(stat.mtime, Some(1_600_000_000));
+        assert!(destination.list_files().unwrap().contains(&PathBuf::from("test/deep/a.txt")));
+
+        destination.rename(Path::new("test/deep"), Path::new("test/moved")).unwrap();
+        assert!(destination.sftp.stat(&destination.remote(Path::new("test/moved/a.txt"))).is_ok());
+        let missing = destination.rename(Path::new("test/deep"), Path::new("test/other"));
+        assert!(matches!(missing, Err(FileTrackerError::IoError(e)) if e.kind() == io::ErrorKind::NotFound));
+
+        destination.remove(Path::new("test")).unwrap();
+        assert!(destination.sftp.stat(&destination.remote(Path::new("test"))).is_err());
+    }
+}
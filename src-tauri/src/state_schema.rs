@@ -0,0 +1,287 @@
+use crate::error::FileTrackerError;
+use crate::file_tracker::{FileMetadata, FileTracker};
+use serde_json::{Map, Value};
+
+/// Version written to every JSON state file.
+pub const CURRENT_VERSION: u64 = 2;
+
+/// Version of the JSON of one `FileMetadata`, recorded in state files and in each row of the
+/// state database.
+pub const METADATA_VERSION: u64 = 6;
+
+type MetadataMigration = fn(&mut Map<String, Value>);
+
+/// `METADATA_MIGRATIONS[n]` upgrades an entry from version `n + 1` to version `n + 2`.
+/// Add a migration here whenever the persisted shape of `FileMetadata` changes. Entries saved
+/// before their version was recorded count as version 1 but may hold later fields, so
+/// migrations leave fields that are already present alone.
+const METADATA_MIGRATIONS: &[MetadataMigration] =
+    &[add_hash, add_status_changed, add_file_id, add_manifest, add_object_name];
+
+type Migration = fn(&mut Map<String, Value>) -> Result<(), FileTrackerError>;
+
+/// `MIGRATIONS[n]` upgrades a state from version `n + 1` to version `n + 2`.
+/// Add a migration here whenever the persisted shape of `FileTracker` changes.
+const MIGRATIONS: &[Migration] = &[v1_to_v2];
+
+/// Parses a JSON state file of any known version, upgrading it to the current one.
+/// Also returns whether an upgrade was needed, so the caller can rewrite the file.
+pub fn parse(json: &str) -> Result<(FileTracker, bool), FileTrackerError> {
+    let Value::Object(mut state) = serde_json::from_str(json)? else {
+        return Err(FileTrackerError::InvalidState("expected a JSON object".to_string()));
+    };
+
+    // Files written before versioning was introduced have no version field.
+    let version = match state.remove("version") {
+        None => 1,
+        Some(value) => value
+            .as_u64()
+            .filter(|version| *version >= 1)
+            .ok_or_else(|| FileTrackerError::InvalidState(format!("invalid version {}", value)))?,
+    };
+    if version > CURRENT_VERSION {
+        return Err(FileTrackerError::InvalidState(format!(
+            "version {} was written by a newer release (supported up to {})",
+            version, CURRENT_VERSION
+        )));
+    }
+
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        migration(&mut state)?;
+    }
+
+    let metadata_version = match state.remove("metadata_version") {
+        None => 1,
+        Some(value) => value
+            .as_u64()
+            .ok_or_else(|| FileTrackerError::InvalidState(format!("invalid metadata version {}", value)))?,
+    };
+    if let Some(Value::Object(files_state)) = state.get_mut("files_state") {
+        for metadata in files_state.values_mut() {
+            upgrade_metadata(metadata, metadata_version)?;
+        }
+    }
+    let file_tracker = serde_json::from_value(Value::Object(state))?;
+    Ok((file_tracker, version < CURRENT_VERSION || metadata_version < METADATA_VERSION))
+}
+
+/// Serializes a tracker as a JSON state file of the current version.
+pub fn to_json(file_tracker: &FileTracker) -> Result<String, FileTrackerError> {
+    let Value::Object(mut state) = serde_json::to_value(file_tracker)? else {
+        return Err(FileTrackerError::InvalidState("expected a JSON object".to_string()));
+    };
+    state.insert("version".to_string(), Value::from(CURRENT_VERSION));
+    state.insert("metadata_version".to_string(), Value::from(METADATA_VERSION));
+    Ok(serde_json::to_string_pretty(&state)?)
+}
+
+/// Parses the JSON of one entry written at metadata `version`, upgrading it to the current one.
+pub fn parse_metadata(json: &str, version: u64) -> Result<FileMetadata, FileTrackerError> {
+    let mut metadata = serde_json::from_str(json)?;
+    upgrade_metadata(&mut metadata, version)?;
+    Ok(serde_json::from_value(metadata)?)
+}
+
+fn upgrade_metadata(metadata: &mut Value, version: u64) -> Result<(), FileTrackerError> {
+    if !(1..=METADATA_VERSION).contains(&version) {
+        return Err(FileTrackerError::InvalidState(format!(
+            "metadata version {} is not supported (supported up to {})",
+            version, METADATA_VERSION
+        )));
+    }
+    let Value::Object(metadata) = metadata else {
+        return Err(FileTrackerError::InvalidState("expected a JSON object for an entry".to_string()));
+    };
+    for migration in &METADATA_MIGRATIONS[(version - 1) as usize..] {
+        migration(metadata);
+    }
+    Ok(())
+}
+
+/// Version 1 covers the unversioned files, from the first release (only `root_target` and
+/// `files_state`) to the ones with a mirror destination and content hashes.
+/// Version 2 always records the destination, even when none is configured.
+fn v1_to_v2(state: &mut Map<String, Value>) -> Result<(), FileTrackerError> {
+    state.entry("root_destination").or_insert(Value::Null);
+    Ok(())
+}
+
+/// Metadata version 1 is the first release's: modification time, size and whether the entry
+/// is a directory. Version 2 adds the content digest, unknown until the file is hashed.
+fn add_hash(metadata: &mut Map<String, Value>) {
+    metadata.entry("hash").or_insert(Value::Null);
+}
+
+/// Version 3 adds the inode change time, filled in by the next scan.
+fn add_status_changed(metadata: &mut Map<String, Value>) {
+    metadata.entry("status_changed").or_insert(Value::Null);
+}
+
+/// Version 4 adds the device and inode numbers used to recognize renames.
+fn add_file_id(metadata: &mut Map<String, Value>) {
+    metadata.entry("file_id").or_insert(Value::Null);
+}
+
+/// Version 5 adds the identifier of the contents' manifest in the chunk store.
+fn add_manifest(metadata: &mut Map<String, Value>) {
+    metadata.entry("manifest").or_insert(Value::Null);
+}
+
+/// Version 6 adds the name an entry is stored under at an encrypted destination.
+fn add_object_name(metadata: &mut Map<String, Value>) {
+    metadata.entry("object_name").or_insert(Value::Null);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    const V1_ORIGINAL: &str = include_str!("../tests/fixtures/state/v1_original.json");
+    const V1_HASHED: &str = include_str!("../tests/fixtures/state/v1_hashed.json");
+    const V2: &str = include_str!("../tests/fixtures/state/v2.json");
+    const METADATA_V1: &str = include_str!("../tests/fixtures/state/metadata/v1_original.json");
+    const METADATA_V2_HASH: &str = include_str!("../tests/fixtures/state/metadata/v2_hash.json");
+    const METADATA_V3_STATUS_CHANGED: &str = include_str!("../tests/fixtures/state/metadata/v3_status_changed.json");
+    const METADATA_V4_FILE_ID: &str = include_str!("../tests/fixtures/state/metadata/v4_file_id.json");
+    const METADATA_V5_MANIFEST: &str = include_str!("../tests/fixtures/state/metadata/v5_manifest.json");
+    const METADATA_V6_OBJECT_NAME: &str = include_str!("../tests/fixtures/state/metadata/v6_object_name.json");
+    const HASH: &str = "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn loads_original_unversioned_state() {
+        let (file_tracker, upgraded) = parse(V1_ORIGINAL).unwrap();
+        assert!(upgraded);
+        assert_eq!(file_tracker.root_target, Path::new("/home/user/docs"));
+        assert_eq!(file_tracker.root_destination, None);
+        assert_eq!(file_tracker.files_state.len(), 2);
+
+        let file = &file_tracker.files_state[Path::new("/home/user/docs/notes.txt")];
+        assert!(!file.is_dir());
+        assert_eq!(file.modified(), at(1_700_000_000));
+        assert_eq!(file.hash(), None);
+    }
+
+    #[test]
+    fn loads_unversioned_state_with_destination_and_hashes() {
+        let (file_tracker, upgraded) = parse(V1_HASHED).unwrap();
+        assert!(upgraded);
+        assert_eq!(file_tracker.root_destination.as_deref(), Some(Path::new("/mnt/backup/docs")));
+
+        let file = &file_tracker.files_state[Path::new("/home/user/docs/notes.txt")];
+        assert_eq!(
+            file.hash(),
+            Some("d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24")
+        );
+    }
+
+    #[test]
+    fn loads_current_state_upgrading_only_its_entries() {
+        // Version 2 files were written before entries had a metadata version.
+        let (file_tracker, upgraded) = parse(V2).unwrap();
+        assert!(upgraded);
+        assert_eq!(file_tracker.files_state.len(), 2);
+    }
+
+    #[test]
+    fn upgraded_state_round_trips_as_current_version() {
+        let (file_tracker, _) = parse(V1_ORIGINAL).unwrap();
+        let json = to_json(&file_tracker).unwrap();
+        assert!(json.contains(&format!("\"version\": {}", CURRENT_VERSION)));
+
+        let (reloaded, upgraded) = parse(&json).unwrap();
+        assert!(!upgraded);
+        assert_eq!(reloaded.files_state, file_tracker.files_state);
+    }
+
+    #[test]
+    fn rejects_states_from_newer_releases() {
+        let json = V2.replacen("\"version\": 2", &format!("\"version\": {}", CURRENT_VERSION + 1), 1);
+        assert!(matches!(parse(&json), Err(FileTrackerError::InvalidState(_))));
+    }
+
+    /// The metadata of the fixtures' file in the current format, with the given extra fields.
+    fn expected(fields: Value) -> FileMetadata {
+        let mut metadata = serde_json::json!({
+            "last_modified": { "secs_since_epoch": 1_700_000_000, "nanos_since_epoch": 0 },
+            "size": 12,
+            "is_dir": false,
+        });
+        metadata.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(metadata).unwrap()
+    }
+
+    fn status_changed() -> Value {
+        serde_json::json!({ "secs_since_epoch": 1_700_000_100, "nanos_since_epoch": 0 })
+    }
+
+    #[test]
+    fn upgrades_original_entries() {
+        let metadata = parse_metadata(METADATA_V1, 1).unwrap();
+        assert_eq!(metadata, expected(serde_json::json!({})));
+        assert_eq!(metadata.hash(), None);
+    }
+
+    #[test]
+    fn upgrades_entries_with_a_hash() {
+        let metadata = parse_metadata(METADATA_V2_HASH, 2).unwrap();
+        assert_eq!(metadata, expected(serde_json::json!({ "hash": HASH })));
+    }
+
+    #[test]
+    fn upgrades_entries_with_a_status_change_time() {
+        let metadata = parse_metadata(METADATA_V3_STATUS_CHANGED, 3).unwrap();
+        assert_eq!(metadata, expected(serde_json::json!({ "hash": HASH, "status_changed": status_changed() })));
+    }
+
+    #[test]
+    fn upgrades_entries_with_a_file_id() {
+        let metadata = parse_metadata(METADATA_V4_FILE_ID, 4).unwrap();
+        let fields = serde_json::json!({
+            "hash": HASH,
+            "status_changed": status_changed(),
+            "file_id": [2049, 131_075],
+        });
+        assert_eq!(metadata, expected(fields));
+    }
+
+    #[test]
+    fn reads_entries_with_a_manifest() {
+        let metadata = parse_metadata(METADATA_V5_MANIFEST, 5).unwrap();
+        let fields = serde_json::json!({
+            "hash": HASH,
+            "status_changed": status_changed(),
+            "file_id": [2049, 131_075],
+            "manifest": HASH,
+        });
+        assert_eq!(metadata, expected(fields));
+        assert_eq!(metadata.manifest(), Some(HASH));
+    }
+
+    #[test]
+    fn reads_entries_with_an_object_name() {
+        let metadata = parse_metadata(METADATA_V6_OBJECT_NAME, 6).unwrap();
+        assert_eq!(metadata.object_name(), Some("MFRGGZDFMZTWQ2LK"));
+        assert_eq!(metadata, parse_metadata(METADATA_V6_OBJECT_NAME, 1).unwrap());
+    }
+
+    #[test]
+    fn unversioned_entries_keep_later_fields() {
+        // Rows saved before versions were recorded count as version 1 whatever they hold.
+        for fixture in [METADATA_V2_HASH, METADATA_V3_STATUS_CHANGED, METADATA_V4_FILE_ID, METADATA_V5_MANIFEST] {
+            let current = serde_json::from_str::<FileMetadata>(fixture).unwrap();
+            assert_eq!(parse_metadata(fixture, 1).unwrap(), current);
+        }
+    }
+
+    #[test]
+    fn rejects_entries_from_newer_releases() {
+        let result = parse_metadata(METADATA_V1, METADATA_VERSION + 1);
+        assert!(matches!(result, Err(FileTrackerError::InvalidState(_))));
+    }
+}
@@ -0,0 +1,568 @@
+use crate::config::Config;
+use crate::error::FileTrackerError;
+use crate::file_tracker::{FileMetadata, FileTracker, StateGeneration};
+use crate::persistence;
+use crate::state_schema;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Entries added, updated or removed since the tracker state was last persisted.
+#[derive(Debug, Default, Clone)]
+pub struct StateDelta {
+    pub upserted: HashMap<PathBuf, FileMetadata>,
+    pub removed: HashSet<PathBuf>,
+    /// What each changed entry was when last persisted (`None` when it was absent), so a
+    /// change can be undone before it is saved.
+    pub previous: HashMap<PathBuf, Option<FileMetadata>>,
+}
+
+impl StateDelta {
+    /// Records the differences between an old and a new snapshot of the same set of paths.
+    pub fn record(&mut self, old: &HashMap<PathBuf, FileMetadata>, new: &HashMap<PathBuf, FileMetadata>) {
+        for path in old.keys().filter(|path| !new.contains_key(*path)) {
+            self.previous.entry(path.clone()).or_insert_with(|| old.get(path).cloned());
+            self.upserted.remove(path);
+            self.removed.insert(path.clone());
+        }
+        for (path, metadata) in new {
+            if old.get(path) != Some(metadata) {
+                self.previous.entry(path.clone()).or_insert_with(|| old.get(path).cloned());
+                self.removed.remove(path);
+                self.upserted.insert(path.clone(), metadata.clone());
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upserted.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Persistent storage for the state of one tracker.
+pub trait StateStore {
+    /// Checks whether a saved state exists.
+    fn exists(&self) -> bool;
+
+    /// Loads the saved state, reporting which generation it came from.
+    fn load(&mut self) -> Result<(FileTracker, StateGeneration), FileTrackerError>;
+
+    /// Replaces the saved state with the full tracker state.
+    fn save_all(&mut self, file_tracker: &FileTracker) -> Result<(), FileTrackerError>;
+
+    /// Persists only the entries that changed since the last save.
+    fn apply_delta(&mut self, file_tracker: &FileTracker, delta: &StateDelta) -> Result<(), FileTrackerError>;
+
+    /// Returns the saved entries for `prefix` and everything below it.
+    fn entries_under(&mut self, prefix: &Path) -> Result<Vec<(PathBuf, FileMetadata)>, FileTrackerError>;
+
+    /// Deletes the saved state.
+    fn delete(&mut self) -> Result<(), FileTrackerError>;
+}
+
+/// Opens the store configured for `config.state_file_path`.
+pub fn open(config: &Config) -> Box<dyn StateStore + Send> {
+    Box::new(SqliteStateStore::new(Path::new(&config.state_file_path)))
+}
+
+/// Stores the whole state as one JSON document, rewritten on every save.
+/// This is the format used before the SQLite store and is still read to migrate old installs.
+pub struct JsonStateStore {
+    path: PathBuf,
+}
+
+impl JsonStateStore {
+    pub fn new(path: &Path) -> Self {
+        JsonStateStore { path: path.to_path_buf() }
+    }
+
+    /// Reads a state file, also returning whether it was written in an older format.
+    fn read(path: &Path) -> Result<(FileTracker, bool), FileTrackerError> {
+        let mut file = File::open(path)?;
+        let mut json_data = String::new();
+        file.read_to_string(&mut json_data)?;
+        state_schema::parse(&json_data)
+    }
+
+    /// Keeps the file as `<name>.migrated` once another store has imported it.
+    fn retire(&self) -> Result<(), FileTrackerError> {
+        let mut retired = self.path.as_os_str().to_os_string();
+        retired.push(".migrated");
+        fs::rename(&self.path, retired)?;
+        persistence::remove_with_backup(&self.path)?;
+        Ok(())
+    }
+}
+
+impl StateStore for JsonStateStore {
+    fn exists(&self) -> bool {
+        self.path.exists() || persistence::backup_path(&self.path).exists()
+    }
+
+    /// Falls back to the previous generation when the state file is missing or corrupt, and
+    /// rewrites states of older versions in the current format.
+    fn load(&mut self) -> Result<(FileTracker, StateGeneration), FileTrackerError> {
+        let ((file_tracker, upgraded), generation) = match Self::read(&self.path) {
+            Ok(loaded) => (loaded, StateGeneration::Current),
+            Err(error) => {
+                let backup_path = persistence::backup_path(&self.path);
+                if !backup_path.exists() {
+                    return Err(error);
+                }
+                log::warn!("State file {} is unreadable ({}), trying its backup", self.path.display(), error);
+                let loaded = Self::read(&backup_path).map_err(|_| error)?;
+                persistence::restore_backup(&self.path)?;
+                log::warn!("Restored previous state generation from {}", backup_path.display());
+                (loaded, StateGeneration::Backup)
+            }
+        };
+
+        if upgraded {
+            self.save_all(&file_tracker)?;
+            log::info!(
+                "Upgraded state file {} to version {}",
+                self.path.display(),
+                state_schema::CURRENT_VERSION
+            );
+        }
+        Ok((file_tracker, generation))
+    }
+
+    fn save_all(&mut self, file_tracker: &FileTracker) -> Result<(), FileTrackerError> {
+        let json = state_schema::to_json(file_tracker)?;
+        persistence::write_with_backup(&self.path, json.as_bytes())?;
+        Ok(())
+    }
+
+    fn apply_delta(&mut self, file_tracker: &FileTracker, _delta: &StateDelta) -> Result<(), FileTrackerError> {
+        self.save_all(file_tracker)
+    }
+
+    fn entries_under(&mut self, prefix: &Path) -> Result<Vec<(PathBuf, FileMetadata)>, FileTrackerError> {
+        let (file_tracker, _) = self.load()?;
+        Ok(file_tracker
+            .files_state
+            .into_iter()
+            .filter(|(path, _)| path.starts_with(prefix))
+            .collect())
+    }
+
+    fn delete(&mut self) -> Result<(), FileTrackerError> {
+        persistence::remove_with_backup(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Schema version of the SQLite database, kept in its `user_version` pragma.
+/// Version 2 records the metadata version of each row; the entry JSON itself is upgraded by
+/// [`state_schema::parse_metadata`] when it is read.
+const DATABASE_VERSION: u64 = 2;
+
+/// Number of incremental saves after which the backup is refreshed.
+const BACKUP_AFTER_DELTAS: u32 = 500;
+/// Longest time incremental saves leave the backup behind before it is refreshed.
+const BACKUP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Stores one row per entry in an SQLite database next to the legacy JSON file,
+/// so a save only touches the rows that changed.
+pub struct SqliteStateStore {
+    path: PathBuf,
+    legacy: JsonStateStore,
+    connection: Option<Connection>,
+    deltas_since_backup: u32,
+    last_backup: Instant,
+}
+
+impl SqliteStateStore {
+    /// Creates the store for a state file path; the database lives at the same path with a `.sqlite3` extension.
+    pub fn new(state_file_path: &Path) -> Self {
+        SqliteStateStore {
+            path: state_file_path.with_extension("sqlite3"),
+            legacy: JsonStateStore::new(state_file_path),
+            connection: None,
+            deltas_since_backup: 0,
+            last_backup: Instant::now(),
+        }
+    }
+
+    fn connection(&mut self) -> Result<&mut Connection, FileTrackerError> {
+        if self.connection.is_none() {
+            let connection = Connection::open(&self.path)?;
+            connection.busy_timeout(Duration::from_secs(5))?;
+            connection.pragma_update(None, "journal_mode", "WAL")?;
+            connection.execute_batch(
+                "CREATE TABLE IF NOT EXISTS tracker (
+                     id INTEGER PRIMARY KEY CHECK (id = 1),
+                     root_target TEXT NOT NULL,
+                     root_destination TEXT
+                 );
+                 CREATE TABLE IF NOT EXISTS files (
+                     path TEXT PRIMARY KEY,
+                     metadata TEXT NOT NULL
+                 ) WITHOUT ROWID;",
+            )?;
+            let version: u64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+            if version > DATABASE_VERSION {
+                return Err(FileTrackerError::InvalidState(format!(
+                    "database version {} was written by a newer release (supported up to {})",
+                    version, DATABASE_VERSION
+                )));
+            }
+            if version < 2 {
+                // Rows written before had no recorded metadata version.
+                connection.execute_batch(
+                    "BEGIN;
+                     ALTER TABLE files ADD COLUMN metadata_version INTEGER NOT NULL DEFAULT 1;
+                     PRAGMA user_version = 2;
+                     COMMIT;",
+                )?;
+            }
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().expect("connection was just opened"))
+    }
+
+    /// Imports the legacy JSON state, then sets the JSON file aside.
+    fn migrate_legacy(&mut self) -> Result<FileTracker, FileTrackerError> {
+        let (file_tracker, _) = self.legacy.load()?;
+        self.save_all(&file_tracker)?;
+        self.legacy.retire()?;
+        log::info!(
+            "Migrated state of {} into {}",
+            file_tracker.root_target.display(),
+            self.path.display()
+        );
+        Ok(file_tracker)
+    }
+
+    /// Reads the saved state, or `None` when the database holds none.
+    fn read(&mut self) -> Result<Option<FileTracker>, FileTrackerError> {
+        let connection = self.connection()?;
+        let roots: Option<(String, Option<String>)> = connection
+            .query_row("SELECT root_target, root_destination FROM tracker WHERE id = 1", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+        let Some((root_target, root_destination)) = roots else { return Ok(None) };
+
+        let mut files_state = HashMap::new();
+        let mut statement = connection.prepare("SELECT path, metadata, metadata_version FROM files")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(0)?;
+            let metadata: String = row.get(1)?;
+            files_state.insert(PathBuf::from(path), state_schema::parse_metadata(&metadata, row.get(2)?)?);
+        }
+        Ok(Some(FileTracker::from_parts(
+            PathBuf::from(root_target),
+            root_destination.map(PathBuf::from),
+            files_state,
+        )))
+    }
+
+    /// Copies the database to its backup path as the generation to fall back to.
+    fn write_backup(&mut self) -> Result<(), FileTrackerError> {
+        let backup = persistence::backup_path(&self.path);
+        let mut temp = backup.as_os_str().to_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        match fs::remove_file(&temp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.connection()?.execute("VACUUM INTO ?1", [path_text(&temp)?])?;
+        fs::rename(&temp, &backup)?;
+        self.deltas_since_backup = 0;
+        self.last_backup = Instant::now();
+        Ok(())
+    }
+
+    /// Refreshes the backup once enough incremental saves or time have accumulated, so it
+    /// does not fall arbitrarily far behind during a long session.
+    fn refresh_backup(&mut self) {
+        self.deltas_since_backup += 1;
+        if self.deltas_since_backup < BACKUP_AFTER_DELTAS && self.last_backup.elapsed() < BACKUP_INTERVAL {
+            return;
+        }
+        if let Err(e) = self.write_backup() {
+            log::warn!("Failed to back up state database {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Replaces an unreadable database with its backup and reads it.
+    fn restore_backup(&mut self, error: FileTrackerError) -> Result<FileTracker, FileTrackerError> {
+        let backup = persistence::backup_path(&self.path);
+        if !backup.exists() {
+            return Err(error);
+        }
+        log::warn!("State database {} is unreadable ({}), trying its backup", self.path.display(), error);
+        self.connection = None;
+        remove_journal(&self.path)?;
+        persistence::restore_backup(&self.path)?;
+        let file_tracker = self.read()?.ok_or(error)?;
+        let age = fs::metadata(&backup)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        match age {
+            Some(age) => log::warn!(
+                "Restored previous state generation from {}, written {} minutes ago",
+                backup.display(),
+                age.as_secs() / 60
+            ),
+            None => log::warn!("Restored previous state generation from {}", backup.display()),
+        }
+        Ok(file_tracker)
+    }
+}
+
+impl StateStore for SqliteStateStore {
+    fn exists(&self) -> bool {
+        self.path.exists() || persistence::backup_path(&self.path).exists() || self.legacy.exists()
+    }
+
+    /// Falls back to the backup if the database is missing or corrupt. The backup is refreshed
+    /// by full saves and periodically by incremental ones; here it is only written when missing.
+    fn load(&mut self) -> Result<(FileTracker, StateGeneration), FileTrackerError> {
+        let missing = || FileTrackerError::from(io::Error::new(io::ErrorKind::NotFound, "no saved state"));
+        let (file_tracker, generation) = match self.read() {
+            Ok(Some(file_tracker)) => (file_tracker, StateGeneration::Current),
+            Ok(None) if self.legacy.exists() => return Ok((self.migrate_legacy()?, StateGeneration::Current)),
+            Ok(None) => (self.restore_backup(missing())?, StateGeneration::Backup),
+            // A database of a newer release is not corrupt; falling back would lose its changes.
+            Err(error @ FileTrackerError::InvalidState(_)) => return Err(error),
+            Err(error) => (self.restore_backup(error)?, StateGeneration::Backup),
+        };
+        if !persistence::backup_path(&self.path).exists() {
+            if let Err(e) = self.write_backup() {
+                log::warn!("Failed to back up state database {}: {}", self.path.display(), e);
+            }
+        }
+        Ok((file_tracker, generation))
+    }
+
+    fn save_all(&mut self, file_tracker: &FileTracker) -> Result<(), FileTrackerError> {
+        let connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        write_roots(&transaction, file_tracker)?;
+        transaction.execute("DELETE FROM files", [])?;
+        {
+            let mut insert =
+                transaction.prepare("INSERT INTO files (path, metadata, metadata_version) VALUES (?1, ?2, ?3)")?;
+            for (path, metadata) in &file_tracker.files_state {
+                insert.execute(params![
+                    path_text(path)?,
+                    serde_json::to_string(metadata)?,
+                    state_schema::METADATA_VERSION
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        self.write_backup()
+    }
+
+    fn apply_delta(&mut self, file_tracker: &FileTracker, delta: &StateDelta) -> Result<(), FileTrackerError> {
+        let connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        write_roots(&transaction, file_tracker)?;
+        {
+            let mut delete = transaction.prepare("DELETE FROM files WHERE path = ?1")?;
+            for path in &delta.removed {
+                delete.execute(params![path_text(path)?])?;
+            }
+            let mut upsert = transaction.prepare(
+                "INSERT INTO files (path, metadata, metadata_version) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (path) DO UPDATE
+                 SET metadata = excluded.metadata, metadata_version = excluded.metadata_version",
+            )?;
+            for (path, metadata) in &delta.upserted {
+                upsert.execute(params![
+                    path_text(path)?,
+                    serde_json::to_string(metadata)?,
+                    state_schema::METADATA_VERSION
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        self.refresh_backup();
+        Ok(())
+    }
+
+    /// Uses the primary key index: descendants of `prefix` sort between `prefix/` and the
+    /// next character after the separator.
+    fn entries_under(&mut self, prefix: &Path) -> Result<Vec<(PathBuf, FileMetadata)>, FileTrackerError> {
+        let prefix = path_text(prefix)?.trim_end_matches(MAIN_SEPARATOR).to_string();
+        let lower = format!("{}{}", prefix, MAIN_SEPARATOR);
+        let upper = format!("{}{}", prefix, char::from(MAIN_SEPARATOR as u8 + 1));
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(
+            "SELECT path, metadata, metadata_version FROM files
+             WHERE path = ?1 OR (path >= ?2 AND path < ?3) ORDER BY path",
+        )?;
+        let mut rows = statement.query(params![prefix, lower, upper])?;
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(0)?;
+            let metadata: String = row.get(1)?;
+            entries.push((PathBuf::from(path), state_schema::parse_metadata(&metadata, row.get(2)?)?));
+        }
+        Ok(entries)
+    }
+
+    fn delete(&mut self) -> Result<(), FileTrackerError> {
+        self.connection = None;
+        remove_journal(&self.path)?;
+        persistence::remove_with_backup(&self.path)?;
+        self.legacy.delete()
+    }
+}
+
+/// Removes the write-ahead log and shared memory files of a closed database.
+fn remove_journal(path: &Path) -> Result<(), FileTrackerError> {
+    for suffix in ["-wal", "-shm"] {
+        let mut file = path.as_os_str().to_os_string();
+        file.push(suffix);
+        match fs::remove_file(PathBuf::from(file)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn write_roots(connection: &Connection, file_tracker: &FileTracker) -> Result<(), FileTrackerError> {
+    let root_destination = file_tracker.root_destination.as_deref().map(path_text).transpose()?;
+    connection.execute(
+        "INSERT INTO tracker (id, root_target, root_destination) VALUES (1, ?1, ?2)
+         ON CONFLICT (id) DO UPDATE
+         SET root_target = excluded.root_target, root_destination = excluded.root_destination",
+        params![path_text(&file_tracker.root_target)?, root_destination],
+    )?;
+    Ok(())
+}
+
+/// Paths are stored as text so prefix lookups can use the index; like the JSON format,
+/// paths that are not valid UTF-8 are rejected.
+fn path_text(path: &Path) -> Result<&str, FileTrackerError> {
+    path.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("path is not valid UTF-8: {}", path.display()),
+        )
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestDir;
+
+    #[test]
+    fn corrupt_database_falls_back_to_its_backup() {
+        let dir = std::env::temp_dir().join(format!("egadsync-state-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let folder = dir.join("folder");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("notes.txt"), "notes").unwrap();
+        let config = Config {
+            data_dir: dir.clone(),
+            state_file_path: dir.join("state.json").to_string_lossy().to_string(),
+            ..Config::default()
+        };
+        let saved = FileTracker::new(&folder, None, &config).unwrap();
+        drop(saved);
+
+        let database = dir.join("state.sqlite3");
+        fs::write(&database, b"not a database at all, just some bytes").unwrap();
+        remove_journal(&database).unwrap();
+        let (file_tracker, generation) = FileTracker::load(&config).unwrap();
+        assert_eq!(generation, StateGeneration::Backup);
+        assert!(file_tracker.files_state.contains_key(&folder.join("notes.txt")));
+        assert!(dir.join("state.sqlite3.corrupt").exists());
+
+        let (_, generation) = FileTracker::load(&config).unwrap();
+        assert_eq!(generation, StateGeneration::Current);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rows_of_the_first_database_version_are_upgraded() {
+        let dir = std::env::temp_dir().join(format!("egadsync-state-store-v1-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let database = dir.join("state.sqlite3");
+        let connection = Connection::open(&database).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE tracker (
+                     id INTEGER PRIMARY KEY CHECK (id = 1),
+                     root_target TEXT NOT NULL,
+                     root_destination TEXT
+                 );
+                 CREATE TABLE files (path TEXT PRIMARY KEY, metadata TEXT NOT NULL) WITHOUT ROWID;
+                 INSERT INTO tracker (id, root_target) VALUES (1, '/home/user/docs');
+                 PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        let metadata = include_str!("../tests/fixtures/state/metadata/v4_file_id.json");
+        connection
+            .execute("INSERT INTO files (path, metadata) VALUES ('/home/user/docs/notes.txt', ?1)", [metadata])
+            .unwrap();
+        drop(connection);
+
+        let mut store = SqliteStateStore::new(&dir.join("state.json"));
+        let (file_tracker, _) = store.load().unwrap();
+        let entry = &file_tracker.files_state[Path::new("/home/user/docs/notes.txt")];
+        assert!(entry.hash().is_some());
+        store.save_all(&file_tracker).unwrap();
+        let saved: (String, u64) = store
+            .connection()
+            .unwrap()
+            .query_row("SELECT metadata, metadata_version FROM files", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert!(saved.0.contains("file_id"));
+        assert_eq!(saved.1, state_schema::METADATA_VERSION);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn incremental_saves_refresh_the_backup_periodically() {
+        let test_dir = TestDir::new("state-store-backup");
+        let folder = test_dir.path().join("folder");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("notes.txt"), "notes").unwrap();
+        let mut store = SqliteStateStore::new(&test_dir.path().join("state.json"));
+        let mut file_tracker = FileTracker::new(&folder, None, &test_dir.config()).unwrap();
+        let metadata = file_tracker.files_state.remove(&folder.join("notes.txt")).unwrap();
+        store.save_all(&file_tracker).unwrap();
+        let backup_path = persistence::backup_path(&store.path);
+        let backed_up_files = || {
+            let backup = Connection::open(&backup_path).unwrap();
+            backup.query_row("SELECT COUNT(*) FROM files", [], |row| row.get::<_, u32>(0)).unwrap()
+        };
+        let saved = backed_up_files();
+
+        for n in 0..BACKUP_AFTER_DELTAS {
+            let mut delta = StateDelta::default();
+            delta.upserted.insert(folder.join(n.to_string()), metadata.clone());
+            file_tracker.files_state.extend(delta.upserted.clone());
+            store.apply_delta(&file_tracker, &delta).unwrap();
+            if n == 0 {
+                assert_eq!(backed_up_files(), saved);
+            }
+        }
+        assert_eq!(backed_up_files(), saved + BACKUP_AFTER_DELTAS);
+
+        // Loading leaves an existing backup alone.
+        let mut delta = StateDelta::default();
+        delta.upserted.insert(folder.join("notes.txt"), metadata);
+        store.apply_delta(&file_tracker, &delta).unwrap();
+        store.load().unwrap();
+        assert_eq!(backed_up_files(), saved + BACKUP_AFTER_DELTAS);
+    }
+}
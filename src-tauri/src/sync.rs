@@ -1,54 +1,579 @@
-use crate::config::Config;
-use crate::file_tracker::{FileChange, FileTracker};
-use tauri::{AppHandle, Emitter};
+use crate::config::{Config, SharedConfig};
+use crate::conflicts::{self, ConflictLog, ConflictPolicy, Resolution};
+use crate::destination;
+use crate::encryption;
+use crate::error::FileTrackerError;
+use crate::file_tracker::{FileChange, FileTracker, StateGeneration};
+use crate::mirror::{self, MirrorReport};
+use crate::peer::PeerNode;
+use crate::roots::{MonitoredRoot, SyncMode};
+use crate::two_way::{self, Conflict, TwoWayReport};
+use crate::versions::VersionHistory;
+use crate::watcher::FolderWatcher;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::{mpsc, oneshot};
 use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
 
 /// Payload for file difference events sent to the frontend.
 #[derive(serde::Serialize, Clone)]
 pub struct FileDiffPayload {
+    root_id: String,
     folder: String,
     changes: Vec<String>,
+    mirror: Option<MirrorReport>,
+    /// Destination changes copied back to the monitored folder (two-way mode).
+    mirror_to_source: Option<MirrorReport>,
+    /// Paths changed on both sides, left untouched (two-way mode).
+    conflicts: Vec<Conflict>,
 }
 
-/// Starts the background sync loop to monitor file changes.
-pub fn start_sync_loop(app_handle: AppHandle) {
-    tauri::async_runtime::spawn(async move {
-        let config = Config::default();
-        let mut file_tracker = match FileTracker::get(&config) {
-            Ok(f) => f,
-            Err(e) => {
-                log::error!("Failed to load state: {}", e);
-                let _ = app_handle.emit("sync_error", format!("Estado não encontrado: {}", e));
-                return;
+/// A request to resolve a pending conflict, handled by the loop that owns the root's trackers.
+struct ResolveRequest {
+    path: PathBuf,
+    resolution: Resolution,
+    reply: oneshot::Sender<Result<(), FileTrackerError>>,
+}
+
+/// A running sync loop, the token that stops it and the channel for conflict resolutions.
+struct SyncLoop {
+    token: CancellationToken,
+    task: JoinHandle<()>,
+    requests: mpsc::UnboundedSender<ResolveRequest>,
+}
+
+/// Owns the background sync loops, at most one per monitored root.
+#[derive(Default)]
+pub struct SyncManager {
+    loops: Mutex<HashMap<String, SyncLoop>>,
+}
+
+impl SyncManager {
+    /// Starts the sync loop of a root. A loop already running for the same root is
+    /// cancelled and awaited first, so two loops never work on the same state.
+    pub fn start(&self, app_handle: AppHandle, root: MonitoredRoot) {
+        let token = CancellationToken::new();
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        let mut loops = self.loops.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = loops.remove(&root.id);
+        let id = root.id.clone();
+        let task = tauri::async_runtime::spawn({
+            let token = token.clone();
+            async move {
+                if let Some(previous) = previous {
+                    previous.token.cancel();
+                    let _ = previous.task.await;
+                }
+                run_sync_loop(app_handle, root, token, requests_rx).await;
             }
-        };
+        });
+        loops.insert(id, SyncLoop { token, task, requests });
+    }
+
+    /// Stops the sync loop of a root and waits for it to finish.
+    /// Returns whether a loop was running.
+    pub async fn stop(&self, root_id: &str) -> bool {
+        let running = self.loops.lock().unwrap_or_else(|e| e.into_inner()).remove(root_id);
+        match running {
+            Some(sync_loop) => {
+                sync_loop.token.cancel();
+                let _ = sync_loop.task.await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks whether a sync loop is running for the root.
+    pub fn is_running(&self, root_id: &str) -> bool {
+        self.loops
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(root_id)
+            .is_some_and(|sync_loop| !sync_loop.task.inner().is_finished())
+    }
 
-        let mut interval = time::interval(Duration::from_secs(config.sync_interval_secs));
-        log::info!("Starting background sync loop with interval {}s", config.sync_interval_secs);
-
-        loop {
-            interval.tick().await;
-            match file_tracker.diff().await {
-                Ok(changes) => {
-                    if !changes.is_empty() {
-                        log_changes(&changes);
-                        let changes = FileTracker::get_only_file_changes(changes);
-
-                        let payload = create_payload(&file_tracker, &changes);
-                        let _ = app_handle.emit("file_diffs", payload);
-                        if let Err(e) = file_tracker.save(&config) {
-                            log::error!("Failed to save state: {}", e);
-                            let _ = app_handle.emit("sync_error", format!("Erro ao salvar estado: {}", e));
-                        }
+    /// Resolves a pending conflict of a two-way root. The running loop applies it with its
+    /// in-memory trackers; a paused root is resolved against its saved state.
+    pub async fn resolve_conflict(
+        &self,
+        base: &Config,
+        root: &MonitoredRoot,
+        path: PathBuf,
+        resolution: Resolution,
+    ) -> Result<(), FileTrackerError> {
+        let requests = self
+            .loops
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&root.id)
+            .map(|sync_loop| sync_loop.requests.clone());
+        if let Some(requests) = requests {
+            let (reply, response) = oneshot::channel();
+            let request = ResolveRequest { path: path.clone(), resolution, reply };
+            if requests.send(request).is_ok() {
+                if let Ok(result) = response.await {
+                    return result;
+                }
+            }
+        }
+
+        if root.sync_mode != SyncMode::TwoWay {
+            return Err(FileTrackerError::ConflictNotFound);
+        }
+        let mut source = FileTracker::get(&root.config(base))?;
+        let mut destination = FileTracker::get(&root.destination_config(base))?;
+        resolve_pending(root, base, &mut source, &mut destination, &path, resolution).await
+    }
+}
+
+/// What woke a sync loop up.
+enum Trigger {
+    /// The periodic full rescan.
+    Rescan,
+    /// Watcher events in the monitored folder.
+    Source(Vec<PathBuf>),
+    /// Watcher events in the destination folder (two-way mode only).
+    Destination(Vec<PathBuf>),
+}
+
+/// Runs the sync loop monitoring file changes of one root until `token` is cancelled.
+async fn run_sync_loop(
+    app_handle: AppHandle,
+    root: MonitoredRoot,
+    token: CancellationToken,
+    mut requests: mpsc::UnboundedReceiver<ResolveRequest>,
+) {
+    let mut config_rx = app_handle.state::<SharedConfig>().subscribe();
+    let mut base = config_rx.borrow_and_update().clone();
+    let mut config = root.config(&base);
+    let mut destination_config = root.destination_config(&base);
+    let loaded = load_tracker(&app_handle, &config).and_then(|file_tracker| match root.sync_mode {
+        SyncMode::Mirror => Some((file_tracker, None)),
+        SyncMode::TwoWay => {
+            load_tracker(&app_handle, &destination_config).map(|destination| (file_tracker, Some(destination)))
+        }
+    });
+    let Some((mut file_tracker, mut destination)) = loaded else {
+        log::info!("Stopped sync loop for root {} without a loaded state", root.id);
+        let _ = app_handle.emit("sync_stopped", "Monitoramento parado");
+        return;
+    };
+
+    // The watchers deliver changes as they happen; the interval rescan only reconciles
+    // anything they missed (overflowed queues, network filesystems, ...).
+    let mut watcher = start_watcher(&file_tracker.root_target, &config);
+    let mut destination_watcher = destination
+        .as_ref()
+        .and_then(|destination| start_watcher(&destination.root_target, &config));
+    let mut interval = time::interval(Duration::from_secs(config.sync_interval_secs));
+    log::info!(
+        "Starting background sync loop for root {} with reconcile interval {}s",
+        root.id,
+        config.sync_interval_secs
+    );
+
+    loop {
+        let trigger = tokio::select! {
+            biased;
+            _ = token.cancelled() => break,
+            _ = interval.tick() => {
+                purge_trash(&root, &config).await;
+                Trigger::Rescan
+            }
+            Some(paths) = next_watcher_batch(&mut watcher) => Trigger::Source(paths),
+            Some(paths) = next_watcher_batch(&mut destination_watcher) => Trigger::Destination(paths),
+            Some(request) = requests.recv() => {
+                let result = match destination.as_mut() {
+                    Some(destination) => {
+                        resolve_pending(&root, &base, &mut file_tracker, destination, &request.path, request.resolution)
+                            .await
                     }
+                    None => Err(FileTrackerError::ConflictNotFound),
+                };
+                if result.is_ok() {
+                    emit_conflicts_changed(&app_handle, &root, &base);
                 }
-                Err(e) => {
-                    log::error!("Failed to compute diff: {}", e);
-                    let _ = app_handle.emit("sync_error", format!("Erro ao calcular diff: {}", e));
+                let _ = request.reply.send(result);
+                continue;
+            }
+            Ok(()) = config_rx.changed() => {
+                base = config_rx.borrow_and_update().clone();
+                config = root.config(&base);
+                destination_config = root.destination_config(&base);
+                log::info!("Applying updated configuration to root {}", root.id);
+                for tracker in std::iter::once(&mut file_tracker).chain(destination.as_mut()) {
+                    if let Err(e) = tracker.set_ignore_patterns(&config.ignore_patterns) {
+                        log::error!("Failed to apply ignore patterns: {}", e);
+                    }
                 }
+                watcher = start_watcher(&file_tracker.root_target, &config);
+                destination_watcher = destination
+                    .as_ref()
+                    .and_then(|destination| start_watcher(&destination.root_target, &config));
+                // The new interval ticks immediately, rescanning with the new rules.
+                interval = time::interval(Duration::from_secs(config.sync_interval_secs));
+                continue;
+            }
+        };
+
+        let rescan = matches!(trigger, Trigger::Rescan);
+        let configs = (&base, &config, &destination_config);
+        sync_round(&app_handle, &root, configs, &mut file_tracker, destination.as_mut(), trigger).await;
+        // The paired device is polled on each rescan; what it changed is picked up right away.
+        if rescan && root.peer.is_some() {
+            let written = peer_round(&app_handle, &root, &base, &file_tracker).await;
+            if !written.is_empty() {
+                let trigger = Trigger::Source(written);
+                sync_round(&app_handle, &root, configs, &mut file_tracker, destination.as_mut(), trigger).await;
             }
         }
-    });
+    }
+
+    log::info!("Stopped sync loop for root {}", root.id);
+    let _ = app_handle.emit("sync_stopped", "Monitoramento parado");
+}
+
+/// Runs the round of the root's sync mode. `configs` holds the global configuration, then the
+/// root's and its destination tracker's.
+async fn sync_round(
+    app_handle: &AppHandle,
+    root: &MonitoredRoot,
+    (base, config, destination_config): (&Config, &Config, &Config),
+    file_tracker: &mut FileTracker,
+    destination: Option<&mut FileTracker>,
+    trigger: Trigger,
+) {
+    match destination {
+        None => mirror_round(app_handle, root, file_tracker, config, trigger).await,
+        Some(destination) => {
+            let sides = [(file_tracker, config), (destination, destination_config)];
+            two_way_round(app_handle, root, base, sides, trigger).await
+        }
+    }
+}
+
+/// Pulls the changes of the root's paired device into the monitored folder. Returns the local
+/// paths written, for the tracker to pick up. An unreachable device is only logged: it is
+/// tried again on the next rescan.
+async fn peer_round(
+    app_handle: &AppHandle,
+    root: &MonitoredRoot,
+    base: &Config,
+    file_tracker: &FileTracker,
+) -> Vec<PathBuf> {
+    let Some(node) = app_handle.try_state::<PeerNode>() else { return Vec::new() };
+    let node = node.inner().clone();
+    match node.sync_root(base, root, file_tracker).await {
+        Ok(report) => {
+            if !report.received.failed.is_empty() {
+                let _ = app_handle.emit(
+                    "sync_error",
+                    format!(
+                        "Falha ao receber {} alteração(ões) de {}",
+                        report.received.failed.len(),
+                        report.device
+                    ),
+                );
+            }
+            if report.received.applied > 0 {
+                let _ = app_handle.emit("peer_synced", (root.id.clone(), &report));
+            }
+            report.written
+        }
+        Err(e) => {
+            log::warn!("Could not sync root {} with its paired device: {}", root.id, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Permanently deletes the entries that stayed in the root's trashes longer than configured.
+async fn purge_trash(root: &MonitoredRoot, config: &Config) {
+    let trashes = root.trashes();
+    let age = Duration::from_secs(config.trash_purge_after_days * 86_400);
+    let result = tokio::task::spawn_blocking(move || {
+        trashes.iter().try_for_each(|trash| trash.purge_older_than(age).map(|_| ()))
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::error!("Failed to purge trash: {}", e),
+        Err(e) => log::error!("Trash purge task failed: {}", e),
+    }
+}
+
+/// Loads a tracker for the sync loop, telling the frontend when its state had to be recovered.
+fn load_tracker(app_handle: &AppHandle, config: &Config) -> Option<FileTracker> {
+    match FileTracker::load(config) {
+        Ok((file_tracker, StateGeneration::Current)) => Some(file_tracker),
+        Ok((file_tracker, StateGeneration::Backup)) => {
+            let _ = app_handle.emit(
+                "state_recovered",
+                format!(
+                    "Estado de {} corrompido; geração anterior restaurada",
+                    file_tracker.root_target.display()
+                ),
+            );
+            Some(file_tracker)
+        }
+        Err(e) => {
+            log::error!("Failed to load state: {}", e);
+            let _ = app_handle.emit("sync_error", format!("Estado não encontrado: {}", e));
+            None
+        }
+    }
+}
+
+/// One round in mirror mode: detects changes in the monitored folder and copies them to the destination.
+async fn mirror_round(
+    app_handle: &AppHandle,
+    root: &MonitoredRoot,
+    file_tracker: &mut FileTracker,
+    config: &Config,
+    trigger: Trigger,
+) {
+    let result = match trigger {
+        Trigger::Rescan => file_tracker.diff().await,
+        Trigger::Source(paths) => file_tracker.diff_paths(paths).await,
+        Trigger::Destination(_) => return,
+    };
+
+    match result {
+        Ok(changes) => {
+            if !changes.is_empty() {
+                log_changes(&changes);
+                let report = mirror_changes(app_handle, root, config, file_tracker, &changes).await;
+                let changes = FileTracker::get_only_file_changes(changes);
+
+                let payload = create_payload(root, file_tracker, &changes, report);
+                let _ = app_handle.emit("file_diffs", payload);
+                save_state(app_handle, file_tracker, config);
+            }
+        }
+        Err(e) => {
+            log::error!("Failed to compute diff: {}", e);
+            let _ = app_handle.emit("sync_error", format!("Erro ao calcular diff: {}", e));
+        }
+    }
+}
+
+/// One round in two-way mode: detects changes on both sides, reconciles them and settles
+/// conflicts with the configured policy. `sides` holds the tracker and configuration of the
+/// monitored folder, then of the destination.
+async fn two_way_round(
+    app_handle: &AppHandle,
+    root: &MonitoredRoot,
+    base: &Config,
+    sides: [(&mut FileTracker, &Config); 2],
+    trigger: Trigger,
+) {
+    let [(source, config), (destination, destination_config)] = sides;
+    let result = async {
+        let blocked = ConflictLog::load(root, base)?.blocked_paths();
+        let (source_changes, destination_changes) = match trigger {
+            Trigger::Rescan => (source.diff().await?, destination.diff().await?),
+            Trigger::Source(paths) => two_way::diff_paths_both(source, destination, paths).await?,
+            Trigger::Destination(paths) => {
+                let (destination_changes, source_changes) =
+                    two_way::diff_paths_both(destination, source, paths).await?;
+                (source_changes, destination_changes)
+            }
+        };
+        let changes: Vec<FileChange> = source_changes.iter().chain(&destination_changes).cloned().collect();
+        let history = VersionHistory::for_root(root, base);
+        let report =
+            two_way::reconcile(source, destination, source_changes, destination_changes, &blocked, &history).await?;
+        record_conflicts(app_handle, root, base, source, destination, &report.conflicts).await?;
+        Ok::<_, FileTrackerError>((changes, report))
+    }
+    .await;
+
+    match result {
+        Ok((changes, report)) => {
+            if !changes.is_empty() {
+                log_changes(&changes);
+                let failed = report.to_destination.failed.len() + report.to_source.failed.len();
+                if failed > 0 {
+                    let _ = app_handle.emit("sync_error", format!("Falha ao replicar {} alteração(ões)", failed));
+                }
+                let changes = FileTracker::get_only_file_changes(changes);
+
+                let _ = app_handle.emit("file_diffs", two_way_payload(root, &changes, report));
+                save_state(app_handle, source, config);
+                save_state(app_handle, destination, destination_config);
+            }
+        }
+        Err(e) => {
+            log::error!("Failed to compute diff: {}", e);
+            let _ = app_handle.emit("sync_error", format!("Erro ao calcular diff: {}", e));
+        }
+    }
+}
+
+/// Settles the conflicts found by a two-way round with the configured policy, and saves the
+/// ones left to the user in the root's conflict log.
+pub async fn record_conflicts(
+    app_handle: &AppHandle,
+    root: &MonitoredRoot,
+    base: &Config,
+    source: &mut FileTracker,
+    destination: &mut FileTracker,
+    conflicts: &[Conflict],
+) -> Result<(), FileTrackerError> {
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    let mut conflict_log = ConflictLog::load(root, base)?;
+    let history = VersionHistory::for_root(root, base);
+    settle_conflicts(&mut conflict_log, base.conflict_policy, source, destination, conflicts, &history).await?;
+    conflict_log.save(root, base)?;
+    emit_conflicts_changed(app_handle, root, base);
+    Ok(())
+}
+
+/// Resolves new conflicts with `policy`. Conflicts left to the user, and new changes to paths
+/// whose conflict is still pending, are recorded in `conflict_log`.
+async fn settle_conflicts(
+    conflict_log: &mut ConflictLog,
+    policy: ConflictPolicy,
+    source: &mut FileTracker,
+    destination: &mut FileTracker,
+    conflicts: &[Conflict],
+    history: &VersionHistory,
+) -> Result<(), FileTrackerError> {
+    let blocked = conflict_log.blocked_paths();
+    let mut resolved: Vec<&Path> = Vec::new();
+    let mut pending = Vec::new();
+    for conflict in conflicts {
+        // Conflicts come sorted, so a resolved directory precedes the conflicts below it.
+        if resolved.iter().any(|path| conflict.path.starts_with(path)) {
+            continue;
+        }
+        let is_blocked = blocked.iter().any(|path| conflict.path.starts_with(path));
+        match policy.resolution_for(conflict) {
+            Some(resolution) if !is_blocked => {
+                conflicts::resolve(source, destination, &conflict.path, resolution, history).await?;
+                resolved.push(&conflict.path);
+            }
+            _ => pending.push(conflict.clone()),
+        }
+    }
+    conflict_log.record(pending);
+    Ok(())
+}
+
+/// Resolves a pending conflict and removes it from the root's conflict log.
+async fn resolve_pending(
+    root: &MonitoredRoot,
+    base: &Config,
+    source: &mut FileTracker,
+    destination: &mut FileTracker,
+    path: &Path,
+    resolution: Resolution,
+) -> Result<(), FileTrackerError> {
+    let mut conflict_log = ConflictLog::load(root, base)?;
+    conflict_log.take(path)?;
+    let history = VersionHistory::for_root(root, base);
+    conflicts::resolve(source, destination, path, resolution, &history).await?;
+    conflict_log.save(root, base)?;
+    source.save(&root.config(base))?;
+    destination.save(&root.destination_config(base))?;
+    Ok(())
+}
+
+/// Tells the frontend how many conflicts of a root are waiting for the user.
+fn emit_conflicts_changed(app_handle: &AppHandle, root: &MonitoredRoot, base: &Config) {
+    if let Ok(conflict_log) = ConflictLog::load(root, base) {
+        let _ = app_handle.emit("conflicts_changed", (root.id.clone(), conflict_log.conflicts.len()));
+    }
+}
+
+fn save_state(app_handle: &AppHandle, file_tracker: &mut FileTracker, config: &Config) {
+    if let Err(e) = file_tracker.save(config) {
+        log::error!("Failed to save state: {}", e);
+        let _ = app_handle.emit("sync_error", format!("Erro ao salvar estado: {}", e));
+    }
+}
+
+/// Starts the filesystem watcher for a folder, or returns `None` to rely on periodic scans only.
+fn start_watcher(folder: &Path, config: &Config) -> Option<FolderWatcher> {
+    match FolderWatcher::new(folder, Duration::from_millis(config.watch_debounce_ms)) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            log::warn!("File watcher unavailable, falling back to periodic scans: {}", e);
+            None
+        }
+    }
+}
+
+/// Waits for the next batch of watcher events, or forever when no watcher is running.
+async fn next_watcher_batch(watcher: &mut Option<FolderWatcher>) -> Option<Vec<PathBuf>> {
+    match watcher {
+        Some(watcher) => watcher.next_batch().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Replicates changes to the destination folder or the remote destination, if one is
+/// configured, and reports failures. Changes that failed are kept out of `file_tracker`'s
+/// saved state so the next round retries them. For an encrypted destination, the object
+/// names of the entries written are recorded in `file_tracker`.
+pub async fn mirror_changes(
+    app_handle: &AppHandle,
+    root: &MonitoredRoot,
+    config: &Config,
+    file_tracker: &mut FileTracker,
+    changes: &[FileChange],
+) -> Option<MirrorReport> {
+    let root_target = file_tracker.root_target.clone();
+    let batch = changes.to_vec();
+    let destinations = (&file_tracker.root_destination, &root.remote_destination);
+    let task: Box<dyn FnOnce() -> MirrorReport + Send> = match destinations {
+        (Some(root_destination), _) => {
+            let root_destination = root_destination.clone();
+            let history = VersionHistory::for_root(root, config);
+            Box::new(move || mirror::apply_changes(&root_target, &root_destination, &batch, &history))
+        }
+        (None, Some(remote)) => {
+            let (remote, encryption) = (remote.clone(), root.encryption.clone());
+            let (base, state_path) = (config.clone(), root.remote_state_path(config));
+            Box::new(move || {
+                destination::apply_changes(&base, &root_target, &remote, encryption.as_ref(), &state_path, &batch)
+            })
+        }
+        (None, None) => return None,
+    };
+
+    match tokio::task::spawn_blocking(task).await {
+        Ok(report) => {
+            if !report.failed.is_empty() {
+                let _ = app_handle.emit(
+                    "sync_error",
+                    format!("Falha ao replicar {} alteração(ões)", report.failed.len()),
+                );
+            }
+            if let Some(encryption) = root.encryption.as_ref().filter(|_| report.applied > 0) {
+                // The key was unlocked for the round, so this does not derive it again.
+                match encryption.unlock(config) {
+                    Ok(cipher) => encryption::record_object_names(file_tracker, &cipher, changes, &report),
+                    Err(e) => log::error!("Failed to record the encrypted names of {}: {}", root.id, e),
+                }
+            }
+            file_tracker.keep_unsynced(changes, report.failed.iter().map(|(path, _)| path.as_path()));
+            Some(report)
+        }
+        Err(e) => {
+            log::error!("Mirror task failed: {}", e);
+            file_tracker.keep_unsynced(changes, changes.iter().map(FileChange::path));
+            let _ = app_handle.emit("sync_error", format!("Erro ao replicar alterações: {}", e));
+            None
+        }
+    }
 }
 
 /// Logs detected file changes.
@@ -60,9 +585,30 @@ fn log_changes(changes: &[FileChange]) {
 }
 
 /// Creates a payload for the frontend from file changes.
-fn create_payload(file_tracker: &FileTracker, changes: &[FileChange]) -> FileDiffPayload {
+fn create_payload(
+    root: &MonitoredRoot,
+    file_tracker: &FileTracker,
+    changes: &[FileChange],
+    mirror: Option<MirrorReport>,
+) -> FileDiffPayload {
     FileDiffPayload {
+        root_id: root.id.clone(),
         folder: file_tracker.root_target.display().to_string(),
         changes: changes.iter().map(|c| c.to_string()).collect(),
+        mirror,
+        mirror_to_source: None,
+        conflicts: Vec::new(),
+    }
+}
+
+/// Creates a payload for the frontend from a two-way round.
+pub fn two_way_payload(root: &MonitoredRoot, changes: &[FileChange], report: TwoWayReport) -> FileDiffPayload {
+    FileDiffPayload {
+        root_id: root.id.clone(),
+        folder: root.root_target.display().to_string(),
+        changes: changes.iter().map(|c| c.to_string()).collect(),
+        mirror: Some(report.to_destination),
+        mirror_to_source: Some(report.to_source),
+        conflicts: report.conflicts,
     }
 }
@@ -0,0 +1,228 @@
+use crate::error::FileTrackerError;
+use crate::persistence;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Name of the directory, at the top of a synced folder, holding the paths deleted by sync.
+/// It is never scanned or replicated.
+pub const TRASH_DIR_NAME: &str = ".egadsync-trash";
+
+/// Serializes access to the trash indexes, shared by the sync loops and the commands.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// A path removed by sync, waiting in the trash of the folder it was removed from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: u64,
+    /// Original path, relative to the folder.
+    pub path: PathBuf,
+    pub deleted_at: SystemTime,
+    pub is_dir: bool,
+}
+
+/// A trash entry together with the root and folder it belongs to.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashedItem {
+    pub root_id: String,
+    pub folder: PathBuf,
+    #[serde(flatten)]
+    pub entry: TrashEntry,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TrashIndex {
+    entries: Vec<TrashEntry>,
+}
+
+/// The trash of one synced folder. Each entry lives in `.egadsync-trash/<id>/` under its
+/// original name; `index.json` records where it came from and when.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    root: PathBuf,
+}
+
+impl Trash {
+    pub fn new(root: &Path) -> Self {
+        Trash { root: root.to_path_buf() }
+    }
+
+    /// The folder this trash belongs to.
+    pub fn folder(&self) -> &Path {
+        &self.root
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.root.join(TRASH_DIR_NAME)
+    }
+
+    /// Moves a path under the folder into the trash. A missing path is not an error.
+    pub fn discard(&self, path: &Path) -> Result<(), FileTrackerError> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let (Ok(relative), Some(name)) = (path.strip_prefix(&self.root), path.file_name()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside {}", path.display(), self.root.display()),
+            )
+            .into());
+        };
+
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut index = self.load_index()?;
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let id = index.entries.iter().map(|entry| entry.id + 1).max().map_or(millis, |next| next.max(millis));
+
+        let entry_dir = self.dir().join(id.to_string());
+        fs::create_dir_all(&entry_dir)?;
+        fs::rename(path, entry_dir.join(name))?;
+        index.entries.push(TrashEntry {
+            id,
+            path: relative.to_path_buf(),
+            deleted_at: SystemTime::now(),
+            is_dir: metadata.is_dir(),
+        });
+        self.save_index(&index)?;
+        log::info!("Moved {} to the trash", path.display());
+        Ok(())
+    }
+
+    /// Lists the entries in the trash, most recently deleted first.
+    pub fn list(&self) -> Result<Vec<TrashEntry>, FileTrackerError> {
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = self.load_index()?.entries;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Moves an entry back to its original path, which must be free. Returns that path.
+    pub fn restore(&self, id: u64) -> Result<PathBuf, FileTrackerError> {
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut index = self.load_index()?;
+        let position = index
+            .entries
+            .iter()
+            .position(|entry| entry.id == id)
+            .ok_or(FileTrackerError::TrashEntryNotFound)?;
+        let entry = &index.entries[position];
+        let target = self.root.join(&entry.path);
+        if fs::symlink_metadata(&target).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", target.display()),
+            )
+            .into());
+        }
+
+        let name = entry.path.file_name().unwrap_or_default();
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.dir().join(id.to_string()).join(name), &target)?;
+        fs::remove_dir_all(self.dir().join(id.to_string()))?;
+        index.entries.remove(position);
+        self.save_index(&index)?;
+        log::info!("Restored {} from the trash", target.display());
+        Ok(target)
+    }
+
+    /// Permanently deletes every entry. Returns how many were removed.
+    pub fn empty(&self) -> Result<usize, FileTrackerError> {
+        self.purge(|_| true)
+    }
+
+    /// Permanently deletes the entries deleted more than `age` ago. Returns how many were removed.
+    pub fn purge_older_than(&self, age: Duration) -> Result<usize, FileTrackerError> {
+        let cutoff = SystemTime::now().checked_sub(age).unwrap_or(SystemTime::UNIX_EPOCH);
+        self.purge(|entry| entry.deleted_at < cutoff)
+    }
+
+    fn purge(&self, expired: impl Fn(&TrashEntry) -> bool) -> Result<usize, FileTrackerError> {
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut index = self.load_index()?;
+        let before = index.entries.len();
+        for entry in index.entries.iter().filter(|entry| expired(entry)) {
+            match fs::remove_dir_all(self.dir().join(entry.id.to_string())) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        index.entries.retain(|entry| !expired(entry));
+
+        let purged = before - index.entries.len();
+        if purged > 0 {
+            self.save_index(&index)?;
+            log::info!("Purged {} entry(ies) from the trash of {}", purged, self.root.display());
+        }
+        Ok(purged)
+    }
+
+    fn load_index(&self) -> Result<TrashIndex, FileTrackerError> {
+        let path = self.dir().join("index.json");
+        if !path.exists() {
+            return Ok(TrashIndex::default());
+        }
+        let mut file = File::open(&path)?;
+        let mut json_data = String::new();
+        file.read_to_string(&mut json_data)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+
+    fn save_index(&self, index: &TrashIndex) -> Result<(), FileTrackerError> {
+        let json = serde_json::to_string_pretty(index)?;
+        persistence::write_atomic(&self.dir().join("index.json"), json.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discarded_paths_are_restored_and_purged() {
+        let dir = std::env::temp_dir().join(format!("egadsync-trash-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let folder = dir.join("folder");
+        fs::create_dir_all(folder.join("docs/drafts")).unwrap();
+        fs::write(dir.join("elsewhere.txt"), "outside").unwrap();
+        fs::write(folder.join("docs/notes.txt"), "notes").unwrap();
+        fs::write(folder.join("docs/drafts/plan.txt"), "plan").unwrap();
+        let trash = Trash::new(&folder);
+
+        trash.discard(&folder.join("docs/notes.txt")).unwrap();
+        trash.discard(&folder.join("docs/drafts")).unwrap();
+        trash.discard(&folder.join("docs/missing.txt")).unwrap();
+        assert!(trash.discard(&dir.join("elsewhere.txt")).is_err());
+        assert!(!folder.join("docs/notes.txt").exists());
+        assert!(!folder.join("docs/drafts").exists());
+
+        let entries = trash.list().unwrap();
+        let paths: Vec<&Path> = entries.iter().map(|entry| entry.path.as_path()).collect();
+        assert_eq!(paths, [Path::new("docs/drafts"), Path::new("docs/notes.txt")]);
+        assert!(entries[0].is_dir && !entries[1].is_dir);
+
+        fs::write(folder.join("docs/notes.txt"), "new notes").unwrap();
+        assert!(trash.restore(entries[1].id).is_err());
+        fs::remove_file(folder.join("docs/notes.txt")).unwrap();
+        assert_eq!(trash.restore(entries[1].id).unwrap(), folder.join("docs/notes.txt"));
+        assert_eq!(fs::read_to_string(folder.join("docs/notes.txt")).unwrap(), "notes");
+        assert!(matches!(trash.restore(entries[1].id), Err(FileTrackerError::TrashEntryNotFound)));
+
+        assert_eq!(trash.purge_older_than(Duration::from_secs(3600)).unwrap(), 0);
+        assert_eq!(trash.purge_older_than(Duration::ZERO).unwrap(), 1);
+        assert!(trash.list().unwrap().is_empty());
+        assert_eq!(fs::read_dir(folder.join(TRASH_DIR_NAME)).unwrap().count(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
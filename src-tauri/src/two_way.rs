@@ -0,0 +1,353 @@
+use crate::error::FileTrackerError;
+use crate::file_tracker::{FileChange, FileMetadata, FileTracker};
+use crate::mirror::{self, MirrorReport};
+use crate::versions::VersionHistory;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A path changed on both sides since the last common state. Neither side is touched
+/// until the conflict is resolved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conflict {
+    /// Path relative to both roots.
+    pub path: PathBuf,
+    /// Modification time on the source side, or `None` when the path was deleted there.
+    pub source_modified: Option<SystemTime>,
+    /// Modification time on the destination side, or `None` when the path was deleted there.
+    pub destination_modified: Option<SystemTime>,
+    pub detected_at: SystemTime,
+}
+
+/// Summary of one two-way synchronization round.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct TwoWayReport {
+    /// Source changes replicated to the destination.
+    pub to_destination: MirrorReport,
+    /// Destination changes replicated to the source.
+    pub to_source: MirrorReport,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// What a batch of changes leaves at a relative path.
+enum Outcome<'a> {
+    Present(&'a FileMetadata),
+    Deleted,
+}
+
+impl Outcome<'_> {
+    /// Whether both sides ended up with the same thing, so the path is not in conflict.
+    fn agrees_with(&self, other: &Outcome) -> bool {
+        match (self, other) {
+            (Outcome::Deleted, Outcome::Deleted) => true,
+            (Outcome::Present(a), Outcome::Present(b)) if a.is_dir() && b.is_dir() => true,
+            (Outcome::Present(a), Outcome::Present(b)) => a.hash().is_some() && a.hash() == b.hash(),
+            _ => false,
+        }
+    }
+}
+
+/// Reconciles the changes detected on each side since the last common state.
+///
+/// Changes that only happened on one side are replicated to the other, and both trackers
+/// absorb the replicated entries so they are not reported back on the next round. Paths
+/// changed on both sides (including edits below a directory deleted on the other side)
+/// are left alone and reported as conflicts, as are new changes at or below `blocked`
+/// paths whose earlier conflict is still unresolved. Files overwritten or deleted on either
+/// side are saved to `history` first.
+pub async fn reconcile(
+    source: &mut FileTracker,
+    destination: &mut FileTracker,
+    source_changes: Vec<FileChange>,
+    destination_changes: Vec<FileChange>,
+    blocked: &HashSet<PathBuf>,
+    history: &VersionHistory,
+) -> Result<TwoWayReport, FileTrackerError> {
+    let source_outcomes = outcomes(&source.root_target, &source_changes);
+    let destination_outcomes = outcomes(&destination.root_target, &destination_changes);
+    let mut conflicted = conflicting_paths(&source_outcomes, &destination_outcomes);
+    conflicted.extend(
+        source_outcomes
+            .keys()
+            .chain(destination_outcomes.keys())
+            .filter(|path| blocked.iter().any(|blocked| path.starts_with(blocked)))
+            .cloned(),
+    );
+
+    let to_destination = without_conflicts(&source.root_target, source_changes, &conflicted);
+    let to_source = without_conflicts(&destination.root_target, destination_changes, &conflicted);
+
+    let mut conflicted: Vec<PathBuf> = conflicted.into_iter().collect();
+    conflicted.sort();
+    let report = TwoWayReport {
+        to_destination: replicate(source, destination, &to_destination, history).await?,
+        to_source: replicate(destination, source, &to_source, history).await?,
+        conflicts: conflicted
+            .into_iter()
+            .map(|path| {
+                log::warn!("Conflict: {} changed on both sides", path.display());
+                Conflict {
+                    source_modified: modified_at(source, &path),
+                    destination_modified: modified_at(destination, &path),
+                    detected_at: SystemTime::now(),
+                    path,
+                }
+            })
+            .collect(),
+    };
+    Ok(report)
+}
+
+/// First round for a newly added pair of folders: everything on each side counts as created
+/// since an empty common state, so files that only exist on one side are copied over and
+/// files with the same path but different contents are conflicts.
+pub async fn initial_reconcile(
+    source: &mut FileTracker,
+    destination: &mut FileTracker,
+    history: &VersionHistory,
+) -> Result<TwoWayReport, FileTrackerError> {
+    source.files_state.clear();
+    destination.files_state.clear();
+    let source_changes = source.diff().await?;
+    let destination_changes = destination.diff().await?;
+    reconcile(source, destination, source_changes, destination_changes, &HashSet::new(), history).await
+}
+
+fn modified_at(file_tracker: &FileTracker, relative: &Path) -> Option<SystemTime> {
+    file_tracker
+        .files_state
+        .get(&file_tracker.root_target.join(relative))
+        .map(|metadata| metadata.modified())
+}
+
+/// Rescans the paths reported by the watcher of one side, then the same paths on the other
+/// side, so a concurrent edit there is seen as a conflict instead of being overwritten.
+/// Returns the changes of `changed` and of `other`, in that order.
+pub async fn diff_paths_both(
+    changed: &mut FileTracker,
+    other: &mut FileTracker,
+    paths: Vec<PathBuf>,
+) -> Result<(Vec<FileChange>, Vec<FileChange>), FileTrackerError> {
+    let changes = changed.diff_paths(paths).await?;
+    let touched: Vec<PathBuf> = changes.iter().flat_map(touched_paths).cloned().collect();
+    let other_changes = other.diff_paths(counterpart_paths(changed, other, &touched)).await?;
+    Ok((changes, other_changes))
+}
+
+/// Maps `paths` under the root of `from` to the equivalent paths under the root of `to`.
+fn counterpart_paths(from: &FileTracker, to: &FileTracker, paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .filter_map(|path| path.strip_prefix(&from.root_target).ok())
+        .map(|relative| to.root_target.join(relative))
+        .collect()
+}
+
+/// Copies changes from one side to the other, then rescans the written paths on the
+/// receiving side so its baseline matches. Changes that failed are kept out of the sending
+/// side's saved state, so they are replicated again on the next round.
+async fn replicate(
+    from: &mut FileTracker,
+    to: &mut FileTracker,
+    changes: &[FileChange],
+    history: &VersionHistory,
+) -> Result<MirrorReport, FileTrackerError> {
+    if changes.is_empty() {
+        return Ok(MirrorReport::default());
+    }
+
+    let report = tokio::task::spawn_blocking({
+        let root_from = from.root_target.clone();
+        let root_to = to.root_target.clone();
+        let changes = changes.to_vec();
+        let history = history.clone();
+        move || mirror::apply_changes(&root_from, &root_to, &changes, &history)
+    })
+    .await?;
+
+    let written: Vec<PathBuf> = changes.iter().flat_map(touched_paths).cloned().collect();
+    to.diff_paths(counterpart_paths(from, to, &written)).await?;
+    from.keep_unsynced(changes, report.failed.iter().map(|(path, _)| path.as_path()));
+    Ok(report)
+}
+
+/// Collects the outcome of a batch of changes per relative path.
+fn outcomes<'a>(root: &Path, changes: &'a [FileChange]) -> BTreeMap<PathBuf, Outcome<'a>> {
+    let mut outcomes = BTreeMap::new();
+    for change in changes {
+        let (deleted, present) = match change {
+            FileChange::Created(path, metadata) | FileChange::Modified(path, metadata) => {
+                (None, Some((path, metadata)))
+            }
+            FileChange::Deleted(path) => (Some(path), None),
+            FileChange::Renamed(from, to, metadata) => (Some(from), Some((to, metadata))),
+        };
+        if let Some(relative) = deleted.and_then(|path| path.strip_prefix(root).ok()) {
+            outcomes.insert(relative.to_path_buf(), Outcome::Deleted);
+        }
+        if let Some((path, metadata)) = present {
+            if let Ok(relative) = path.strip_prefix(root) {
+                outcomes.insert(relative.to_path_buf(), Outcome::Present(metadata));
+            }
+        }
+    }
+    outcomes
+}
+
+/// Finds the relative paths changed differently on both sides. A deletion on one side
+/// also conflicts with any change below the deleted path on the other side.
+fn conflicting_paths(
+    source: &BTreeMap<PathBuf, Outcome>,
+    destination: &BTreeMap<PathBuf, Outcome>,
+) -> HashSet<PathBuf> {
+    let mut conflicted = HashSet::new();
+    for (ours, theirs) in [(source, destination), (destination, source)] {
+        for (path, outcome) in ours {
+            match theirs.get(path) {
+                Some(other) if !outcome.agrees_with(other) => {
+                    conflicted.insert(path.clone());
+                }
+                _ => {}
+            }
+            if matches!(outcome, Outcome::Deleted) {
+                let below = theirs
+                    .range(path.clone()..)
+                    .take_while(|(other, _)| other.starts_with(path))
+                    .filter(|(other, other_outcome)| *other != path && matches!(other_outcome, Outcome::Present(_)));
+                for (other, _) in below {
+                    conflicted.insert(path.clone());
+                    conflicted.insert(other.clone());
+                }
+            }
+        }
+    }
+    conflicted
+}
+
+/// Drops the changes touching a conflicted path.
+fn without_conflicts(root: &Path, changes: Vec<FileChange>, conflicted: &HashSet<PathBuf>) -> Vec<FileChange> {
+    changes
+        .into_iter()
+        .filter(|change| {
+            touched_paths(change)
+                .filter_map(|path| path.strip_prefix(root).ok())
+                .all(|relative| !conflicted.contains(relative))
+        })
+        .collect()
+}
+
+fn touched_paths(change: &FileChange) -> impl Iterator<Item = &PathBuf> {
+    let (first, second) = match change {
+        FileChange::Created(path, _) | FileChange::Modified(path, _) | FileChange::Deleted(path) => (path, None),
+        FileChange::Renamed(from, to, _) => (from, Some(to)),
+    };
+    std::iter::once(first).chain(second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::roots::{RootRegistry, SyncMode};
+    use std::fs;
+
+    fn metadata(is_dir: bool, hash: Option<&str>) -> FileMetadata {
+        serde_json::from_value(serde_json::json!({
+            "last_modified": { "secs_since_epoch": 1_700_000_000, "nanos_since_epoch": 0 },
+            "size": 8,
+            "is_dir": is_dir,
+            "hash": hash,
+        }))
+        .unwrap()
+    }
+
+    fn sorted(paths: HashSet<PathBuf>) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = paths.into_iter().collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn paths_changed_differently_on_both_sides_conflict() {
+        let (file, same, other, dir) =
+            (metadata(false, Some("a")), metadata(false, Some("a")), metadata(false, Some("b")), metadata(true, None));
+        let source = vec![
+            FileChange::Modified(PathBuf::from("/s/edited"), file.clone()),
+            FileChange::Modified(PathBuf::from("/s/same"), file.clone()),
+            FileChange::Deleted(PathBuf::from("/s/gone")),
+            FileChange::Deleted(PathBuf::from("/s/removed")),
+            FileChange::Created(PathBuf::from("/s/dir"), dir.clone()),
+            FileChange::Created(PathBuf::from("/s/only-source"), file.clone()),
+        ];
+        let destination = vec![
+            FileChange::Modified(PathBuf::from("/d/edited"), other.clone()),
+            FileChange::Modified(PathBuf::from("/d/same"), same),
+            FileChange::Deleted(PathBuf::from("/d/gone")),
+            FileChange::Modified(PathBuf::from("/d/removed/inner"), other.clone()),
+            FileChange::Created(PathBuf::from("/d/dir"), dir),
+            FileChange::Created(PathBuf::from("/d/only-destination"), other),
+        ];
+
+        let conflicted = conflicting_paths(
+            &outcomes(Path::new("/s"), &source),
+            &outcomes(Path::new("/d"), &destination),
+        );
+        let expected: Vec<PathBuf> = ["edited", "removed", "removed/inner"].iter().map(PathBuf::from).collect();
+        assert_eq!(sorted(conflicted), expected);
+    }
+
+    #[tokio::test]
+    async fn one_sided_changes_are_copied_and_conflicts_left_alone() {
+        let dir = std::env::temp_dir().join(format!("egadsync-two-way-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (folder, mirror) = (dir.join("folder"), dir.join("mirror"));
+        for side in [&folder, &mirror] {
+            fs::create_dir_all(side).unwrap();
+            fs::write(side.join("shared.txt"), "shared").unwrap();
+            fs::write(side.join("both.txt"), "both").unwrap();
+        }
+        let config = Config {
+            data_dir: dir.join("data"),
+            state_file_path: dir.join("data").join("state.json").to_string_lossy().to_string(),
+            ..Config::default()
+        };
+        let root = RootRegistry::load(&config)
+            .unwrap()
+            .add(&folder, Some(mirror.clone()), None, None, None, SyncMode::TwoWay)
+            .unwrap();
+        let history = VersionHistory::for_root(&root, &config);
+        let mut source = FileTracker::new(&folder, Some(mirror.clone()), &root.config(&config)).unwrap();
+        let mut destination = FileTracker::new(&mirror, None, &root.destination_config(&config)).unwrap();
+
+        fs::write(folder.join("new.txt"), "from the source").unwrap();
+        fs::write(mirror.join("shared.txt"), "edited at the destination").unwrap();
+        fs::write(folder.join("both.txt"), "source edit").unwrap();
+        fs::write(mirror.join("both.txt"), "destination edit").unwrap();
+        let source_changes = source.diff().await.unwrap();
+        let destination_changes = destination.diff().await.unwrap();
+        let report = reconcile(
+            &mut source,
+            &mut destination,
+            source_changes,
+            destination_changes,
+            &HashSet::new(),
+            &history,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(mirror.join("new.txt")).unwrap(), "from the source");
+        assert_eq!(fs::read_to_string(folder.join("shared.txt")).unwrap(), "edited at the destination");
+        assert_eq!(fs::read_to_string(folder.join("both.txt")).unwrap(), "source edit");
+        assert_eq!(fs::read_to_string(mirror.join("both.txt")).unwrap(), "destination edit");
+        let conflicts: Vec<&Path> = report.conflicts.iter().map(|conflict| conflict.path.as_path()).collect();
+        assert_eq!(conflicts, [Path::new("both.txt")]);
+        assert!(report.to_destination.failed.is_empty() && report.to_source.failed.is_empty());
+
+        // Replicated entries are absorbed by the receiving side and not reported back.
+        let changes = FileTracker::get_only_file_changes(destination.diff().await.unwrap());
+        assert!(changes.is_empty(), "{:?}", changes);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
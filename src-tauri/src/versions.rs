@@ -0,0 +1,347 @@
+use crate::chunks::ChunkStore;
+use crate::config::Config;
+use crate::error::FileTrackerError;
+use crate::persistence;
+use crate::roots::MonitoredRoot;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How many previous versions of each file are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VersionRetention {
+    /// The most recent versions are always kept, up to this many.
+    pub keep_last: usize,
+    /// On top of those, the last version of each day is kept for this many days.
+    pub keep_daily_days: u64,
+}
+
+impl VersionRetention {
+    /// Whether versions are kept at all.
+    pub fn is_enabled(&self) -> bool {
+        self.keep_last > 0 || self.keep_daily_days > 0
+    }
+
+    /// Picks the versions to keep; `versions` is ordered from oldest to newest.
+    fn kept(&self, versions: &[FileVersion], now: SystemTime) -> HashSet<u64> {
+        let mut kept: HashSet<u64> = versions.iter().rev().take(self.keep_last).map(|version| version.id).collect();
+
+        let cutoff = now
+            .checked_sub(Duration::from_secs(self.keep_daily_days * 86_400))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut days = HashSet::new();
+        for version in versions.iter().rev().filter(|version| version.saved_at > cutoff) {
+            if days.insert(day(version.saved_at)) {
+                kept.insert(version.id);
+            }
+        }
+        kept
+    }
+}
+
+impl Default for VersionRetention {
+    fn default() -> Self {
+        VersionRetention {
+            keep_last: 10,
+            keep_daily_days: 30,
+        }
+    }
+}
+
+/// A previous version of a file, saved before a sync overwrote or deleted it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileVersion {
+    /// Identifier of the version within its path, in milliseconds since the epoch.
+    pub id: u64,
+    pub saved_at: SystemTime,
+    /// Modification time of the file when it was saved.
+    pub modified: SystemTime,
+    pub size: u64,
+    /// Manifest of the contents in the chunk store. Versions saved before it was used are plain copies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<String>,
+}
+
+/// The saved versions of one path. Stored in a directory named after the path's digest.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VersionIndex {
+    path: PathBuf,
+    versions: Vec<FileVersion>,
+}
+
+/// Previous versions of the files of one root, indexed under `versions/<root id>` in the app
+/// data directory. Their contents go to the chunk store, so unchanged parts are stored once.
+#[derive(Debug, Clone)]
+pub struct VersionHistory {
+    dir: PathBuf,
+    retention: VersionRetention,
+    store: ChunkStore,
+}
+
+impl VersionHistory {
+    pub fn for_root(root: &MonitoredRoot, base: &Config) -> Self {
+        VersionHistory {
+            dir: root.versions_dir(base),
+            retention: base.version_retention,
+            store: ChunkStore::new(base),
+        }
+    }
+
+    /// Saves the current contents of `file` as a version of `relative` before it is overwritten
+    /// or deleted. A directory saves every file below it; a missing path saves nothing.
+    pub fn preserve(&self, relative: &Path, file: &Path) -> Result<(), FileTrackerError> {
+        if !self.retention.is_enabled() {
+            return Ok(());
+        }
+        let metadata = match fs::symlink_metadata(file) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        if metadata.is_dir() {
+            for entry in walkdir::WalkDir::new(file).follow_links(false) {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    if let Ok(below) = entry.path().strip_prefix(file) {
+                        self.preserve_file(&relative.join(below), entry.path(), &entry.metadata()?)?;
+                    }
+                }
+            }
+            Ok(())
+        } else if metadata.is_file() {
+            self.preserve_file(relative, file, &metadata)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn preserve_file(&self, relative: &Path, file: &Path, metadata: &fs::Metadata) -> Result<(), FileTrackerError> {
+        let entry_dir = self.entry_dir(relative);
+        let mut index = self.load_index(relative)?;
+        let saved_at = SystemTime::now();
+        let millis = saved_at.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let id = index.versions.last().map_or(millis, |last| millis.max(last.id + 1));
+
+        let manifest = self.store.store_file(file)?;
+        index.versions.push(FileVersion {
+            id,
+            saved_at,
+            modified: metadata.modified()?,
+            size: metadata.len(),
+            manifest: Some(manifest),
+        });
+        log::info!("Saved version {} of {}", id, relative.display());
+
+        let kept = self.retention.kept(&index.versions, saved_at);
+        for version in index.versions.iter().filter(|version| !kept.contains(&version.id)) {
+            log::info!("Pruning version {} of {}", version.id, relative.display());
+            match fs::remove_file(entry_dir.join(version.id.to_string())) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        index.versions.retain(|version| kept.contains(&version.id));
+
+        fs::create_dir_all(&entry_dir)?;
+        let json = serde_json::to_string_pretty(&index)?;
+        persistence::write_atomic(&entry_dir.join("index.json"), json.as_bytes())?;
+        Ok(())
+    }
+
+    /// Lists the saved versions of a path, newest first.
+    pub fn list(&self, relative: &Path) -> Result<Vec<FileVersion>, FileTrackerError> {
+        let mut versions = self.load_index(relative)?.versions;
+        versions.reverse();
+        Ok(versions)
+    }
+
+    /// Writes a saved version of `relative` to `target`, replacing what is there.
+    pub fn restore(&self, relative: &Path, id: u64, target: &Path) -> Result<(), FileTrackerError> {
+        let index = self.load_index(relative)?;
+        let version = index
+            .versions
+            .iter()
+            .find(|version| version.id == id)
+            .ok_or(FileTrackerError::VersionNotFound)?;
+        if let Some(manifest) = &version.manifest {
+            self.store.restore(manifest, target)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(self.entry_dir(relative).join(id.to_string()), target)?;
+        }
+        log::info!("Restored version {} of {} to {}", id, relative.display(), target.display());
+        Ok(())
+    }
+
+    /// Manifests referenced by the saved versions, which chunk store garbage collection must keep.
+    pub fn manifests(&self) -> Result<HashSet<String>, FileTrackerError> {
+        let mut manifests = HashSet::new();
+        if !self.dir.exists() {
+            return Ok(manifests);
+        }
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path().join("index.json");
+            if !path.exists() {
+                continue;
+            }
+            let index: VersionIndex = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            manifests.extend(index.versions.into_iter().filter_map(|version| version.manifest));
+        }
+        Ok(manifests)
+    }
+
+    fn load_index(&self, relative: &Path) -> Result<VersionIndex, FileTrackerError> {
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(FileTrackerError::VersionNotFound);
+        }
+        let path = self.entry_dir(relative).join("index.json");
+        if !path.exists() {
+            return Ok(VersionIndex {
+                path: relative.to_path_buf(),
+                versions: Vec::new(),
+            });
+        }
+        let mut file = File::open(&path)?;
+        let mut json_data = String::new();
+        file.read_to_string(&mut json_data)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+
+    fn entry_dir(&self, relative: &Path) -> PathBuf {
+        let digest = blake3::hash(relative.to_string_lossy().as_bytes());
+        self.dir.join(&digest.to_hex()[..32])
+    }
+}
+
+/// Days since the epoch (UTC), used to keep one version per day.
+fn day(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs() / 86_400).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestDir;
+
+    fn history(test_dir: &TestDir, retention: VersionRetention) -> VersionHistory {
+        let config = test_dir.config();
+        VersionHistory {
+            dir: config.data_dir.join("versions").join("root"),
+            retention,
+            store: ChunkStore::new(&config),
+        }
+    }
+
+    fn version(id: u64, saved_at: SystemTime) -> FileVersion {
+        FileVersion {
+            id,
+            saved_at,
+            modified: saved_at,
+            size: 0,
+            manifest: None,
+        }
+    }
+
+    #[test]
+    fn retention_keeps_the_latest_versions_and_one_per_day() {
+        let at = |day: u64, hour: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(day * 86_400 + hour * 3_600);
+        let now = at(100, 0);
+        let versions = [
+            version(1, at(90, 10)),
+            version(2, at(90, 12)),
+            version(3, at(95, 0)),
+            version(4, at(99, 8)),
+            version(5, at(99, 9)),
+        ];
+        let kept = |keep_last, keep_daily_days| {
+            let mut kept: Vec<u64> = VersionRetention {
+                keep_last,
+                keep_daily_days,
+            }
+            .kept(&versions, now)
+            .into_iter()
+            .collect();
+            kept.sort();
+            kept
+        };
+        assert_eq!(kept(2, 0), [4, 5]);
+        assert_eq!(kept(0, 7), [3, 5]);
+        assert_eq!(kept(1, 30), [2, 3, 5]);
+        assert_eq!(kept(0, 0), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn preserved_versions_are_listed_restored_and_pruned() {
+        let test_dir = TestDir::new("versions");
+        let dir = test_dir.path();
+        let history = history(
+            &test_dir,
+            VersionRetention {
+                keep_last: 2,
+                keep_daily_days: 0,
+            },
+        );
+        let file = dir.join("notes.txt");
+        let relative = Path::new("docs/notes.txt");
+        for contents in ["one", "two", "three"] {
+            fs::write(&file, contents).unwrap();
+            history.preserve(relative, &file).unwrap();
+        }
+
+        let versions = history.list(relative).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(versions[0].id > versions[1].id);
+        assert!(versions.iter().all(|version| version.manifest.is_some()));
+        for (version, expected) in versions.iter().zip(["three", "two"]) {
+            let restored = dir.join("restored").join("notes.txt");
+            history.restore(relative, version.id, &restored).unwrap();
+            assert_eq!(fs::read_to_string(&restored).unwrap(), expected);
+        }
+        let manifests = history.manifests().unwrap();
+        assert_eq!(manifests.len(), 2);
+        assert!(versions.iter().all(|version| manifests.contains(version.manifest.as_ref().unwrap())));
+        assert!(matches!(
+            history.restore(relative, 1, &dir.join("missing.txt")),
+            Err(FileTrackerError::VersionNotFound)
+        ));
+    }
+
+    #[test]
+    fn plain_copies_of_older_versions_are_restored_and_pruned() {
+        let test_dir = TestDir::new("versions-legacy");
+        let dir = test_dir.path();
+        let history = history(
+            &test_dir,
+            VersionRetention {
+                keep_last: 1,
+                keep_daily_days: 0,
+            },
+        );
+        let relative = Path::new("notes.txt");
+        let entry_dir = history.entry_dir(relative);
+        fs::create_dir_all(&entry_dir).unwrap();
+        fs::write(entry_dir.join("1000"), "legacy").unwrap();
+        let index = VersionIndex {
+            path: relative.to_path_buf(),
+            versions: vec![version(1000, SystemTime::UNIX_EPOCH + Duration::from_secs(1))],
+        };
+        fs::write(entry_dir.join("index.json"), serde_json::to_string(&index).unwrap()).unwrap();
+
+        let restored = dir.join("restored.txt");
+        history.restore(relative, 1000, &restored).unwrap();
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "legacy");
+
+        // Once pruned, the copy goes away with its index entry.
+        fs::write(dir.join("notes.txt"), "current").unwrap();
+        history.preserve(relative, &dir.join("notes.txt")).unwrap();
+        assert_eq!(history.list(relative).unwrap().len(), 1);
+        assert!(!entry_dir.join("1000").exists());
+    }
+}
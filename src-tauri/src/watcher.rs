@@ -0,0 +1,42 @@
+use crate::error::FileTrackerError;
+use notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+/// Watches a directory tree through the OS notification API (inotify on Linux)
+/// and delivers debounced batches of changed paths.
+pub struct FolderWatcher {
+    // Dropping the debouncer stops the underlying watcher.
+    _debouncer: Debouncer<RecommendedWatcher>,
+    events: UnboundedReceiver<Vec<PathBuf>>,
+}
+
+impl FolderWatcher {
+    /// Starts watching `root` recursively, grouping bursts of events within `debounce`.
+    pub fn new(root: &Path, debounce: Duration) -> Result<Self, FileTrackerError> {
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut debouncer = new_debouncer(debounce, move |result: DebounceEventResult| match result {
+            Ok(events) => {
+                let paths: Vec<PathBuf> = events.into_iter().map(|event| event.path).collect();
+                if !paths.is_empty() {
+                    let _ = tx.send(paths);
+                }
+            }
+            Err(e) => log::warn!("File watcher error: {}", e),
+        })?;
+        debouncer.watcher().watch(root, RecursiveMode::Recursive)?;
+        log::info!("Watching {} for changes", root.display());
+
+        Ok(FolderWatcher {
+            _debouncer: debouncer,
+            events,
+        })
+    }
+
+    /// Waits for the next batch of changed paths.
+    pub async fn next_batch(&mut self) -> Option<Vec<PathBuf>> {
+        self.events.recv().await
+    }
+}
@@ -0,0 +1,621 @@
+use crate::config::Config;
+use crate::conflicts;
+use crate::destination::Destination;
+use crate::encryption::Cipher;
+use crate::error::FileTrackerError;
+use crate::persistence;
+use crate::secrets;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, ETAG};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
+
+/// How long a single request may take, uploads included.
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+const DAV_NAMESPACE: &str = "DAV:";
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getetag/></d:prop></d:propfind>"#;
+
+/// Connection settings of a WebDAV collection (Nextcloud, ownCloud, Apache mod_dav, ...).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebDavConfig {
+    /// URL of the collection receiving the mirror, such as
+    /// `https://cloud.example.com/remote.php/dav/files/alice/Backup`.
+    pub url: String,
+    pub username: String,
+    /// Password or app token, accepted when the root is added and then moved to the secret
+    /// store under the account `webdav/<username>@<host>`, where it is read from when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl WebDavConfig {
+    /// Secret store account holding the password of `username` on the server of `url`.
+    pub fn secret_account(url: &str, username: &str) -> String {
+        let host = Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default();
+        format!("webdav/{}@{}", username, host)
+    }
+
+    fn password(&self, base: &Config) -> Result<String, FileTrackerError> {
+        match &self.password {
+            Some(password) => Ok(password.clone()),
+            None => secrets::get(base, &Self::secret_account(&self.url, &self.username)),
+        }
+    }
+}
+
+/// An entry of a collection listing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteEntry {
+    /// Path relative to the destination collection, with `/` separators.
+    pub path: String,
+    pub is_dir: bool,
+    pub etag: Option<String>,
+}
+
+/// ETags of the files as last written by this app, keyed by relative path. A different ETag on
+/// the server means someone else changed the file since.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct EtagIndex {
+    etags: BTreeMap<String, String>,
+}
+
+/// A collection on a WebDAV server.
+///
+/// Overwrites and deletes are conditional on the ETag recorded when the file was last uploaded,
+/// and new files and moves do not replace what is already there. A file changed or added on the
+/// server in the meantime is moved aside as a conflict copy instead of being lost. Like the S3
+/// client, the HTTP client is asynchronous and used from blocking tasks.
+pub struct WebDavDestination {
+    client: Client,
+    runtime: Handle,
+    /// Collection URL, always ending with `/`.
+    base: Url,
+    username: String,
+    password: String,
+    index: EtagIndex,
+    state_path: PathBuf,
+    /// Collections known to exist, so uploads do not create their parents every time.
+    created: HashSet<String>,
+    /// Key the names are encrypted with, if they are, so conflict copies can be named alike.
+    name_cipher: Option<Cipher>,
+}
+
+impl WebDavDestination {
+    /// Checks that the collection exists and the credentials are accepted, then loads the ETags
+    /// recorded by earlier rounds. Without any, they are read from the server. The password is
+    /// read from the secret store of `base` when the settings do not give it.
+    pub fn connect(config: &WebDavConfig, base: &Config, state_path: &Path) -> Result<Self, FileTrackerError> {
+        let runtime = Handle::try_current()
+            .map_err(|e| FileTrackerError::DestinationError(format!("no async runtime: {}", e)))?;
+        let password = config.password(base)?;
+        let mut base = Url::parse(&config.url)
+            .map_err(|e| FileTrackerError::InvalidConfig(format!("invalid WebDAV URL {}: {}", config.url, e)))?;
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        let client = Client::builder().timeout(TIMEOUT).build().map_err(http_error)?;
+
+        let mut destination = WebDavDestination {
+            client,
+            runtime,
+            base,
+            username: config.username.clone(),
+            password,
+            index: EtagIndex::default(),
+            state_path: state_path.to_path_buf(),
+            created: HashSet::new(),
+            name_cipher: None,
+        };
+        if !destination.stat("")?.is_some_and(|entry| entry.is_dir) {
+            return Err(FileTrackerError::DestinationError(format!("{} is not a collection", config.url)));
+        }
+        destination.created.insert(String::new());
+
+        if state_path.exists() {
+            destination.index = serde_json::from_str(&fs::read_to_string(state_path)?)?;
+        } else {
+            for entry in destination.list("")? {
+                if let (false, Some(etag)) = (entry.is_dir, entry.etag) {
+                    destination.index.etags.insert(entry.path, etag);
+                }
+            }
+        }
+        log::info!("Connected to WebDAV collection {}", destination.base);
+        Ok(destination)
+    }
+
+    /// URL of a relative path; collections get a trailing `/`.
+    fn url(&self, path: &str, is_dir: bool) -> Url {
+        let mut url = self.base.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend(path.split('/').filter(|part| !part.is_empty()));
+            if is_dir || path.is_empty() {
+                segments.push("");
+            }
+        }
+        url
+    }
+
+    fn request(&self, method: &str, url: Url) -> RequestBuilder {
+        let method = Method::from_bytes(method.as_bytes()).unwrap_or(Method::GET);
+        self.client
+            .request(method, url)
+            .basic_auth(&self.username, Some(&self.password))
+    }
+
+    fn send(&self, request: RequestBuilder) -> Result<Response, FileTrackerError> {
+        let response = self.runtime.block_on(request.send()).map_err(http_error)?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(FileTrackerError::DestinationError(format!(
+                "{} rejected the credentials of {}",
+                self.base, self.username
+            )));
+        }
+        Ok(response)
+    }
+
+    /// Looks up a single entry, or `None` when nothing is at `path`.
+    fn stat(&self, path: &str) -> Result<Option<RemoteEntry>, FileTrackerError> {
+        let request = self
+            .request("PROPFIND", self.url(path, false))
+            .header("Depth", "0")
+            .header(CONTENT_TYPE, "application/xml")
+            .body(PROPFIND_BODY);
+        let response = self.send(request)?;
+        match response.status() {
+            StatusCode::MULTI_STATUS => {
+                let body = self.runtime.block_on(response.text()).map_err(http_error)?;
+                Ok(parse_multistatus(&self.base, &body)?.into_iter().next())
+            }
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(status_error("PROPFIND", path, status)),
+        }
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, FileTrackerError> {
+        Ok(self.stat(path)?.is_some())
+    }
+
+    /// Lists everything below a collection, walking it one level at a time since many
+    /// servers refuse `Depth: infinity`.
+    pub fn list(&self, path: &str) -> Result<Vec<RemoteEntry>, FileTrackerError> {
+        let mut entries = Vec::new();
+        let mut pending = vec![path.to_string()];
+        while let Some(dir) = pending.pop() {
+            let request = self
+                .request("PROPFIND", self.url(&dir, true))
+                .header("Depth", "1")
+                .header(CONTENT_TYPE, "application/xml")
+                .body(PROPFIND_BODY);
+            let response = self.send(request)?;
+            if response.status() != StatusCode::MULTI_STATUS {
+                return Err(status_error("PROPFIND", &dir, response.status()));
+            }
+            let body = self.runtime.block_on(response.text()).map_err(http_error)?;
+            for entry in parse_multistatus(&self.base, &body)? {
+                if entry.path == dir {
+                    continue;
+                }
+                if entry.is_dir {
+                    pending.push(entry.path.clone());
+                }
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Creates a collection and its missing parents.
+    fn create_collections(&mut self, path: &str) -> Result<(), FileTrackerError> {
+        let mut current = String::new();
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            if !current.is_empty() {
+                current.push('/');
+            }
+            current.push_str(part);
+            if self.created.contains(&current) {
+                continue;
+            }
+            let response = self.send(self.request("MKCOL", self.url(&current, true)))?;
+            match response.status() {
+                // 405: the collection already exists.
+                StatusCode::CREATED | StatusCode::METHOD_NOT_ALLOWED => {}
+                status => return Err(status_error("MKCOL", &current, status)),
+            }
+            self.created.insert(current.clone());
+        }
+        Ok(())
+    }
+
+    /// Moves an entry that changed on the server since it was last uploaded out of the way,
+    /// next to it, so replacing or deleting it loses nothing.
+    fn keep_remote_copy(&mut self, path: &str, is_dir: bool) -> Result<(), FileTrackerError> {
+        let now = SystemTime::now();
+        // A failed lookup ends the search for a free name; its error is returned below.
+        let error = Cell::new(None);
+        let is_taken = |candidate: &str| {
+            self.exists(candidate).unwrap_or_else(|e| {
+                error.set(Some(e));
+                false
+            })
+        };
+        let copy = match &self.name_cipher {
+            Some(cipher) => cipher.conflict_copy_path(path, now, is_taken)?,
+            None => conflicts::conflict_copy_path(Path::new(path), now, |candidate| {
+                is_taken(&candidate.to_string_lossy())
+            })
+            .to_string_lossy()
+            .to_string(),
+        };
+        if let Some(e) = error.take() {
+            return Err(e);
+        }
+        self.move_entry(path, &copy, is_dir)?;
+        log::warn!("{} changed on the server; kept it as {}", path, copy);
+        Ok(())
+    }
+
+    /// Moves an entry without overwriting what is at `to`. A file this app uploaded there is
+    /// replaced, anything else is kept as a conflict copy first.
+    fn move_entry(&mut self, from: &str, to: &str, is_dir: bool) -> Result<(), FileTrackerError> {
+        let request = |destination: &Self| {
+            destination
+                .request("MOVE", destination.url(from, is_dir))
+                .header("Destination", destination.url(to, is_dir).as_str())
+                .header("Overwrite", "F")
+        };
+        let mut response = self.send(request(self))?;
+        if response.status() == StatusCode::PRECONDITION_FAILED {
+            match self.stat(to)? {
+                Some(entry) if !entry.is_dir && self.index.etags.contains_key(to) => self.remove(Path::new(to))?,
+                Some(entry) => self.keep_remote_copy(to, entry.is_dir)?,
+                None => {}
+            }
+            response = self.send(request(self))?;
+        }
+        match response.status() {
+            StatusCode::CREATED | StatusCode::NO_CONTENT => {}
+            StatusCode::NOT_FOUND => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not on the server", from)).into())
+            }
+            status => return Err(status_error("MOVE", from, status)),
+        }
+
+        let moved: Vec<(String, String)> = self
+            .index
+            .etags
+            .iter()
+            .filter_map(|(path, etag)| below(path, from).map(|rest| (format!("{}{}", to, rest), etag.clone())))
+            .collect();
+        self.forget(from);
+        self.index.etags.extend(moved);
+        self.created.retain(|dir| below(dir, from).is_none());
+        Ok(())
+    }
+
+    /// Drops the recorded ETags of `path` and everything below it.
+    fn forget(&mut self, path: &str) {
+        self.index.etags.retain(|recorded, _| below(recorded, path).is_none());
+    }
+
+    fn put(&self, path: &str, source: &Path, headers: HeaderMap) -> Result<Response, FileTrackerError> {
+        let file = self.runtime.block_on(tokio::fs::File::open(source))?;
+        self.send(self.request("PUT", self.url(path, false)).headers(headers).body(file))
+    }
+}
+
+impl Destination for WebDavDestination {
+    fn create_dir(&mut self, relative: &Path) -> Result<(), FileTrackerError> {
+        self.create_collections(&remote_path(relative))
+    }
+
+    /// The modification time is sent in the `X-OC-Mtime` header, which Nextcloud and ownCloud apply.
+    fn upload(&mut self, relative: &Path, source: &Path, modified: SystemTime) -> Result<u64, FileTrackerError> {
+        let path = remote_path(relative);
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            self.create_collections(parent)?;
+        }
+        let size = fs::metadata(source)?.len();
+        let mtime = modified.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut headers = HeaderMap::new();
+        headers.insert("X-OC-Mtime", HeaderValue::from(mtime));
+
+        let mut response = match self.index.etags.get(&path).and_then(|etag| HeaderValue::from_str(etag).ok()) {
+            Some(etag) => {
+                let mut conditional = headers.clone();
+                conditional.insert("If-Match", etag);
+                self.put(&path, source, conditional)?
+            }
+            // Nothing was uploaded there yet, so anything already at that path is someone else's.
+            None => {
+                let mut conditional = headers.clone();
+                conditional.insert("If-None-Match", HeaderValue::from_static("*"));
+                self.put(&path, source, conditional)?
+            }
+        };
+        if response.status() == StatusCode::PRECONDITION_FAILED {
+            self.keep_remote_copy(&path, false)?;
+            response = self.put(&path, source, headers)?;
+        }
+        if !response.status().is_success() {
+            return Err(status_error("PUT", &path, response.status()));
+        }
+
+        let etag = match response.headers().get(ETAG).and_then(|etag| etag.to_str().ok()) {
+            // Weak ETags cannot be used with If-Match.
+            Some(etag) if !etag.starts_with("W/") => Some(etag.to_string()),
+            // Not every server returns it from PUT.
+            _ => self.stat(&path)?.and_then(|entry| entry.etag),
+        };
+        match etag {
+            Some(etag) => self.index.etags.insert(path, etag),
+            None => self.index.etags.remove(&path),
+        };
+        Ok(size)
+    }
+
+    fn download(&mut self, relative: &Path, target: &Path) -> Result<u64, FileTrackerError> {
+        let path = remote_path(relative);
+        let mut response = self.send(self.request("GET", self.url(&path, false)))?;
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::NOT_FOUND => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not on the server", path)).into())
+            }
+            status => return Err(status_error("GET", &path, status)),
+        }
+        let mut file = fs::File::create(target)?;
+        let mut received = 0;
+        while let Some(chunk) = self.runtime.block_on(response.chunk()).map_err(http_error)? {
+            file.write_all(&chunk)?;
+            received += chunk.len() as u64;
+        }
+        Ok(received)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), FileTrackerError> {
+        let (from, to) = (remote_path(from), remote_path(to));
+        if let Some((parent, _)) = to.rsplit_once('/') {
+            self.create_collections(parent)?;
+        }
+        // Collections need their trailing slash on some servers; files must not have one.
+        let entry = self
+            .stat(&from)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not on the server", from)))?;
+        self.move_entry(&from, &to, entry.is_dir)
+    }
+
+    fn remove(&mut self, relative: &Path) -> Result<(), FileTrackerError> {
+        let path = remote_path(relative);
+        let Some(entry) = self.stat(&path)? else {
+            self.forget(&path);
+            return Ok(());
+        };
+        let mut request = self.request("DELETE", self.url(&path, entry.is_dir));
+        if let Some(etag) = self.index.etags.get(&path) {
+            request = request.header("If-Match", etag.as_str());
+        }
+        let response = self.send(request)?;
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::NOT_FOUND => {}
+            // Moving it aside removes it from its path as well.
+            StatusCode::PRECONDITION_FAILED => self.keep_remote_copy(&path, entry.is_dir)?,
+            status => return Err(status_error("DELETE", &path, status)),
+        }
+        self.forget(&path);
+        self.created.retain(|dir| below(dir, &path).is_none());
+        Ok(())
+    }
+
+    fn list_files(&mut self) -> Result<Vec<PathBuf>, FileTrackerError> {
+        Ok(self
+            .list("")?
+            .into_iter()
+            .filter(|entry| !entry.is_dir)
+            .map(|entry| PathBuf::from(entry.path))
+            .collect())
+    }
+
+    fn set_name_cipher(&mut self, cipher: Cipher) {
+        self.name_cipher = Some(cipher);
+    }
+
+    fn finish(&mut self) -> Result<(), FileTrackerError> {
+        let json = serde_json::to_string_pretty(&self.index)?;
+        if let Some(parent) = self.state_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        persistence::write_atomic(&self.state_path, json.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Reads the entries of a PROPFIND response, with their paths made relative to the collection `base`.
+fn parse_multistatus(base: &Url, body: &str) -> Result<Vec<RemoteEntry>, FileTrackerError> {
+    let document = roxmltree::Document::parse(body)
+        .map_err(|e| FileTrackerError::DestinationError(format!("invalid PROPFIND response: {}", e)))?;
+    let mut entries = Vec::new();
+    for response in document.descendants().filter(|node| node.has_tag_name((DAV_NAMESPACE, "response"))) {
+        let find = |name: &str| response.descendants().find(|node| node.has_tag_name((DAV_NAMESPACE, name)));
+        let Some(href) = find("href").and_then(|node| node.text()) else { continue };
+        let Some(path) = base.join(href.trim()).ok().and_then(|url| relative(base, &url)) else {
+            continue;
+        };
+        entries.push(RemoteEntry {
+            path,
+            is_dir: find("collection").is_some(),
+            etag: find("getetag").and_then(|node| node.text()).map(|etag| etag.trim().to_string()),
+        });
+    }
+    Ok(entries)
+}
+
+/// Decoded path of a URL relative to the collection `base`, or `None` for URLs outside it.
+/// Servers do not all escape the same characters, so both sides are decoded first.
+fn relative(base: &Url, url: &Url) -> Option<String> {
+    let decode = |path: &str| percent_encoding::percent_decode_str(path).decode_utf8().ok().map(String::from);
+    let (path, base) = (decode(url.path())?, decode(base.path())?);
+    // The collection itself is often listed without its trailing slash.
+    let rest = below(path.trim_end_matches('/'), base.trim_end_matches('/'))?;
+    Some(rest.trim_matches('/').to_string())
+}
+
+/// Relative path with `/` separators, as used in URLs and in the ETag index.
+fn remote_path(relative: &Path) -> String {
+    let parts: Vec<_> = relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    parts.join("/")
+}
+
+/// What follows `prefix` in `path` when `path` is `prefix` itself or lies below it.
+fn below<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+fn status_error(method: &str, path: &str, status: StatusCode) -> FileTrackerError {
+    FileTrackerError::DestinationError(format!("{} {} failed: {}", method, path, status))
+}
+
+fn http_error(err: reqwest::Error) -> FileTrackerError {
+    FileTrackerError::DestinationError(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TestDir;
+
+    fn base() -> Url {
+        Url::parse("https://cloud.example/remote.php/dav/files/alice/My%20Backup/").unwrap()
+    }
+
+    #[test]
+    fn paths_below_a_prefix_are_matched_by_component() {
+        assert_eq!(below("docs", "docs"), Some(""));
+        assert_eq!(below("docs/a.txt", "docs"), Some("/a.txt"));
+        assert_eq!(below("docs/deep/a.txt", "docs/deep"), Some("/a.txt"));
+        assert_eq!(below("docs2/a.txt", "docs"), None);
+        assert_eq!(below("doc", "docs"), None);
+        assert_eq!(below("docs/a.txt", ""), None);
+    }
+
+    #[test]
+    fn urls_are_made_relative_to_the_collection() {
+        let url = |path: &str| base().join(path).unwrap();
+        assert_eq!(relative(&base(), &base()).as_deref(), Some(""));
+        assert_eq!(relative(&base(), &url("/remote.php/dav/files/alice/My%20Backup")).as_deref(), Some(""));
+        assert_eq!(relative(&base(), &url("docs/a%20b.txt")).as_deref(), Some("docs/a b.txt"));
+        // Escaped differently from the configured URL, or not at all.
+        assert_eq!(relative(&base(), &url("/remote.php/dav/files/alice/My Backup/docs/")).as_deref(), Some("docs"));
+        assert_eq!(relative(&base(), &url("/remote.php/dav/files/%61lice/My%20Backup/x")).as_deref(), Some("x"));
+        assert_eq!(relative(&base(), &url("/remote.php/dav/files/alice/My%20Backup2/x")), None);
+        assert_eq!(relative(&base(), &url("/remote.php/dav/files/alice/")), None);
+    }
+
+    #[test]
+    fn propfind_responses_are_parsed() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <D:response>
+    <D:href>/remote.php/dav/files/alice/My%20Backup/</D:href>
+    <D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>
+      https://cloud.example/remote.php/dav/files/alice/My%20Backup/docs/
+    </D:href>
+    <D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype><oc:id>7</oc:id></D:prop></D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>/remote.php/dav/files/alice/My%20Backup/docs/r%C3%A9sum%C3%A9.txt</D:href>
+    <D:propstat><D:prop><D:resourcetype/><D:getetag> "5f2a" </D:getetag></D:prop></D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>/remote.php/dav/files/bob/other.txt</D:href>
+    <D:propstat><D:prop><D:resourcetype/></D:prop></D:propstat>
+  </D:response>
+</D:multistatus>"#;
+        let entry = |path: &str, is_dir: bool, etag: Option<&str>| RemoteEntry {
+            path: path.to_string(),
+            is_dir,
+            etag: etag.map(str::to_string),
+        };
+        assert_eq!(
+            parse_multistatus(&base(), body).unwrap(),
+            [
+                entry("", true, None),
+                entry("docs", true, None),
+                entry("docs/résumé.txt", false, Some("\"5f2a\"")),
+            ]
+        );
+        assert!(parse_multistatus(&base(), "<html>").is_err());
+    }
+
+    /// Runs against a real server, e.g. `rclone serve webdav /tmp/dav --addr 127.0.0.1:8080 --user sync --pass sync`:
+    ///
+    /// ```text
+    /// EGADSYNC_WEBDAV_URL=http://127.0.0.1:8080/ EGADSYNC_WEBDAV_USER=sync EGADSYNC_WEBDAV_PASSWORD=sync \
+    /// cargo test webdav -- --ignored
+    /// ```
+    #[test]
+    #[ignore = "needs a WebDAV server"]
+    fn webdav_applies_changes() {
+        let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+        let config = WebDavConfig {
+            url: var("EGADSYNC_WEBDAV_URL"),
+            username: var("EGADSYNC_WEBDAV_USER"),
+            password: Some(var("EGADSYNC_WEBDAV_PASSWORD")),
+        };
+        let test_dir = TestDir::new("webdav");
+        let local = test_dir.path();
+        let source = local.join("a.txt");
+        fs::write(&source, b"hello").unwrap();
+        let state_path = local.join("state.json");
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let mut destination = WebDavDestination::connect(&config, &test_dir.config(), &state_path).unwrap();
+        destination.remove(Path::new("test")).unwrap();
+        destination.upload(Path::new("test/deep/a b.txt"), &source, SystemTime::now()).unwrap();
+        let listed = destination.list("test").unwrap();
+        assert!(listed.iter().any(|entry| entry.path == "test/deep/a b.txt" && !entry.is_dir));
+        assert!(destination.index.etags.contains_key("test/deep/a b.txt"));
+        assert!(destination.list_files().unwrap().contains(&PathBuf::from("test/deep/a b.txt")));
+
+        destination.rename(Path::new("test/deep"), Path::new("test/moved")).unwrap();
+        assert!(destination.exists("test/moved/a b.txt").unwrap());
+        assert!(destination.index.etags.contains_key("test/moved/a b.txt"));
+        let missing = destination.rename(Path::new("test/deep"), Path::new("test/other"));
+        assert!(matches!(missing, Err(FileTrackerError::IoError(e)) if e.kind() == io::ErrorKind::NotFound));
+
+        // A change made by someone else is kept as a conflict copy when overwritten.
+        destination.index.etags.insert("test/moved/a b.txt".to_string(), "\"stale\"".to_string());
+        destination.upload(Path::new("test/moved/a b.txt"), &source, SystemTime::now()).unwrap();
+        assert_eq!(destination.list("test/moved").unwrap().len(), 2);
+        // So is a file added by someone else at a path this app never uploaded to.
+        destination.forget("test/moved/a b.txt");
+        destination.upload(Path::new("test/moved/a b.txt"), &source, SystemTime::now()).unwrap();
+        assert_eq!(destination.list("test/moved").unwrap().len(), 3);
+
+        destination.remove(Path::new("test")).unwrap();
+        assert!(!destination.exists("test").unwrap());
+        destination.finish().unwrap();
+    }
+}
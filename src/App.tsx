@@ -4,9 +4,42 @@ import { listen } from "@tauri-apps/api/event";
 import { Folder, Play, Square, Trash2, RefreshCw, Settings, ChevronDown, ChevronUp, FolderOpen } from "lucide-react";
 import "./App.css";
 
+interface TransferStats {
+  file_bytes: number;
+  sent_bytes: number;
+}
+
+interface MirrorReport {
+  applied: number;
+  failed: [string, string][];
+  transfer: TransferStats;
+}
+
+interface Conflict {
+  path: string;
+}
+
+interface PendingConflict extends Conflict {
+  root_id: string;
+}
+
+interface TrashedItem {
+  root_id: string;
+  folder: string;
+  id: number;
+  path: string;
+  is_dir: boolean;
+}
+
+type Resolution = "keep_source" | "keep_destination" | "keep_both";
+
 interface FileDiffEvent {
+  root_id: string;
   folder: string;
   changes: string[];
+  mirror: MirrorReport | null;
+  mirror_to_source: MirrorReport | null;
+  conflicts: Conflict[];
 }
 
 interface Change {
@@ -18,16 +51,23 @@ interface Change {
 function App() {
   const [monitoredFolder, setMonitoredFolder] = useState<string>("");
   const [inputFolder, setInputFolder] = useState<string>("");
+  const [destinationFolder, setDestinationFolder] = useState<string>("");
+  const [inputDestination, setInputDestination] = useState<string>("");
+  const [twoWay, setTwoWay] = useState<boolean>(false);
   const [isMonitoring, setIsMonitoring] = useState<boolean>(false);
   const [syncStatus, setSyncStatus] = useState<string>("Parado");
   const [changes, setChanges] = useState<Change[]>([]);
   const [error, setError] = useState<string>("");
   const [showChangelog, setShowChangelog] = useState<boolean>(true);
   const [isConfiguring, setIsConfiguring] = useState<boolean>(false);
+  const [pendingConflicts, setPendingConflicts] = useState<PendingConflict[]>([]);
+  const [trashedItems, setTrashedItems] = useState<TrashedItem[]>([]);
 
   useEffect(() => {
     checkMonitoringStatus();
     loadSavedState();
+    loadConflicts();
+    loadTrash();
 
     const unlistenSyncStarted = listen<string>("sync_started", (event) => {
       setSyncStatus("Monitorando");
@@ -48,12 +88,16 @@ function App() {
         const newChange = {
           timestamp: new Date().toLocaleString('pt-BR'),
           folder: data.folder,
-          changes: data.changes,
+          changes: [
+            ...data.changes,
+            ...(data.conflicts ?? []).map((conflict) => `Conflito: ${conflict.path}`),
+          ],
         };
         setChanges((prev) => {
           const updated = [newChange, ...prev];
           return updated.slice(0, 100);
         });
+        loadTrash();
       } else {
         console.error("Formato de dados inválido no evento file_diffs:", data);
         setError("Erro: Dados de alterações inválidos recebidos");
@@ -66,11 +110,28 @@ function App() {
       setIsMonitoring(false);
     });
 
+    const unlistenConfigError = listen<string>("config_error", (event) => {
+      setError(event.payload);
+      setSyncStatus("Erro");
+      setIsMonitoring(false);
+    });
+
+    const unlistenConflicts = listen("conflicts_changed", () => {
+      loadConflicts();
+    });
+
+    const unlistenTrash = listen("trash_changed", () => {
+      loadTrash();
+    });
+
     return () => {
       unlistenSyncStarted.then(fn => fn());
       unlistenSyncStopped.then(fn => fn());
       unlistenFileDiffs.then(fn => fn());
       unlistenSyncError.then(fn => fn());
+      unlistenConfigError.then(fn => fn());
+      unlistenConflicts.then(fn => fn());
+      unlistenTrash.then(fn => fn());
     };
   }, []);
 
@@ -92,16 +153,70 @@ function App() {
         setMonitoredFolder(savedState.root_target);
         setInputFolder(savedState.root_target);
       }
+      if (savedState && savedState.root_destination) {
+        setDestinationFolder(savedState.root_destination);
+        setInputDestination(savedState.root_destination);
+      }
     } catch (error) {
       console.log("Nenhum estado salvo encontrado");
     }
   }
 
-  async function selectFolder(): Promise<void> {
+  async function loadConflicts(): Promise<void> {
+    try {
+      setPendingConflicts(await invoke<PendingConflict[]>("list_conflicts"));
+    } catch (error) {
+      console.error("Erro ao carregar conflitos:", error);
+    }
+  }
+
+  async function resolveConflict(conflict: PendingConflict, resolution: Resolution): Promise<void> {
+    try {
+      await invoke("resolve_conflict", {
+        rootId: conflict.root_id,
+        path: conflict.path,
+        resolution,
+      });
+      await loadConflicts();
+    } catch (error) {
+      setError(`Erro ao resolver conflito: ${error}`);
+      console.error("Erro ao resolver conflito:", error);
+    }
+  }
+
+  async function loadTrash(): Promise<void> {
+    try {
+      setTrashedItems(await invoke<TrashedItem[]>("list_trash"));
+    } catch (error) {
+      console.error("Erro ao carregar lixeira:", error);
+    }
+  }
+
+  async function restoreFromTrash(item: TrashedItem): Promise<void> {
+    try {
+      await invoke("restore_from_trash", { rootId: item.root_id, folder: item.folder, id: item.id });
+      await loadTrash();
+    } catch (error) {
+      setError(`Erro ao restaurar da lixeira: ${error}`);
+      console.error("Erro ao restaurar da lixeira:", error);
+    }
+  }
+
+  async function emptyTrash(): Promise<void> {
+    try {
+      await invoke("empty_trash");
+      await loadTrash();
+    } catch (error) {
+      setError(`Erro ao esvaziar lixeira: ${error}`);
+      console.error("Erro ao esvaziar lixeira:", error);
+    }
+  }
+
+  async function selectFolder(setter: (folder: string) => void): Promise<void> {
     try {
       const result = await invoke<string | null>("select_folder");
       if (result) {
-        setInputFolder(result);
+        setter(result);
       }
     } catch (error) {
       setError(`Erro ao selecionar pasta: ${error}`);
@@ -117,8 +232,13 @@ function App() {
 
     try {
       setError("");
-      await invoke("setup", { targetFolder: inputFolder });
+      await invoke("setup", {
+        targetFolder: inputFolder,
+        destinationFolder: inputDestination.trim() || null,
+        syncMode: twoWay ? "two_way" : "mirror",
+      });
       setMonitoredFolder(inputFolder);
+      setDestinationFolder(inputDestination.trim());
       setIsConfiguring(false);
       setChanges([]);
     } catch (error) {
@@ -132,6 +252,8 @@ function App() {
       await invoke("stop_monitoring");
       setMonitoredFolder("");
       setInputFolder("");
+      setDestinationFolder("");
+      setInputDestination("");
       setChanges([]);
       setError("");
       setIsConfiguring(false);
@@ -151,6 +273,7 @@ function App() {
     setIsConfiguring(!isConfiguring);
     if (!isConfiguring) {
       setInputFolder(monitoredFolder);
+      setInputDestination(destinationFolder);
     }
     setError("");
   }
@@ -222,6 +345,17 @@ function App() {
                 <div className="folder-path">
                   {monitoredFolder}
                 </div>
+                {destinationFolder && (
+                  <>
+                    <div className="folder-label">
+                      <Folder className="folder-icon" />
+                      <span>Pasta de destino</span>
+                    </div>
+                    <div className="folder-path">
+                      {destinationFolder}
+                    </div>
+                  </>
+                )}
               </div>
             )}
           </div>
@@ -243,7 +377,31 @@ function App() {
                     disabled={isMonitoring}
                   />
                   <button
-                    onClick={selectFolder}
+                    onClick={() => selectFolder(setInputFolder)}
+                    disabled={isMonitoring}
+                    className="browse-btn"
+                    title="Procurar pasta"
+                  >
+                    <Folder className="icon" />
+                  </button>
+                </div>
+              </div>
+
+              <div className="form-group">
+                <label className="form-label">
+                  Pasta de destino (opcional)
+                </label>
+                <div className="input-group">
+                  <input
+                    type="text"
+                    value={inputDestination}
+                    onChange={(e) => setInputDestination(e.target.value)}
+                    placeholder="Caminho da pasta de destino..."
+                    className="form-input"
+                    disabled={isMonitoring}
+                  />
+                  <button
+                    onClick={() => selectFolder(setInputDestination)}
                     disabled={isMonitoring}
                     className="browse-btn"
                     title="Procurar pasta"
@@ -251,6 +409,15 @@ function App() {
                     <Folder className="icon" />
                   </button>
                 </div>
+                <label className="form-label">
+                  <input
+                    type="checkbox"
+                    checked={twoWay}
+                    onChange={(e) => setTwoWay(e.target.checked)}
+                    disabled={isMonitoring || !inputDestination.trim()}
+                  />
+                  Sincronização bidirecional
+                </label>
               </div>
 
               <div className="button-group">
@@ -294,6 +461,73 @@ function App() {
           )}
         </div>
 
+        {/* Pending conflicts */}
+        {pendingConflicts.length > 0 && (
+          <div className="changelog-card">
+            <div className="changelog-header">
+              <div className="changelog-title">
+                <h2>Conflitos Pendentes</h2>
+                <span className="changes-count">
+                  {pendingConflicts.length}
+                </span>
+              </div>
+            </div>
+            <div className="changes-list">
+              {pendingConflicts.map((conflict) => (
+                <div key={`${conflict.root_id}:${conflict.path}`} className="change-item">
+                  <div className="change-line">{conflict.path}</div>
+                  <div className="button-group">
+                    <button onClick={() => resolveConflict(conflict, "keep_source")} className="btn btn-secondary">
+                      Manter origem
+                    </button>
+                    <button onClick={() => resolveConflict(conflict, "keep_destination")} className="btn btn-secondary">
+                      Manter destino
+                    </button>
+                    <button onClick={() => resolveConflict(conflict, "keep_both")} className="btn btn-secondary">
+                      Manter ambos
+                    </button>
+                  </div>
+                </div>
+              ))}
+            </div>
+          </div>
+        )}
+
+        {/* Trash */}
+        {trashedItems.length > 0 && (
+          <div className="changelog-card">
+            <div className="changelog-header">
+              <div className="changelog-title">
+                <h2>Lixeira</h2>
+                <span className="changes-count">
+                  {trashedItems.length}
+                </span>
+              </div>
+              <div className="changelog-controls">
+                <button
+                  onClick={emptyTrash}
+                  className="control-btn"
+                  title="Esvaziar lixeira"
+                >
+                  <Trash2 className="icon" />
+                </button>
+              </div>
+            </div>
+            <div className="changes-list">
+              {trashedItems.map((item) => (
+                <div key={`${item.folder}:${item.id}`} className="change-item">
+                  <div className="change-line">{item.folder}/{item.path}</div>
+                  <div className="button-group">
+                    <button onClick={() => restoreFromTrash(item)} className="btn btn-secondary">
+                      Restaurar
+                    </button>
+                  </div>
+                </div>
+              ))}
+            </div>
+          </div>
+        )}
+
         {/* Changelog */}
         <div className="changelog-card">
           <div className="changelog-header">